@@ -0,0 +1,47 @@
+// Calls of a global function in a tight loop, against calls of the same function through a
+// local variable, which need no global lookup. The VM caches the slot of the global at each
+// `GetGlobal`, so both should take about the same time.
+//
+// Run with `cargo run --release --no-default-features --example call_bench`
+// (without the execution trace of the default features).
+use std::time::Instant;
+
+use rust_lox::output::Output;
+use rust_lox::vm::VM;
+
+const CALLS: usize = 1_000_000;
+
+// Best time of `runs` runs of `source`, in seconds
+fn best_time(source: &str, runs: usize) -> f64 {
+    let mut vm = VM::new();
+    vm.output = Output::capture();
+    vm.enable_compile_cache(1);
+    let mut best = f64::MAX;
+    for _ in 0..runs {
+        let start = Instant::now();
+        vm.interpret(source).expect("The benchmark failed");
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+    best
+}
+
+fn main() {
+    let setup = format!("fun inc(x) {{ return x + 1; }} var n = {};", CALLS);
+    let global = format!(
+        "{} {{ var total = 0; for (var i = 0; i < n; i = i + 1) total = inc(total); }}",
+        setup
+    );
+    let local = format!(
+        "{} {{ var f = inc; var total = 0; for (var i = 0; i < n; i = i + 1) total = f(total); }}",
+        setup
+    );
+    for (name, source) in [("global", &global), ("local", &local)] {
+        let best = best_time(source, 5);
+        println!(
+            "{} callee: best of 5 runs {:.1} ms, {:.0} ns per call",
+            name,
+            best * 1000.0,
+            best * 1e9 / CALLS as f64
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// A host program that uses a Lox script as its configuration file.
+// The host defines a few globals the script can read, runs the script,
+// then reads the values the script defined back into a Rust struct.
+//
+// Run with `cargo run --example embed_config`.
+use rust_lox::error::LoxError;
+use rust_lox::value::{format_value, Value};
+use rust_lox::vm::VM;
+
+const CONFIG: &str = r#"
+// `environment` and `cpus` are set by the host
+var workers = cpus * 2;
+if (environment == "production") {
+  workers = workers + 2;
+}
+var name = "server-" + environment;
+var debug = environment != "production";
+"#;
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub name: String,
+    pub workers: f64,
+    pub debug: bool,
+}
+
+pub fn load(environment: &str, cpus: f64) -> Result<Config, LoxError> {
+    let mut vm = VM::new();
+
+    // Strings are interned by the VM, a string value is the index of the interned string
+    let environment = Value::string(&mut vm.interner, environment);
+    vm.set_global("environment", environment);
+    vm.set_global("cpus", Value::Number(cpus));
+
+    // Errors are also printed to stderr; the returned LoxError has the same details
+    vm.interpret_from(CONFIG, "config.lox")?;
+
+    // Globals the script didn't define are None
+    let name = match vm.get_global("name") {
+        Some(value) => format_value(&value, &vm.interner),
+        None => "unnamed".to_owned(),
+    };
+    let workers = match vm.get_global("workers") {
+        Some(Value::Number(n)) => n,
+        _ => 1.0,
+    };
+    let debug = matches!(vm.get_global("debug"), Some(Value::Bool(true)));
+    Ok(Config {
+        name,
+        workers,
+        debug,
+    })
+}
+
+#[allow(dead_code)] // not called when the example is included by tests/examples.rs
+fn main() {
+    match load("production", 4.0) {
+        Ok(config) => println!("{:?}", config),
+        Err(error) => eprintln!("{}", error),
+    }
+}
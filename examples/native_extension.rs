@@ -0,0 +1,59 @@
+// Extending the language with natives written in Rust.
+// A native gets the VM (e.g. to intern strings) and its arguments,
+// the arity is checked by the VM before the native is called.
+//
+// Run with `cargo run --example native_extension`.
+use rust_lox::native::NativeResult;
+use rust_lox::output::Output;
+use rust_lox::value::{as_f64, format_value, Value};
+use rust_lox::vm::VM;
+
+// clamp(x, lo, hi)
+fn clamp(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    let mut numbers = [0.0; 3];
+    for (n, arg) in numbers.iter_mut().zip(args) {
+        // Returning an error message raises a runtime error at the call site
+        *n = as_f64(arg).ok_or_else(|| "clamp() expects numbers.".to_owned())?;
+    }
+    let [x, lo, hi] = numbers;
+    Ok(Value::Number(x.max(lo).min(hi)))
+}
+
+// text.upper(s)
+fn upper(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let upper = format_value(&args[0], &vm.interner).to_uppercase();
+    Ok(Value::StringObj(vm.interner.intern_string(upper)))
+}
+
+// text.repeat(s, n)
+fn repeat(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = format_value(&args[0], &vm.interner);
+    let n = as_f64(&args[1]).ok_or_else(|| "text.repeat() expects a count.".to_owned())?;
+    Ok(Value::StringObj(
+        vm.interner.intern_string(s.repeat(n as usize)),
+    ))
+}
+
+// Runs `source` with the natives above and returns what it printed
+pub fn run(source: &str) -> String {
+    let mut vm = VM::new();
+    vm.output = Output::capture();
+
+    // A plain global...
+    vm.define_native("clamp", 3, clamp);
+    // ...and natives grouped under a namespace, called as `text.upper(s)`
+    vm.define_namespace("text", &[("upper", 1, upper), ("repeat", 2, repeat)]);
+
+    if let Err(error) = vm.interpret(source) {
+        return error.to_string();
+    }
+    vm.output.out.captured().to_owned()
+}
+
+#[allow(dead_code)] // not called when the example is included by tests/examples.rs
+fn main() {
+    print!(
+        "{}",
+        run("print clamp(15, 0, 10);\nprint text.upper(\"lox\") + text.repeat(\"!\", 3);")
+    );
+}
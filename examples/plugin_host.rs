@@ -0,0 +1,183 @@
+// A host that extends itself with Lox plugins: every `.lox` file of a directory is a plugin.
+// Each plugin defines `register()`, which returns an object describing the plugin, and
+// `onEvent(e)`, which the host calls for every event. Plugins run in a VM of their own,
+// without the standard library and with a gas budget per call, so a plugin that loops forever
+// is disabled without affecting the others. Changed plugin files are reloaded.
+//
+// A plugin looks like:
+//
+//     class Info { name = "greeter"; version = 1; }
+//     fun register() { return Info(); }
+//     fun onEvent(e) { return "hello " + e; }
+//
+// Run with `cargo run --example plugin_host -- <plugins directory> <event>...`.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rust_lox::limits::Limits;
+use rust_lox::native::NativeResult;
+use rust_lox::output::Output;
+use rust_lox::value::{format_value, Value};
+use rust_lox::vm::VM;
+
+pub struct Plugin {
+    pub name: String,
+    pub version: f64,
+    // why the plugin was disabled, e.g. it failed to load or ran out of gas; it's skipped
+    // until its file changes
+    pub disabled: Option<String>,
+    path: PathBuf,
+    source: String, // as loaded, to find out if the file changed
+    vm: VM,
+    on_event: Value, // `onEvent`, called for every event
+}
+
+pub struct PluginHost {
+    pub plugins: Vec<Plugin>,
+}
+
+impl PluginHost {
+    // Loads the `.lox` files of `dir`, by file name. `gas` is the budget of each call of a plugin.
+    pub fn load_dir(dir: &Path, gas: u64) -> Result<PluginHost, String> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| format!("Could not read '{}': {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+            .collect();
+        paths.sort();
+        let plugins = paths.into_iter().map(|path| load(path, gas)).collect();
+        Ok(PluginHost { plugins })
+    }
+
+    // Sends the event to every enabled plugin and returns what each one returned, by name
+    pub fn dispatch(&mut self, event: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        for plugin in self.plugins.iter_mut().filter(|p| p.disabled.is_none()) {
+            let event = Value::string(&mut plugin.vm.interner, event);
+            match plugin.vm.call_function(plugin.on_event, &[event]) {
+                Ok(result) => {
+                    let result = format_value(&result, &plugin.vm.interner);
+                    results.push((plugin.name.clone(), result));
+                }
+                Err(error) => plugin.disabled = Some(error.to_string()),
+            }
+        }
+        results
+    }
+
+    // Reloads the plugins whose file changed and returns their names. A plugin keeps its VM:
+    // running the new source redefines its functions in place, so `onEvent` handles stay valid.
+    pub fn reload_changed(&mut self) -> Vec<String> {
+        let mut reloaded = Vec::new();
+        for plugin in &mut self.plugins {
+            let source = fs::read_to_string(&plugin.path).unwrap_or_default();
+            if source == plugin.source {
+                continue;
+            }
+            plugin.source = source;
+            plugin.disabled = None;
+            if let Err(msg) = register(plugin) {
+                plugin.disabled = Some(msg);
+            }
+            reloaded.push(plugin.name.clone());
+        }
+        reloaded
+    }
+
+    pub fn plugin(&self, name: &str) -> Option<&Plugin> {
+        self.plugins.iter().find(|p| p.name == name)
+    }
+}
+
+// The VM of a plugin: only the natives defined by the host, and a gas budget for every run
+fn sandbox(gas: u64) -> VM {
+    let limits = Limits {
+        max_gas: gas,
+        ..Limits::default()
+    };
+    let mut vm = VM::builder()
+        .stdlib(false)
+        .limits(limits)
+        .output(Output::capture())
+        .build()
+        .expect("The sandbox options are valid.");
+    vm.define_native("log", 1, log);
+    vm
+}
+
+// `log(message)` prints a line for the host, e.g. to debug a plugin
+fn log(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let line = format!("[plugin] {}", format_value(&args[0], &vm.interner));
+    vm.output.out.write_line(&line);
+    Ok(Value::Nil)
+}
+
+fn load(path: PathBuf, gas: u64) -> Plugin {
+    let source = fs::read_to_string(&path).unwrap_or_default();
+    let mut plugin = Plugin {
+        // until `register` names it
+        name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+        version: 0.0,
+        disabled: None,
+        path,
+        source,
+        vm: sandbox(gas),
+        on_event: Value::Nil,
+    };
+    if let Err(msg) = register(&mut plugin) {
+        plugin.disabled = Some(msg);
+    }
+    plugin
+}
+
+// Runs the source of the plugin, then calls `register()` and looks up `onEvent`
+fn register(plugin: &mut Plugin) -> Result<(), String> {
+    let origin = plugin.path.display().to_string();
+    let vm = &mut plugin.vm;
+    vm.interpret_from(&plugin.source, &origin)
+        .map_err(|e| e.to_string())?;
+    let register = vm
+        .get_global("register")
+        .ok_or("The plugin doesn't define register().")?;
+    let info = vm.call_function(register, &[]).map_err(|e| e.to_string())?;
+    if let Some(name) = vm.get_field(info, "name") {
+        plugin.name = format_value(&name, &vm.interner);
+    }
+    if let Some(Value::Number(version)) = vm.get_field(info, "version") {
+        plugin.version = version;
+    }
+    plugin.on_event = vm
+        .get_global("onEvent")
+        .ok_or("The plugin doesn't define onEvent(e).")?;
+    Ok(())
+}
+
+#[allow(dead_code)] // not called when the example is included by tests/examples.rs
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (dir, events) = match args.split_first() {
+        Some((dir, events)) => (dir, events),
+        None => {
+            eprintln!("Usage: plugin_host <plugins directory> <event>...");
+            return;
+        }
+    };
+    let mut host = match PluginHost::load_dir(Path::new(dir), 10_000) {
+        Ok(host) => host,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return;
+        }
+    };
+    for event in events {
+        host.reload_changed();
+        for (name, result) in host.dispatch(event) {
+            println!("{}: {}", name, result);
+        }
+    }
+    for plugin in &host.plugins {
+        if let Some(reason) = &plugin.disabled {
+            println!("{} (disabled): {}", plugin.name, reason);
+        }
+    }
+}
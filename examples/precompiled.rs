@@ -0,0 +1,49 @@
+// Running the same script many times, e.g. a pricing rule evaluated for every order.
+// With the compile cache enabled the script is compiled on the first run only;
+// later runs with the same source go straight to the VM.
+//
+// Run with `cargo run --example precompiled`.
+use rust_lox::error::LoxError;
+use rust_lox::value::Value;
+use rust_lox::vm::VM;
+
+const RULE: &str = r#"
+var price = quantity * unit_price;
+if (quantity >= 10) {
+  price = price * 0.9; // bulk discount
+}
+"#;
+
+// Prices every (quantity, unit_price) order, and returns the prices and
+// how many functions the VM compiled in total
+pub fn price_orders(orders: &[(f64, f64)]) -> Result<(Vec<f64>, usize), LoxError> {
+    // Remember the compiled code of up to 16 distinct sources
+    let mut vm = VM::builder()
+        .compile_cache(16)
+        .build()
+        .expect("The options are valid.");
+
+    let mut prices = Vec::with_capacity(orders.len());
+    for &(quantity, unit_price) in orders {
+        // Inputs are passed as globals, so the source (and the cache key) stays the same
+        vm.set_global("quantity", Value::Number(quantity));
+        vm.set_global("unit_price", Value::Number(unit_price));
+        vm.interpret(RULE)?;
+        match vm.get_global("price") {
+            Some(Value::Number(price)) => prices.push(price),
+            _ => prices.push(f64::NAN),
+        }
+    }
+    Ok((prices, vm.functions.len()))
+}
+
+#[allow(dead_code)] // not called when the example is included by tests/examples.rs
+fn main() {
+    match price_orders(&[(1.0, 5.0), (10.0, 5.0), (3.0, 2.5)]) {
+        Ok((prices, compiled)) => {
+            println!("prices: {:?}", prices);
+            println!("functions compiled: {}", compiled);
+        }
+        Err(error) => eprintln!("{}", error),
+    }
+}
@@ -0,0 +1,63 @@
+// A custom REPL: reads lines from any `BufRead` and collects what they print,
+// e.g. for a chat bot or a web console instead of a terminal.
+// It reuses `repl::eval` and `repl::command`, so `:globals` and friends work too.
+//
+// Run with `cargo run --example repl_custom` and type Lox code, one line at a time.
+use std::io::{self, BufRead};
+
+use rust_lox::output::{Output, Sink};
+use rust_lox::repl;
+use rust_lox::vm::VM;
+
+// Evaluates every line of `input` and returns one response per line:
+// what the line printed and its errors, or the output of a `:command`
+pub fn session(input: impl BufRead) -> Vec<String> {
+    let mut vm = VM::new();
+    let mut responses = Vec::new();
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let response = match line.trim().strip_prefix(':') {
+            Some(cmd) => repl::command(&mut vm, cmd.trim()),
+            None => {
+                // A fresh capture per line, so each response only has that line's output
+                vm.output = Output::capture();
+                repl::eval(&mut vm, &line);
+                let output = std::mem::take(&mut vm.output);
+                let printed = match (output.out, output.err) {
+                    (Sink::Capture(out), Sink::Capture(err)) => out + &err,
+                    _ => String::new(),
+                };
+                printed + &errors(&vm)
+            }
+        };
+        responses.push(response);
+    }
+    responses
+}
+
+// The VM reports errors on stderr, so they are read back from it: the compile errors of the
+// line, or else the runtime error it stopped with
+fn errors(vm: &VM) -> String {
+    if !vm.diagnostics.errors.is_empty() {
+        return vm
+            .diagnostics
+            .errors
+            .iter()
+            .map(|error| format!("{}\n", error))
+            .collect();
+    }
+    match &vm.last_error {
+        Some(error) => format!("{}\n", error),
+        None => String::new(),
+    }
+}
+
+#[allow(dead_code)] // not called when the example is included by tests/examples.rs
+fn main() {
+    for response in session(io::stdin().lock()) {
+        print!("{}", response);
+    }
+}
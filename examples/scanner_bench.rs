@@ -0,0 +1,47 @@
+// Scanner throughput on a synthetic ~5MB source.
+//
+// Run with `cargo run --release --example scanner_bench`.
+use std::time::Instant;
+
+use rust_lox::scanner::{LangLevel, Scanner, TokenType};
+
+const SNIPPET: &str = r#"// compute the sum of some numbers
+fun accumulate(values_count, start_value) {
+    var total_so_far = start_value;   // running total
+    for (var index = 0; index < values_count; index = index + 1) {
+        total_so_far = total_so_far + index * 1.5;
+    }
+    print "accumulated " + "value";
+    return total_so_far;
+}
+"#;
+
+// Number of tokens in `source`
+pub fn count_tokens(source: &str) -> usize {
+    let mut scanner = Scanner::with_level(source, LangLevel::newest());
+    let mut count = 0;
+    while scanner.scan_token().token_type != TokenType::Eof {
+        count += 1;
+    }
+    count
+}
+
+fn main() {
+    let source = SNIPPET.repeat(5_000_000 / SNIPPET.len());
+    let runs = 10;
+    let mut best = f64::MAX;
+    let mut tokens = 0;
+    for _ in 0..runs {
+        let start = Instant::now();
+        tokens = count_tokens(&source);
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+    println!(
+        "{} bytes, {} tokens: best of {} runs {:.1} ms, {:.0} MB/s",
+        source.len(),
+        tokens,
+        runs,
+        best * 1000.0,
+        source.len() as f64 / best / 1e6
+    );
+}
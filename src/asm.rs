@@ -0,0 +1,527 @@
+// Assembler for the labeled disassembly (`debug::disassemble_labeled`, `rust-lox disasm`):
+// turns its text back into functions, so bytecode tests can be written by hand and the
+// disassembler can be checked for missing operands with a round trip.
+//
+// The text is a list of sections, one per function, the top-level script first:
+//
+//   == <script> ==
+//      1 OP_CONSTANT 0 'Function id: 7'
+//      | OP_DEFINE_FUNCTION 1 'Identifier: 5: f'
+//   == f/1 (util.lox) ==
+//   L2:
+//      2 OP_GET_LOCAL 1
+//      | OP_JUMP_IF_FALSE -> L2
+//
+// A section title is the function's name with its arity, the variables its closures capture
+// (`inner/0 [local 1, upvalue 0]`) if any, and optionally the file it came from.
+// Instructions start with their source line, or `|` for the line of the previous instruction.
+// In functions compiled without lines (`CompileOptions::strip_lines`) every instruction starts
+// with `?` instead.
+// Constants are written inline at every use, with their index in the constant pool; interned
+// indices (`Identifier: 5: f`) are ignored and function ids name the section of the function
+// (the first function constant referenced is the second section, and so on).
+// Blank lines and lines starting with `;` are skipped.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::chunk::OpCode;
+use crate::function::{Capture, Function, SCRIPT_NAME};
+use crate::interner::Interner;
+use crate::value::{TypeTag, Value};
+
+const TYPE_TAGS: [TypeTag; 8] = [
+    TypeTag::Nil,
+    TypeTag::Bool,
+    TypeTag::Number,
+    TypeTag::String,
+    TypeTag::Function,
+    TypeTag::Range,
+    TypeTag::Class,
+    TypeTag::Instance,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize, // of the assembly text, starting at 1
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[asm line {}] {}", self.line, self.message)
+    }
+}
+
+// A jump whose offset is known once its label is
+struct Fixup {
+    offset: usize,
+    label: String,
+    line: usize, // of the assembly text
+}
+
+struct Section {
+    function: Function,
+    constants: Vec<Option<(String, Value)>>, // text and value, by index
+    labels: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+    line: Option<usize>, // source line of the last instruction
+}
+
+// Assembles `text` into functions, in the order of their sections. Function constants become
+// `Value::Function(first_id + section)`, for functions pushed at `first_id` of the VM's list.
+pub fn assemble(
+    text: &str,
+    interner: &mut Interner,
+    first_id: usize,
+) -> Result<Vec<Function>, AsmError> {
+    let mut sections: Vec<Section> = Vec::new();
+    // function id in the text -> section
+    let mut function_sections: HashMap<usize, usize> = HashMap::new();
+    for (idx, text_line) in text.lines().enumerate() {
+        let line = idx + 1;
+        let error = |message: String| AsmError { line, message };
+        let trimmed = text_line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') {
+            continue;
+        }
+        if let Some(title) = trimmed
+            .strip_prefix("== ")
+            .and_then(|rest| rest.strip_suffix(" =="))
+        {
+            let function = parse_title(title, interner).map_err(error)?;
+            sections.push(Section {
+                function,
+                constants: Vec::new(),
+                labels: HashMap::new(),
+                fixups: Vec::new(),
+                line: None,
+            });
+            continue;
+        }
+        let section = match sections.last_mut() {
+            Some(section) => section,
+            None => {
+                return Err(error(
+                    "Expected a section title like '== <script> =='.".to_owned(),
+                ))
+            }
+        };
+        if let Some(label) = trimmed.strip_suffix(':') {
+            let offset = section.function.chunk.len();
+            if section.labels.insert(label.to_owned(), offset).is_some() {
+                return Err(error(format!("Label '{}' is defined twice.", label)));
+            }
+            continue;
+        }
+
+        let (source_line, instruction) = match trimmed.split_once(' ') {
+            Some(("|", instruction)) => match section.line {
+                Some(source_line) => (Some(source_line), instruction),
+                None => {
+                    return Err(error(
+                        "The first instruction needs a line number.".to_owned(),
+                    ))
+                }
+            },
+            Some(("?", instruction)) => (None, instruction),
+            Some((number, instruction)) => match number.parse() {
+                Ok(source_line) => (Some(source_line), instruction),
+                Err(_) => return Err(error(format!("Expected a line number, got '{}'.", number))),
+            },
+            None => {
+                return Err(error(format!(
+                    "Expected an instruction after '{}'.",
+                    trimmed
+                )))
+            }
+        };
+        // the first instruction tells if the function has lines
+        let chunk = &mut section.function.chunk;
+        if chunk.is_empty() && source_line.is_none() {
+            chunk.strip_lines();
+        }
+        if chunk.has_lines() != source_line.is_some() {
+            return Err(error(
+                "Either every instruction of a function has a line number or none ('?')."
+                    .to_owned(),
+            ));
+        }
+        // the script is section 0, the functions follow in the order of their first use
+        let mut function_section = |id: usize| {
+            let next = function_sections.len() + 1;
+            *function_sections.entry(id).or_insert(next)
+        };
+        let op = parse_instruction(
+            instruction.trim(),
+            section,
+            interner,
+            &mut function_section,
+            first_id,
+        )
+        .map_err(error)?;
+        if let OpCode::Jump(_) | OpCode::JumpIfFalse(_) | OpCode::Loop(_) | OpCode::ForIn(..) = op {
+            section.fixups.push(Fixup {
+                offset: section.function.chunk.len(),
+                label: jump_label(instruction).to_owned(),
+                line,
+            });
+        }
+        section.function.chunk.write(op, source_line.unwrap_or(0));
+        section.line = source_line;
+    }
+
+    if sections.is_empty() {
+        return Err(AsmError {
+            line: text.lines().count().max(1),
+            message: "Expected a section title like '== <script> =='.".to_owned(),
+        });
+    }
+    if function_sections.len() >= sections.len() {
+        return Err(AsmError {
+            line: text.lines().count(),
+            message: format!(
+                "Function constants refer to {} functions, but there are only {} after the script.",
+                function_sections.len(),
+                sections.len() - 1
+            ),
+        });
+    }
+    sections.into_iter().map(finish_section).collect()
+}
+
+// Resolves the labels of the jumps and fills the constant pool
+fn finish_section(mut section: Section) -> Result<Function, AsmError> {
+    for fixup in &section.fixups {
+        let target = match section.labels.get(&fixup.label) {
+            Some(&target) => target,
+            None => {
+                return Err(AsmError {
+                    line: fixup.line,
+                    message: format!("Undefined label '{}'.", fixup.label),
+                })
+            }
+        };
+        let error = |message: &str| AsmError {
+            line: fixup.line,
+            message: message.to_owned(),
+        };
+        let op = section.function.chunk.op_mut(fixup.offset).unwrap();
+        match op {
+            OpCode::Loop(jump) => {
+                *jump = fixup
+                    .offset
+                    .checked_sub(target)
+                    .ok_or_else(|| error("OP_LOOP can only jump backwards."))?;
+            }
+            OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) | OpCode::ForIn(_, jump) => {
+                *jump = target
+                    .checked_sub(fixup.offset + 1)
+                    .ok_or_else(|| error("Only OP_LOOP can jump backwards."))?;
+            }
+            _ => unreachable!("only jumps have labels"),
+        }
+    }
+    // constants that no instruction uses, e.g. after optimizations, are nil
+    for constant in section.constants {
+        let value = constant.map_or(Value::Nil, |(_, value)| value);
+        section.function.chunk.add_constant(value);
+    }
+    section.function.chunk.freeze();
+    Ok(section.function)
+}
+
+// e.g. "f/1 (util.lox)", "inner/0 [local 1, upvalue 0]" or "<script>"
+fn parse_title(title: &str, interner: &mut Interner) -> Result<Function, String> {
+    let mut function = Function::new();
+    let name = match title.split_once(" (") {
+        Some((name, origin)) => {
+            let origin = origin
+                .strip_suffix(')')
+                .ok_or_else(|| format!("Expected ')' after the origin in '{}'.", title))?;
+            function.origin = Some(interner.intern(origin));
+            name
+        }
+        None => title,
+    };
+    let name = match name.split_once(" [") {
+        Some((name, captures)) => {
+            let captures = captures
+                .strip_suffix(']')
+                .ok_or_else(|| format!("Expected ']' after the captures in '{}'.", title))?;
+            function.upvalues = captures
+                .split(", ")
+                .map(parse_capture)
+                .collect::<Result<_, _>>()?;
+            name
+        }
+        None => name,
+    };
+    if name != SCRIPT_NAME {
+        let (name, arity) = match name.split_once('/') {
+            Some((name, arity)) => {
+                let arity = arity
+                    .parse()
+                    .map_err(|_| format!("Invalid arity '{}' of '{}'.", arity, name))?;
+                (name, arity)
+            }
+            None => (name, 0),
+        };
+        function.name = Some(interner.intern(name));
+        function.arity = arity;
+    }
+    Ok(function)
+}
+
+// e.g. "local 1" or "upvalue 0"
+fn parse_capture(capture: &str) -> Result<Capture, String> {
+    let invalid = || {
+        format!(
+            "Invalid capture '{}', expected 'local N' or 'upvalue N'.",
+            capture
+        )
+    };
+    let (kind, index) = capture.split_once(' ').ok_or_else(invalid)?;
+    let index = index.parse().map_err(|_| invalid())?;
+    match kind {
+        "local" => Ok(Capture {
+            index,
+            is_local: true,
+        }),
+        "upvalue" => Ok(Capture {
+            index,
+            is_local: false,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+// e.g. "L3" of "OP_JUMP -> L3", checked by `parse_instruction`
+fn jump_label(instruction: &str) -> &str {
+    instruction
+        .split_once(" -> ")
+        .map_or("", |(_, label)| label.trim())
+}
+
+fn parse_instruction(
+    instruction: &str,
+    section: &mut Section,
+    interner: &mut Interner,
+    function_section: &mut impl FnMut(usize) -> usize,
+    first_id: usize,
+) -> Result<OpCode, String> {
+    let (name, operands) = match instruction.split_once(' ') {
+        Some((name, operands)) => (name, operands.trim()),
+        None => (instruction, ""),
+    };
+    let no_operands = |op: OpCode| {
+        if operands.is_empty() {
+            Ok(op)
+        } else {
+            Err(format!("{} has no operands, got '{}'.", name, operands))
+        }
+    };
+    let byte = |operand: &str| {
+        operand.parse::<u8>().map_err(|_| {
+            format!(
+                "{} expects a number from 0 to 255, got '{}'.",
+                name, operand
+            )
+        })
+    };
+    let mut constant = || {
+        parse_constant_operand(
+            name,
+            operands,
+            section,
+            interner,
+            function_section,
+            first_id,
+        )
+    };
+    // the offset is filled in once the label is known
+    let jump = || match operands.strip_prefix("-> ") {
+        Some(_) => Ok(0),
+        None => Err(format!("{} expects '-> label', got '{}'.", name, operands)),
+    };
+    match name {
+        "OP_CONSTANT" => constant().map(OpCode::Constant),
+        "OP_ZERO" => no_operands(OpCode::Zero),
+        "OP_ONE" => no_operands(OpCode::One),
+        "OP_MINUS_ONE" => no_operands(OpCode::MinusOne),
+        "OP_NIL" => no_operands(OpCode::Nil),
+        "OP_TRUE" => no_operands(OpCode::True),
+        "OP_FALSE" => no_operands(OpCode::False),
+        "OP_POP" => no_operands(OpCode::Pop),
+        "OP_POP_N" => byte(operands).map(OpCode::PopN),
+        "OP_DEFINE_GLOBAL" => constant().map(OpCode::DefineGlobal),
+        "OP_DEFINE_FUNCTION" => constant().map(OpCode::DefineFunction),
+        "OP_GET_GLOBAL" => constant().map(OpCode::GetGlobal),
+        "OP_SET_GLOBAL" => constant().map(OpCode::SetGlobal),
+        "OP_GET_LOCAL" => byte(operands).map(OpCode::GetLocal),
+        "OP_SET_LOCAL" => byte(operands).map(OpCode::SetLocal),
+        "OP_GET_UPVALUE" => byte(operands).map(OpCode::GetUpvalue),
+        "OP_SET_UPVALUE" => byte(operands).map(OpCode::SetUpvalue),
+        "OP_CLOSURE" => constant().map(OpCode::Closure),
+        "OP_CLOSE_UPVALUE" => no_operands(OpCode::CloseUpvalue),
+        "OP_CLASS" => constant().map(OpCode::Class),
+        "OP_METHOD" => constant().map(OpCode::Method),
+        "OP_FIELD" => constant().map(OpCode::Field),
+        "OP_INHERIT" => no_operands(OpCode::Inherit),
+        "OP_GET_SUPER" => constant().map(OpCode::GetSuper),
+        "OP_GET_PROPERTY" => constant().map(OpCode::GetProperty),
+        "OP_SET_PROPERTY" => constant().map(OpCode::SetProperty),
+        "OP_EQUAL" => no_operands(OpCode::Equal),
+        "OP_IS_NIL" => no_operands(OpCode::IsNil),
+        "OP_IS_TRUE" => no_operands(OpCode::IsTrue),
+        "OP_IS_FALSE" => no_operands(OpCode::IsFalse),
+        "OP_GREATER" => no_operands(OpCode::Greater),
+        "OP_LESS" => no_operands(OpCode::Less),
+        "OP_GREATER_EQUAL" => no_operands(OpCode::GreaterEqual),
+        "OP_LESS_EQUAL" => no_operands(OpCode::LessEqual),
+        "OP_ADD" => no_operands(OpCode::Add),
+        "OP_ADD_NUMBERS" => no_operands(OpCode::AddNumbers),
+        "OP_SUBTRACT" => no_operands(OpCode::Subtract),
+        "OP_MULTIPLY" => no_operands(OpCode::Multiply),
+        "OP_DIVIDE" => no_operands(OpCode::Divide),
+        "OP_MODULO" => no_operands(OpCode::Modulo),
+        "OP_POWER" => no_operands(OpCode::Power),
+        "OP_BIT_AND" => no_operands(OpCode::BitAnd),
+        "OP_BIT_OR" => no_operands(OpCode::BitOr),
+        "OP_BIT_XOR" => no_operands(OpCode::BitXor),
+        "OP_SHIFT_LEFT" => no_operands(OpCode::ShiftLeft),
+        "OP_SHIFT_RIGHT" => no_operands(OpCode::ShiftRight),
+        "OP_BIT_NOT" => no_operands(OpCode::BitNot),
+        "OP_NOT" => no_operands(OpCode::Not),
+        "OP_NEGATE" => no_operands(OpCode::Negate),
+        "OP_PRINT" => no_operands(OpCode::Print),
+        "OP_JUMP" => jump().map(OpCode::Jump),
+        "OP_JUMP_IF_FALSE" => jump().map(OpCode::JumpIfFalse),
+        "OP_LOOP" => jump().map(OpCode::Loop),
+        "OP_RETURN" => no_operands(OpCode::Return),
+        "OP_CALL" => byte(operands).map(OpCode::Call),
+        "OP_RANGE" => no_operands(OpCode::Range),
+        "OP_IN" => no_operands(OpCode::In),
+        "OP_INDEX" => no_operands(OpCode::Index),
+        "OP_FOR_IN" => match operands.split_once(' ') {
+            Some((slot, label)) => {
+                let slot = byte(slot)?;
+                match label.strip_prefix("-> ") {
+                    Some(_) => Ok(OpCode::ForIn(slot, 0)),
+                    None => Err(format!("{} expects '-> label', got '{}'.", name, label)),
+                }
+            }
+            None => Err(format!("{} expects a slot and '-> label'.", name)),
+        },
+        "OP_IS_TYPE" => TYPE_TAGS
+            .iter()
+            .copied()
+            .find(|tag| tag.name() == operands)
+            .map(OpCode::IsType)
+            .ok_or_else(|| format!("Unknown type '{}'.", operands)),
+        "OP_POP_BELOW" => no_operands(OpCode::PopBelow),
+        "OP_CHECK_CONDITION" => no_operands(OpCode::CheckCondition),
+        "OP_CHECK_SLOT" => byte(operands).map(OpCode::CheckSlot),
+        "OP_CHECK_ARITY" => byte(operands).map(OpCode::CheckArity),
+        "OP_CHECK_OPERANDS" => constant().map(OpCode::CheckOperands),
+        "OP_NOP" => no_operands(OpCode::Nop),
+        _ => Err(format!("Unknown instruction '{}'.", name)),
+    }
+}
+
+// e.g. "3 'number: 1.5'": stores the constant at index 3 and returns the index
+fn parse_constant_operand(
+    name: &str,
+    operands: &str,
+    section: &mut Section,
+    interner: &mut Interner,
+    function_section: &mut impl FnMut(usize) -> usize,
+    first_id: usize,
+) -> Result<u8, String> {
+    let (idx, quoted) = operands
+        .split_once(' ')
+        .ok_or_else(|| format!("{} expects an index and a quoted constant.", name))?;
+    let idx: u8 = idx
+        .parse()
+        .map_err(|_| format!("Invalid constant index '{}'.", idx))?;
+    let text = quoted
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+        .ok_or_else(|| format!("Expected a constant in quotes, got '{}'.", quoted))?;
+    let slot = usize::from(idx);
+    if let Some(Some((existing, _))) = section.constants.get(slot) {
+        return if existing == text {
+            Ok(idx)
+        } else {
+            Err(format!(
+                "Constant {} is '{}', it can't also be '{}'.",
+                idx, existing, text
+            ))
+        };
+    }
+    let value = parse_value(text, interner, function_section, first_id)?;
+    if section.constants.len() <= slot {
+        section.constants.resize(slot + 1, None);
+    }
+    section.constants[slot] = Some((text.to_owned(), value));
+    Ok(idx)
+}
+
+// Parses the output of `debug_value`
+fn parse_value(
+    text: &str,
+    interner: &mut Interner,
+    function_section: &mut impl FnMut(usize) -> usize,
+    first_id: usize,
+) -> Result<Value, String> {
+    let invalid = || format!("Invalid constant '{}'.", text);
+    let (kind, rest) = match text.split_once(": ") {
+        Some((kind, rest)) => (kind, rest),
+        None if text == "nil" => return Ok(Value::Nil),
+        None => return Err(invalid()),
+    };
+    let id = |rest: &str| rest.parse::<usize>().map_err(|_| invalid());
+    match kind {
+        "bool" => rest.parse().map(Value::Bool).map_err(|_| invalid()),
+        "number" => rest.parse().map(Value::Number).map_err(|_| invalid()),
+        "int" => rest.parse().map(Value::Int).map_err(|_| invalid()),
+        "StringObj" | "Identifier" => {
+            // the interned index of the disassembled program doesn't matter
+            let (_, string) = rest.split_once(": ").ok_or_else(invalid)?;
+            let idx = interner.intern_string(unescape(string).ok_or_else(invalid)?);
+            Ok(match kind {
+                "StringObj" => Value::StringObj(idx),
+                _ => Value::Identifier(idx),
+            })
+        }
+        "Function id" => Ok(Value::Function(first_id + function_section(id(rest)?))),
+        "Native id" => Ok(Value::Native(id(rest)?)),
+        "range" => {
+            let (start, end) = rest.split_once("..").ok_or_else(invalid)?;
+            match (start.parse(), end.parse()) {
+                (Ok(start), Ok(end)) => Ok(Value::Range(start, end)),
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Reverts the escapes of `debug_value`
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '\\' => '\\',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
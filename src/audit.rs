@@ -0,0 +1,44 @@
+// What a script touched, in order, for embedders that need to explain a run
+// (e.g. rule engines): global variables it defined or assigned and natives it called.
+// Only recorded after `VM::enable_audit`, see `VM::take_audit_log`.
+use std::fmt;
+
+// Values are rendered like `print` does, when the event happens
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditEvent {
+    GlobalDefined {
+        name: String,
+        old: Option<String>, // the global was defined before, e.g. in an earlier REPL line
+        new: String,
+    },
+    GlobalAssigned {
+        name: String,
+        old: String,
+        new: String,
+    },
+    NativeCalled {
+        name: String,
+        args: Vec<String>,
+    },
+}
+
+// e.g. `define x = 1`, `assign x = 2 (was 1)`, `call sqrt(4)`
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditEvent::GlobalDefined { name, old, new } => {
+                write!(f, "define {} = {}", name, new)?;
+                match old {
+                    Some(old) => write!(f, " (was {})", old),
+                    None => Ok(()),
+                }
+            }
+            AuditEvent::GlobalAssigned { name, old, new } => {
+                write!(f, "assign {} = {} (was {})", name, new, old)
+            }
+            AuditEvent::NativeCalled { name, args } => {
+                write!(f, "call {}({})", name, args.join(", "))
+            }
+        }
+    }
+}
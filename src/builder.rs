@@ -0,0 +1,262 @@
+// Configures a VM in one place, for embedders and the CLI:
+//
+//     let vm = VM::builder().limits(limits).strict_bool(true).output(Output::capture()).build()?;
+//
+// `VM::new()` is the builder with every default. Options that don't fit together are reported by
+// `build`, instead of surfacing later as odd runtime behavior.
+use crate::compiler::CompileOptions;
+use crate::environment::Environment;
+use crate::gas::CostModel;
+use crate::limits::Limits;
+use crate::native::define_stdlib;
+use crate::output::Output;
+use crate::scanner::LangLevel;
+use crate::trace::Tracer;
+use crate::vm::VM;
+use crate::warnings::WarningLevels;
+
+pub struct VmBuilder {
+    limits: Limits,
+    cost_model: Option<CostModel>,
+    output: Output,
+    compile_options: CompileOptions,
+    warning_levels: WarningLevels,
+    checked_arithmetic: bool,
+    runtime_warnings: bool,
+    stdlib: bool,
+    compat_bare_natives: bool,
+    compile_cache: Option<usize>, // capacity
+    source_map: bool,
+    crash_reports: bool,
+    audit: bool,
+    tracer: Option<Tracer>,
+    call_main: bool,
+    args: Vec<String>,
+    environment: Option<Environment>,
+}
+
+impl Default for VmBuilder {
+    fn default() -> VmBuilder {
+        VmBuilder::new()
+    }
+}
+
+impl VmBuilder {
+    pub fn new() -> VmBuilder {
+        VmBuilder {
+            limits: Limits::default(),
+            cost_model: None,
+            output: Output::default(),
+            compile_options: CompileOptions::default(),
+            warning_levels: WarningLevels::default(),
+            checked_arithmetic: false,
+            runtime_warnings: false,
+            stdlib: true,
+            compat_bare_natives: false,
+            compile_cache: None,
+            source_map: false,
+            crash_reports: false,
+            audit: false,
+            tracer: None,
+            call_main: false,
+            args: Vec::new(),
+            environment: None,
+        }
+    }
+
+    pub fn limits(mut self, limits: Limits) -> VmBuilder {
+        self.limits = limits;
+        self
+    }
+
+    // Prices the gas of `Limits::max_gas`. A gas limit without a cost model uses the default one,
+    // which charges 1 per instruction.
+    pub fn cost_model(mut self, cost_model: CostModel) -> VmBuilder {
+        self.cost_model = Some(cost_model);
+        self
+    }
+
+    // Where `print` and `printErr` write to
+    pub fn output(mut self, output: Output) -> VmBuilder {
+        self.output = output;
+        self
+    }
+
+    pub fn compile_options(mut self, options: CompileOptions) -> VmBuilder {
+        self.compile_options = options;
+        self
+    }
+
+    pub fn lang_level(mut self, level: LangLevel) -> VmBuilder {
+        self.compile_options.lang_level = level;
+        self
+    }
+
+    pub fn optimize(mut self, optimize: bool) -> VmBuilder {
+        self.compile_options.optimize = optimize;
+        self
+    }
+
+    pub fn integers(mut self, integers: bool) -> VmBuilder {
+        self.compile_options.integers = integers;
+        self
+    }
+
+    pub fn paranoid(mut self, paranoid: bool) -> VmBuilder {
+        self.compile_options.paranoid = paranoid;
+        self
+    }
+
+    pub fn strip_lines(mut self, strip_lines: bool) -> VmBuilder {
+        self.compile_options.strip_lines = strip_lines;
+        self
+    }
+
+    pub fn max_errors(mut self, max_errors: usize) -> VmBuilder {
+        self.compile_options.max_errors = max_errors;
+        self
+    }
+
+    pub fn max_nesting(mut self, max_nesting: usize) -> VmBuilder {
+        self.compile_options.max_nesting = max_nesting;
+        self
+    }
+
+    pub fn warning_levels(mut self, levels: WarningLevels) -> VmBuilder {
+        self.warning_levels = levels;
+        self
+    }
+
+    pub fn strict_bool(mut self, strict_bool: bool) -> VmBuilder {
+        self.compile_options.strict_bool = strict_bool;
+        self
+    }
+
+    pub fn checked_arithmetic(mut self, checked: bool) -> VmBuilder {
+        self.checked_arithmetic = checked;
+        self
+    }
+
+    pub fn runtime_warnings(mut self, warn: bool) -> VmBuilder {
+        self.runtime_warnings = warn;
+        self
+    }
+
+    // Without the standard library, the VM starts without natives, e.g. for a sandbox whose
+    // host defines every native itself
+    pub fn stdlib(mut self, stdlib: bool) -> VmBuilder {
+        self.stdlib = stdlib;
+        self
+    }
+
+    // Also define the members of namespaces as bare globals, e.g. `sqrt` for `math.sqrt`
+    pub fn compat_bare_natives(mut self, enabled: bool) -> VmBuilder {
+        self.compat_bare_natives = enabled;
+        self
+    }
+
+    // See `VM::enable_compile_cache`
+    pub fn compile_cache(mut self, capacity: usize) -> VmBuilder {
+        self.compile_cache = Some(capacity);
+        self
+    }
+
+    // See `VM::enable_source_map`
+    pub fn source_map(mut self, enabled: bool) -> VmBuilder {
+        self.source_map = enabled;
+        self
+    }
+
+    // See `VM::enable_crash_reports`
+    pub fn crash_reports(mut self, enabled: bool) -> VmBuilder {
+        self.crash_reports = enabled;
+        self
+    }
+
+    // See `VM::enable_audit`
+    pub fn audit(mut self, enabled: bool) -> VmBuilder {
+        self.audit = enabled;
+        self
+    }
+
+    // See `VM::enable_trace`
+    pub fn trace(mut self, tracer: Tracer) -> VmBuilder {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    // See `VM::run_script`
+    pub fn call_main(mut self, enabled: bool) -> VmBuilder {
+        self.call_main = enabled;
+        self
+    }
+
+    // See `VM::set_args`
+    pub fn args(mut self, args: Vec<String>) -> VmBuilder {
+        self.args = args;
+        self
+    }
+
+    // What `env` reads. The default is the process environment, or an empty one for a VM
+    // without the standard library, which is usually a sandbox.
+    pub fn environment(mut self, environment: Environment) -> VmBuilder {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn build(self) -> Result<VM, String> {
+        if self.limits.max_call_depth == 0 {
+            return Err(
+                "The call depth limit must be at least 1, the script is a call.".to_owned(),
+            );
+        }
+        if self.compile_options.max_errors == 0 {
+            return Err("The compile error limit must be at least 1.".to_owned());
+        }
+        if self.compile_options.max_nesting == 0 {
+            return Err("The nesting limit must be at least 1.".to_owned());
+        }
+        if self.compat_bare_natives && !self.stdlib {
+            return Err(
+                "Bare natives are aliases of the standard library, which is disabled.".to_owned(),
+            );
+        }
+
+        let mut vm = VM::empty(self.limits);
+        vm.cost_model = self.cost_model.unwrap_or_default();
+        vm.output = self.output;
+        vm.compile_options = self.compile_options;
+        vm.warning_levels = self.warning_levels;
+        vm.checked_arithmetic = self.checked_arithmetic;
+        vm.runtime_warnings = self.runtime_warnings;
+        vm.call_main = self.call_main;
+        vm.args = self.args;
+        vm.environment = self.environment.unwrap_or(if self.stdlib {
+            Environment::Process
+        } else {
+            Environment::Empty
+        });
+        if self.compat_bare_natives {
+            vm.enable_compat_bare_natives();
+        }
+        if self.stdlib {
+            define_stdlib(&mut vm);
+        }
+        if let Some(capacity) = self.compile_cache {
+            vm.enable_compile_cache(capacity);
+        }
+        if self.source_map {
+            vm.enable_source_map();
+        }
+        if self.crash_reports {
+            vm.enable_crash_reports();
+        }
+        if self.audit {
+            vm.enable_audit();
+        }
+        if let Some(tracer) = self.tracer {
+            vm.enable_trace(tracer);
+        }
+        Ok(vm)
+    }
+}
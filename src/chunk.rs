@@ -1,26 +1,83 @@
-use crate::value::{Value, ValueArray};
+use std::mem;
+use std::ops::Range;
+
+use crate::value::{TypeTag, Value, ValueArray};
 
 #[derive(Clone, Copy, Debug)]
 pub enum OpCode {
     Constant(u8), // u8 = constant_idx
+    // Common numbers, which don't need a slot in the constant pool
+    Zero,
+    One,
+    MinusOne,
     Nil,
     True,
     False,
     Pop,
+    PopN(u8), // u8 = number of values to pop, e.g. locals leaving a scope
     // Global u8 = constant_idx (constants store name of var)
     DefineGlobal(u8),
+    // like DefineGlobal, but redefining a global function replaces the old function's body,
+    // so references to the old function (e.g. in other globals) call the new one
+    DefineFunction(u8),
     GetGlobal(u8),
     SetGlobal(u8),
     // Local u8 = idx on stakc/locals array
     GetLocal(u8),
     SetLocal(u8),
+    // Upvalue u8 = idx in the closure's captured variables
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    // u8 = constant_idx of a function that captures variables (`Function::upvalues`),
+    // pushes a closure of it with the captured variables of the current frame
+    Closure(u8),
+    // Pops the local on top of the stack, which a closure captured: its upvalue keeps the value
+    CloseUpvalue,
+    // u8 = constant_idx of the class name, pushes a new class
+    Class(u8),
+    // u8 = constant_idx of the method name, pops the method and adds it to the class below it
+    Method(u8),
+    // u8 = constant_idx of the field name, pops the method computing the field's default value
+    // and adds it to the fields of the class below it
+    Field(u8),
+    // copies the methods of the superclass below the class on top of the stack, pops the class
+    Inherit,
+    // u8 = constant_idx of the method name: pops the superclass and the instance, pushes the
+    // superclass' method bound to the instance, e.g. for `super.init()`
+    GetSuper(u8),
+    // u8 = constant_idx of the field name: pops the instance, pushes the field's value
+    // (or the method of its class with that name, bound to the instance)
+    GetProperty(u8),
+    // pops the value and the instance, sets the field and pushes the value back
+    SetProperty(u8),
     Equal,
+    // `Equal` with a literal nil, true or false, which the compiler emits instead of the literal
+    // for `x == nil`, `x == true` and `x == false`: pops the value, pushes whether it's equal
+    IsNil,
+    IsTrue,
+    IsFalse,
     Greater,
     Less,
+    // `a >= b` and `a <= b`, the negations of `Less` and `Greater` (so a NaN operand makes them
+    // true, like `!(a < b)`), of their own so errors name the operator of the source
+    GreaterEqual,
+    LessEqual,
     Add,
+    // `Add` of operands the compiler proved are numbers
+    AddNumbers,
     Subtract,
     Multiply,
     Divide,
+    // the remainder of the truncated division, with the sign of the dividend: -7 % 3 is -1
+    Modulo,
+    Power, // `**`, right-associative
+    // bitwise operators on integral numbers, see `VM::bitwise_op`
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight, // arithmetic, keeps the sign
+    BitNot,
     Not,
     Negate,
     Print,
@@ -30,16 +87,52 @@ pub enum OpCode {
     Loop(usize),
     Return,
     Call(u8), // u8 = number of args
+    // start..end from the two values on top of the stack
+    Range,
+    // whether the value is in the range on top of the stack
+    In,
+    // pops the index and the indexed value (a range), pushes the element
+    Index,
+    // next iteration of `for (var x in range)`: u8 = slot of `x`, followed by the slots of the
+    // range and the index of the next element; usize = offset to jump over when the range is done
+    ForIn(u8, usize),
+    // Pushes whether the value on top of the stack has the type, without popping it
+    IsType(TypeTag),
+    // Pops the value below the top one, e.g. the operand of `x is Number` under the result
+    PopBelow,
+    // With `CompileOptions::strict_bool`, after `IsType(Bool)` of a condition: pops the result,
+    // and reports the condition below it if it isn't a boolean
+    CheckCondition,
+    // Checks emitted in paranoid mode (`CompileOptions::paranoid`), to catch miscompiled code:
+    // the slot of the following GetLocal/SetLocal is inside of the frame
+    CheckSlot(u8),
+    // u8 = arity, the frame of the function holds exactly its arguments (and the function)
+    CheckArity(u8),
+    // the operands of the following binary instruction have valid types,
+    // u8 = constant_idx of the expression's source, for the error
+    CheckOperands(u8),
+    // Does nothing. Instructions can be overwritten with it in place, without re-offsetting jumps
+    Nop,
 }
 
+#[derive(Clone)]
 pub struct Chunk {
     // Vec is already a dynamic array, also see:
     // https://doc.rust-lang.org/std/vec/struct.Vec.html#capacity-and-reallocation
     // When count > capacity, capacity will be doubled (as of today's rust vec implementation)
     // https://github.com/rust-lang/rust/blob/68dfa07e3bbbfe9100a9b1047c274717bdf452a1/library/alloc/src/raw_vec.rs#L422
-    pub code: Vec<OpCode>,
-    pub constants: ValueArray,
-    pub lines: Vec<usize>,
+    code: Vec<OpCode>,
+    constants: ValueArray,
+    // source line of each instruction, None once stripped (see `CompileOptions::strip_lines`)
+    lines: Option<Vec<usize>>,
+    // Set once compilation finishes, so the VM can rely on code and constants not changing under it
+    frozen: bool,
+}
+
+impl Default for Chunk {
+    fn default() -> Chunk {
+        Chunk::new()
+    }
 }
 
 impl Chunk {
@@ -47,17 +140,141 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: ValueArray::new(),
-            lines: Vec::new(),
+            lines: Some(Vec::new()),
+            frozen: false,
         }
     }
 
     pub fn write(&mut self, byte: OpCode, line: usize) {
+        self.assert_writable();
         self.code.push(byte);
-        self.lines.push(line);
+        if let Some(lines) = &mut self.lines {
+            lines.push(line);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn code(&self) -> &[OpCode] {
+        &self.code
+    }
+
+    // Every instruction with its offset, in order.
+    // The one place that knows how instructions are laid out in `code`; tools walk chunks with
+    // this and `jump_target` instead of computing offsets themselves.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        self.code.iter().copied().enumerate()
+    }
+
+    // Offset of the instruction a jump or loop at `offset` continues at, None for other
+    // instructions. The target of a forward jump can be `len()`, i.e. the end of the chunk.
+    pub fn jump_target(&self, offset: usize, op: &OpCode) -> Option<usize> {
+        match *op {
+            OpCode::Jump(jump) | OpCode::JumpIfFalse(jump) | OpCode::ForIn(_, jump) => {
+                Some(offset + jump + 1)
+            }
+            OpCode::Loop(jump) => offset.checked_sub(jump),
+            _ => None,
+        }
+    }
+
+    pub fn op(&self, offset: usize) -> Option<OpCode> {
+        self.code.get(offset).copied()
+    }
+
+    // e.g. to fill in the offset of a jump once its target is known
+    pub fn op_mut(&mut self, offset: usize) -> Option<&mut OpCode> {
+        self.assert_writable();
+        self.code.get_mut(offset)
+    }
+
+    // Type of the value pushed by the instruction at `offset`, if it is a literal,
+    // e.g. to fold an `is` check of it
+    pub fn literal_type(&self, offset: usize) -> Option<TypeTag> {
+        match self.op(offset)? {
+            OpCode::Nil => Some(TypeTag::Nil),
+            OpCode::True | OpCode::False => Some(TypeTag::Bool),
+            OpCode::Zero | OpCode::One | OpCode::MinusOne => Some(TypeTag::Number),
+            OpCode::Constant(idx) => self.constant(idx.into()).map(TypeTag::of),
+            _ => None,
+        }
+    }
+
+    // None if the offset is out of bounds or the lines were stripped
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.lines.as_ref()?.get(offset).copied()
+    }
+
+    pub fn has_lines(&self) -> bool {
+        self.lines.is_some()
+    }
+
+    // Bytes taken by the instructions, constants and lines, e.g. to measure what stripping saves
+    pub fn memory_size(&self) -> usize {
+        let lines = self.lines.as_ref().map_or(0, Vec::len);
+        self.code.len() * mem::size_of::<OpCode>()
+            + self.constants.len() * mem::size_of::<Value>()
+            + lines * mem::size_of::<usize>()
+    }
+
+    // Drops the line of every instruction (and of those written later), to save memory.
+    // Doesn't change the code, so frozen chunks can be stripped too.
+    pub fn strip_lines(&mut self) {
+        self.lines = None;
+    }
+
+    // Replaces every constant, e.g. to move the chunk to another VM (see `CompilationUnit`).
+    // Doesn't change the code, so frozen chunks can be remapped too.
+    pub(crate) fn map_constants(&mut self, f: impl Fn(Value) -> Value) {
+        self.constants.map_in_place(f);
+    }
+
+    // Removes every instruction from `len` on
+    pub fn truncate(&mut self, len: usize) {
+        self.assert_writable();
+        self.code.truncate(len);
+        if let Some(lines) = &mut self.lines {
+            lines.truncate(len);
+        }
+    }
+
+    // Replaces the instructions in `range` with Nop, keeping their lines.
+    // Jumps over or into the range stay valid.
+    pub fn nop_out(&mut self, range: Range<usize>) {
+        self.assert_writable();
+        for op in &mut self.code[range] {
+            *op = OpCode::Nop;
+        }
+    }
+
+    pub fn constants(&self) -> &ValueArray {
+        &self.constants
+    }
+
+    pub fn constant(&self, idx: usize) -> Option<&Value> {
+        self.constants.get(idx)
     }
 
     pub fn add_constant(&mut self, v: Value) -> usize {
-        self.constants.write(v);
-        self.constants.values.len() - 1
+        self.assert_writable();
+        self.constants.push(v)
+    }
+
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    fn assert_writable(&self) {
+        debug_assert!(!self.frozen, "Chunk is frozen after compilation.");
     }
 }
@@ -0,0 +1,56 @@
+// Classes, their instances and bound methods. Like functions, they are saved to lists in the VM,
+// while `Value::Class`, `Value::Instance` and `Value::BoundMethod` store the index.
+use std::collections::HashMap;
+
+use crate::{
+    interner::{Interner, StringObjIdx},
+    value::Value,
+};
+
+pub struct Class {
+    pub name: StringObjIdx,
+    pub methods: HashMap<StringObjIdx, Value>, // functions or closures, by name
+    // Declared fields, e.g. `x = 0;`, in declaration order (those of the superclass first), with
+    // the method that computes the default value of each new instance
+    pub fields: Vec<(StringObjIdx, Value)>,
+}
+
+impl Class {
+    pub fn new(name: StringObjIdx) -> Class {
+        Class {
+            name,
+            methods: HashMap::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    // The `init` method, called with the arguments of a call of the class
+    pub fn initializer(&self, interner: &Interner) -> Option<Value> {
+        let init = interner.get("init")?;
+        self.methods.get(&init).copied()
+    }
+}
+
+#[derive(Clone)]
+pub struct Instance {
+    pub class: usize, // = idx in the class list in VM
+    pub fields: HashMap<StringObjIdx, Value>,
+    pub frozen: bool, // set by the `freeze` native, fields can't be set anymore
+}
+
+impl Instance {
+    pub fn new(class: usize) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+            frozen: false,
+        }
+    }
+}
+
+// A method read from an instance, e.g. `p.area`: calling it runs the method with `this` = receiver
+#[derive(Clone, Copy)]
+pub struct BoundMethod {
+    pub receiver: Value, // the instance
+    pub method: Value,   // a function or closure
+}
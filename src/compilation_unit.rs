@@ -0,0 +1,89 @@
+// Compiled code that doesn't belong to a VM yet, e.g. to compile on another thread while the VM
+// runs, or to compile a source once for several VMs:
+//
+//     let unit = CompilationUnit::compile(source, None, options, &vm.namespace_names())?;
+//     let f_idx = vm.install(&unit)?;
+//     vm.run_compiled(f_idx)?;
+//
+// The unit has its own interner, and its functions are numbered from 0. `VM::install` interns
+// the unit's strings in the VM and renumbers the functions (see `CompilationUnit::remap`).
+use std::collections::HashSet;
+
+use crate::compiler::{CompileDiagnostic, CompileOptions, Diagnostics, Parser};
+use crate::error::LoxError;
+use crate::function::Function;
+use crate::interner::{Interner, StringObjIdx};
+use crate::value::Value;
+
+pub struct CompilationUnit {
+    interner: Interner,
+    // the functions declared in the source, by id, and the top-level script
+    functions: Vec<Function>,
+    script: Function,
+    // reported again by every `VM::install`, which applies its warning levels
+    pub warnings: Vec<CompileDiagnostic>,
+}
+
+impl CompilationUnit {
+    // Compiles `source` like `VM::compile_source`. `namespaces` are the namespaces of the VMs
+    // the unit will be installed in (see `VM::namespace_names`), e.g. for `math.sqrt`.
+    pub fn compile(
+        source: &str,
+        origin: Option<&str>,
+        options: CompileOptions,
+        namespaces: &[String],
+    ) -> Result<CompilationUnit, LoxError> {
+        let mut interner = Interner::default();
+        let namespaces: HashSet<StringObjIdx> = namespaces
+            .iter()
+            .map(|name| interner.intern(name))
+            .collect();
+        let origin = origin.map(|origin| interner.intern(origin));
+        let mut diagnostics = Diagnostics::default();
+        let parser = Parser::new(
+            source,
+            options,
+            &namespaces,
+            &mut interner,
+            0,
+            &mut diagnostics,
+            origin,
+        );
+        match parser.compile() {
+            Some(compiled) if diagnostics.errors.is_empty() => Ok(CompilationUnit {
+                interner,
+                functions: compiled.functions,
+                script: compiled.script,
+                warnings: diagnostics.warnings,
+            }),
+            _ => Err(LoxError::Compile(diagnostics.errors)),
+        }
+    }
+
+    // The unit's functions for a VM whose functions Vec has `first_id` entries, in the order
+    // to append them: the declared functions, then the script. `strings` maps the unit's
+    // string ids to the VM's.
+    pub(crate) fn remap(&self, strings: &[StringObjIdx], first_id: usize) -> Vec<Function> {
+        let remap_value = |value: Value| match value {
+            Value::StringObj(idx) => Value::StringObj(strings[idx as usize]),
+            Value::Identifier(idx) => Value::Identifier(strings[idx as usize]),
+            Value::Function(f_idx) => Value::Function(f_idx + first_id),
+            other => other,
+        };
+        self.functions
+            .iter()
+            .chain(Some(&self.script))
+            .map(|function| {
+                let mut function = function.clone();
+                function.name = function.name.map(|idx| strings[idx as usize]);
+                function.origin = function.origin.map(|idx| strings[idx as usize]);
+                function.chunk.map_constants(remap_value);
+                function
+            })
+            .collect()
+    }
+
+    pub(crate) fn interner(&self) -> &Interner {
+        &self.interner
+    }
+}
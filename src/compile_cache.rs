@@ -0,0 +1,99 @@
+// Compiled top-level functions of recently interpreted sources, see `VM::enable_compile_cache`.
+// Hosts that run the same snippet many times (e.g. template expressions) only compile it once.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::compiler::{CompileDiagnostic, CompileOptions};
+use crate::interner::StringObjIdx;
+
+// Everything besides the source that changes the compiled code
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompileSettings {
+    pub origin: Option<StringObjIdx>,
+    pub options: CompileOptions,
+    pub expression: bool, // compiled with `compile_expression`
+}
+
+struct Entry {
+    hash: u64,
+    source: Box<str>, // compared on lookup, so sources with the same hash don't collide
+    settings: CompileSettings,
+    f_idx: usize,                     // compiled top-level function in `vm.functions`
+    warnings: Vec<CompileDiagnostic>, // reported again on every hit
+    last_used: u64,
+}
+
+pub struct CompileCache {
+    capacity: usize,
+    entries: Vec<Entry>,
+    clock: u64, // incremented on every lookup, for least-recently-used eviction
+}
+
+impl CompileCache {
+    pub fn new(capacity: usize) -> CompileCache {
+        CompileCache {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            clock: 0,
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        source: &str,
+        settings: CompileSettings,
+    ) -> Option<(usize, &[CompileDiagnostic])> {
+        self.clock += 1;
+        let hash = hash(source, settings);
+        let entry = self.entries.iter_mut().find(|entry| {
+            entry.hash == hash && entry.settings == settings && &*entry.source == source
+        })?;
+        entry.last_used = self.clock;
+        Some((entry.f_idx, &entry.warnings))
+    }
+
+    pub fn insert(
+        &mut self,
+        source: &str,
+        settings: CompileSettings,
+        f_idx: usize,
+        warnings: Vec<CompileDiagnostic>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            let oldest = (0..self.entries.len())
+                .min_by_key(|&i| self.entries[i].last_used)
+                .unwrap();
+            self.entries.swap_remove(oldest);
+        }
+        self.entries.push(Entry {
+            hash: hash(source, settings),
+            source: source.into(),
+            settings,
+            f_idx,
+            warnings,
+            last_used: self.clock,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+fn hash(source: &str, settings: CompileSettings) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    settings.hash(&mut hasher);
+    hasher.finish()
+}
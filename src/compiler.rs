@@ -1,14 +1,128 @@
 use crate::{
-    chunk::OpCode,
-    function::Function,
-    interner::Interner,
-    scanner::{Scanner, Token, TokenType},
-    value::Value,
+    chunk::{Chunk, OpCode},
+    function::{Capture, Function, StatementRange},
+    interner::{Interner, StringObjIdx},
+    scanner::{LangLevel, Scanner, Token, TokenType},
+    value::{TypeTag, Value},
+    warnings::{self, Level, WarningLevels},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt, mem,
 };
-use std::{collections::HashMap, convert::TryFrom, mem};
 
 pub const USIZE_COUNT: usize = u8::MAX as usize + 1;
 
+// Settings that change how source code is compiled
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompileOptions {
+    pub lang_level: LangLevel,  // keywords the scanner accepts
+    pub optimize: bool,         // e.g. eliminate branches whose condition is a literal
+    pub integers: bool,         // integer literals are `Value::Int` instead of floats
+    pub statement_ranges: bool, // record `Function::statement_ranges`, e.g. for tooling
+    pub max_errors: usize,      // compilation stops after reporting this many errors
+    // nested expressions, statements and function bodies the parser recurses into,
+    // deeper code is a compile error instead of overflowing the stack
+    pub max_nesting: usize,
+    // emit runtime checks of what the compiler assumes, to chase miscompilations
+    // (roughly doubles the code size, see `OpCode::CheckSlot` and the others)
+    pub paranoid: bool,
+    // drop the source line of every instruction once compiled, to save memory; runtime errors
+    // then show "[unknown line]"
+    pub strip_lines: bool,
+    // conditions and `!` only accept booleans, instead of treating every other value as truthy.
+    // Checked at runtime; the compiler keeps conditions that aren't booleans for the check.
+    pub strict_bool: bool,
+}
+
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+pub const DEFAULT_MAX_NESTING: usize = 256;
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            lang_level: LangLevel::default(),
+            optimize: false,
+            integers: false,
+            statement_ranges: false,
+            max_errors: DEFAULT_MAX_ERRORS,
+            max_nesting: DEFAULT_MAX_NESTING,
+            paranoid: false,
+            strip_lines: false,
+            strict_bool: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileDiagnostic {
+    pub severity: Severity,
+    pub code: Option<&'static str>, // warnings have a code, see `warnings::CODES`
+    pub line: usize,
+    pub column: Option<usize>, // in chars, starting at 1; None if the position is unknown
+    pub message: String,
+    pub snippet: Option<String>, // source around the error with a caret below, see `render_snippet`
+}
+
+// e.g. `[line 2] Warning W0001: Condition is always false.`
+impl fmt::Display for CompileDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "Warning",
+            Severity::Error => "Error",
+        };
+        write!(f, "[line {}] {}", self.line, severity)?;
+        if let Some(code) = self.code {
+            write!(f, " {}", code)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+// Everything reported by a compilation
+#[derive(Default)]
+pub struct Diagnostics {
+    pub errors: Vec<CompileDiagnostic>,
+    pub warnings: Vec<CompileDiagnostic>,
+    pub notes: Vec<String>, // reported after the errors, e.g. that compilation stopped at `max_errors`
+}
+
+impl Diagnostics {
+    pub fn clear(&mut self) {
+        self.errors.clear();
+        self.warnings.clear();
+        self.notes.clear();
+    }
+
+    // Errors and warnings, ordered by position
+    pub fn all(&self) -> Vec<&CompileDiagnostic> {
+        let mut all: Vec<_> = self.errors.iter().chain(&self.warnings).collect();
+        all.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+        all
+    }
+
+    // Applies the configured levels: drops the warnings that are off and turns the denied ones into errors
+    pub fn apply_levels(&mut self, levels: &WarningLevels) {
+        for mut warning in mem::take(&mut self.warnings) {
+            match levels.level(warning.code) {
+                Level::Off => {}
+                Level::Warn => self.warnings.push(warning),
+                Level::Error => {
+                    warning.severity = Severity::Error;
+                    self.errors.push(warning);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, PartialOrd)]
 enum Precedence {
     // "When derived on enums, variants are ordered by their top-to-bottom discriminant order."
@@ -18,11 +132,17 @@ enum Precedence {
     Or,         // or
     And,        // and
     Equality,   // == !=
-    Comparison, // < > <= >=
+    BitOr,      // |
+    BitXor,     // ^
+    BitAnd,     // &, above `==` unlike C, so `a & 1 == 1` is `(a & 1) == 1`
+    Comparison, // < > <= >= in
+    Range,      // ..
+    Shift,      // << >>
     Term,       // + -
-    Factor,     // * /
+    Factor,     // * / %
+    Power,      // **, binds the operand of a unary operator: -2 ** 2 is -(2 ** 2)
     Unary,      // ! -
-    Call,       // . ()
+    Call,       // . () []
     Primary,
 }
 impl Precedence {
@@ -33,10 +153,16 @@ impl Precedence {
             Assignment => Or,
             Or => And,
             And => Equality,
-            Equality => Comparison,
-            Comparison => Term,
+            Equality => BitOr,
+            BitOr => BitXor,
+            BitXor => BitAnd,
+            BitAnd => Comparison,
+            Comparison => Range,
+            Range => Shift,
+            Shift => Term,
             Term => Factor,
-            Factor => Unary,
+            Factor => Power,
+            Power => Unary,
             Unary => Call,
             Call => Primary,
             Primary => None,
@@ -69,18 +195,54 @@ impl<'src> ParseRule<'src> {
 pub struct Local<'src> {
     name: Token<'src>,
     depth: i32,
+    is_captured: bool, // by a closure, so it's closed over when it goes out of scope
 }
 
 impl<'src> Local<'src> {
     pub fn new(name: Token<'src>, depth: i32) -> Local<'src> {
-        Local { name, depth }
+        Local {
+            name,
+            depth,
+            is_captured: false,
+        }
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum FunctionType {
-    TypeFunction, // function code
-    TypeScript,   // top-level code
+    TypeFunction,    // function code
+    TypeScript,      // top-level code
+    TypeMethod,      // method of a class, `this` is in slot zero
+    TypeInitializer, // the `init` method, which returns `this`
+}
+
+// What kind of expression was compiled last, for diagnostics that depend on the shape of operands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExprKind {
+    Literal,    // number, string, true, false, nil
+    Variable,   // get or set of a named variable
+    Grouping,   // ( expression )
+    Unary,      // ! -
+    Arithmetic, // + - * /
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Logical,    // and or
+    Call,       // f()
+    Index,      // r[i]
+    Property,   // get or set of obj.field
+}
+
+// A class declaration being compiled
+struct ClassCompiler<'src> {
+    name: &'src str,
+    has_superclass: bool,
+}
+
+// A source that compiled: its top-level function and the functions declared in it, in the
+// order of their ids. The caller adds them to its functions in that order, then the script.
+pub struct Compiled {
+    pub script: Function,
+    pub functions: Vec<Function>,
 }
 
 pub struct Compiler<'src> {
@@ -93,6 +255,10 @@ pub struct Compiler<'src> {
 
     locals: Vec<Local<'src>>, // tracks how many locals are in scope
     scope_depth: i32,         // # of blocks surrounding the current bit of code
+    // the last statement compiled was a `return`, so code emitted right after it is unreachable
+    after_return: bool,
+    // indices in `function.statement_ranges` of the statements being compiled, innermost last
+    open_statements: Vec<usize>,
 }
 
 impl<'src> Compiler<'src> {
@@ -100,7 +266,8 @@ impl<'src> Compiler<'src> {
         enclosing: Option<Box<Compiler<'src>>>,
         f_type: FunctionType,
     ) -> Box<Compiler<'src>> {
-        // Setstack slot zero for the VM’s own internal use
+        // Set stack slot zero for the VM’s own internal use, i.e. the function being called.
+        // Its empty name can't be referenced by scripts, `push_compiler` names it for functions.
         let mut locals = Vec::with_capacity(USIZE_COUNT);
         let dummy_token = Local::new(Token::new(TokenType::Eof, 0, ""), 0);
         locals.push(dummy_token);
@@ -108,30 +275,101 @@ impl<'src> Compiler<'src> {
         Box::new(Compiler {
             enclosing,
             function: Function::new(),
-            f_type: f_type,
+            f_type,
             locals,
             scope_depth: 0,
+            after_return: false,
+            open_statements: Vec::new(),
         })
     }
+
+    // Upvalue index of a variable declared by an enclosing function, which is captured
+    // from the enclosing function's locals, or from its own upvalues if it's declared further out
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+        let local = enclosing
+            .locals
+            .iter()
+            .rposition(|local| local.name.lexeme == name);
+        if let Some(slot) = local {
+            if enclosing.locals[slot].depth == -1 {
+                return Err("Cannot read local variable in its own initializer.");
+            }
+            enclosing.locals[slot].is_captured = true;
+            return self.add_upvalue(slot as u8, true).map(Some);
+        }
+        match enclosing.resolve_upvalue(name)? {
+            Some(index) => self.add_upvalue(index, false).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // A closure captures each variable once, however often the function uses it
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, &'static str> {
+        let capture = Capture { index, is_local };
+        let upvalues = &mut self.function.upvalues;
+        if let Some(existing) = upvalues.iter().position(|&upvalue| upvalue == capture) {
+            return Ok(existing as u8);
+        }
+        if upvalues.len() == USIZE_COUNT {
+            return Err("Too many closure variables in function.");
+        }
+        upvalues.push(capture);
+        Ok((upvalues.len() - 1) as u8)
+    }
 }
 // Parse code to output OpCode to chunk
 pub struct Parser<'src> {
     pub compiler: Box<Compiler<'src>>,
     interner: &'src mut Interner,
+    namespaces: &'src HashSet<StringObjIdx>,
     current: Token<'src>,
     previous: Token<'src>,
     scanner: Scanner<'src>,
     rules: HashMap<TokenType, ParseRule<'src>>,
     had_error: bool,
     panic_mode: bool,
-    functions: &'src mut Vec<Function>,
+    // reported `max_errors` errors, the rest of the source is skipped
+    gave_up: bool,
+    max_errors: usize,
+    nesting: usize, // current depth, see `enter_nesting`
+    // the class declarations surrounding the code, innermost last, for `this`, `super` and
+    // the names of methods
+    classes: Vec<ClassCompiler<'src>>,
+    max_nesting: usize,
+    // the functions declared in the source, kept until the whole source compiled, so a
+    // submission with errors doesn't leave any behind. Their ids start at `first_function_id`.
+    functions: Vec<Function>,
+    first_function_id: usize,
+    diagnostics: &'src mut Diagnostics,
+    last_expr: ExprKind,
+    // type of the value the last expression compiled leaves on the stack, None if unknown,
+    // e.g. of a variable or a call
+    last_type: Option<TypeTag>,
+    optimize: bool,
+    integers: bool,
+    statement_ranges: bool,
+    paranoid: bool,
+    strip_lines: bool,
+    strict_bool: bool,
+    // where the left operand of the infix expression being compiled starts, in the source
+    operand_start: Option<usize>,
+    origin: Option<StringObjIdx>, // recorded on every compiled function
+    source: &'src str,
 }
 
 impl<'src> Parser<'src> {
     pub fn new(
         src: &'src str,
+        options: CompileOptions,
+        namespaces: &'src HashSet<StringObjIdx>,
         interner: &'src mut Interner,
-        functions: &'src mut Vec<Function>,
+        first_function_id: usize, // e.g. the number of functions the VM has
+        diagnostics: &'src mut Diagnostics,
+        origin: Option<StringObjIdx>,
     ) -> Parser<'src> {
         let mut rule_map = HashMap::new();
         rule_map.insert(
@@ -158,7 +396,22 @@ impl<'src> Parser<'src> {
             TokenType::Comma,
             ParseRule::new(None, None, Precedence::None),
         );
-        rule_map.insert(TokenType::Dot, ParseRule::new(None, None, Precedence::None));
+        rule_map.insert(
+            TokenType::LeftBracket,
+            ParseRule::new(None, Some(Parser::rule_index), Precedence::Call),
+        );
+        rule_map.insert(
+            TokenType::RightBracket,
+            ParseRule::new(None, None, Precedence::None),
+        );
+        rule_map.insert(
+            TokenType::Dot,
+            ParseRule::new(None, Some(Parser::rule_dot), Precedence::Call),
+        );
+        rule_map.insert(
+            TokenType::DotDot,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Range),
+        );
         rule_map.insert(
             TokenType::Minus,
             ParseRule::new(
@@ -175,14 +428,50 @@ impl<'src> Parser<'src> {
             TokenType::Semicolon,
             ParseRule::new(None, None, Precedence::None),
         );
+        rule_map.insert(
+            TokenType::Colon,
+            ParseRule::new(None, None, Precedence::None),
+        );
         rule_map.insert(
             TokenType::Slash,
             ParseRule::new(None, Some(Parser::rule_binary), Precedence::Factor),
         );
+        rule_map.insert(
+            TokenType::Percent,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Factor),
+        );
         rule_map.insert(
             TokenType::Star,
             ParseRule::new(None, Some(Parser::rule_binary), Precedence::Factor),
         );
+        rule_map.insert(
+            TokenType::StarStar,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Power),
+        );
+        rule_map.insert(
+            TokenType::Ampersand,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::BitAnd),
+        );
+        rule_map.insert(
+            TokenType::Pipe,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::BitOr),
+        );
+        rule_map.insert(
+            TokenType::Caret,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::BitXor),
+        );
+        rule_map.insert(
+            TokenType::LessLess,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Shift),
+        );
+        rule_map.insert(
+            TokenType::GreaterGreater,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Shift),
+        );
+        rule_map.insert(
+            TokenType::Tilde,
+            ParseRule::new(Some(Parser::rule_unary), None, Precedence::None),
+        );
         rule_map.insert(
             TokenType::Bang,
             ParseRule::new(Some(Parser::rule_unary), None, Precedence::None),
@@ -264,11 +553,11 @@ impl<'src> Parser<'src> {
         );
         rule_map.insert(
             TokenType::Super,
-            ParseRule::new(None, None, Precedence::None),
+            ParseRule::new(Some(Parser::rule_super), None, Precedence::None),
         );
         rule_map.insert(
             TokenType::This,
-            ParseRule::new(None, None, Precedence::None),
+            ParseRule::new(Some(Parser::rule_this), None, Precedence::None),
         );
         rule_map.insert(
             TokenType::True,
@@ -279,6 +568,23 @@ impl<'src> Parser<'src> {
             TokenType::While,
             ParseRule::new(None, None, Precedence::None),
         );
+        for token_type in [
+            TokenType::Break,
+            TokenType::Case,
+            TokenType::Continue,
+            TokenType::Default,
+            TokenType::Switch,
+        ] {
+            rule_map.insert(token_type, ParseRule::new(None, None, Precedence::None));
+        }
+        rule_map.insert(
+            TokenType::In,
+            ParseRule::new(None, Some(Parser::rule_binary), Precedence::Comparison),
+        );
+        rule_map.insert(
+            TokenType::Is,
+            ParseRule::new(None, Some(Parser::rule_is), Precedence::Comparison),
+        );
         rule_map.insert(
             TokenType::Error,
             ParseRule::new(None, None, Precedence::None),
@@ -287,41 +593,88 @@ impl<'src> Parser<'src> {
 
         let dummy_token = Token::new(TokenType::Eof, 0, "");
         let dummy_token2 = Token::new(TokenType::Eof, 0, "");
+        let mut compiler = Compiler::new(None, FunctionType::TypeScript);
+        compiler.function.origin = origin;
         Parser {
-            compiler: Compiler::new(None, FunctionType::TypeScript),
+            compiler,
             interner,
+            namespaces,
             current: dummy_token,
             previous: dummy_token2,
-            scanner: Scanner::new(src),
+            scanner: Scanner::with_level(src, options.lang_level),
             rules: rule_map,
             had_error: false,
             panic_mode: false,
-            functions,
+            gave_up: false,
+            max_errors: options.max_errors,
+            nesting: 0,
+            classes: Vec::new(),
+            max_nesting: options.max_nesting,
+            functions: Vec::new(),
+            first_function_id,
+            diagnostics,
+            last_expr: ExprKind::Literal,
+            last_type: None,
+            optimize: options.optimize,
+            integers: options.integers,
+            statement_ranges: options.statement_ranges,
+            paranoid: options.paranoid,
+            strip_lines: options.strip_lines,
+            strict_bool: options.strict_bool,
+            operand_start: None,
+            origin,
+            source: src,
         }
     }
 
-    pub fn compile(mut self) -> Option<Function> {
+    pub fn compile(mut self) -> Option<Compiled> {
         self.advance();
         while !self.equal(TokenType::Eof) {
             self.declaration();
         }
-        let had_error = self.had_error;
-
         // let f = self.end_compiler();
         self.emit_return();
-        let f = self.compiler.function;
-        if had_error {
-            None
-        } else {
-            Some(f)
+        self.finish()
+    }
+
+    // Compiles a single expression into a script that returns its value
+    pub fn compile_expression(mut self) -> Option<Compiled> {
+        self.advance();
+        self.expression();
+        self.consume(TokenType::Eof, "Expect end of expression.");
+        self.emit_byte(OpCode::Return);
+        self.finish()
+    }
+
+    // Nothing is returned if there were errors, not even the functions that compiled
+    fn finish(self) -> Option<Compiled> {
+        if self.had_error {
+            return None;
         }
+        let mut script = self.compiler.function;
+        finish_chunk(&mut script, self.strip_lines);
+        Some(Compiled {
+            script,
+            functions: self.functions,
+        })
     }
 
     fn advance(&mut self) {
         self.previous = self.current;
+        if self.gave_up {
+            // stop scanning, every loop of the parser ends at Eof
+            self.current = Token::new(TokenType::Eof, self.previous.line, "");
+            return;
+        }
 
         loop {
             self.current = self.scanner.scan_token();
+            let scan_warnings: Vec<_> = self.scanner.warnings.drain(..).collect();
+            for warning in scan_warnings {
+                // scan warnings are about the token that was just scanned
+                let current = self.current;
+                self.warning_at(current, warning.code, warning.message);
+            }
             if self.current.token_type != TokenType::Error {
                 break;
             };
@@ -353,36 +706,44 @@ impl<'src> Parser<'src> {
     }
 
     fn emit_byte(&mut self, byte: OpCode) {
-        self.compiler.function.chunk.write(byte, self.previous.line);
+        self.emit_byte_at(byte, self.previous.line);
     }
 
-    fn emit_bytes(&mut self, byte1: OpCode, byte2: OpCode) {
-        self.compiler
-            .function
-            .chunk
-            .write(byte1, self.previous.line);
-        self.compiler
-            .function
-            .chunk
-            .write(byte2, self.previous.line);
+    // Structural instructions of a statement (e.g. its jumps, or the Pop of an expression statement)
+    // are attributed to the line where the statement starts, instead of its last token
+    fn emit_byte_at(&mut self, byte: OpCode, line: usize) {
+        if self.paranoid {
+            if let OpCode::GetLocal(slot) | OpCode::SetLocal(slot) = byte {
+                self.current_chunk_mut()
+                    .write(OpCode::CheckSlot(slot), line);
+            }
+        }
+        self.current_chunk_mut().write(byte, line);
     }
 
-    fn emit_loop(&mut self, loop_start: usize) {
-        let offset = self.compiler.function.chunk.code.len() - loop_start;
+    fn emit_loop(&mut self, loop_start: usize, line: usize) {
+        let offset = self.current_chunk().len() - loop_start;
         if offset > USIZE_COUNT {
             self.error("Loop body too large.");
         }
 
-        self.emit_byte(OpCode::Loop(offset));
+        self.emit_byte_at(OpCode::Loop(offset), line);
     }
 
-    fn emit_jump(&mut self, instruction: OpCode) -> usize {
-        self.emit_byte(instruction);
-        self.compiler.function.chunk.code.len() - 1
+    fn emit_jump(&mut self, instruction: OpCode, line: usize) -> usize {
+        self.emit_byte_at(instruction, line);
+        self.current_chunk().len() - 1
     }
 
+    // The implicit return at the end of a function or script, and `return;`. It returns an explicit
+    // value, so nothing left on the stack by the body is mistaken for the result.
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Nil); // if a function does not returns, it will still always return nil
+        if self.compiler.f_type == FunctionType::TypeInitializer {
+            // an initializer returns the instance, also after `return;`
+            self.emit_byte(OpCode::GetLocal(0));
+        } else {
+            self.emit_byte(OpCode::Nil); // if a function does not returns, it will still always return nil
+        }
         self.emit_byte(OpCode::Return);
     }
 
@@ -390,24 +751,26 @@ impl<'src> Parser<'src> {
     // current location (i.e. chunk.code[len-1])
     fn patch_jump(&mut self, offset: usize) {
         // -1 because offset is 0-based index.
-        let jump = self.compiler.function.chunk.code.len() - 1 - offset;
+        let jump = self.current_chunk().len() - 1 - offset;
 
         if jump > USIZE_COUNT {
             self.error("Too much code to jump over.");
         }
 
         // Replaces the operand at the given location with the calculated jump offset
-        match self.compiler.function.chunk.code[offset] {
-            OpCode::Jump(ref mut o) | OpCode::JumpIfFalse(ref mut o) => *o = jump,
+        match self.current_chunk_mut().op_mut(offset) {
+            Some(OpCode::Jump(o)) | Some(OpCode::JumpIfFalse(o)) | Some(OpCode::ForIn(_, o)) => {
+                *o = jump
+            }
             _ => {
                 self.error("Operand is not Jump!");
-                println!("{:?}", self.compiler.function.chunk.code)
+                println!("{:?}", self.current_chunk().code())
             }
         }
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        let idx = self.compiler.function.chunk.add_constant(value);
+        let idx = self.current_chunk_mut().add_constant(value);
         match u8::try_from(idx) {
             Ok(idx) => idx,
             Err(_) => {
@@ -418,36 +781,72 @@ impl<'src> Parser<'src> {
     }
 
     fn emit_constant(&mut self, val: Value) {
-        let constant_idx = self.make_constant(val);
-        self.emit_byte(OpCode::Constant(constant_idx));
+        // compare bits, so that -0.0 still goes to the constant pool
+        match val {
+            Value::Number(n) if n.to_bits() == 0.0f64.to_bits() => self.emit_byte(OpCode::Zero),
+            Value::Number(n) if n.to_bits() == 1.0f64.to_bits() => self.emit_byte(OpCode::One),
+            Value::Number(n) if n.to_bits() == (-1.0f64).to_bits() => {
+                self.emit_byte(OpCode::MinusOne)
+            }
+            _ => {
+                let constant_idx = self.make_constant(val);
+                self.emit_byte(OpCode::Constant(constant_idx));
+            }
+        }
     }
 
-    fn end_compiler(&mut self) -> Function {
-        self.emit_return();
-        if let Some(enclosing) = self.compiler.enclosing.take() {
-            let compiler = mem::replace(&mut self.compiler, enclosing);
-            let f = compiler.function;
-            #[cfg(feature = "debug_trace_execution")]
-            if !self.had_error {
-                match f.name {
-                    Some(name_idx) => {
-                        crate::debug::disassemble_chunk(
-                            &f.chunk,
-                            self.interner.lookup(name_idx),
-                            &self.interner,
-                        );
-                    }
-                    None => {
-                        crate::debug::disassemble_chunk(&f.chunk, "<script>", &self.interner);
-                    }
-                }
+    // Chunk of the function being compiled
+    fn current_chunk(&self) -> &Chunk {
+        &self.compiler.function.chunk
+    }
+
+    fn current_chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    // Starts compiling a nested function: the new compiler has its own locals, scopes and chunk,
+    // and links to the enclosing one until `pop_compiler`
+    fn push_compiler(&mut self, f_type: FunctionType, name: Token<'src>) {
+        let new_compiler = Compiler::new(None, f_type);
+        let enclosing = mem::replace(&mut self.compiler, new_compiler);
+        self.compiler.enclosing = Some(enclosing);
+        // methods (and field initializers) are named after their class, e.g. "Point.init"
+        let full_name = match (f_type, self.classes.last()) {
+            (FunctionType::TypeMethod | FunctionType::TypeInitializer, Some(class)) => {
+                format!("{}.{}", class.name, name.lexeme)
             }
-            f
-        } else {
+            _ => name.lexeme.to_owned(),
+        };
+        self.compiler.function.name = Some(self.interner.intern(&full_name));
+        self.compiler.function.origin = self.origin;
+        // Slot zero holds the function being called, so its own name resolves to it,
+        // even if the global with that name is reassigned. Methods hold the receiver there.
+        self.compiler.locals[0] = match f_type {
+            FunctionType::TypeMethod | FunctionType::TypeInitializer => {
+                Local::new(Token::new(TokenType::This, name.line, "this"), 0)
+            }
+            _ => Local::new(name, 0),
+        };
+    }
+
+    // Finishes the current function and returns to the enclosing compiler
+    fn pop_compiler(&mut self) -> Function {
+        self.emit_return();
+        let enclosing = match self.compiler.enclosing.take() {
+            Some(enclosing) => enclosing,
             // there should always be an enclosing compiler
             // unless this compiler is top-level (which won't call this method)
-            panic!("No enclosing compiler!")
+            None => panic!("No enclosing compiler!"),
+        };
+        let compiler = mem::replace(&mut self.compiler, enclosing);
+        let mut f = compiler.function;
+        finish_chunk(&mut f, self.strip_lines);
+        #[cfg(feature = "debug_trace_execution")]
+        if !self.had_error {
+            let title = crate::debug::chunk_title(&f, self.interner);
+            crate::debug::disassemble_chunk(&f.chunk, &title, self.interner);
         }
+        f
     }
 
     fn begin_scope(&mut self) {
@@ -457,69 +856,310 @@ impl<'src> Parser<'src> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
+        // Remove the vars from the stack, the top one first. Not needed after a `return`,
+        // which discards the whole frame (and closes its captured variables).
+        let mut count: u8 = 0;
         while !self.compiler.locals.is_empty()
             && self.compiler.locals[self.compiler.locals.len() - 1].depth
                 > self.compiler.scope_depth
         {
-            // Remove the var from the stack
-            self.emit_byte(OpCode::Pop);
             // Remove the var from local array
-            self.compiler.locals.pop();
+            let local = self.compiler.locals.pop().unwrap();
+            if self.compiler.after_return {
+                continue;
+            }
+            if local.is_captured {
+                // the vars above it are popped first
+                self.emit_pops(count);
+                count = 0;
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                count += 1; // at most 255, slot zero is never in a scope
+            }
         }
+        self.emit_pops(count);
     }
 
-    fn rule_binary(&mut self, can_assign: bool) {
-        let operator_type = self.previous.token_type;
-        // let rule = self.get_rule(operator_type);
-        self.parse_precedence(self.get_rule(operator_type).precedence.next());
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => {}
+            1 => self.emit_byte(OpCode::Pop),
+            _ => self.emit_byte(OpCode::PopN(count)),
+        }
+    }
 
+    fn rule_binary(&mut self, _can_assign: bool) {
+        let operand_start = self.operand_start;
+        let operator_type = self.previous.token_type;
+        let operator = self.previous;
+        let operator_line = self.previous.line;
+        let kind = binary_expr_kind(operator_type);
+        let left_type = self.last_type;
+        // `a < b < c` would compare a bool to a number at runtime, far away from the real mistake
+        if kind == ExprKind::Comparison && self.last_expr == ExprKind::Comparison {
+            self.error("Chained comparisons are not supported; use 'a < b and b < c'.");
+        }
+        let equality = matches!(operator_type, TokenType::EqualEqual | TokenType::BangEqual);
+        let mut literal_test = if equality {
+            self.take_literal_test()
+        } else {
+            None
+        };
+        // `**` is right-associative: 2 ** 3 ** 2 is 2 ** (3 ** 2)
         match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            _ => {} // Unreachable.
+            TokenType::StarStar => self.parse_precedence(Precedence::Power),
+            _ => self.parse_precedence(self.get_rule(operator_type).precedence.next()),
         }
+        let result_type = self.binary_type(operator, left_type, self.last_type);
+        if equality && literal_test.is_none() {
+            literal_test = self.take_literal_test();
+        }
+
+        // the instructions are attributed to the operator, not the end of the right operand
+        let lowered = literal_test.map(|test| [test, OpCode::Not]);
+        let instructions: &[OpCode] = match operator_type {
+            // `x == nil` and the like test `x` directly, see `take_literal_test`
+            TokenType::BangEqual => match &lowered {
+                Some(instructions) => instructions,
+                None => &[OpCode::Equal, OpCode::Not],
+            },
+            TokenType::EqualEqual => match &lowered {
+                Some(instructions) => &instructions[..1],
+                None => &[OpCode::Equal],
+            },
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::GreaterEqual],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::LessEqual],
+            TokenType::Plus if result_type == Some(TypeTag::Number) => &[OpCode::AddNumbers],
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            TokenType::Percent => &[OpCode::Modulo],
+            TokenType::StarStar => &[OpCode::Power],
+            TokenType::Ampersand => &[OpCode::BitAnd],
+            TokenType::Pipe => &[OpCode::BitOr],
+            TokenType::Caret => &[OpCode::BitXor],
+            TokenType::LessLess => &[OpCode::ShiftLeft],
+            TokenType::GreaterGreater => &[OpCode::ShiftRight],
+            TokenType::DotDot => &[OpCode::Range],
+            TokenType::In => &[OpCode::In],
+            _ => &[], // Unreachable.
+        };
+        if self.paranoid && instructions.first().is_some_and(checks_operands) {
+            // the whole expression, e.g. `a - b`, so the error points at it
+            let end = self
+                .source_offset(self.previous)
+                .map(|offset| offset + self.previous.lexeme.len());
+            let text = match (operand_start, end) {
+                (Some(start), Some(end)) if start <= end => &self.source[start..end],
+                _ => self.previous.lexeme,
+            };
+            let text = self.interner.intern(&truncate_lexeme(text));
+            let constant = self.make_constant(Value::StringObj(text));
+            self.emit_byte_at(OpCode::CheckOperands(constant), operator_line);
+        }
+        for &instruction in instructions {
+            self.emit_byte_at(instruction, operator_line);
+        }
+        self.last_expr = kind;
+        self.last_type = result_type;
     }
 
-    fn rule_call(&mut self, can_assign: bool) {
+    // Type of `left <operator> right`, if the operand types tell. Operands of known types that
+    // the operator rejects are a compile error, e.g. `true + 1`; unknown ones are left to the VM.
+    fn binary_type(
+        &mut self,
+        operator: Token,
+        left: Option<TypeTag>,
+        right: Option<TypeTag>,
+    ) -> Option<TypeTag> {
+        let (expected, result) = match operator.token_type {
+            TokenType::BangEqual | TokenType::EqualEqual => return Some(TypeTag::Bool),
+            TokenType::Plus => match (left?, right?) {
+                (TypeTag::Number, TypeTag::Number) => return Some(TypeTag::Number),
+                (TypeTag::String, TypeTag::String) => return Some(TypeTag::String),
+                _ => ("two numbers or two strings", None),
+            },
+            TokenType::Minus
+            | TokenType::Star
+            | TokenType::Slash
+            | TokenType::Percent
+            | TokenType::StarStar
+            | TokenType::Ampersand
+            | TokenType::Pipe
+            | TokenType::Caret
+            | TokenType::LessLess
+            | TokenType::GreaterGreater => ("two numbers", Some(TypeTag::Number)),
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => ("two numbers", Some(TypeTag::Bool)),
+            _ => return None, // `..` and `in`
+        };
+        match (left, right) {
+            (Some(TypeTag::Number), Some(TypeTag::Number)) => result,
+            (Some(left), Some(right)) => {
+                let msg = format!(
+                    "Operands to '{}' must be {}, got {} and {}.",
+                    operator.lexeme,
+                    expected,
+                    left.name(),
+                    right.name()
+                );
+                self.error_at(operator, &msg);
+                None
+            }
+            // e.g. a comparison is a bool whatever the operands, if it doesn't fail
+            _ if result == Some(TypeTag::Bool) => result,
+            _ => None,
+        }
+    }
+
+    fn rule_call(&mut self, _can_assign: bool) {
         let arg_count = self.argument_list();
         self.emit_byte(OpCode::Call(arg_count));
+        self.last_expr = ExprKind::Call;
+        self.last_type = None;
+    }
+
+    fn rule_index(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        self.emit_byte(OpCode::Index);
+        self.last_expr = ExprKind::Index;
+        self.last_type = None;
+    }
+
+    fn rule_dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous);
+        if can_assign && self.equal(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetProperty(name));
+        } else {
+            self.emit_byte(OpCode::GetProperty(name));
+        }
+        self.last_expr = ExprKind::Property;
+        self.last_type = None;
+    }
+
+    // `x is Number`. With `optimize`, an operand whose type is known at compile time is
+    // checked right away: a literal is dropped, any other operand is still evaluated and popped.
+    fn rule_is(&mut self, _can_assign: bool) {
+        let line = self.previous.line;
+        if self.last_expr == ExprKind::Comparison {
+            self.error("Chained comparisons are not supported; use 'a < b and b < c'.");
+        }
+        self.consume(TokenType::Identifier, "Expect type name after 'is'.");
+        let tag = match TypeTag::from_type_name(self.previous.lexeme) {
+            Some(tag) => tag,
+            None => {
+                let message = format!("Unknown type '{}'.", self.previous.lexeme);
+                self.error(&message);
+                return;
+            }
+        };
+        match self.last_type.filter(|_| self.optimize) {
+            Some(known) => {
+                let last = self.current_chunk().len().checked_sub(1);
+                let literal = last.filter(|&last| {
+                    self.last_expr == ExprKind::Literal
+                        && self.current_chunk().literal_type(last) == Some(known)
+                });
+                match literal {
+                    Some(last) => self.current_chunk_mut().truncate(last),
+                    None => self.emit_byte_at(OpCode::Pop, line),
+                }
+                let result = if known == tag {
+                    OpCode::True
+                } else {
+                    OpCode::False
+                };
+                self.emit_byte_at(result, line);
+            }
+            None => {
+                self.emit_byte_at(OpCode::IsType(tag), line);
+                self.emit_byte_at(OpCode::PopBelow, line);
+            }
+        }
+        self.last_expr = ExprKind::Comparison;
+        self.last_type = Some(TypeTag::Bool);
     }
 
-    fn rule_literal(&mut self, can_assign: bool) {
+    // `this` is the local in slot zero of a method, or an upvalue of a function nested in one
+    fn rule_this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        self.named_variable(self.previous, false);
+    }
+
+    // `super.method` is the method of the superclass, bound to `this`
+    fn rule_super(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+        let line = self.previous.line;
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous);
+
+        self.named_variable(Token::new(TokenType::This, line, "this"), false);
+        self.named_variable(Token::new(TokenType::Super, line, "super"), false);
+        self.emit_byte(OpCode::GetSuper(name));
+        self.last_expr = ExprKind::Property;
+        self.last_type = None;
+    }
+
+    fn rule_literal(&mut self, _can_assign: bool) {
         match self.previous.token_type {
             TokenType::False => self.emit_byte(OpCode::False),
             TokenType::Nil => self.emit_byte(OpCode::Nil),
             TokenType::True => self.emit_byte(OpCode::True),
             _ => {} // Unreachable.
         }
+        self.last_expr = ExprKind::Literal;
+        self.last_type = match self.previous.token_type {
+            TokenType::Nil => Some(TypeTag::Nil),
+            _ => Some(TypeTag::Bool),
+        };
     }
 
-    fn rule_grouping(&mut self, can_assign: bool) {
+    fn rule_grouping(&mut self, _can_assign: bool) {
         // i.e. "(", grouping has no meaning for backend
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.last_expr = ExprKind::Grouping;
     }
 
-    fn rule_number(&mut self, can_assign: bool) {
-        self.emit_constant(Value::Number(
-            self.previous
-                .lexeme
-                .parse()
-                .expect("Cannot convert str to f64"),
-        ));
+    fn rule_number(&mut self, _can_assign: bool) {
+        let lexeme = self.previous.lexeme;
+        // literals too big for an i64 stay floats
+        let int = match lexeme.parse::<i64>() {
+            Ok(int) if self.integers => Some(int),
+            _ => None,
+        };
+        let value = match int {
+            Some(int) => Value::Int(int),
+            None => Value::Number(lexeme.parse().expect("Cannot convert str to f64")),
+        };
+        self.emit_constant(value);
+        self.last_expr = ExprKind::Literal;
+        self.last_type = Some(TypeTag::Number);
     }
 
-    fn rule_or(&mut self, can_assign: bool) {
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0xff));
-        let end_jump = self.emit_jump(OpCode::Jump(0xff));
+    fn rule_or(&mut self, _can_assign: bool) {
+        let left_type = self.last_type;
+        self.check_condition(self.previous.line);
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0xff), self.previous.line);
+        let end_jump = self.emit_jump(OpCode::Jump(0xff), self.previous.line);
 
         // if LHS is falsey, skip `end_jump`, in order to evaluate RHS expression
         self.patch_jump(else_jump);
@@ -528,12 +1168,16 @@ impl<'src> Parser<'src> {
         self.parse_precedence(Precedence::Or);
         // if LHS is truthy, `end_jump` will be run, skipping RHS expression
         self.patch_jump(end_jump);
+        self.last_expr = ExprKind::Logical;
+        self.last_type = logical_type(left_type, self.last_type);
     }
 
-    fn rule_string(&mut self, can_assign: bool) {
+    fn rule_string(&mut self, _can_assign: bool) {
         let key = &self.previous.lexeme[1..self.previous.lexeme.len() - 1];
         let idx = self.interner.intern(key);
         self.emit_constant(Value::StringObj(idx));
+        self.last_expr = ExprKind::Literal;
+        self.last_type = Some(TypeTag::String);
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
@@ -543,6 +1187,9 @@ impl<'src> Parser<'src> {
             let idx = arg as u8;
             get_op = OpCode::GetLocal(idx);
             set_op = OpCode::SetLocal(idx);
+        } else if let Some(idx) = self.resolve_upvalue(name) {
+            get_op = OpCode::GetUpvalue(idx);
+            set_op = OpCode::SetUpvalue(idx);
         } else {
             let idx = self.identifier_constant(name);
             get_op = OpCode::GetGlobal(idx);
@@ -557,28 +1204,107 @@ impl<'src> Parser<'src> {
         } else {
             self.emit_byte(get_op);
         }
+        self.last_expr = ExprKind::Variable;
+        self.last_type = None;
     }
 
     fn rule_variable(&mut self, can_assign: bool) {
-        self.named_variable(self.previous, can_assign);
+        let name = self.previous;
+        // a local variable, also of an enclosing function, shadows a namespace of the same name
+        if self.is_namespace(name)
+            && self.resolve_local(name).is_none()
+            && self.resolve_upvalue(name).is_none()
+        {
+            self.namespace_member(name, can_assign);
+        } else {
+            self.named_variable(name, can_assign);
+        }
+    }
+
+    fn is_namespace(&mut self, name: Token) -> bool {
+        let name_idx = self.interner.intern(name.lexeme);
+        self.namespaces.contains(&name_idx)
+    }
+
+    // `namespace.member` is the global variable named "namespace.member"
+    fn namespace_member(&mut self, namespace: Token, can_assign: bool) {
+        self.consume(TokenType::Dot, "Expect '.' after namespace name.");
+        self.consume(TokenType::Identifier, "Expect member name after '.'.");
+        let full_name = format!("{}.{}", namespace.lexeme, self.previous.lexeme);
+        let identifier = self.interner.intern_string(full_name);
+        let idx = self.make_constant(Value::Identifier(identifier));
+
+        if can_assign && self.equal(TokenType::Equal) {
+            self.error("Cannot assign to a namespace member.");
+        }
+        self.emit_byte(OpCode::GetGlobal(idx));
+        self.last_expr = ExprKind::Variable;
+        self.last_type = None;
     }
 
-    fn rule_unary(&mut self, can_assign: bool) {
+    fn rule_unary(&mut self, _can_assign: bool) {
+        let operator = self.previous;
         let operator_type = self.previous.token_type;
+        let operator_line = self.previous.line;
 
         // Compile the operand.
-        self.parse_precedence(Precedence::Unary); // permit nested unary expressions
+        // permit nested unary expressions, and `**` binds tighter: -2 ** 2 is -(2 ** 2)
+        self.parse_precedence(Precedence::Power);
+        let operand_type = self.last_type;
 
         // Emit the operator instruction.
         match operator_type {
             // operator_type is the previous token, e.g. "-" in "-50"
-            TokenType::Bang => self.emit_byte(OpCode::Not),
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
+            TokenType::Bang => {
+                self.check_condition(operator_line);
+                self.emit_byte_at(OpCode::Not, operator_line);
+            }
+            TokenType::Minus => self.emit_byte_at(OpCode::Negate, operator_line),
+            TokenType::Tilde => self.emit_byte_at(OpCode::BitNot, operator_line),
             _ => {} // Unreachable.
         }
+        self.last_expr = ExprKind::Unary;
+        self.last_type = match (operator_type, operand_type) {
+            (TokenType::Bang, _) => Some(TypeTag::Bool),
+            (_, Some(TypeTag::Number)) => Some(TypeTag::Number),
+            (_, Some(operand)) => {
+                let msg = format!(
+                    "Operand to '{}' must be a number, got {}.",
+                    operator.lexeme,
+                    operand.name()
+                );
+                self.error_at(operator, &msg);
+                None
+            }
+            (_, None) => None,
+        };
+    }
+
+    // For an operand of `==` or `!=` that is a nil, true or false literal: removes the literal,
+    // which was compiled last, and returns the instruction comparing the other operand to it.
+    // `x == true` stays exact, only `true` itself is equal, not every truthy value. The literal
+    // may be the left operand: jumps to its offset then land on the right operand's code, which
+    // starts the comparison just as well.
+    fn take_literal_test(&mut self) -> Option<OpCode> {
+        if self.last_expr != ExprKind::Literal {
+            return None;
+        }
+        let chunk = self.current_chunk_mut();
+        let last = chunk.len().checked_sub(1)?;
+        let test = match chunk.op(last)? {
+            OpCode::Nil => OpCode::IsNil,
+            OpCode::True => OpCode::IsTrue,
+            OpCode::False => OpCode::IsFalse,
+            _ => return None,
+        };
+        chunk.truncate(last);
+        Some(test)
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter_nesting() {
+            return;
+        }
         // read the next token and look up the corresponding ParseRule
         self.advance();
 
@@ -590,10 +1316,12 @@ impl<'src> Parser<'src> {
         #[cfg(feature = "debug_trace_execution")]
         println!("prefix_rule of {:?} ", self.previous.token_type);
         let can_assign = precedence <= Precedence::Assignment;
+        let start = self.previous;
         match prefix_rule {
             Some(r) => r(self, can_assign),
             None => {
                 self.error("Expect expression.");
+                self.leave_nesting();
                 return;
             }
         }
@@ -609,12 +1337,14 @@ impl<'src> Parser<'src> {
             let infix_rule = self.get_rule(self.previous.token_type).infix;
             #[cfg(feature = "debug_trace_execution")]
             println!("infix_rule of {:?} ", self.previous.token_type);
+            self.operand_start = self.source_offset(start);
             match infix_rule {
                 // Then we loop back around and see if the next token is also a valid infix operator
                 // that can take the entire preceding expression as its operand.
                 Some(r) => r(self, can_assign),
                 None => {
                     self.error("Infix rule not found.");
+                    self.leave_nesting();
                     return;
                 }
             }
@@ -622,9 +1352,29 @@ impl<'src> Parser<'src> {
         // If the next token is too low precedence, or isn’t an infix operator at all, we’re done.
         // i.e., we’ve parsed as much expression as we can.
 
+        // the rules of assignable expressions consumed their `=` already, e.g. `a.b = 1`
         if can_assign && self.equal(TokenType::Equal) {
-            self.error("Invalid assignment target.");
+            self.error(invalid_target_message(self.last_expr));
         }
+        self.leave_nesting();
+    }
+
+    // The parser recurses on the Rust stack, so e.g. thousands of nested parentheses would overflow
+    // it. Past `max_nesting` levels this reports an error and, like too many errors, gives up on
+    // the rest of the source: the current token becomes Eof, which ends every loop of the parser.
+    fn enter_nesting(&mut self) -> bool {
+        if self.nesting >= self.max_nesting {
+            self.error_at_current("Expression too deeply nested.");
+            self.gave_up = true;
+            self.current = Token::new(TokenType::Eof, self.current.line, "");
+            return false;
+        }
+        self.nesting += 1;
+        true
+    }
+
+    fn leave_nesting(&mut self) {
+        self.nesting -= 1;
     }
 
     fn identifier_constant(&mut self, name: Token) -> u8 {
@@ -650,6 +1400,17 @@ impl<'src> Parser<'src> {
         None
     }
 
+    // Variables of enclosing functions are captured by the closure, see `Compiler::resolve_upvalue`
+    fn resolve_upvalue(&mut self, name: Token) -> Option<u8> {
+        match self.compiler.resolve_upvalue(name.lexeme) {
+            Ok(idx) => idx,
+            Err(msg) => {
+                self.error(msg);
+                None
+            }
+        }
+    }
+
     // Initializes the next available Local
     fn add_local(&mut self, name: Token<'src>) {
         if self.compiler.locals.len() == USIZE_COUNT {
@@ -713,14 +1474,15 @@ impl<'src> Parser<'src> {
     }
 
     fn argument_list(&mut self) -> u8 {
-        let mut arg_count = 0;
+        let mut arg_count: u8 = 0;
         if !self.check(TokenType::RightParen) {
             loop {
                 self.expression();
-                if arg_count == 255 {
-                    self.error("Cannot have more than 255 arguments.");
+                // the count stays at the limit, instead of overflowing
+                match arg_count.checked_add(1) {
+                    Some(count) => arg_count = count,
+                    None => self.error("Cannot have more than 255 arguments."),
                 }
-                arg_count += 1;
 
                 if !self.equal(TokenType::Comma) {
                     break;
@@ -731,20 +1493,23 @@ impl<'src> Parser<'src> {
         arg_count
     }
 
-    fn rule_and(&mut self, can_assign: bool) {
-        let end_jump = self.emit_jump(OpCode::JumpIfFalse(0xff));
+    fn rule_and(&mut self, _can_assign: bool) {
+        let left_type = self.last_type;
+        self.check_condition(self.previous.line);
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse(0xff), self.previous.line);
 
         self.emit_byte(OpCode::Pop);
         self.parse_precedence(Precedence::And);
 
         self.patch_jump(end_jump);
+        self.last_expr = ExprKind::Logical;
+        self.last_type = logical_type(left_type, self.last_type);
     }
 
     fn get_rule(&self, token_type: TokenType) -> &ParseRule<'src> {
-        return self
-            .rules
+        self.rules
             .get(&token_type)
-            .expect("<TokenType, ParseRule> pair not found.");
+            .expect("<TokenType, ParseRule> pair not found.")
     }
 
     fn expression(&mut self) {
@@ -763,11 +1528,7 @@ impl<'src> Parser<'src> {
 
     fn function(&mut self, f_type: FunctionType) {
         // new compiler for this function
-        let new_compiler = Compiler::new(None, f_type);
-        // swap the compiler with the new one
-        let old_compiler = mem::replace(&mut self.compiler, new_compiler);
-        self.compiler.enclosing = Some(old_compiler);
-        self.compiler.function.name = Some(self.interner.intern(self.previous.lexeme));
+        self.push_compiler(f_type, self.previous);
 
         // beginScope() doesn’t have a corresponding endScope() call
         // Because we end Compiler completely when we reach the end of the function body
@@ -776,9 +1537,10 @@ impl<'src> Parser<'src> {
         self.consume(TokenType::LeftParen, "Expect '(' after function name.");
         if !self.check(TokenType::RightParen) {
             loop {
-                self.compiler.function.arity += 1;
-                if self.compiler.function.arity > 255 {
+                if self.compiler.function.arity == u8::MAX {
                     self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.compiler.function.arity += 1;
                 }
                 let constant = self.parse_variable("Expect parameter name.");
                 self.define_variable(constant);
@@ -788,26 +1550,143 @@ impl<'src> Parser<'src> {
             }
         }
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        if self.paranoid {
+            let arity = self.compiler.function.arity;
+            self.emit_byte(OpCode::CheckArity(arity));
+        }
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
-        self.block();
+        if self.enter_nesting() {
+            self.block();
+            self.leave_nesting();
+        }
+        self.end_function();
+    }
 
-        let function = self.end_compiler();
+    // Finishes the current function and loads it in the enclosing one
+    fn end_function(&mut self) {
+        let function = self.pop_compiler();
+        let captures = !function.upvalues.is_empty();
+        let f_idx = self.first_function_id + self.functions.len();
         self.functions.push(function);
-        let f_idx = self.functions.len() - 1;
         let constant_idx = self.make_constant(Value::Function(f_idx));
-        self.emit_byte(OpCode::Constant(constant_idx));
+        // functions that capture nothing are loaded as they are, without creating a closure
+        if captures {
+            self.emit_byte(OpCode::Closure(constant_idx));
+        } else {
+            self.emit_byte(OpCode::Constant(constant_idx));
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_byte(OpCode::Class(name_constant));
+        self.define_variable(name_constant);
+
+        let has_superclass = self.equal(TokenType::Less);
+        if has_superclass {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            let superclass = self.previous;
+            if self.identifiers_equal(&class_name, &superclass) {
+                self.error("A class can't inherit from itself.");
+            }
+            self.named_variable(superclass, false);
+            // the superclass stays on the stack as the local `super`, in a scope of its own
+            // so each class captures its own superclass
+            self.begin_scope();
+            self.add_local(Token::new(TokenType::Super, superclass.line, "super"));
+            self.define_variable(0);
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCode::Inherit);
+        }
+        self.classes.push(ClassCompiler {
+            name: class_name.lexeme,
+            has_superclass,
+        });
+
+        // the class stays on the stack while its methods are added to it
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        // a field and a method with the same name would hide one another
+        let mut methods = Vec::new();
+        let mut fields = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.consume(TokenType::Identifier, "Expect method name.");
+            let name = self.previous;
+            let is_field = self.check(TokenType::Equal);
+            let (same_kind, other_kind) = if is_field {
+                (&mut fields, &methods)
+            } else {
+                (&mut methods, &fields)
+            };
+            if other_kind.contains(&name.lexeme) {
+                self.error("A field can't have the same name as a method.");
+            }
+            same_kind.push(name.lexeme);
+            if is_field {
+                self.field(name);
+            } else {
+                self.method(name);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop);
+
+        if has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn method(&mut self, name: Token<'src>) {
+        let constant = self.identifier_constant(name);
+        let f_type = if name.lexeme == "init" {
+            FunctionType::TypeInitializer
+        } else {
+            FunctionType::TypeMethod
+        };
+        self.function(f_type);
+        self.emit_byte(OpCode::Method(constant));
+    }
+
+    // `x = value;` in a class body. The value is compiled into a method of its own, which the VM
+    // calls on every new instance, before `init`, so it can use the fields declared before it.
+    fn field(&mut self, name: Token<'src>) {
+        let constant = self.identifier_constant(name);
+        self.consume(TokenType::Equal, "Expect '=' after field name.");
+        self.push_compiler(FunctionType::TypeMethod, name);
+        self.begin_scope();
+        if self.enter_nesting() {
+            self.expression();
+            self.leave_nesting();
+        }
+        self.emit_byte(OpCode::Return);
+        self.end_function();
+        self.consume(TokenType::Semicolon, "Expect ';' after field value.");
+        self.emit_byte(OpCode::Field(constant));
     }
 
     fn fun_declaration(&mut self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized(); // you’ll never see the variable in an uninitialized state
         self.function(FunctionType::TypeFunction);
-        self.define_variable(global);
+        if self.compiler.scope_depth > 0 {
+            self.define_variable(global);
+        } else {
+            self.emit_byte(OpCode::DefineFunction(global));
+        }
     }
 
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
+        self.var_initializer(global);
+    }
 
+    // The rest of a var declaration after its name: `= value;` or `;`
+    fn var_initializer(&mut self, global: u8) {
         if self.equal(TokenType::Equal) {
             self.expression();
         } else {
@@ -822,13 +1701,13 @@ impl<'src> Parser<'src> {
     }
 
     // Semantically, an expression statement evaluates the expression and discards the result.
-    fn expression_statement(&mut self) {
+    fn expression_statement(&mut self, line: usize) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(OpCode::Pop);
+        self.emit_byte_at(OpCode::Pop, line);
     }
 
-    fn for_statement(&mut self) {
+    fn for_statement(&mut self, line: usize) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
 
@@ -836,12 +1715,18 @@ impl<'src> Parser<'src> {
         if self.equal(TokenType::Semicolon) {
             // No initializer.
         } else if self.equal(TokenType::Var) {
-            self.var_declaration();
+            let global = self.parse_variable("Expect variable name.");
+            if self.equal(TokenType::In) {
+                self.for_in_statement(line);
+                self.end_scope();
+                return;
+            }
+            self.var_initializer(global);
         } else {
-            self.expression_statement();
+            self.expression_statement(self.current.line);
         }
 
-        let mut loop_start = self.compiler.function.chunk.code.len();
+        let mut loop_start = self.current_chunk().len();
 
         // Condition clause (Optional)
         let mut exit_jump = None;
@@ -850,47 +1735,159 @@ impl<'src> Parser<'src> {
             self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
 
             // Jump out of the loop if the condition is false.
-            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0xff)));
-            self.emit_byte(OpCode::Pop); // Condition.
+            self.check_condition(line);
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(0xff), line));
+            self.emit_byte_at(OpCode::Pop, line); // Condition.
         }
 
         // Increment clause (Optional)
         if !self.equal(TokenType::RightParen) {
-            let body_jump = self.emit_jump(OpCode::Jump(0xff));
-            let increment_start = self.compiler.function.chunk.code.len();
+            let body_jump = self.emit_jump(OpCode::Jump(0xff), line);
+            let increment_start = self.current_chunk().len();
             self.expression();
-            self.emit_byte(OpCode::Pop); // discard increment expression's value
+            self.emit_byte_at(OpCode::Pop, line); // discard increment expression's value
             self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
 
-            self.emit_loop(loop_start);
+            self.emit_loop(loop_start, line);
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
 
         self.statement();
-        self.emit_loop(loop_start);
+        // the loop also ends through its condition, even if the body is a `return`
+        self.compiler.after_return = false;
+        self.emit_loop(loop_start, line);
 
         // If there is a condition clause, patch jumpand pop condition value.
         if let Some(offset) = exit_jump {
             self.patch_jump(offset);
-            self.emit_byte(OpCode::Pop); // Condition.
+            self.emit_byte_at(OpCode::Pop, line); // Condition.
         }
 
         self.end_scope();
     }
 
-    fn if_statement(&mut self) {
+    // `for (var x in range) body`, after the `in`. `x` is the last local, not initialized yet.
+    // Two hidden locals follow it: the range and the index of the next element.
+    fn for_in_statement(&mut self, line: usize) {
+        let slot = self.compiler.locals.len() - 1;
+        self.emit_byte_at(OpCode::Nil, line); // `x`, set by ForIn before every iteration
+        self.expression();
+        self.mark_initialized();
+        self.add_hidden_local(" range", line);
+        self.emit_byte_at(OpCode::Zero, line);
+        self.add_hidden_local(" index", line);
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        let loop_start = self.current_chunk().len();
+        let exit_jump = self.emit_jump(OpCode::ForIn(slot as u8, 0xff), line);
+        self.statement();
+        // the range can run out, even if the body is a `return`
+        self.compiler.after_return = false;
+        self.emit_loop(loop_start, line);
+        self.patch_jump(exit_jump);
+    }
+
+    // A local the compiler uses itself, the name can't be written in Lox
+    fn add_hidden_local(&mut self, name: &'static str, line: usize) {
+        self.add_local(Token::new(TokenType::Identifier, line, name));
+        self.mark_initialized();
+    }
+
+    // `switch (value) { case a: ... case b: ... default: ... }`. The value is a hidden local, each
+    // case compares it to its own value with `==`: the first equal case runs, then jumps to the
+    // end (no fallthrough), the others jump to the next case. Whichever case ran, the value is
+    // popped once, by the end of the scope.
+    fn switch_statement(&mut self, line: usize) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.");
+        self.add_hidden_local(" switch", line);
+        let slot = (self.compiler.locals.len() - 1) as u8;
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.equal(TokenType::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+                let case_line = self.previous.line;
+                self.emit_byte_at(OpCode::GetLocal(slot), case_line);
+                self.expression();
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+                self.emit_byte_at(OpCode::Equal, case_line);
+                let next_case = self.emit_jump(OpCode::JumpIfFalse(0xff), case_line);
+                self.emit_byte_at(OpCode::Pop, case_line); // the comparison
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump(0xff), case_line));
+                self.patch_jump(next_case);
+                self.emit_byte_at(OpCode::Pop, case_line);
+            } else if self.equal(TokenType::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
+                has_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default' in switch.");
+                break;
+            }
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        // the other cases jump here even if the last one ended with a `return`
+        self.compiler.after_return = false;
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+        self.end_scope();
+    }
+
+    // The statements of a case, up to the next case or the end of the switch
+    fn case_body(&mut self) {
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.statement();
+        }
+    }
+
+    fn if_statement(&mut self, line: usize) {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition_start = self.current_chunk().len();
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0xff));
-        self.emit_byte(OpCode::Pop); // pop the condition value, each statement is required to have zero stack effect
+        if let Some(condition) = self.constant_condition(condition_start) {
+            if self.optimize {
+                self.constant_if_statement(condition_start, condition);
+                return;
+            }
+            self.warning(
+                warnings::CONSTANT_CONDITION,
+                &format!("Condition is always {}.", condition),
+            );
+        } else if let Some(condition) = self.last_type.and_then(|ty| self.type_truthiness(ty)) {
+            // not a literal, but of a type that is always true or false, e.g. `if ("a" + "b")`
+            self.warning(
+                warnings::CONSTANT_CONDITION,
+                &format!("Condition is always {}.", condition),
+            );
+        }
+
+        self.check_condition(line);
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0xff), line);
+        self.emit_byte_at(OpCode::Pop, line); // pop the condition value, each statement is required to have zero stack effect
         self.statement();
 
-        let else_jump = self.emit_jump(OpCode::Jump(0xff));
+        let else_jump = self.emit_jump(OpCode::Jump(0xff), line);
         self.patch_jump(then_jump);
-        self.emit_byte(OpCode::Pop); // pop the condition value, each statement is required to have zero stack effect
+        self.emit_byte_at(OpCode::Pop, line); // pop the condition value, each statement is required to have zero stack effect
 
         if self.equal(TokenType::Else) {
             self.statement();
@@ -898,43 +1895,161 @@ impl<'src> Parser<'src> {
         self.patch_jump(else_jump);
     }
 
-    fn print_statement(&mut self) {
+    // Only the branch that can run is emitted, without the condition and jumps.
+    // The other branch is still parsed (and checked for errors), then its code is discarded.
+    fn constant_if_statement(&mut self, condition_start: usize, condition: bool) {
+        self.discard_code(condition_start);
+
+        self.statement_if_live(condition);
+        if self.equal(TokenType::Else) {
+            self.statement_if_live(!condition);
+        }
+    }
+
+    fn statement_if_live(&mut self, live: bool) {
+        let start = self.current_chunk().len();
+        self.statement();
+        if !live {
+            self.discard_code(start);
+        }
+    }
+
+    // Truthiness of the expression compiled since `start`, if it is a single literal.
+    // With `strict_bool` only booleans are, the others are an error at runtime.
+    fn constant_condition(&self, start: usize) -> Option<bool> {
+        let code = self.current_chunk().code();
+        if code.len() != start + 1 {
+            return None;
+        }
+        match code[start] {
+            OpCode::False => Some(false),
+            OpCode::True => Some(true),
+            _ if self.strict_bool => None,
+            OpCode::Nil => Some(false),
+            OpCode::Zero | OpCode::One | OpCode::MinusOne | OpCode::Constant(_) => Some(true),
+            _ => None,
+        }
+    }
+
+    // Truthiness of every value of the type, if they all agree: only nil and false are falsey.
+    // None with `strict_bool`, where values other than booleans are an error.
+    // With `strict_bool`, a condition that isn't known to be a boolean is checked before it's
+    // tested, so e.g. `if (nil)` is a runtime error instead of false
+    fn check_condition(&mut self, line: usize) {
+        if self.strict_bool && self.last_type != Some(TypeTag::Bool) {
+            self.emit_byte_at(OpCode::IsType(TypeTag::Bool), line);
+            self.emit_byte_at(OpCode::CheckCondition, line);
+        }
+    }
+
+    fn type_truthiness(&self, ty: TypeTag) -> Option<bool> {
+        match ty {
+            _ if self.strict_bool => None,
+            TypeTag::Nil => Some(false),
+            TypeTag::Bool => None,
+            _ => Some(true),
+        }
+    }
+
+    // Removes the instructions emitted since `start`.
+    // Constants they added are left in the pool, as later code may share their indices.
+    fn discard_code(&mut self, start: usize) {
+        self.current_chunk_mut().truncate(start);
+        // so are the ranges of the statements compiled into it
+        let compiler = &mut self.compiler;
+        let first_closed = compiler.open_statements.last().map_or(0, |&idx| idx + 1);
+        let ranges = &mut compiler.function.statement_ranges;
+        while ranges.len() > first_closed && ranges.last().unwrap().start >= start {
+            ranges.pop();
+        }
+    }
+
+    // Opens the range of a statement starting at `line`, if statement ranges are recorded
+    fn begin_statement(&mut self, line: usize) {
+        if !self.statement_ranges {
+            return;
+        }
+        let start = self.current_chunk().len();
+        let ranges = &mut self.compiler.function.statement_ranges;
+        ranges.push(StatementRange {
+            line,
+            start,
+            end: start,
+        });
+        let idx = ranges.len() - 1;
+        self.compiler.open_statements.push(idx);
+    }
+
+    fn end_statement(&mut self) {
+        if !self.statement_ranges {
+            return;
+        }
+        let end = self.current_chunk().len();
+        if let Some(idx) = self.compiler.open_statements.pop() {
+            self.compiler.function.statement_ranges[idx].end = end;
+        }
+    }
+
+    fn print_statement(&mut self, line: usize) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Print);
+        self.emit_byte_at(OpCode::Print, line);
     }
 
-    fn return_statement(&mut self) {
+    fn return_statement(&mut self, line: usize) {
         if self.compiler.f_type == FunctionType::TypeScript {
             self.error("Can't return from top-level code.");
         }
-        if self.equal(TokenType::Semicolon) { // i.e. return;
+        if self.equal(TokenType::Semicolon) {
+            // i.e. return;
             self.emit_return(); // return nil
-        } else { // i.e. return $value;
-            // if self.compiler.function.function_type == FunctionType::TypeInitializer {
-            //     self.error("Can't return a value from an initializer.");
-            // }
+        } else {
+            // i.e. return $value;
+            if self.compiler.f_type == FunctionType::TypeInitializer {
+                self.error("Can't return a value from an initializer.");
+            }
             self.expression(); // compile the value to be returned
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
-            self.emit_byte(OpCode::Return); 
+            self.emit_byte_at(OpCode::Return, line);
         }
     }
 
-    fn while_statement(&mut self) {
-        let loop_start = self.compiler.function.chunk.code.len(); // start location of loop
+    fn while_statement(&mut self, line: usize) {
+        let loop_start = self.current_chunk().len(); // start location of loop
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
 
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0xff));
-        self.emit_byte(OpCode::Pop);
+        match self.constant_condition(loop_start) {
+            Some(condition) if self.optimize => {
+                // `while (true)` loops without testing the condition, `while (false)` is dropped
+                self.discard_code(loop_start);
+                self.statement_if_live(condition);
+                if condition {
+                    self.emit_loop(loop_start, line);
+                }
+                return;
+            }
+            Some(false) => self.warning(warnings::CONSTANT_CONDITION, "Condition is always false."),
+            Some(true) => {}
+            // `while (true)` is a common loop, but no expression is meant to be always nil
+            None if self.last_type == Some(TypeTag::Nil) => {
+                self.warning(warnings::CONSTANT_CONDITION, "Condition is always false.")
+            }
+            None => {}
+        }
+
+        self.check_condition(line);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0xff), line);
+        self.emit_byte_at(OpCode::Pop, line);
         self.statement();
-        self.emit_loop(loop_start);
+        self.emit_loop(loop_start, line);
 
         self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop);
+        self.emit_byte_at(OpCode::Pop, line);
     }
 
+    // Skips tokens until a statement boundary, so one error doesn't cascade into many
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -943,27 +2058,44 @@ impl<'src> Parser<'src> {
                 return;
             }
             match self.current.token_type {
-                // TokenType::Class => ,
-                // TokenType::Fun => ,
-                // TokenType::Var => ,
-                // TokenType::For => ,
-                // TokenType::If => ,
-                // TokenType::While => ,
-                // TokenType::Print => ,
-                TokenType::Return => {
-                    return;
-                }
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Switch
+                | TokenType::Print
+                | TokenType::Return => return,
+                // the end of the enclosing block, which `block()` will consume
+                TokenType::RightBrace if self.in_block() => return,
                 _ => (), // Do nothing.
             }
             self.advance();
         }
     }
 
+    fn in_block(&self) -> bool {
+        self.compiler.scope_depth > 0
+    }
+
     fn declaration(&mut self) {
-        if self.equal(TokenType::Fun) {
+        let line = self.current.line;
+        if self.equal(TokenType::Class) {
+            self.begin_statement(line);
+            self.class_declaration();
+            self.end_statement();
+            self.compiler.after_return = false;
+        } else if self.equal(TokenType::Fun) {
+            self.begin_statement(line);
             self.fun_declaration();
+            self.end_statement();
+            self.compiler.after_return = false;
         } else if self.equal(TokenType::Var) {
+            self.begin_statement(line);
             self.var_declaration();
+            self.end_statement();
+            self.compiler.after_return = false;
         } else {
             self.statement();
         }
@@ -974,28 +2106,39 @@ impl<'src> Parser<'src> {
     }
 
     fn statement(&mut self) {
+        if !self.enter_nesting() {
+            return;
+        }
+        let line = self.current.line; // of the statement's first token
+        let is_return = self.check(TokenType::Return);
+        self.begin_statement(line);
         if self.equal(TokenType::Print) {
-            self.print_statement();
+            self.print_statement(line);
         } else if self.equal(TokenType::For) {
-            self.for_statement();
+            self.for_statement(line);
         } else if self.equal(TokenType::If) {
-            self.if_statement();
+            self.if_statement(line);
         } else if self.equal(TokenType::Return) {
-            self.return_statement();
+            self.return_statement(line);
         } else if self.equal(TokenType::While) {
-            self.while_statement();
+            self.while_statement(line);
+        } else if self.equal(TokenType::Switch) {
+            self.switch_statement(line);
         } else if self.equal(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
             self.end_scope();
         } else {
-            self.expression_statement();
+            self.expression_statement(line);
         }
+        self.end_statement();
+        self.compiler.after_return = is_return;
+        self.leave_nesting();
     }
 
     fn error_at(&mut self, token: Token, message: &str) {
         // while panic mode, suppress any other detected errors
-        if self.panic_mode {
+        if self.panic_mode || self.gave_up {
             return;
         };
         self.panic_mode = true;
@@ -1006,11 +2149,66 @@ impl<'src> Parser<'src> {
         } else if token.token_type == TokenType::Error {
             // Nothing.
         } else {
-            eprint!(" at {}'", token.lexeme);
+            eprint!(" at '{}'", truncate_lexeme(token.lexeme));
         }
 
-        eprintln!(": {}\n", message);
+        eprintln!(": {}", message);
+        let snippet = self
+            .source_offset(token)
+            .map(|offset| render_snippet(self.source, offset));
+        if let Some(snippet) = &snippet {
+            eprintln!("{}", snippet);
+        }
+        eprintln!();
         self.had_error = true;
+        let column = self
+            .source_offset(token)
+            .map(|offset| column(self.source, offset));
+        self.diagnostics.errors.push(CompileDiagnostic {
+            severity: Severity::Error,
+            code: None,
+            line: token.line,
+            column,
+            message: message.to_owned(),
+            snippet,
+        });
+        if self.diagnostics.errors.len() >= self.max_errors {
+            let note = format!("Too many errors ({}), stopping.", self.max_errors);
+            eprintln!("{}", note);
+            self.diagnostics.notes.push(note);
+            self.gave_up = true;
+        }
+    }
+
+    // Byte offset of the token in the source. Error tokens carry their message instead of a slice of the source.
+    fn source_offset(&self, token: Token) -> Option<usize> {
+        let start = self.source.as_ptr() as usize;
+        let lexeme = token.lexeme.as_ptr() as usize;
+        if token.token_type == TokenType::Error
+            || lexeme < start
+            || lexeme > start + self.source.len()
+        {
+            return None;
+        }
+        Some(lexeme - start)
+    }
+
+    fn warning(&mut self, code: &'static str, message: &str) {
+        self.warning_at(self.previous, code, message.to_owned());
+    }
+
+    fn warning_at(&mut self, token: Token, code: &'static str, message: String) {
+        let column = self
+            .source_offset(token)
+            .map(|offset| column(self.source, offset));
+        self.diagnostics.warnings.push(CompileDiagnostic {
+            severity: Severity::Warning,
+            code: Some(code),
+            line: token.line,
+            column,
+            message,
+            snippet: None,
+        });
     }
 
     fn error(&mut self, message: &str) {
@@ -1021,3 +2219,120 @@ impl<'src> Parser<'src> {
         self.error_at(self.current, message);
     }
 }
+
+const SNIPPET_WIDTH: usize = 120; // in chars, long lines are cut around the error
+const LEXEME_WIDTH: usize = 40;
+
+// The source line containing `offset`, and a caret under it:
+//     var x = ;
+//             ^
+// Lines longer than SNIPPET_WIDTH are shown around the caret, with '…' where they were cut.
+pub fn render_snippet(source: &str, offset: usize) -> String {
+    let line_start = source[..offset].rfind(['\n', '\r']).map_or(0, |i| i + 1);
+    let line_end = source[offset..]
+        .find(['\n', '\r'])
+        .map_or(source.len(), |i| offset + i);
+    let line: Vec<char> = source[line_start..line_end].chars().collect();
+    let column = column(source, offset) - 1;
+
+    let (mut start, mut end) = (0, line.len());
+    if line.len() > SNIPPET_WIDTH {
+        start = column.saturating_sub(SNIPPET_WIDTH / 2);
+        end = (start + SNIPPET_WIDTH).min(line.len());
+        start = end - SNIPPET_WIDTH;
+    }
+    let mut window = String::new();
+    if start > 0 {
+        window.push('…');
+    }
+    window.extend(&line[start..end]);
+    if end < line.len() {
+        window.push('…');
+    }
+    let caret_column = column - start + usize::from(start > 0);
+    format!("    {}\n    {}^", window, " ".repeat(caret_column))
+}
+
+// Column of `offset` in its line, in chars starting at 1
+fn column(source: &str, offset: usize) -> usize {
+    let line_start = source[..offset].rfind(['\n', '\r']).map_or(0, |i| i + 1);
+    source[line_start..offset].chars().count() + 1
+}
+
+// The chunk of a compiled function doesn't change anymore
+fn finish_chunk(f: &mut Function, strip_lines: bool) {
+    f.chunk.freeze();
+    if strip_lines {
+        f.chunk.strip_lines();
+    }
+}
+
+// Lexemes quoted in messages, e.g. a huge string literal, are cut to LEXEME_WIDTH chars
+pub fn truncate_lexeme(lexeme: &str) -> String {
+    match lexeme.char_indices().nth(LEXEME_WIDTH) {
+        Some((end, _)) => format!("{}…", &lexeme[..end]),
+        None => lexeme.to_owned(),
+    }
+}
+
+// Binary instructions that only accept operands of some types, see `OpCode::CheckOperands`
+pub fn checks_operands(op: &OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Add
+            | OpCode::AddNumbers
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual
+    )
+}
+
+// `a and b`, `a or b` is one of the operands
+fn logical_type(left: Option<TypeTag>, right: Option<TypeTag>) -> Option<TypeTag> {
+    if left == right {
+        left
+    } else {
+        None
+    }
+}
+
+// Error for `target = value`, when the target is an expression of the kind that isn't assignable.
+// Parenthesized targets aren't assignable either, not even `(a) = 1`.
+fn invalid_target_message(kind: ExprKind) -> &'static str {
+    match kind {
+        ExprKind::Call => "Cannot assign to a function call.",
+        ExprKind::Index => "Cannot assign to an indexed element, ranges are immutable.",
+        ExprKind::Grouping => "Cannot assign to a parenthesized expression.",
+        ExprKind::Literal
+        | ExprKind::Unary
+        | ExprKind::Arithmetic
+        | ExprKind::Equality
+        | ExprKind::Comparison
+        | ExprKind::Logical => "Cannot assign to the result of an expression.",
+        // `this` and `super.method`
+        ExprKind::Variable | ExprKind::Property => "Invalid assignment target.",
+    }
+}
+
+fn binary_expr_kind(operator_type: TokenType) -> ExprKind {
+    match operator_type {
+        TokenType::BangEqual | TokenType::EqualEqual => ExprKind::Equality,
+        TokenType::Greater
+        | TokenType::GreaterEqual
+        | TokenType::Less
+        | TokenType::LessEqual
+        | TokenType::In => ExprKind::Comparison,
+        _ => ExprKind::Arithmetic,
+    }
+}
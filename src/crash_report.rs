@@ -0,0 +1,149 @@
+// State dump for internal errors: when the VM panics (a bug, e.g. an invalid chunk indexing past
+// the stack), the report shows what it was executing instead of only the panic location.
+// See `VM::enable_crash_reports`.
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{chunk::OpCode, debug, value::debug_value, vm::VM};
+
+pub const HISTORY_LEN: usize = 32; // executed instructions kept for the report
+const STACK_VALUES: usize = 16; // values shown from the top of the stack
+
+#[derive(Clone, Copy)]
+pub struct ExecutedOp {
+    pub f_idx: usize,
+    pub ip: usize,
+    pub op: OpCode,
+}
+
+// The last executed instructions (HISTORY_LEN for crash reports), in a fixed-size ring buffer
+pub struct OpHistory {
+    entries: Vec<ExecutedOp>,
+    capacity: usize,
+    next: usize, // slot of the next record, i.e. the oldest entry once the buffer is full
+}
+
+impl Default for OpHistory {
+    fn default() -> OpHistory {
+        OpHistory::new()
+    }
+}
+
+impl OpHistory {
+    pub fn new() -> OpHistory {
+        OpHistory::with_capacity(HISTORY_LEN)
+    }
+
+    // Keeps the last `capacity` instructions, at least one
+    pub fn with_capacity(capacity: usize) -> OpHistory {
+        let capacity = capacity.max(1);
+        OpHistory {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, f_idx: usize, ip: usize, op: OpCode) {
+        let executed = ExecutedOp { f_idx, ip, op };
+        if self.entries.len() < self.capacity {
+            self.entries.push(executed);
+        } else {
+            self.entries[self.next] = executed;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.next = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &ExecutedOp> {
+        let (newer, older) = self.entries.split_at(self.next.min(self.entries.len()));
+        older.iter().chain(newer)
+    }
+}
+
+// Runs `f`, and if it panics, prints `VM::crash_report` to stderr before the panic continues
+pub fn run_reporting_crashes<T>(vm: &mut VM, f: impl FnOnce(&mut VM) -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(vm))) {
+        Ok(result) => result,
+        Err(payload) => {
+            eprintln!("{}", report(vm));
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+// The current function and instruction, the top of the stack, the call frames and
+// the last executed instructions (if the VM records them)
+pub fn report(vm: &VM) -> String {
+    let mut out = String::from("== VM state ==\n");
+    match vm.frames.last() {
+        Some(frame) => {
+            let function = &vm.functions[frame.f_idx];
+            let _ = writeln!(
+                out,
+                "function: {}",
+                debug::chunk_title(function, &vm.interner)
+            );
+            let _ = writeln!(out, "ip: {}", frame.ip);
+            let _ = writeln!(
+                out,
+                "instruction: {}",
+                debug::disassemble_instruction(&function.chunk, frame.ip, &vm.interner)
+            );
+        }
+        None => out.push_str("no function is running\n"),
+    }
+
+    let _ = writeln!(out, "stack ({} values, top first):", vm.stack.len());
+    for (depth, value) in vm.stack.iter().rev().take(STACK_VALUES).enumerate() {
+        let slot = vm.stack.len() - 1 - depth;
+        let _ = writeln!(out, "  [{}] {}", slot, debug_value(value, &vm.interner));
+    }
+
+    out.push_str("frames (innermost first):\n");
+    for frame in vm.frames.iter().rev() {
+        let function = &vm.functions[frame.f_idx];
+        let _ = writeln!(
+            out,
+            "  {} at ip {}, line {}, slots from {}",
+            debug::chunk_title(function, &vm.interner),
+            frame.ip,
+            function
+                .chunk
+                .line(frame.ip)
+                .map_or("?".to_owned(), |line| line.to_string()),
+            frame.slot_offset()
+        );
+    }
+
+    match vm.op_history() {
+        Some(history) => {
+            let _ = writeln!(out, "last {} instructions (oldest first):", history.len());
+            for executed in history.iter() {
+                let function = &vm.functions[executed.f_idx];
+                let _ = writeln!(
+                    out,
+                    "  {}: {} {}",
+                    function.display_name(&vm.interner),
+                    executed.ip,
+                    debug::instruction(&function.chunk, executed.ip, &executed.op, &vm.interner)
+                );
+            }
+        }
+        None => out.push_str("executed instructions are not recorded\n"),
+    }
+    out
+}
@@ -1,109 +1,239 @@
 use crate::{
     chunk::{Chunk, OpCode},
+    function::{Function, SCRIPT_NAME},
     interner::Interner,
-    value::print_value,
+    value::debug_value,
 };
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str, interner: &Interner) {
-    println!("== {} ==", name);
-    let mut offset = 0;
-    while offset < chunk.code.len() {
-        offset = disassemble_instruction(chunk, offset, interner);
+// e.g. "add (util.lox)" for a function compiled from util.lox
+pub fn chunk_title(function: &Function, interner: &Interner) -> String {
+    let name = function.display_name(interner);
+    match function.origin {
+        Some(origin) => format!("{} ({})", name, interner.lookup(origin)),
+        None => name.to_owned(),
     }
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner) -> usize {
-    print!("{} ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        print!("   | ");
-    } else {
-        print!("{} ", chunk.lines[offset]);
+// Title of the function's section in a labeled disassembly, with the arity and the captured
+// variables that `asm` needs to assemble it again, e.g. "add/2 (util.lox)" or
+// "inner/0 [local 1, upvalue 0]"
+pub fn section_title(function: &Function, interner: &Interner) -> String {
+    let mut name = match function.name {
+        Some(name) => format!("{}/{}", interner.lookup(name), function.arity),
+        None => SCRIPT_NAME.to_owned(),
+    };
+    if !function.upvalues.is_empty() {
+        let captures: Vec<String> = function
+            .upvalues
+            .iter()
+            .map(|capture| match capture.is_local {
+                true => format!("local {}", capture.index),
+                false => format!("upvalue {}", capture.index),
+            })
+            .collect();
+        name = format!("{} [{}]", name, captures.join(", "));
+    }
+    match function.origin {
+        Some(origin) => format!("{} ({})", name, interner.lookup(origin)),
+        None => name,
     }
+}
 
-    let instruction = &chunk.code[offset];
-    match instruction {
-        OpCode::Constant(idx) => {
-            constant_instruction("OP_CONSTANT", chunk, offset, (*idx).into(), interner)
-        }
-        OpCode::Nil => simple_instruction("OP_NIL", offset),
-        OpCode::True => simple_instruction("OP_TRUE", offset),
-        OpCode::False => simple_instruction("OP_FALSE", offset),
-        OpCode::Pop => simple_instruction("OP_POP", offset),
-        OpCode::DefineGlobal(idx) => {
-            constant_instruction("OP_DEFINE_GLOBAL", chunk, offset, (*idx).into(), interner)
+pub fn disassemble_chunk(chunk: &Chunk, name: &str, interner: &Interner) {
+    print!("{}", disassemble(chunk, name, interner));
+}
+
+// One line per instruction: offset, line (or '|' if it's the line of the previous instruction)
+// and the instruction with its operands
+pub fn disassemble(chunk: &Chunk, name: &str, interner: &Interner) -> String {
+    let mut out = format!("== {} ==\n", name);
+    for (offset, op) in chunk.instructions() {
+        match chunk.line(offset) {
+            Some(line) if offset > 0 && chunk.line(offset - 1) == Some(line) => {
+                out.push_str(&format!("{}    | ", offset))
+            }
+            Some(line) => out.push_str(&format!("{} {} ", offset, line)),
+            None => out.push_str(&format!("{} ? ", offset)),
         }
-        OpCode::GetGlobal(idx) => {
-            constant_instruction("OP_GET_GLOBAL", chunk, offset, (*idx).into(), interner)
+        out.push_str(&instruction(chunk, offset, &op, interner));
+        out.push('\n');
+    }
+    out
+}
+
+// Like `disassemble`, without offsets: jumps name a label (`OP_LOOP -> L3`) that is shown as
+// `L3:` before its target. Inserting or removing an instruction then only changes its own line,
+// e.g. to diff the code of two compiler versions. Labels are named after the source line of
+// their target (`L3`, then `L3.2`, ...), so removed code doesn't rename the labels after it.
+pub fn disassemble_labeled(chunk: &Chunk, name: &str, interner: &Interner) -> String {
+    let mut targets: Vec<usize> = chunk
+        .instructions()
+        .filter_map(|(offset, op)| chunk.jump_target(offset, &op))
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    // the end of the chunk has the line of the last instruction
+    let target_line = |target: usize| chunk.line(target.min(chunk.len().saturating_sub(1)));
+    let labels: Vec<String> = targets
+        .iter()
+        .enumerate()
+        .map(|(idx, &target)| {
+            let line = target_line(target);
+            let same_line = targets[..idx]
+                .iter()
+                .filter(|&&earlier| target_line(earlier) == line)
+                .count();
+            // `L?` in chunks without lines
+            let line = line.map_or("?".to_owned(), |line| line.to_string());
+            match same_line {
+                0 => format!("L{}", line),
+                n => format!("L{}.{}", line, n + 1),
+            }
+        })
+        .collect();
+    let label = |target: usize| match targets.binary_search(&target) {
+        Ok(idx) => labels[idx].clone(),
+        Err(_) => "<invalid>".to_owned(),
+    };
+
+    let mut out = format!("== {} ==\n", name);
+    for (offset, op) in chunk.instructions() {
+        if targets.binary_search(&offset).is_ok() {
+            out.push_str(&format!("{}:\n", label(offset)));
         }
-        OpCode::SetGlobal(idx) => {
-            constant_instruction("OP_SET_GLOBAL", chunk, offset, (*idx).into(), interner)
+        // `?` for every instruction of a chunk without lines, which `asm` reads back as such
+        match chunk.line(offset) {
+            Some(line) if offset > 0 && chunk.line(offset - 1) == Some(line) => {
+                out.push_str("   | ")
+            }
+            Some(line) => out.push_str(&format!("{:>4} ", line)),
+            None => out.push_str("   ? "),
         }
-        OpCode::GetLocal(idx) => byte_instruction("OP_GET_LOCAL", offset, (*idx).into()),
-        OpCode::SetLocal(idx) => byte_instruction("OP_SET_LOCAL", offset, (*idx).into()),
-        OpCode::Equal => simple_instruction("OP_EQUAL", offset),
-        OpCode::Greater => simple_instruction("OP_GREATER", offset),
-        OpCode::Less => simple_instruction("OP_LESS", offset),
-        OpCode::Add => simple_instruction("OP_ADD", offset),
-        OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
-        OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
-        OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
-        OpCode::Not => simple_instruction("OP_NOT", offset),
-        OpCode::Negate => simple_instruction("OP_NEGATE", offset),
-        OpCode::Print => simple_instruction("OP_PRINT", offset),
-        OpCode::Jump(jump) => jump_instruction("OP_JUMP", chunk, offset, jump, true),
-        OpCode::JumpIfFalse(jump) => {
-            jump_instruction("OP_JUMP_IF_FALSE", chunk, offset, jump, true)
+        match (jump_name(&op), chunk.jump_target(offset, &op)) {
+            (Some(name), Some(target)) => out.push_str(&format!("{} -> {}", name, label(target))),
+            _ => out.push_str(&instruction(chunk, offset, &op, interner)),
         }
-        OpCode::Loop(jump) => jump_instruction("OP_LOOP", chunk, offset, jump, false),
-        OpCode::Return => simple_instruction("OP_RETURN", offset),
-        OpCode::Call(arg_count) => byte_instruction("OP_GET_LOCAL", offset, (*arg_count).into()),
-        // _ => {
-        //     println!("Unknown opcode {:?}\n", instruction);
-        //     offset + 1
-        // }
+        out.push('\n');
+    }
+    // a jump to the end of the chunk
+    if targets.last() == Some(&chunk.len()) {
+        out.push_str(&format!("{}:\n", label(chunk.len())));
     }
+    out
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+// e.g. "3 1 OP_GET_LOCAL 2", like a line of `disassemble`
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize, interner: &Interner) -> String {
+    match chunk.op(offset) {
+        Some(op) => format!(
+            "{} {} {}",
+            offset,
+            chunk
+                .line(offset)
+                .map_or("?".to_owned(), |line| line.to_string()),
+            instruction(chunk, offset, &op, interner)
+        ),
+        None => format!("{} <out of bounds>", offset),
+    }
 }
 
-fn byte_instruction(name: &str, offset: usize, constant_idx: usize) -> usize {
-    println!("{} {:?} '", name, constant_idx);
-    offset + 1
+pub fn instruction(chunk: &Chunk, offset: usize, op: &OpCode, interner: &Interner) -> String {
+    match *op {
+        OpCode::Constant(idx) => constant_instruction("OP_CONSTANT", chunk, idx, interner),
+        OpCode::Zero => "OP_ZERO".to_owned(),
+        OpCode::One => "OP_ONE".to_owned(),
+        OpCode::MinusOne => "OP_MINUS_ONE".to_owned(),
+        OpCode::Nil => "OP_NIL".to_owned(),
+        OpCode::True => "OP_TRUE".to_owned(),
+        OpCode::False => "OP_FALSE".to_owned(),
+        OpCode::Pop => "OP_POP".to_owned(),
+        OpCode::PopN(n) => format!("OP_POP_N {}", n),
+        OpCode::DefineGlobal(idx) => constant_instruction("OP_DEFINE_GLOBAL", chunk, idx, interner),
+        OpCode::DefineFunction(idx) => {
+            constant_instruction("OP_DEFINE_FUNCTION", chunk, idx, interner)
+        }
+        OpCode::Range => "OP_RANGE".to_owned(),
+        OpCode::In => "OP_IN".to_owned(),
+        OpCode::Index => "OP_INDEX".to_owned(),
+        OpCode::ForIn(slot, _) => {
+            jump_instruction(&format!("OP_FOR_IN {}", slot), chunk, offset, op)
+        }
+        OpCode::GetGlobal(idx) => constant_instruction("OP_GET_GLOBAL", chunk, idx, interner),
+        OpCode::SetGlobal(idx) => constant_instruction("OP_SET_GLOBAL", chunk, idx, interner),
+        OpCode::GetLocal(idx) => format!("OP_GET_LOCAL {}", idx),
+        OpCode::SetLocal(idx) => format!("OP_SET_LOCAL {}", idx),
+        OpCode::GetUpvalue(idx) => format!("OP_GET_UPVALUE {}", idx),
+        OpCode::SetUpvalue(idx) => format!("OP_SET_UPVALUE {}", idx),
+        OpCode::Closure(idx) => constant_instruction("OP_CLOSURE", chunk, idx, interner),
+        OpCode::CloseUpvalue => "OP_CLOSE_UPVALUE".to_owned(),
+        OpCode::Class(idx) => constant_instruction("OP_CLASS", chunk, idx, interner),
+        OpCode::Method(idx) => constant_instruction("OP_METHOD", chunk, idx, interner),
+        OpCode::Field(idx) => constant_instruction("OP_FIELD", chunk, idx, interner),
+        OpCode::Inherit => "OP_INHERIT".to_owned(),
+        OpCode::GetSuper(idx) => constant_instruction("OP_GET_SUPER", chunk, idx, interner),
+        OpCode::GetProperty(idx) => constant_instruction("OP_GET_PROPERTY", chunk, idx, interner),
+        OpCode::SetProperty(idx) => constant_instruction("OP_SET_PROPERTY", chunk, idx, interner),
+        OpCode::Equal => "OP_EQUAL".to_owned(),
+        OpCode::IsNil => "OP_IS_NIL".to_owned(),
+        OpCode::IsTrue => "OP_IS_TRUE".to_owned(),
+        OpCode::IsFalse => "OP_IS_FALSE".to_owned(),
+        OpCode::Greater => "OP_GREATER".to_owned(),
+        OpCode::Less => "OP_LESS".to_owned(),
+        OpCode::GreaterEqual => "OP_GREATER_EQUAL".to_owned(),
+        OpCode::LessEqual => "OP_LESS_EQUAL".to_owned(),
+        OpCode::Add => "OP_ADD".to_owned(),
+        OpCode::AddNumbers => "OP_ADD_NUMBERS".to_owned(),
+        OpCode::Subtract => "OP_SUBTRACT".to_owned(),
+        OpCode::Multiply => "OP_MULTIPLY".to_owned(),
+        OpCode::Divide => "OP_DIVIDE".to_owned(),
+        OpCode::Modulo => "OP_MODULO".to_owned(),
+        OpCode::Power => "OP_POWER".to_owned(),
+        OpCode::BitAnd => "OP_BIT_AND".to_owned(),
+        OpCode::BitOr => "OP_BIT_OR".to_owned(),
+        OpCode::BitXor => "OP_BIT_XOR".to_owned(),
+        OpCode::ShiftLeft => "OP_SHIFT_LEFT".to_owned(),
+        OpCode::ShiftRight => "OP_SHIFT_RIGHT".to_owned(),
+        OpCode::BitNot => "OP_BIT_NOT".to_owned(),
+        OpCode::Not => "OP_NOT".to_owned(),
+        OpCode::Negate => "OP_NEGATE".to_owned(),
+        OpCode::Print => "OP_PRINT".to_owned(),
+        OpCode::Jump(_) => jump_instruction("OP_JUMP", chunk, offset, op),
+        OpCode::JumpIfFalse(_) => jump_instruction("OP_JUMP_IF_FALSE", chunk, offset, op),
+        OpCode::Loop(_) => jump_instruction("OP_LOOP", chunk, offset, op),
+        OpCode::Return => "OP_RETURN".to_owned(),
+        OpCode::Call(arg_count) => format!("OP_CALL {}", arg_count),
+        OpCode::IsType(tag) => format!("OP_IS_TYPE {}", tag.name()),
+        OpCode::PopBelow => "OP_POP_BELOW".to_owned(),
+        OpCode::CheckCondition => "OP_CHECK_CONDITION".to_owned(),
+        OpCode::CheckSlot(idx) => format!("OP_CHECK_SLOT {}", idx),
+        OpCode::CheckArity(arity) => format!("OP_CHECK_ARITY {}", arity),
+        OpCode::CheckOperands(idx) => {
+            constant_instruction("OP_CHECK_OPERANDS", chunk, idx, interner)
+        }
+        OpCode::Nop => "OP_NOP".to_owned(),
+    }
 }
 
-fn jump_instruction(
-    name: &str,
-    chunk: &Chunk,
-    offset: usize,
-    jump: &usize,
-    forward: bool,
-) -> usize {
-    let mut dest_idx = offset + jump;
-    let mut signed_jump = *jump as i128;
-    if !forward {
-        dest_idx = offset - jump;
-        signed_jump = -signed_jump;
+fn jump_name(op: &OpCode) -> Option<String> {
+    match *op {
+        OpCode::Jump(_) => Some("OP_JUMP".to_owned()),
+        OpCode::JumpIfFalse(_) => Some("OP_JUMP_IF_FALSE".to_owned()),
+        OpCode::Loop(_) => Some("OP_LOOP".to_owned()),
+        OpCode::ForIn(slot, _) => Some(format!("OP_FOR_IN {}", slot)),
+        _ => None,
     }
+}
 
-    println!(
-        "{} offset:{} jump:{} -> {:?}",
-        name, offset, signed_jump, chunk.code[dest_idx]
-    );
-    offset + 1
+fn jump_instruction(name: &str, chunk: &Chunk, offset: usize, op: &OpCode) -> String {
+    match chunk.jump_target(offset, op) {
+        Some(target) => format!("{} {} -> {}", name, offset, target),
+        None => format!("{} {} -> <invalid>", name, offset),
+    }
 }
 
-fn constant_instruction(
-    name: &str,
-    chunk: &Chunk,
-    offset: usize,
-    constant_idx: usize,
-    interner: &Interner,
-) -> usize {
-    print!("{} {:?} '", name, constant_idx);
-    print_value(&chunk.constants.values[constant_idx], interner);
-    println!("'");
-    offset + 1
+fn constant_instruction(name: &str, chunk: &Chunk, idx: u8, interner: &Interner) -> String {
+    match chunk.constant(idx.into()) {
+        Some(value) => format!("{} {} '{}'", name, idx, debug_value(value, interner)),
+        None => format!("{} {} <invalid>", name, idx),
+    }
 }
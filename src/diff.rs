@@ -0,0 +1,113 @@
+// Line diff of two texts in the unified format (without the file headers), e.g. to compare
+// the disassembly of a program before and after a compiler change:
+//     @@ -3,4 +3,3 @@
+//      unchanged line
+//     -removed line
+//     +added line
+// Identical texts give an empty diff.
+
+const CONTEXT: usize = 2; // unchanged lines shown around a change
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    Same,
+    Removed,
+    Added,
+}
+
+pub fn unified(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let edits = edits(&old, &new);
+
+    // lines of each text before every edit, for the hunk headers
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old_line, mut new_line) = (0, 0);
+    for (edit, _) in &edits {
+        positions.push((old_line, new_line));
+        match edit {
+            Edit::Same => {
+                old_line += 1;
+                new_line += 1;
+            }
+            Edit::Removed => old_line += 1,
+            Edit::Added => new_line += 1,
+        }
+    }
+    positions.push((old_line, new_line));
+
+    let mut out = String::new();
+    let mut k = 0;
+    while let Some(first) = (k..edits.len()).find(|&i| edits[i].0 != Edit::Same) {
+        // extend the hunk while the next change is close enough to share the context
+        let start = first.saturating_sub(CONTEXT);
+        let mut end = first;
+        while let Some(next) = (end + 1..edits.len()).find(|&i| edits[i].0 != Edit::Same) {
+            if next - end > 2 * CONTEXT {
+                break;
+            }
+            end = next;
+        }
+        let end = (end + 1 + CONTEXT).min(edits.len());
+
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        ));
+        for (edit, line) in &edits[start..end] {
+            let marker = match edit {
+                Edit::Same => ' ',
+                Edit::Removed => '-',
+                Edit::Added => '+',
+            };
+            out.push(marker);
+            out.push_str(line);
+            out.push('\n');
+        }
+        k = end;
+    }
+    out
+}
+
+// `start,len` with lines counted from 1, like diff(1): an empty range names the line before it
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+// The shortest edit script, from the longest common subsequence of the lines
+fn edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    // common[i][j] = length of the LCS of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push((Edit::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] > common[i + 1][j]) {
+            edits.push((Edit::Added, new[j]));
+            j += 1;
+        } else {
+            edits.push((Edit::Removed, old[i]));
+            i += 1;
+        }
+    }
+    edits
+}
@@ -0,0 +1,88 @@
+// Hex and base64 (standard alphabet, with `=` padding) of the `toHex`/`fromHex` and
+// `toBase64`/`fromBase64` natives. Decoding errors name the index of the bad character.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Lowercase, two digits per byte
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Upper- or lowercase digits
+pub fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().collect();
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    for (idx, pair) in digits.chunks(2).enumerate() {
+        let mut byte = 0;
+        for (offset, &c) in pair.iter().enumerate() {
+            let digit = c.to_digit(16).ok_or_else(|| {
+                format!("Invalid hex digit '{}' at index {}.", c, idx * 2 + offset)
+            })?;
+            byte = byte * 16 + digit as u8;
+        }
+        if pair.len() == 1 {
+            return Err(format!(
+                "Hex string needs an even number of digits, got {}.",
+                digits.len()
+            ));
+        }
+        bytes.push(byte);
+    }
+    Ok(bytes)
+}
+
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let n = group
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        // a group of k bytes is k + 1 characters, padded to 4
+        for i in 0..4 {
+            if i <= group.len() {
+                text.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+pub fn from_base64(text: &str) -> Result<Vec<u8>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    if !chars.len().is_multiple_of(4) {
+        return Err(format!(
+            "Base64 string needs a multiple of 4 characters, got {}.",
+            chars.len()
+        ));
+    }
+    let mut bytes = Vec::with_capacity(chars.len() / 4 * 3);
+    for (group_idx, group) in chars.chunks(4).enumerate() {
+        let last_group = (group_idx + 1) * 4 == chars.len();
+        let mut n = 0u32;
+        let mut padding = 0;
+        for (offset, &c) in group.iter().enumerate() {
+            let idx = group_idx * 4 + offset;
+            // `=` only pads the last group, after at least 2 characters
+            let sextet = if c == '=' && last_group && offset >= 2 {
+                padding += 1;
+                0
+            } else if padding > 0 {
+                return Err(format!(
+                    "Invalid base64 character '{}' at index {}.",
+                    c, idx
+                ));
+            } else {
+                BASE64
+                    .iter()
+                    .position(|&digit| digit as char == c)
+                    .ok_or_else(|| format!("Invalid base64 character '{}' at index {}.", c, idx))?
+                    as u32
+            };
+            n = n << 6 | sextet;
+        }
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,22 @@
+// What the `env` native sees, see `VM::environment`.
+// Tests and sandboxes replace the process environment, so scripts can't observe the host's.
+use std::collections::HashMap;
+use std::env;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Environment {
+    Process,                      // the real environment variables of the process
+    Empty,                        // no variables, e.g. for a sandbox
+    Map(HashMap<String, String>), // only these variables, see `VM::set_env_map`
+}
+
+impl Environment {
+    // Value of a variable, None if it isn't set (or isn't valid Unicode)
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self {
+            Environment::Process => env::var(name).ok(),
+            Environment::Empty => None,
+            Environment::Map(map) => map.get(name).cloned(),
+        }
+    }
+}
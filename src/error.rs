@@ -0,0 +1,84 @@
+// Errors returned by `VM::interpret`.
+// The exit code of the CLI is derived from the variant (65 for compile errors, 70 for runtime errors,
+// 74 if the script couldn't be read).
+use std::{fmt, path::PathBuf};
+
+use crate::compiler::CompileDiagnostic;
+use crate::function::SCRIPT_NAME;
+use crate::source_map::SessionPosition;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoxError {
+    Compile(Vec<CompileDiagnostic>), // every error reported while compiling
+    Runtime(RuntimeError),
+    Io { path: PathBuf, message: String }, // e.g. the file passed to `VM::interpret_file` doesn't exist
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub trace: Vec<TraceFrame>, // innermost frame first
+}
+
+// One line of a stack trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    pub function: Option<String>,          // None for the top-level script
+    pub origin: Option<String>,            // file the function was compiled from, if known
+    pub line: Option<usize>,               // None if the instruction has no line info
+    pub position: Option<SessionPosition>, // with `VM::enable_source_map`
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.origin, self.line) {
+            (Some(origin), Some(line)) => write!(f, "[file {}, line {}]", origin, line)?,
+            (Some(origin), None) => write!(f, "[file {}, unknown line]", origin)?,
+            (None, Some(line)) => write!(f, "[line {}]", line)?,
+            (None, None) => write!(f, "[unknown line]")?,
+        }
+        match &self.function {
+            Some(name) => write!(f, " in {}()", name)?,
+            None => write!(f, " in {}", SCRIPT_NAME)?,
+        }
+        // e.g. a function defined in an earlier REPL line
+        if let Some(position) = &self.position {
+            write!(
+                f,
+                " (submission {}, session line {})\n    {}",
+                position.submission, position.session_line, position.text
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                write!(f, "Compile error")?;
+                for error in errors {
+                    write!(f, "\n[line {}] {}", error.line, error.message)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error) => write!(f, "{}", error),
+            LoxError::Io { path, message } => {
+                write!(f, "Could not open file '{}': {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
@@ -1,9 +1,49 @@
-use crate::{chunk::Chunk, interner::StringObjIdx};
+use crate::{
+    chunk::Chunk,
+    globals::GlobalSlot,
+    interner::{Interner, StringObjIdx},
+    value::Value,
+};
 
+// Instructions `start..end` of a chunk were compiled from the statement starting at `line`.
+// Ranges of nested statements (e.g. the branches of an `if`) lie within their parent's range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatementRange {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+// Where a closure of the function finds a variable it captures when it's created: a local slot
+// of the enclosing function's frame, or an upvalue of the enclosing closure
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capture {
+    pub index: u8,
+    pub is_local: bool,
+}
+
+// Name of the top-level function of a script, which has no declared name
+pub const SCRIPT_NAME: &str = "<script>";
+
+#[derive(Clone)]
 pub struct Function {
     pub arity: u8, // # of parameters
     pub chunk: Chunk,
     pub name: Option<StringObjIdx>,
+    pub origin: Option<StringObjIdx>, // file path or e.g. "<repl>" the function was compiled from
+    pub submission: Option<usize>,    // index in the VM's source map, if it's enabled
+    pub upvalues: Vec<Capture>,       // variables of enclosing functions, by upvalue index
+    // in source order, outer statements before the ones nested in them; empty unless
+    // compiled with `CompileOptions::statement_ranges`
+    pub(crate) statement_ranges: Vec<StatementRange>,
+    // slot of the global read by the GetGlobal at each offset, filled in when it first runs
+    pub(crate) global_cache: Vec<Option<GlobalSlot>>,
+}
+
+impl Default for Function {
+    fn default() -> Function {
+        Function::new()
+    }
 }
 
 impl Function {
@@ -12,6 +52,39 @@ impl Function {
             arity: 0,
             chunk: Chunk::new(),
             name: None,
+            origin: None,
+            submission: None,
+            upvalues: Vec::new(),
+            statement_ranges: Vec::new(),
+            global_cache: Vec::new(),
         }
     }
+
+    pub fn statement_ranges(&self) -> &[StatementRange] {
+        &self.statement_ranges
+    }
+
+    // The one name used for the function in traces, disassembly and error messages:
+    // declared functions by their name (methods as e.g. "Point.init"), the top-level script
+    // as "<script>"
+    pub fn display_name<'a>(&self, interner: &'a Interner) -> &'a str {
+        match self.name {
+            Some(name) => interner.lookup(name),
+            None => SCRIPT_NAME,
+        }
+    }
+}
+
+// A function with the variables it captured, created by `OpCode::Closure` at runtime
+pub struct Closure {
+    pub f_idx: usize,
+    pub upvalues: Vec<usize>, // = idx in the upvalue list in VM
+}
+
+// A captured variable. It lives on the stack of the function that declared it, until it goes
+// out of scope and the upvalue keeps its value instead.
+#[derive(Clone, Copy, Debug)]
+pub enum Upvalue {
+    Open(usize), // absolute index in the VM's stack
+    Closed(Value),
 }
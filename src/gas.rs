@@ -0,0 +1,40 @@
+// What running a script costs, for embedders that meter untrusted code (see `VM::cost_model`
+// and `Limits::max_gas`). Costs only depend on what the script does, never on the clock,
+// so the same script always uses the same gas.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CostModel {
+    pub instruction: u64, // every executed instruction
+    pub call: u64,        // calling a Lox function, on top of the Call instruction
+    pub native_call: u64, // calling a native, on top of the Call instruction
+    pub per_byte: u64,    // every byte of a string built by concatenation
+}
+
+// 1 per instruction, i.e. gas is the number of executed instructions
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel {
+            instruction: 1,
+            call: 0,
+            native_call: 0,
+            per_byte: 0,
+        }
+    }
+}
+
+impl CostModel {
+    // Closer to what operations cost: calls push frames and natives do arbitrary work,
+    // and building a string copies both operands
+    pub fn weighted() -> CostModel {
+        CostModel {
+            instruction: 1,
+            call: 10,
+            native_call: 20,
+            per_byte: 1,
+        }
+    }
+
+    pub fn concatenation(&self, length: usize) -> u64 {
+        self.per_byte.saturating_mul(length as u64)
+    }
+}
@@ -0,0 +1,144 @@
+// Glob patterns for the `match` and `find` natives: `*` matches any run of chars, `?` one char,
+// `[a-z0-9_]` one char of a class (`[!...]` or `[^...]` negates it) and `\` makes the next char
+// literal. Patterns and texts are compared by chars, not bytes.
+//
+// Matching is the two-pointer algorithm: on a mismatch only the most recent `*` takes one more
+// char, earlier ones stay fixed. That is enough for globs and takes O(text * pattern) time
+// at worst, so patterns like `*a*a*a*b` can't backtrack exponentially.
+use std::str::Chars;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    AnyChar, // ?
+    AnyRun,  // *
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    // for every token except `*`
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(expected) => c == *expected,
+            Token::AnyChar => true,
+            Token::AnyRun => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(from, to)| from <= c && c <= to) != *negated
+            }
+        }
+    }
+}
+
+pub struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern, String> {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => {
+                    // `**` is the same as `*`
+                    if tokens.last() == Some(&Token::AnyRun) {
+                        continue;
+                    }
+                    Token::AnyRun
+                }
+                '?' => Token::AnyChar,
+                '\\' => match chars.next() {
+                    Some(escaped) => Token::Char(escaped),
+                    None => return Err("Pattern ends with '\\'.".to_owned()),
+                },
+                '[' => parse_class(&mut chars)?,
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Pattern { tokens })
+    }
+
+    // Whether the pattern matches the whole text
+    pub fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.match_at(&text, true)
+    }
+
+    // Index (in chars) of the first position where the pattern matches the text that follows
+    pub fn find(&self, text: &str) -> Option<usize> {
+        let text: Vec<char> = text.chars().collect();
+        // a leading `*` already tries every position
+        if self.tokens.first() == Some(&Token::AnyRun) {
+            return self.match_at(&text, false).then_some(0);
+        }
+        (0..=text.len()).find(|&start| self.match_at(&text[start..], false))
+    }
+
+    // Matches the pattern against the start of the text, or all of it if `whole`
+    fn match_at(&self, text: &[char], whole: bool) -> bool {
+        let (mut p, mut t) = (0, 0);
+        // pattern index after the last `*`, and the text index where its run ends for now
+        let mut star: Option<(usize, usize)> = None;
+        loop {
+            match self.tokens.get(p) {
+                Some(Token::AnyRun) => {
+                    star = Some((p + 1, t));
+                    p += 1;
+                    continue;
+                }
+                Some(token) if t < text.len() && token.matches(text[t]) => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                Some(_) => {}
+                None if !whole || t == text.len() => return true,
+                None => {}
+            }
+            // mismatch: the last `*` takes one more char and the rest is matched again
+            match star {
+                Some((after_star, run_end)) if run_end < text.len() => {
+                    star = Some((after_star, run_end + 1));
+                    p = after_star;
+                    t = run_end + 1;
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+// After the `[`: `a-z`, single chars and an optional leading `!` or `^`, up to the `]`.
+// A `]` right after the `[` (or the negation) is a literal.
+fn parse_class(chars: &mut Chars) -> Result<Token, String> {
+    let negated = matches!(chars.clone().next(), Some('!') | Some('^'));
+    if negated {
+        chars.next();
+    }
+    let mut ranges = Vec::new();
+    loop {
+        let c = match chars.next() {
+            Some(']') if !ranges.is_empty() => return Ok(Token::Class { negated, ranges }),
+            Some('\\') => chars.next(),
+            c => c,
+        };
+        let from = c.ok_or("Unterminated character class in pattern.")?;
+        let mut lookahead = chars.clone();
+        let to = match (lookahead.next(), lookahead.next()) {
+            (Some('-'), Some(to)) if to != ']' => {
+                chars.next();
+                chars.next();
+                if to < from {
+                    return Err(format!("Invalid range '{}-{}' in pattern.", from, to));
+                }
+                to
+            }
+            _ => from,
+        };
+        ranges.push((from, to));
+    }
+}
@@ -0,0 +1,99 @@
+// Global variables, keyed by interner idx.
+// Iteration follows definition order, so anything that lists globals is deterministic.
+// Globals defined by the host (e.g. natives) are remembered, so user state can be reset without them.
+// A global keeps its slot until the user state is reset, so the VM can cache the slot of a name
+// (`GlobalSlot`) at each instruction that reads it instead of hashing the name every time.
+use std::collections::HashMap;
+
+use crate::{interner::StringObjIdx, value::Value};
+
+pub struct Globals {
+    slots: HashMap<StringObjIdx, usize>, // name -> idx in `entries`
+    entries: Vec<(StringObjIdx, Value)>,
+    host_values: HashMap<StringObjIdx, Value>, // as defined by the host, before scripts ran
+    generation: u32,                           // bumped when the slots are renumbered
+}
+
+// Where a global is stored, valid as long as the slots aren't renumbered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GlobalSlot {
+    slot: u32,
+    generation: u32,
+}
+
+impl Globals {
+    pub fn with_capacity(capacity: usize) -> Globals {
+        Globals {
+            slots: HashMap::with_capacity(capacity),
+            entries: Vec::with_capacity(capacity),
+            host_values: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn slot(&self, name: &StringObjIdx) -> Option<GlobalSlot> {
+        self.slots.get(name).map(|&slot| GlobalSlot {
+            slot: slot as u32,
+            generation: self.generation,
+        })
+    }
+
+    // The current value of the global at `slot`, None if the slots were renumbered since
+    pub fn get_at(&self, slot: GlobalSlot) -> Option<&Value> {
+        if slot.generation != self.generation {
+            return None;
+        }
+        self.entries.get(slot.slot as usize).map(|(_, value)| value)
+    }
+
+    pub fn get(&self, name: &StringObjIdx) -> Option<&Value> {
+        self.slots.get(name).map(|&slot| &self.entries[slot].1)
+    }
+
+    pub fn contains_key(&self, name: &StringObjIdx) -> bool {
+        self.slots.contains_key(name)
+    }
+
+    // Redefining a global keeps its original position
+    pub fn insert(&mut self, name: StringObjIdx, value: Value) {
+        match self.slots.get(&name) {
+            Some(&slot) => self.entries[slot].1 = value,
+            None => {
+                self.slots.insert(name, self.entries.len());
+                self.entries.push((name, value));
+            }
+        }
+    }
+
+    // Defines a global that survives `reset_user_state`
+    pub fn insert_host(&mut self, name: StringObjIdx, value: Value) {
+        self.insert(name, value);
+        self.host_values.insert(name, value);
+    }
+
+    // The globals defined so far (e.g. by a prelude script) survive `reset_user_state`,
+    // with their current values, like the ones defined by the host
+    pub fn keep_on_reset(&mut self) {
+        for &(name, value) in &self.entries {
+            self.host_values.insert(name, value);
+        }
+    }
+
+    // Removes the globals defined by scripts, and restores host globals they reassigned
+    pub fn reset_user_state(&mut self) {
+        let host_values = &self.host_values;
+        self.entries
+            .retain(|(name, _)| host_values.contains_key(name));
+        self.slots.clear();
+        self.generation = self.generation.wrapping_add(1);
+        for (slot, (name, value)) in self.entries.iter_mut().enumerate() {
+            *value = host_values[name];
+            self.slots.insert(*name, slot);
+        }
+    }
+
+    // In definition order
+    pub fn iter(&self) -> impl Iterator<Item = &(StringObjIdx, Value)> {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,368 @@
+// Golden tests: Lox scripts that state what they print in comments, like the test suite of
+// Crafting Interpreters:
+//
+//     print 1 + 2; // expect: 3
+//
+// The output of the script has to be the expected lines, in order. `tests/lox_suite.rs` runs
+// every script under `tests/scripts/`. When the output changes on purpose (e.g. the format of
+// numbers), blessing rewrites the expectations from the actual output and leaves the rest of
+// the file as it was, byte for byte.
+//
+// A script that fails on purpose states the error, by a substring of its message and the line
+// it's reported at (the line of the comment, unless anchored to another one):
+//
+//     print x; // expect runtime error: Undefined variable 'x'.
+//     // expect compile error at line 9: Expect ';' after value.
+//
+// The line of a runtime error is the one of its innermost frame. A script expects at most one
+// runtime error, but any number of compile errors, which must be all the errors reported.
+//
+// Every script sees the same program arguments and environment (`GOLDEN_ARGS`, `GOLDEN_ENV`),
+// not the ones of the process running the tests.
+use std::{
+    collections::HashMap,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compiler::CompileDiagnostic, diff, error::LoxError, glob::Pattern, output::Output, vm::VM,
+};
+
+const EXPECT: &str = "// expect: ";
+pub const GOLDEN_ARGS: &[&str] = &["golden"];
+pub const GOLDEN_ENV: &[(&str, &str)] = &[("LOX_GOLDEN", "1")];
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error";
+const EXPECT_COMPILE_ERROR: &str = "// expect compile error";
+
+pub struct Expectation {
+    pub line: usize,         // of the script, starting at 1
+    pub text: String,        // the expected output line
+    pub range: Range<usize>, // of `text` in the script
+}
+
+// The expectations of a script, in order
+pub fn expectations(source: &str) -> Vec<Expectation> {
+    let mut expectations = Vec::new();
+    let mut line_start = 0;
+    for (idx, line) in source.split('\n').enumerate() {
+        // a Windows line ending isn't part of the expected text
+        let content = line.strip_suffix('\r').unwrap_or(line);
+        if let Some(at) = content.find(EXPECT) {
+            let start = line_start + at + EXPECT.len();
+            let end = line_start + content.len();
+            expectations.push(Expectation {
+                line: idx + 1,
+                text: source[start..end].to_owned(),
+                range: start..end,
+            });
+        }
+        line_start += line.len() + 1;
+    }
+    expectations
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ErrorExpectation {
+    pub kind: ErrorKind,
+    pub line: usize,     // of the comment
+    pub at_line: usize,  // the error is reported at
+    pub message: String, // a substring of the error message
+}
+
+impl ErrorExpectation {
+    fn describe(&self) -> String {
+        let kind = match self.kind {
+            ErrorKind::Compile => "compile",
+            ErrorKind::Runtime => "runtime",
+        };
+        format!(
+            "[line {}] expected {} error '{}' at line {}",
+            self.line, kind, self.message, self.at_line
+        )
+    }
+}
+
+// The error expectations of a script, in order, or what's wrong with one of the comments
+pub fn error_expectations(source: &str) -> Result<Vec<ErrorExpectation>, String> {
+    let mut expectations = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        for (prefix, kind) in [
+            (EXPECT_RUNTIME_ERROR, ErrorKind::Runtime),
+            (EXPECT_COMPILE_ERROR, ErrorKind::Compile),
+        ] {
+            if let Some(at) = line.find(prefix) {
+                let rest = &line[at + prefix.len()..];
+                let expectation = error_expectation(kind, idx + 1, rest).ok_or_else(|| {
+                    format!("[line {}] malformed expectation '{}'", idx + 1, &line[at..])
+                })?;
+                expectations.push(expectation);
+            }
+        }
+    }
+    let runtime = expectations.iter().filter(|e| e.kind == ErrorKind::Runtime);
+    if runtime.count() > 1 {
+        return Err("a script can expect only one runtime error".to_owned());
+    }
+    Ok(expectations)
+}
+
+// `rest` is what follows "// expect runtime error", e.g. " at line 7: Undefined variable 'x'."
+fn error_expectation(kind: ErrorKind, line: usize, rest: &str) -> Option<ErrorExpectation> {
+    let (anchor, message) = rest.split_once(": ")?;
+    let at_line = match anchor.strip_prefix(" at line ") {
+        Some(at_line) => at_line.parse().ok().filter(|&at_line| at_line > 0)?,
+        None if anchor.is_empty() => line,
+        None => return None,
+    };
+    if message.is_empty() {
+        return None;
+    }
+    Some(ErrorExpectation {
+        kind,
+        line,
+        at_line,
+        message: message.to_owned(),
+    })
+}
+
+pub struct GoldenResult {
+    pub path: PathBuf,
+    pub expected: Vec<Expectation>,
+    pub expected_errors: Result<Vec<ErrorExpectation>, String>,
+    pub actual: Vec<String>,     // lines printed by the script
+    pub error: Option<LoxError>, // the script failed to compile or hit a runtime error
+}
+
+impl GoldenResult {
+    pub fn is_success(&self) -> bool {
+        self.expected.len() == self.actual.len()
+            && self
+                .expected
+                .iter()
+                .zip(&self.actual)
+                .all(|(expected, actual)| expected.text == *actual)
+            && self.error_failures().is_empty()
+    }
+
+    // What went wrong, e.g. "[line 3] expected '3' but got '4'"
+    pub fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        for (expected, actual) in self.expected.iter().zip(&self.actual) {
+            if expected.text != *actual {
+                failures.push(format!(
+                    "[line {}] expected '{}' but got '{}'",
+                    expected.line, expected.text, actual
+                ));
+            }
+        }
+        for expected in self.expected.iter().skip(self.actual.len()) {
+            failures.push(format!(
+                "[line {}] expected '{}' but nothing more was printed",
+                expected.line, expected.text
+            ));
+        }
+        for actual in self.actual.iter().skip(self.expected.len()) {
+            failures.push(format!("unexpected output '{}'", actual));
+        }
+        failures.extend(self.error_failures());
+        failures
+    }
+
+    // How the error of the script differs from the expected one. A wrong line and a wrong
+    // message are told apart, e.g. "[line 3] expected runtime error 'x' at line 3 but got it
+    // at line 4".
+    pub fn error_failures(&self) -> Vec<String> {
+        let expected = match &self.expected_errors {
+            Ok(expected) => expected,
+            Err(malformed) => return vec![malformed.clone()],
+        };
+        let mut failures = Vec::new();
+        match &self.error {
+            None => {
+                for expectation in expected {
+                    failures.push(format!("{} but there was no error", expectation.describe()));
+                }
+            }
+            Some(LoxError::Runtime(error)) => {
+                let line = error.trace.first().and_then(|frame| frame.line);
+                let actual = (error.message.as_str(), line);
+                match expected.iter().find(|e| e.kind == ErrorKind::Runtime) {
+                    Some(expectation) => {
+                        if let Some(failure) = mismatch(expectation, actual) {
+                            failures.push(failure);
+                        }
+                    }
+                    None => failures.push(format!("error: {}", error)),
+                }
+                for expectation in expected.iter().filter(|e| e.kind == ErrorKind::Compile) {
+                    failures.push(format!(
+                        "{} but got a runtime error",
+                        expectation.describe()
+                    ));
+                }
+            }
+            Some(LoxError::Compile(diagnostics)) => {
+                let mut unmatched: Vec<_> = diagnostics.iter().collect();
+                for expectation in expected {
+                    if expectation.kind == ErrorKind::Runtime {
+                        failures.push(format!(
+                            "{} but the script didn't compile",
+                            expectation.describe()
+                        ));
+                        continue;
+                    }
+                    // the best candidate: right message and line, else either of them
+                    let score = |d: &&CompileDiagnostic| {
+                        (d.message.contains(&expectation.message) as u8) * 2
+                            + (d.line == expectation.at_line) as u8
+                    };
+                    let best = (0..unmatched.len()).max_by_key(|&idx| score(&unmatched[idx]));
+                    match best {
+                        Some(idx) if score(&unmatched[idx]) > 0 => {
+                            let diagnostic = unmatched.remove(idx);
+                            let actual = (diagnostic.message.as_str(), Some(diagnostic.line));
+                            if let Some(failure) = mismatch(expectation, actual) {
+                                failures.push(failure);
+                            }
+                        }
+                        _ => {
+                            failures.push(format!("{} but there was none", expectation.describe()))
+                        }
+                    }
+                }
+                for diagnostic in unmatched {
+                    failures.push(format!("error: {}", diagnostic));
+                }
+            }
+            Some(error) => failures.push(format!("error: {}", error)),
+        }
+        failures
+    }
+}
+
+// e.g. "expected ... at line 3 but got it at line 4", None if the error is the expected one
+fn mismatch(
+    expectation: &ErrorExpectation,
+    (message, line): (&str, Option<usize>),
+) -> Option<String> {
+    if !message.contains(&expectation.message) {
+        return Some(format!("{} but got '{}'", expectation.describe(), message));
+    }
+    match line {
+        Some(line) if line == expectation.at_line => None,
+        Some(line) => Some(format!(
+            "{} but got it at line {}",
+            expectation.describe(),
+            line
+        )),
+        None => Some(format!(
+            "{} but got it without a line",
+            expectation.describe()
+        )),
+    }
+}
+
+pub fn run_golden_file(path: &Path) -> io::Result<GoldenResult> {
+    let source = fs::read_to_string(path)?;
+    let mut vm = VM::new();
+    vm.output = Output::capture();
+    vm.set_args(GOLDEN_ARGS.iter().map(|&arg| arg.to_owned()).collect());
+    vm.set_env_map(
+        GOLDEN_ENV
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>(),
+    );
+    let error = vm.interpret_from(&source, &path.to_string_lossy()).err();
+    Ok(GoldenResult {
+        path: path.to_owned(),
+        expected: expectations(&source),
+        expected_errors: error_expectations(&source),
+        actual: vm
+            .output
+            .out
+            .captured()
+            .lines()
+            .map(str::to_owned)
+            .collect(),
+        error,
+    })
+}
+
+// Replaces the expected texts with the actual output. Only the texts change, so the script
+// needs one expectation per output line; new output lines need a new comment by hand.
+pub fn bless(source: &str, actual: &[String]) -> Result<String, String> {
+    let expectations = expectations(source);
+    if expectations.len() != actual.len() {
+        return Err(format!(
+            "Can't bless {} expectations with {} lines of output.",
+            expectations.len(),
+            actual.len()
+        ));
+    }
+    let mut blessed = String::with_capacity(source.len());
+    let mut copied = 0;
+    for (expectation, actual) in expectations.iter().zip(actual) {
+        blessed.push_str(&source[copied..expectation.range.start]);
+        blessed.push_str(actual);
+        copied = expectation.range.end;
+    }
+    blessed.push_str(&source[copied..]);
+    Ok(blessed)
+}
+
+// Blesses a script whose run failed, and returns the diff of the change (empty if the
+// expectations were right already). Scripts with unexpected errors aren't blessed.
+pub fn bless_file(result: &GoldenResult) -> Result<String, String> {
+    // an expected error is part of the result, blessing only fixes the output
+    let error_failures = result.error_failures();
+    if let Some(failure) = error_failures.first() {
+        return Err(match &result.error {
+            Some(error) => format!("Can't bless a script that failed: {}", error),
+            None => format!(
+                "Can't bless a script that didn't fail as expected: {}",
+                failure
+            ),
+        });
+    }
+    let source = fs::read_to_string(&result.path).map_err(|e| e.to_string())?;
+    let blessed = bless(&source, &result.actual)?;
+    if blessed != source {
+        fs::write(&result.path, &blessed).map_err(|e| e.to_string())?;
+    }
+    Ok(diff::unified(&source, &blessed))
+}
+
+// Scripts under `dir` sorted by path, only the ones whose path relative to `dir` (with `/`
+// separators, e.g. "closures/counter.lox") matches `filter` if it's given
+pub fn discover(dir: &Path, filter: Option<&Pattern>) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "lox") {
+                let relative = path.strip_prefix(dir).unwrap_or(&path);
+                let relative: Vec<_> = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect();
+                if filter.is_none_or(|filter| filter.matches(&relative.join("/"))) {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
@@ -1,38 +1,137 @@
 // simple interner from https://matklad.github.io/2020/03/22/fast-simple-rust-interner.html
 // e.g. "A"+"B"+"A" -> intern "A", "B", "AB", "ABA"
-use std::collections::HashMap;
+// Every string is stored once in `vec`; the map only holds the hash of the content and the index.
+// Like clox, the hash of every string is kept, so it is only computed when a string is interned.
+use std::collections::hash_map::RandomState;
+use std::collections::{hash_map::Entry, HashMap};
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
 
 pub type StringObjIdx = u32;
+
+// Strings almost every program ends up using, interned up front
+const COMMON_STRINGS: &[&str] = &["", "nil", "true", "false"];
+
+// Tables keyed by the hash of a string use it as is, instead of hashing the hash again
+pub type PrehashedMap<V> = HashMap<u64, V, BuildHasherDefault<PrehashedHasher>>;
+
 #[derive(Default)]
-pub struct Interner {
-    map: HashMap<String, u32>,
-    vec: Vec<String>,
+pub struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    // only u64 keys are expected, anything else is still hashed correctly
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+}
+
+pub struct Interner<S = RandomState> {
+    map: PrehashedMap<StringObjIdx>, // hash of the content -> first string with that hash
+    collisions: PrehashedMap<Vec<StringObjIdx>>, // further strings with an already used hash
+    vec: Vec<Box<str>>,
+    hashes: Vec<u64>, // of the strings in `vec`
+    hasher: S,
+}
+
+impl<S: BuildHasher + Default> Default for Interner<S> {
+    fn default() -> Self {
+        Interner::with_hasher(S::default())
+    }
 }
 
 impl Interner {
-    pub fn intern(&mut self, name: &str) -> StringObjIdx {
-        if let Some(&idx) = self.map.get(name) {
-            return idx;
+    pub fn with_common_strings() -> Interner {
+        let mut interner = Interner::default();
+        for s in COMMON_STRINGS {
+            interner.intern(s);
         }
-        let idx = self.map.len() as StringObjIdx;
-        self.map.insert(name.to_owned(), idx);
-        self.vec.push(name.to_owned());
+        interner
+    }
+}
 
-        idx
+impl<S: BuildHasher> Interner<S> {
+    pub fn with_hasher(hasher: S) -> Interner<S> {
+        Interner {
+            map: PrehashedMap::default(),
+            collisions: PrehashedMap::default(),
+            vec: Vec::new(),
+            hashes: Vec::new(),
+            hasher,
+        }
     }
 
+    pub fn intern(&mut self, name: &str) -> StringObjIdx {
+        let hash = self.hasher.hash_one(name);
+        match self.find(hash, name) {
+            Some(idx) => idx,
+            None => self.insert(hash, name.into()),
+        }
+    }
+
+    // Interns an owned string (e.g. the result of a concatenation) without copying it
     pub fn intern_string(&mut self, name: String) -> StringObjIdx {
-        if let Some(&idx) = self.map.get(&name) {
-            return idx;
+        let hash = self.hasher.hash_one(name.as_str());
+        match self.find(hash, &name) {
+            Some(idx) => idx,
+            None => self.insert(hash, name.into_boxed_str()),
         }
-        let idx = self.map.len() as StringObjIdx;
-        self.map.insert(name.clone(), idx);
-        self.vec.push(name);
+    }
 
-        idx
+    // Index of an already interned string, without interning it
+    pub fn get(&self, name: &str) -> Option<StringObjIdx> {
+        self.find(self.hasher.hash_one(name), name)
     }
 
     pub fn lookup(&self, idx: StringObjIdx) -> &str {
-        self.vec[idx as usize].as_str()
+        &self.vec[idx as usize]
+    }
+
+    // Number of interned strings. Strings are never freed, so this only grows.
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    // Hash of the string's content, computed when it was interned.
+    // Equal strings have equal hashes, for tables keyed by string content.
+    pub fn hash_of(&self, idx: StringObjIdx) -> u64 {
+        self.hashes[idx as usize]
+    }
+
+    fn find(&self, hash: u64, name: &str) -> Option<StringObjIdx> {
+        let &first = self.map.get(&hash)?;
+        if self.lookup(first) == name {
+            return Some(first);
+        }
+        self.collisions
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|&idx| self.lookup(idx) == name)
+    }
+
+    fn insert(&mut self, hash: u64, name: Box<str>) -> StringObjIdx {
+        let idx = self.vec.len() as StringObjIdx;
+        self.vec.push(name);
+        self.hashes.push(hash);
+        match self.map.entry(hash) {
+            Entry::Occupied(_) => self.collisions.entry(hash).or_default().push(idx),
+            Entry::Vacant(entry) => {
+                entry.insert(idx);
+            }
+        }
+        idx
     }
 }
@@ -0,0 +1,34 @@
+// The interpreter as a library, for embedding rust-lox in other programs (see `examples/`).
+// The `rust-lox` binary is a thin CLI on top of it.
+pub mod asm;
+pub mod audit;
+pub mod builder;
+pub mod chunk;
+pub mod class;
+pub mod compilation_unit;
+pub mod compile_cache;
+pub mod compiler;
+pub mod crash_report;
+pub mod debug;
+pub mod diff;
+pub mod encoding;
+pub mod environment;
+pub mod error;
+pub mod function;
+pub mod gas;
+pub mod glob;
+pub mod globals;
+pub mod golden;
+pub mod interner;
+pub mod limits;
+pub mod native;
+pub mod output;
+pub mod paths;
+pub mod repl;
+pub mod scanner;
+pub mod source_map;
+pub mod test_runner;
+pub mod trace;
+pub mod value;
+pub mod vm;
+pub mod warnings;
@@ -0,0 +1,20 @@
+// Resource limits an embedder can put on scripts, see `VmBuilder::limits`.
+// Exceeding one is a runtime error that names the limit.
+use crate::vm::FRAMES_MAX;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_call_depth: usize, // # of nested calls, including the top-level script
+    pub max_string_length: usize, // in bytes, of strings built by concatenation
+    pub max_gas: u64,          // per run, priced by `VM::cost_model`
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_call_depth: FRAMES_MAX,
+            max_string_length: usize::MAX,
+            max_gas: u64::MAX,
+        }
+    }
+}
@@ -1,81 +1,400 @@
 use std::process::exit;
 use std::time::Instant;
 
-use vm::{InterpretResult, VM};
-
-mod chunk;
-mod compiler;
-mod debug;
-mod function;
-mod interner;
-mod scanner;
-mod value;
-mod vm;
-use std::{env, fs, io};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use rust_lox::builder::VmBuilder;
+use rust_lox::compiler::CompileOptions;
+use rust_lox::error::LoxError;
+use rust_lox::scanner::LangLevel;
+use rust_lox::trace::Tracer;
+use rust_lox::vm::VM;
+use rust_lox::warnings::WarningLevels;
+use rust_lox::{crash_report, diff, repl, test_runner, warnings};
 
 fn main() {
     let now = Instant::now();
+    enable_utf8_console();
 
-    let mut vm = VM::new();
-    let mut argv = env::args();
-    match argv.len() {
-        1 => {
-            repl(&mut vm);
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let (builder, preludes) = apply_options(&mut args);
+    let mut vm = builder.build().unwrap_or_else(|msg| {
+        eprintln!("{}", msg);
+        exit(64)
+    });
+    match args.as_slice() {
+        [] => {
+            run_reporting_crashes(&mut vm, |vm| {
+                run_preludes(vm, &preludes);
+                repl::run(vm);
+            });
+            vm.flush_trace();
+            print_audit_log(&mut vm);
+        }
+        [command, path] if command == "test" => {
+            run_tests(path);
+        }
+        [command, path] if command == "disasm" => {
+            let disassembly = disassemble_file(vm.compile_options, path);
+            print!("{}", disassembly);
         }
-        2 => {
-            run_file(&mut vm, &argv.nth(1).expect("Could not parse argv"));
+        [command, flag, old, new] if command == "disasm" && flag == "--diff" => {
+            let old = disassemble_file(vm.compile_options, old);
+            let new = disassemble_file(vm.compile_options, new);
+            print!("{}", diff::unified(&old, &new));
         }
-        _ => {
-            eprintln!("Usage: clox [path]");
-            exit(64);
+        [command, flag, path] if command == "disasm" && flag == "--diff-opt" => {
+            let options = CompileOptions {
+                optimize: false,
+                ..vm.compile_options
+            };
+            let plain = disassemble_file(options, path);
+            let optimized = disassemble_file(
+                CompileOptions {
+                    optimize: true,
+                    ..options
+                },
+                path,
+            );
+            print!("{}", diff::unified(&plain, &optimized));
         }
+        // the arguments after the path are the program arguments, e.g. for `main`
+        [path, script_args @ ..] if path != "test" && path != "disasm" => {
+            run_reporting_crashes(&mut vm, |vm| {
+                run_preludes(vm, &preludes);
+                vm.set_args(script_args.to_vec());
+                run_file(vm, path);
+            });
+        }
+        _ => usage(),
     }
 
     let elapsed = now.elapsed();
     println!("Elapsed: {:.2?}", elapsed);
 }
 
-fn repl(vm: &mut VM) {
-    // char line[1024];
-    let mut buffer = String::new();
-    let stdin = io::stdin();
+// The Windows console decodes output with the legacy code page by default,
+// which garbles non-ASCII strings printed by scripts
+#[cfg(windows)]
+fn enable_utf8_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleOutputCP(code_page: u32) -> i32;
+    }
+    const CP_UTF8: u32 = 65001;
+    // fails if there is no console, e.g. when the output is redirected, which needs no fix
+    unsafe {
+        SetConsoleOutputCP(CP_UTF8);
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_utf8_console() {}
 
-    loop {
-        print!("> ");
-        match stdin.read_line(&mut buffer) {
-            Ok(0) | Err(_) => {
-                println!();
-                break;
+fn usage() -> ! {
+    eprintln!("Usage: clox [options] [--] [path [args...]]");
+    eprintln!("       clox [options] test [path]");
+    eprintln!("       clox [options] disasm [--diff old.lox new.lox | --diff-opt path | path]");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --lang-level <classic|extended>  keywords to accept (default: newest)");
+    eprintln!("  --opt                            optimize the compiled bytecode");
+    eprintln!("  --integers                       integer literals are 64-bit integers");
+    eprintln!("  --strict-bool                    conditions must be booleans");
+    eprintln!("  --paranoid                       emit runtime checks of the compiled code");
+    eprintln!("  --strip-lines                    compile without line info, to save memory");
+    eprintln!("  --checked-math                   arithmetic errors instead of inf and NaN");
+    eprintln!(
+        "  --call-main                      then call main(args...), its result is the exit code"
+    );
+    eprintln!("  --prelude <file>                 run the file first, e.g. to define helpers");
+    eprintln!("  --compat-bare-natives            also define e.g. `sqrt` for `math.sqrt`");
+    eprintln!("  --runtime-warn                   warn about suspicious operations while running");
+    eprintln!("  --warn <code>=<off|warn|error>   report a warning, e.g. W0001, differently");
+    eprintln!("  --deny-warnings                  warnings are compile errors");
+    eprintln!("  --max-errors <n>                 stop compiling after n errors (default 100)");
+    eprintln!("  --dev                            print the VM state if the interpreter panics");
+    eprintln!("  --audit                          list the globals and natives the script used");
+    eprintln!("  --trace-file <file>              write each executed instruction to the file");
+    eprintln!("  --trace-filter <function>        only trace the instructions of the function");
+    eprintln!("  --trace-last <n>                 only trace the last n instructions, e.g. before an error");
+    eprintln!();
+    eprintln!("Warnings:");
+    for (code, description) in warnings::CODES {
+        eprintln!("  {}  {}", code, description);
+    }
+    exit(64);
+}
+
+// Applies the options to the VM and removes them from `args`, leaving the command, the path and
+// the program arguments. Options end at the first argument that isn't one, or at `--`, so the
+// arguments after the path reach the script even if they look like options.
+// Returns the `--prelude` files, in order.
+fn apply_options(args: &mut Vec<String>) -> (VmBuilder, Vec<String>) {
+    let mut builder = VM::builder().crash_reports(cfg!(debug_assertions));
+    let mut warning_levels = WarningLevels::default();
+    let mut preludes = Vec::new();
+    let mut trace_file = None;
+    let mut trace_filter = None;
+    let mut trace_last = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--lang-level" => {
+                match args.get(i + 1).and_then(|level| LangLevel::parse(level)) {
+                    Some(level) => builder = builder.lang_level(level),
+                    None => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--opt" => {
+                builder = builder.optimize(true);
+                args.remove(i);
+            }
+            "--integers" => {
+                builder = builder.integers(true);
+                args.remove(i);
+            }
+            "--strict-bool" => {
+                builder = builder.strict_bool(true);
+                args.remove(i);
             }
-            Ok(_) => {
-                vm.interpret(&buffer);
+            "--paranoid" => {
+                builder = builder.paranoid(true);
+                args.remove(i);
+            }
+            "--strip-lines" => {
+                builder = builder.strip_lines(true);
+                args.remove(i);
+            }
+            "--checked-math" => {
+                builder = builder.checked_arithmetic(true);
+                args.remove(i);
+            }
+            "--runtime-warn" => {
+                builder = builder.runtime_warnings(true);
+                args.remove(i);
+            }
+            "--warn" => {
+                match args.get(i + 1) {
+                    Some(setting) if warning_levels.set_from_str(setting) => {}
+                    _ => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--audit" => {
+                builder = builder.audit(true);
+                args.remove(i);
+            }
+            "--dev" => {
+                builder = builder.crash_reports(true);
+                args.remove(i);
+            }
+            "--max-errors" => {
+                match args.get(i + 1).and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => builder = builder.max_errors(n),
+                    _ => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--deny-warnings" => {
+                warning_levels.deny_all(true);
+                args.remove(i);
+            }
+            "--compat-bare-natives" => {
+                builder = builder.compat_bare_natives(true);
+                args.remove(i);
+            }
+            "--call-main" => {
+                builder = builder.call_main(true);
+                args.remove(i);
+            }
+            "--prelude" => {
+                match args.get(i + 1) {
+                    Some(path) => preludes.push(path.clone()),
+                    None => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--trace-file" => {
+                match args.get(i + 1) {
+                    Some(path) => trace_file = Some(path.clone()),
+                    None => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--trace-filter" => {
+                match args.get(i + 1) {
+                    Some(function) => trace_filter = Some(function.clone()),
+                    None => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--trace-last" => {
+                match args.get(i + 1).and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => trace_last = Some(n),
+                    _ => usage(),
+                }
+                args.drain(i..i + 2);
+            }
+            "--" => {
+                args.remove(i);
+                break;
             }
+            arg if !arg.starts_with('-') => break,
+            _ => i += 1,
         }
     }
+    if trace_file.is_some() || trace_filter.is_some() || trace_last.is_some() {
+        // without a file, e.g. just `--trace-filter`, the trace goes to stderr
+        let mut tracer = match &trace_file {
+            Some(path) => Tracer::to_file(Path::new(path)).unwrap_or_else(|e| {
+                eprintln!("Could not create trace file '{}': {}", path, e);
+                exit(74)
+            }),
+            None => Tracer::new(Box::new(std::io::stderr())),
+        };
+        if let Some(function) = &trace_filter {
+            tracer = tracer.filter(function);
+        }
+        if let Some(count) = trace_last {
+            tracer = tracer.last(count);
+        }
+        builder = builder.trace(tracer);
+    }
+    (builder.warning_levels(warning_levels), preludes)
 }
 
-fn run_file(vm: &mut VM, path: &str) {
-    let source = fs::read_to_string(path).expect("Could not open file");
-    let result = vm.interpret(&source);
-    // free(source);
+// Errors in a prelude are reported, but the script (or the REPL) still runs
+fn run_preludes(vm: &mut VM, preludes: &[String]) {
+    for path in preludes {
+        // compile and runtime errors are already reported by the VM
+        if let Err(error @ LoxError::Io { .. }) = vm.run_prelude(Path::new(path)) {
+            eprintln!("{}", error);
+        }
+    }
+}
+
+// With crash reports enabled (`--dev`, or any debug build), a panic also prints the VM state
+fn run_reporting_crashes(vm: &mut VM, f: impl FnOnce(&mut VM)) {
+    if vm.op_history().is_some() {
+        crash_report::run_reporting_crashes(vm, f);
+    } else {
+        f(vm);
+    }
+}
 
+fn run_file(vm: &mut VM, path: &str) {
+    let result = vm.run_script(Path::new(path));
+    vm.flush_trace();
+    print_audit_log(vm);
     match result {
-        Ok(_) => exit(0),
-        Err(InterpretResult::CompileError) => exit(65),
-        Err(InterpretResult::RuntimeError) => exit(70),
-        Err(InterpretResult::Ok) => exit(0), // should not happen
+        Ok(code) => exit(code),
+        Err(LoxError::Compile(_)) => exit(65),
+        Err(LoxError::Runtime(_)) => exit(70),
+        Err(error @ LoxError::Io { .. }) => {
+            eprintln!("{}", error);
+            exit(74)
+        }
+    }
+}
+
+// With `--audit`, what the script touched, after the run
+fn print_audit_log(vm: &mut VM) {
+    for event in vm.take_audit_log() {
+        eprintln!("[audit] {}", event);
+    }
+}
+
+// Labeled disassembly of the script and its functions, exits like `run_file` on errors
+fn disassemble_file(options: CompileOptions, path: &str) -> String {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Could not open file '{}': {}", path, e);
+            exit(74)
+        }
+    };
+    let mut vm = VM::new();
+    vm.compile_options = options;
+    match vm.disassemble_source(&source, Some(path)) {
+        Ok(disassembly) => disassembly,
+        Err(_) => exit(65), // already reported
+    }
+}
+
+fn run_tests(path: &str) {
+    match test_runner::run_tests(Path::new(path)) {
+        Ok(summary) => exit(summary.exit_code()),
+        Err(e) => {
+            eprintln!("Could not run tests in '{}': {}", path, e);
+            exit(74);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::convert::TryInto;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+    use std::path::Path;
 
-    use crate::chunk::{Chunk, OpCode};
-    use crate::debug::disassemble_chunk;
-    use crate::interner::Interner;
-    use crate::value;
-    use crate::vm::VM;
+    use rust_lox::audit::AuditEvent;
+    use rust_lox::builder::VmBuilder;
+    use rust_lox::chunk::OpCode;
+    use rust_lox::compilation_unit::CompilationUnit;
+    use rust_lox::compiler::{
+        render_snippet, truncate_lexeme, CompileOptions, Severity, DEFAULT_MAX_ERRORS,
+    };
+    use rust_lox::crash_report::{self, OpHistory};
+    use rust_lox::debug;
+    use rust_lox::diff;
+    use rust_lox::environment::Environment;
+    use rust_lox::error::LoxError;
+    use rust_lox::function::Function;
+    use rust_lox::gas::CostModel;
+    use rust_lox::glob::Pattern;
+    use rust_lox::golden;
+    use rust_lox::interner::Interner;
+    use rust_lox::limits::Limits;
+    use rust_lox::native::NativeResult;
+    use rust_lox::output::Output;
+    use rust_lox::paths;
+    use rust_lox::repl;
+    use rust_lox::scanner::{LangLevel, Scanner, TokenType, TriviaKind};
+    use rust_lox::source_map::SessionPosition;
+    use rust_lox::test_runner;
+    use rust_lox::trace::Tracer;
+    use rust_lox::value::{
+        as_i64, as_index, format_json_number, format_number, format_value, type_name, values_equal,
+        TypeTag, Value,
+    };
+    use rust_lox::vm::{RunState, VM};
+    use rust_lox::warnings::{self, WarningLevels};
+
+    fn global(vm: &VM, name: &str) -> Option<Value> {
+        vm.get_global(name)
+    }
+
+    fn scan_all(source: &str) -> Vec<(TokenType, usize, String)> {
+        scan_all_at(source, LangLevel::default())
+    }
+
+    fn scan_all_at(source: &str, level: LangLevel) -> Vec<(TokenType, usize, String)> {
+        let mut scanner = Scanner::with_level(source, level);
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            tokens.push((token.token_type, token.line, token.lexeme.to_owned()));
+            if token.token_type == TokenType::Eof {
+                return tokens;
+            }
+        }
+    }
 
     #[test]
     fn ch14_chunk() {
@@ -108,7 +427,7 @@ mod tests {
     fn ch21_global() {
         let mut vm = VM::new();
         let res = vm.interpret("print (1 * 2 = 3 + 4);");
-        assert_eq!(res.err(), Some(crate::vm::InterpretResult::CompileError));
+        assert!(matches!(res, Err(LoxError::Compile(_))));
     }
 
     #[test]
@@ -145,7 +464,7 @@ mod tests {
             a();
             ",
         );
-        assert_eq!(res.err(), Some(crate::vm::InterpretResult::RuntimeError));
+        assert!(matches!(res, Err(LoxError::Runtime(_))));
     }
 
     #[test]
@@ -173,4 +492,5090 @@ mod tests {
         );
         assert_eq!(res.err(), None);
     }
+
+    #[test]
+    fn scanner_line_endings() {
+        let lf = "var a = 1; // one\n\nprint a;\n// trailing comment\n@\n";
+        let crlf = lf.replace('\n', "\r\n");
+        let cr = lf.replace('\n', "\r");
+
+        let expected = scan_all(lf);
+        assert_eq!(expected.iter().find(|t| t.2 == "print").unwrap().1, 3);
+        // the unexpected '@' is reported on the same line for every encoding
+        let error = expected.iter().find(|t| t.0 == TokenType::Error).unwrap();
+        assert_eq!(error.1, 5);
+        assert_eq!(scan_all(&crlf), expected);
+        assert_eq!(scan_all(&cr), expected);
+    }
+
+    #[test]
+    fn scanner_line_endings_in_strings() {
+        // the string keeps its '\r' verbatim, but lines are still counted once per EOL
+        for eol in &["\n", "\r\n", "\r"] {
+            let source = format!("\"a{}b\" x", eol);
+            let tokens = scan_all(&source);
+            assert_eq!(tokens[0].0, TokenType::String);
+            assert_eq!(tokens[0].2, format!("\"a{}b\"", eol));
+            assert_eq!(tokens[1].1, 2);
+        }
+    }
+
+    // One `line type lexeme` entry per token
+    fn token_dump(source: &str) -> String {
+        scan_all(source)
+            .iter()
+            .map(|(token_type, line, lexeme)| format!("{} {:?} {:?}\n", line, token_type, lexeme))
+            .collect()
+    }
+
+    #[test]
+    fn scanner_token_stream() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/scanner");
+        let source = fs::read_to_string(fixtures.join("tokens.lox")).unwrap();
+        let expected = fs::read_to_string(fixtures.join("tokens.txt")).unwrap();
+        assert_eq!(token_dump(&source), expected);
+        // only the line endings inside the string literals differ
+        let crlf = source.replace('\n', "\r\n");
+        assert_eq!(token_dump(&crlf).replace("\\r", ""), expected);
+    }
+
+    #[test]
+    fn scanner_trivia() {
+        let source = "// header\n\
+                      /// Adds one.\n\
+                      fun inc(n) {\n\
+                      \x20 return n + 1; // the result\n\
+                      }\n\
+                      //// separator\n\
+                      print inc(1);\n\
+                      // trailing";
+        let mut scanner = Scanner::with_level(source, LangLevel::newest());
+        scanner.enable_trivia();
+        let mut lexemes = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            lexemes.push(token.lexeme);
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+        }
+        let trivia: Vec<_> = scanner
+            .take_trivia()
+            .into_iter()
+            .map(|t| (lexemes[t.token], t.kind, t.line, t.text))
+            .collect();
+        assert_eq!(
+            trivia,
+            vec![
+                ("fun", TriviaKind::Comment, 1, "// header"),
+                ("fun", TriviaKind::Doc, 2, "/// Adds one."),
+                ("}", TriviaKind::Comment, 4, "// the result"),
+                ("print", TriviaKind::Comment, 6, "//// separator"),
+                ("", TriviaKind::Comment, 8, "// trailing"),
+            ]
+        );
+
+        // not collected by default
+        let mut scanner = Scanner::with_level(source, LangLevel::newest());
+        while scanner.scan_token().token_type != TokenType::Eof {}
+        assert!(scanner.take_trivia().is_empty());
+    }
+
+    #[test]
+    fn scanner_mixed_line_endings() {
+        let tokens = scan_all("a\nb\r\nc\rd\r\n\re");
+        let lines: Vec<usize> = tokens.iter().map(|t| t.1).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4, 6, 6]);
+    }
+
+    #[test]
+    fn test_runner_summary() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test_runner");
+        let summary = test_runner::run_tests(&fixtures).unwrap();
+
+        // helper.lox is not a test file
+        assert_eq!(summary.files.len(), 3);
+        assert_eq!(summary.passed(), 6);
+        assert_eq!(summary.failed(), 2);
+        assert_eq!(summary.errored(), 1);
+        assert_eq!(summary.exit_code(), 1);
+
+        let failing = &summary.files[1];
+        assert!(failing.path.ends_with("failing_test.lox"));
+        let lines: Vec<usize> = failing.report.failures.iter().map(|f| f.line).collect();
+        assert_eq!(lines, vec![3, 6]);
+        assert_eq!(failing.report.failures[0].message, "expected 3 but got 4");
+    }
+
+    #[test]
+    fn test_runner_passing_file() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/test_runner/nested/passing_test.lox");
+        let summary = test_runner::run_tests(&fixture).unwrap();
+        assert_eq!(summary.files.len(), 1);
+        assert_eq!(summary.passed(), 3);
+        assert_eq!(summary.exit_code(), 0);
+    }
+
+    #[test]
+    fn golden_harness() {
+        let dir = std::env::temp_dir().join(format!("rust-lox-golden-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("closures")).unwrap();
+        fs::create_dir_all(dir.join("numbers")).unwrap();
+        fs::write(
+            dir.join("closures/counter.lox"),
+            "fun f() { var i = 0; fun g() { i = i + 1; return i; } return g; }\n\
+             var g = f(); print g(); // expect: 1\n",
+        )
+        .unwrap();
+        // stale expectations, with a Windows line ending and text around them
+        let stale = "// numbers\r\nprint 1 / 4;   // expect: 0.250\r\nprint \"a\" + \"b\"; // expect: a b\n\
+                     // expect: ok\nprint \"ok\";\n";
+        fs::write(dir.join("numbers/format.lox"), stale).unwrap();
+        fs::write(dir.join("numbers/notes.txt"), "not a script").unwrap();
+
+        let relative = |paths: Vec<std::path::PathBuf>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| {
+                    path.strip_prefix(&dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                })
+                .collect()
+        };
+        let all = golden::discover(&dir, None).unwrap();
+        assert_eq!(
+            relative(all),
+            ["closures/counter.lox", "numbers/format.lox"]
+        );
+        let filter = Pattern::parse("closures/*").unwrap();
+        let closures = golden::discover(&dir, Some(&filter)).unwrap();
+        assert_eq!(relative(closures), ["closures/counter.lox"]);
+        let filter = Pattern::parse("*/format.lox").unwrap();
+        assert_eq!(golden::discover(&dir, Some(&filter)).unwrap().len(), 1);
+        let filter = Pattern::parse("strings/*").unwrap();
+        assert!(golden::discover(&dir, Some(&filter)).unwrap().is_empty());
+
+        let counter = golden::run_golden_file(&dir.join("closures/counter.lox")).unwrap();
+        assert!(counter.is_success(), "{:?}", counter.failures());
+
+        let path = dir.join("numbers/format.lox");
+        let result = golden::run_golden_file(&path).unwrap();
+        assert!(!result.is_success());
+        assert_eq!(
+            result.failures(),
+            [
+                "[line 2] expected '0.250' but got '0.25'",
+                "[line 3] expected 'a b' but got 'ab'"
+            ]
+        );
+        let diff = golden::bless_file(&result).unwrap();
+        assert!(
+            diff.contains("-print 1 / 4;   // expect: 0.250\n"),
+            "{}",
+            diff
+        );
+        assert!(
+            diff.contains("+print 1 / 4;   // expect: 0.25\n"),
+            "{}",
+            diff
+        );
+        // only the expected texts changed
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            stale.replace("0.250", "0.25").replace("a b", "ab")
+        );
+        let blessed = golden::run_golden_file(&path).unwrap();
+        assert!(blessed.is_success(), "{:?}", blessed.failures());
+        assert_eq!(golden::bless_file(&blessed).unwrap(), "");
+
+        // output lines without an expectation (or the other way round) need a new comment
+        let error = golden::bless(
+            "print 1; // expect: 1\nprint 2;\n",
+            &["1".into(), "2".into()],
+        );
+        assert_eq!(
+            error.unwrap_err(),
+            "Can't bless 1 expectations with 2 lines of output."
+        );
+        fs::write(&path, "print 1; // expect: 2\nnil();\n").unwrap();
+        let failed = golden::run_golden_file(&path).unwrap();
+        assert!(golden::bless_file(&failed)
+            .unwrap_err()
+            .starts_with("Can't bless a script that failed: Can only call functions"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "print 1; // expect: 2\nnil();\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn golden_error_expectations() {
+        use golden::{ErrorExpectation, ErrorKind};
+        let expectations = golden::error_expectations(
+            "x; // expect runtime error: Undefined variable 'x'.\n\
+             // expect compile error at line 7: Expect ';'\n",
+        );
+        assert_eq!(
+            expectations.unwrap(),
+            [
+                ErrorExpectation {
+                    kind: ErrorKind::Runtime,
+                    line: 1,
+                    at_line: 1,
+                    message: "Undefined variable 'x'.".to_owned()
+                },
+                ErrorExpectation {
+                    kind: ErrorKind::Compile,
+                    line: 2,
+                    at_line: 7,
+                    message: "Expect ';'".to_owned()
+                }
+            ]
+        );
+        for (source, error) in [
+            (
+                "// expect runtime error at line x: oops",
+                "[line 1] malformed expectation '// expect runtime error at line x: oops'",
+            ),
+            (
+                "// expect compile error:",
+                "[line 1] malformed expectation '// expect compile error:'",
+            ),
+            (
+                "// expect runtime error: a\n// expect runtime error: b",
+                "a script can expect only one runtime error",
+            ),
+        ] {
+            assert_eq!(golden::error_expectations(source).unwrap_err(), error);
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("rust-lox-golden-errors-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.lox");
+        let failures = |source: &str| {
+            fs::write(&path, source).unwrap();
+            golden::run_golden_file(&path).unwrap().failures()
+        };
+        let script = "print 1; // expect: 1\nfun f() {\n  return -f;\n}\nf();\n";
+        let ok = "// expect runtime error at line 3: Operand to '-' must be a number";
+        assert!(failures(&format!("{}{}", script, ok)).is_empty());
+        // the wrong line and the wrong message are told apart
+        assert_eq!(
+            failures(&format!(
+                "{}// expect runtime error at line 5: Operand",
+                script
+            )),
+            ["[line 6] expected runtime error 'Operand' at line 5 but got it at line 3"]
+        );
+        assert_eq!(
+            failures(&format!(
+                "{}// expect runtime error at line 3: Undefined",
+                script
+            )),
+            [
+                "[line 6] expected runtime error 'Undefined' at line 3 but got \
+              'Operand to '-' must be a number, got function (<fn>).'"
+            ]
+        );
+        assert_eq!(
+            failures("print 1; // expect runtime error: nope\n"),
+            [
+                "unexpected output '1'",
+                "[line 1] expected runtime error 'nope' at line 1 but there was no error"
+            ]
+        );
+        let script = "print -\"a\";\nprint 1\n";
+        assert!(failures(&format!(
+            "{}// expect compile error at line 1: got string.\n// expect compile error at line 4: Expect ';'",
+            script
+        ))
+        .is_empty());
+        assert_eq!(
+            failures(&format!(
+                "{}// expect compile error at line 2: got string.",
+                script
+            )),
+            [
+                "[line 3] expected compile error 'got string.' at line 2 but got it at line 1",
+                "error: [line 3] Error: Expect ';' after value."
+            ]
+        );
+        // blessing fixes the output of a script that fails as expected
+        fs::write(
+            &path,
+            "print 2; // expect: 1\nnil();\n// expect runtime error at line 2: Can only call",
+        )
+        .unwrap();
+        let result = golden::run_golden_file(&path).unwrap();
+        assert!(golden::bless_file(&result)
+            .unwrap()
+            .contains("+print 2; // expect: 2"));
+        fs::write(
+            &path,
+            "print 2; // expect: 1\n// expect runtime error: nope",
+        )
+        .unwrap();
+        let result = golden::run_golden_file(&path).unwrap();
+        assert!(golden::bless_file(&result)
+            .unwrap_err()
+            .starts_with("Can't bless a script that didn't fail as expected: [line 2]"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lang_level_reserved_keywords() {
+        assert_eq!(LangLevel::default(), LangLevel::newest());
+
+        let source = "var break = 1;";
+        let classic = scan_all_at(source, LangLevel::Classic);
+        assert_eq!(classic[1].0, TokenType::Identifier);
+        let extended = scan_all_at(source, LangLevel::Extended);
+        assert_eq!(extended[1].0, TokenType::Break);
+
+        let mut scanner = Scanner::with_level("var a;\nvar switch;", LangLevel::Classic);
+        while scanner.scan_token().token_type != TokenType::Eof {}
+        assert_eq!(scanner.warnings.len(), 1);
+        assert_eq!(scanner.warnings[0].line, 2);
+        assert_eq!(
+            scanner.warnings[0].message,
+            "'switch' will become a reserved word."
+        );
+    }
+
+    #[test]
+    fn lang_level_compile() {
+        let source = "var continue = 1; print continue;";
+        let mut vm = VM::new();
+        vm.compile_options.lang_level = LangLevel::Classic;
+        assert_eq!(vm.interpret(source).err(), None);
+
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret(source), Err(LoxError::Compile(_))));
+    }
+
+    #[test]
+    fn stack_empty_after_interpret() {
+        let programs = [
+            "print 1 + 2;",
+            "var a = 1; a = a + 1;",
+            "{ var a = 1; { var b = a; { var c = b; print c; } } }",
+            "fun f(a) { var b = a; { var c = b; return c; } } print f(1);",
+            "fun f() { { var a = 1; } } f(); { var x = f(); }",
+            "for (var i = 0; i < 3; i = i + 1) { var j = i; }",
+            "if (true) { var a = 1; } else { var b = 2; }",
+            "var a = nil or 1; var b = 1 and nil;",
+        ];
+        let mut vm = VM::new();
+        for program in programs.iter() {
+            assert_eq!(vm.interpret(program).err(), None, "{}", program);
+            assert!(vm.stack.is_empty(), "{}", program);
+            assert!(vm.frames.is_empty(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn stack_empty_after_runtime_error() {
+        let mut vm = VM::new();
+        let res = vm.interpret("{ var a = 1; print a + nil; }");
+        assert!(matches!(res, Err(LoxError::Runtime(_))));
+        assert!(vm.stack.is_empty());
+        // the VM is still usable afterwards
+        assert_eq!(vm.interpret("{ var b = 2; print b; }").err(), None);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn small_number_opcodes() {
+        let mut vm = VM::new();
+        let res = vm.interpret("var a = 0; var b = 1; var c = 0 - 1 + 1 + 1; var d = 2;");
+        assert_eq!(res.err(), None);
+        // only the 4 global names and `2` are left in the constant pool
+        let script = vm.functions.last().unwrap();
+        assert_eq!(script.chunk.constants().len(), 5);
+        let ops = &script.chunk.code();
+        assert!(matches!(ops[0], OpCode::Zero));
+        assert!(matches!(ops[2], OpCode::One));
+
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(0.0)));
+        assert!(values_equal(global(&vm, "b").unwrap(), Value::Number(1.0)));
+        assert!(values_equal(global(&vm, "c").unwrap(), Value::Number(1.0)));
+        assert!(values_equal(global(&vm, "d").unwrap(), Value::Number(2.0)));
+    }
+
+    #[test]
+    fn negative_zero_stays_a_constant() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("var z = -0;").err(), None);
+        if let Some(Value::Number(z)) = global(&vm, "z") {
+            assert!(z == 0.0 && z.is_sign_negative());
+        } else {
+            panic!("z is not a number");
+        }
+    }
+
+    #[test]
+    fn common_strings_are_preinterned() {
+        let mut vm = VM::new();
+        // "" was interned when the VM was constructed
+        let empty = vm.interner.intern("");
+        assert_eq!(empty, 0);
+        assert_eq!(vm.interpret("var e = \"\";").err(), None);
+        assert!(matches!(global(&vm, "e"), Some(Value::StringObj(idx)) if idx == empty));
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        let mut vm = VM::new();
+        for op in ["<", ">", "<=", ">="].iter() {
+            let source = format!("print 1 {} 2 {} 3;", op, op);
+            assert!(
+                matches!(vm.interpret(&source), Err(LoxError::Compile(_))),
+                "{}",
+                source
+            );
+        }
+        assert!(matches!(
+            vm.interpret("print 1 < 2 >= 3;"),
+            Err(LoxError::Compile(_))
+        ));
+
+        // not a chain
+        assert_eq!(vm.interpret("print (1 < 2) == true;").err(), None);
+        assert_eq!(vm.interpret("print 1 < 2 == 2 > 1;").err(), None);
+        assert_eq!(vm.interpret("print 1 < 2 and 2 < 3;").err(), None);
+        assert_eq!(vm.interpret("print 1 + 1 < 3;").err(), None);
+    }
+
+    #[test]
+    fn namespaced_natives() {
+        let mut vm = VM::new();
+        let res = vm.interpret("var a = math.sqrt(4); var b = math.floor(2.5) + math . abs(-1);");
+        assert_eq!(res.err(), None);
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(2.0)));
+        assert!(values_equal(global(&vm, "b").unwrap(), Value::Number(3.0)));
+        // no bare aliases by default
+        assert!(matches!(
+            vm.interpret("sqrt(4);"),
+            Err(LoxError::Runtime(_))
+        ));
+        assert!(matches!(
+            vm.interpret("math.nope(4);"),
+            Err(LoxError::Runtime(_))
+        ));
+        assert!(matches!(
+            vm.interpret("math.sqrt = 1;"),
+            Err(LoxError::Compile(_))
+        ));
+        assert!(matches!(
+            vm.interpret("print math;"),
+            Err(LoxError::Compile(_))
+        ));
+    }
+
+    #[test]
+    fn builtin_methods() {
+        let source = "\
+            print \"héllo\".len();\n\
+            print (-3).abs();\n\
+            print (3.7).floor();\n\
+            print \"abcd\".len().sqrt().ceil();\n\
+            print (0..4).len();\n\
+            print \"abc\".startsWith(\"ab\");\n\
+            var ends = \"abc\".endsWith;\n\
+            print ends(\"bc\");";
+        assert_eq!(run_output(source).unwrap(), "5\n3\n3\n2\n4\ntrue\ntrue\n");
+        let runtime_error = |source: &str| match run_output(source) {
+            Err(LoxError::Runtime(error)) => error.message,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        // the receiver isn't one of the arguments
+        assert_eq!(
+            runtime_error("print \"abc\".len(1);"),
+            "Expected 0 arguments but got 1."
+        );
+        assert_eq!(
+            runtime_error("var n; print n.x;"),
+            "Only instances have properties, got nil."
+        );
+        assert_eq!(
+            runtime_error("print \"abc\".floor();"),
+            "Only instances have properties, got string (\"abc\")."
+        );
+        assert_eq!(
+            runtime_error("var s = \"abc\"; s.len = 1;"),
+            "Only instances have fields, got string (\"abc\")."
+        );
+        // a VM without the standard library has no methods either
+        let mut vm = VM::builder().stdlib(false).build().unwrap();
+        assert!(matches!(
+            vm.interpret("\"abc\".len();"),
+            Err(LoxError::Runtime(_))
+        ));
+    }
+
+    #[test]
+    fn namespace_shadowed_by_local() {
+        let mut vm = VM::new();
+        // a local named like the namespace is an ordinary variable...
+        let res = vm.interpret("var r; { var math = 2; r = math; }");
+        assert_eq!(res.err(), None);
+        assert!(values_equal(global(&vm, "r").unwrap(), Value::Number(2.0)));
+        // ...which hides the namespace members: `math.sqrt` is the method of the number
+        vm.output = Output::capture();
+        let res = vm.interpret("{ var math = 4; print math.sqrt(); }");
+        assert_eq!(res.err(), None);
+        assert_eq!(vm.output.out.captured(), "2\n");
+        let error = vm
+            .interpret("{ var math = 2; print math.log(4); }")
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Only instances have properties, got number (2)."));
+    }
+
+    #[test]
+    fn compat_bare_natives() {
+        let mut vm = VM::builder().compat_bare_natives(true).build().unwrap();
+        assert_eq!(vm.interpret("var a = sqrt(9) + math.sqrt(9);").err(), None);
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(6.0)));
+        // namespaces the host defines later get the aliases too
+        vm.define_namespace("str", &[("shout", 1, |_, args| Ok(args[0]))]);
+        assert_eq!(vm.interpret("var b = shout(1) + str.shout(1);").err(), None);
+        assert!(values_equal(global(&vm, "b").unwrap(), Value::Number(2.0)));
+        // without the option, namespace members are only reachable through the namespace
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert!(vm.interpret("sqrt(9);").is_err());
+    }
+
+    #[test]
+    fn vm_builder() {
+        // the defaults are those of `VM::new()`
+        let mut vm = VM::builder().output(Output::capture()).build().unwrap();
+        vm.interpret("print math.sqrt(9);").unwrap();
+        assert_eq!(vm.output.out.captured(), "3\n");
+        assert!(
+            !vm.compile_options.strict_bool
+                && vm.op_history().is_none()
+                && vm.source_map().is_none()
+        );
+
+        let mut levels = WarningLevels::default();
+        levels.deny_all(true);
+        let mut vm = VM::builder()
+            .lang_level(LangLevel::Classic)
+            .optimize(true)
+            .integers(true)
+            .paranoid(true)
+            .strip_lines(true)
+            .max_errors(3)
+            .max_nesting(10)
+            .warning_levels(levels)
+            .strict_bool(true)
+            .checked_arithmetic(true)
+            .runtime_warnings(true)
+            .build()
+            .unwrap();
+        let options = vm.compile_options;
+        assert_eq!(options.lang_level, LangLevel::Classic);
+        assert!(options.optimize && options.integers && options.paranoid && options.strip_lines);
+        assert_eq!((options.max_errors, options.max_nesting), (3, 10));
+        assert!(options.strict_bool && vm.checked_arithmetic && vm.runtime_warnings);
+        assert!(vm.interpret("var n = 1; if (n) print n;").is_err());
+
+        // a gas limit without a cost model charges 1 per instruction
+        let limits = Limits {
+            max_gas: 50,
+            ..Limits::default()
+        };
+        let mut vm = VM::builder().limits(limits).build().unwrap();
+        assert!(vm.interpret("while (true) {}").is_err());
+        assert_eq!(vm.gas_used(), 51);
+        let mut vm = VM::builder()
+            .limits(limits)
+            .cost_model(CostModel::weighted())
+            .build()
+            .unwrap();
+        vm.interpret("fun f() {} f();").unwrap();
+        assert!(vm.gas_used() > 10);
+
+        // without the standard library there are no natives
+        let mut vm = VM::builder().stdlib(false).build().unwrap();
+        assert!(vm.interpret("print clock();").is_err());
+        let mut vm = VM::builder().compat_bare_natives(true).build().unwrap();
+        assert_eq!(vm.interpret("var a = sqrt(9);").err(), None);
+
+        let path = std::env::temp_dir().join(format!("rust-lox-builder-{}", std::process::id()));
+        let mut vm = VM::builder()
+            .compile_cache(4)
+            .source_map(true)
+            .crash_reports(true)
+            .audit(true)
+            .trace(Tracer::to_file(&path).unwrap())
+            .build()
+            .unwrap();
+        vm.interpret("var x = 1;").unwrap();
+        vm.flush_trace();
+        assert_eq!(vm.compile_cache_len(), 1);
+        assert!(vm.source_map().is_some());
+        assert!(vm.op_history().is_some());
+        assert_eq!(vm.take_audit_log().len(), 1);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .contains("OP_DEFINE_GLOBAL"));
+        fs::remove_file(&path).unwrap();
+
+        // invalid combinations are reported by `build`
+        let error = |builder: VmBuilder| builder.build().err().unwrap();
+        let no_calls = Limits {
+            max_call_depth: 0,
+            ..Limits::default()
+        };
+        assert_eq!(
+            error(VM::builder().limits(no_calls)),
+            "The call depth limit must be at least 1, the script is a call."
+        );
+        assert_eq!(
+            error(VM::builder().max_errors(0)),
+            "The compile error limit must be at least 1."
+        );
+        assert_eq!(
+            error(VM::builder().max_nesting(0)),
+            "The nesting limit must be at least 1."
+        );
+        assert_eq!(
+            error(VM::builder().stdlib(false).compat_bare_natives(true)),
+            "Bare natives are aliases of the standard library, which is disabled."
+        );
+    }
+
+    #[test]
+    fn globals_in_definition_order() {
+        let source = "var zeta = 1; var alpha = 2; fun mid() {} zeta = 3; var beta;";
+        let names = |vm: &VM| -> Vec<String> {
+            vm.globals_iter().map(|(name, _)| name.to_owned()).collect()
+        };
+
+        let mut first = VM::new();
+        assert_eq!(first.interpret(source).err(), None);
+        let mut second = VM::new();
+        assert_eq!(second.interpret(source).err(), None);
+
+        let first_names = names(&first);
+        assert_eq!(first_names, names(&second));
+        // the stdlib comes first
+        assert_eq!(first_names[0], "clock");
+        assert_eq!(
+            first_names[first_names.len() - 4..],
+            ["zeta", "alpha", "mid", "beta"]
+        );
+    }
+
+    #[test]
+    fn repl_globals_command() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("var b = 2; var a = \"x\";").err(), None);
+        let output = repl::command(&mut vm, "globals");
+        assert!(output.starts_with("clock = <native fn>\n"));
+        assert!(output.ends_with("b = 2\na = x\n"));
+        assert_eq!(repl::command(&mut vm, "nope"), "Unknown command ':nope'.\n");
+    }
+
+    // a submission with compile errors leaves no functions behind, not even the ones of it
+    // that compiled, so only committed functions are in `vm.functions`
+    #[test]
+    fn repl_failed_functions_roll_back() {
+        let mut vm = VM::new();
+        let base = vm.functions.len();
+        let calls = |vm: &mut VM, source: &str| {
+            vm.output = Output::capture();
+            repl::eval(vm, source);
+            vm.output.out.captured().to_owned()
+        };
+
+        // a bad function, then a good one of the same name
+        repl::eval(
+            &mut vm,
+            "fun f() {\n  fun inner() { return 1; }\n  return inner( +;\n}",
+        );
+        assert_eq!(vm.functions.len(), base);
+        assert!(global(&vm, "f").is_none());
+        repl::eval(
+            &mut vm,
+            "fun f() { fun inner() { return 2; } return inner(); }",
+        );
+        assert_eq!(vm.functions.len(), base + 3); // f, inner and the script
+        assert_eq!(calls(&mut vm, "print f();"), "2\n");
+        let base = vm.functions.len();
+
+        // a good function, then a bad redefinition: the old one keeps working
+        repl::eval(&mut vm, "fun f() {\n  fun other() {}\n  return 3\n}");
+        assert_eq!(vm.functions.len(), base);
+        assert_eq!(calls(&mut vm, "print f();"), "2\n");
+        let base = vm.functions.len();
+
+        // a bad class body, whose methods before the error compiled
+        repl::eval(
+            &mut vm,
+            "class A {\n  ok() { return 1; }\n  x = 1;\n  bad() { return ; ; }\n  x() {}\n}",
+        );
+        assert_eq!(vm.functions.len(), base);
+        assert!(global(&vm, "A").is_none());
+        repl::eval(&mut vm, "class A { ok() { return f() + 1; } }");
+        assert_eq!(vm.functions.len(), base + 2); // ok and the script
+        assert_eq!(calls(&mut vm, "print A().ok();"), "3\n");
+        assert_eq!(vm.functions.len(), base + 3);
+    }
+
+    #[test]
+    fn repl_last_error() {
+        let mut vm = VM::new();
+        repl::eval(&mut vm, "var n; var a = -n;");
+        let error = vm.last_error.clone().unwrap();
+        assert_eq!(error.message, "Operand to '-' must be a number, got nil.");
+        assert_eq!(error.trace[0].line, Some(1));
+
+        repl::eval(
+            &mut vm,
+            "var message = _lastError; var fromNative = lastError();",
+        );
+        for name in ["message", "fromNative"] {
+            let message = format_value(&global(&vm, name).unwrap(), &vm.interner);
+            assert_eq!(message, "Operand to '-' must be a number, got nil.");
+        }
+        assert!(vm.last_error.is_none());
+
+        repl::eval(
+            &mut vm,
+            "var after = _lastError; var afterNative = lastError();",
+        );
+        assert!(values_equal(global(&vm, "after").unwrap(), Value::Nil));
+        assert!(values_equal(
+            global(&vm, "afterNative").unwrap(),
+            Value::Nil
+        ));
+    }
+
+    #[test]
+    fn interner_dedup() {
+        let mut interner = Interner::with_common_strings();
+        let a = interner.intern("a");
+        let ab = interner.intern_string("a".to_owned() + "b");
+        assert_eq!(interner.intern("ab"), ab);
+        assert_eq!(interner.intern_string("a".to_owned()), a);
+        assert_eq!(interner.get("a"), Some(a));
+        assert_eq!(interner.get("missing"), None);
+        assert_eq!(interner.get("nil"), Some(1));
+
+        // indices stay stable as new strings are added
+        for i in 0..1000 {
+            interner.intern(&i.to_string());
+        }
+        assert_eq!(interner.lookup(a), "a");
+        assert_eq!(interner.lookup(ab), "ab");
+        assert_eq!(interner.intern("ab"), ab);
+    }
+
+    #[test]
+    fn string_equality() {
+        // literals, concatenations and native results compare by content
+        let source = "var ab = \"ab\"; var a = \"a\"; \
+                      print ab == \"ab\"; print ab == a + \"b\"; print a + \"b\" == \"a\" + \"b\"; \
+                      print ab == \"a\"; print a + \"b\" == \"b\" + a; \
+                      print fromCharCode(97) + \"b\" == ab; print deepEquals(a + \"b\", ab);";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\ntrue\ntrue\nfalse\nfalse\ntrue\ntrue\n"
+        );
+
+        let mut vm = VM::new();
+        let a = vm.evaluate("\"a\" + \"b\"").unwrap();
+        let b = vm.evaluate("\"ab\"").unwrap();
+        match (a, b) {
+            (Value::StringObj(a), Value::StringObj(b)) => assert_eq!(a, b),
+            other => panic!("unexpected values {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interner_hashes() {
+        let mut interner: Interner<BuildHasherDefault<DefaultHasher>> = Interner::default();
+        let expected = |s: &str| BuildHasherDefault::<DefaultHasher>::default().hash_one(s);
+        let abc = interner.intern("abc");
+        let d = interner.intern_string("d".to_owned());
+        assert_eq!(interner.hash_of(abc), expected("abc"));
+        assert_eq!(interner.hash_of(d), expected("d"));
+        // stable as strings are added, and the same for every copy of a string
+        for i in 0..1000 {
+            interner.intern(&i.to_string());
+        }
+        assert_eq!(interner.hash_of(abc), expected("abc"));
+        let abc_again = interner.intern_string("ab".to_owned() + "c");
+        assert_eq!(abc_again, abc);
+        let n = interner.get("999").unwrap();
+        assert_eq!(interner.hash_of(n), expected("999"));
+    }
+
+    // Every string gets the same hash
+    #[derive(Default)]
+    struct CollidingHasher;
+
+    impl Hasher for CollidingHasher {
+        fn finish(&self) -> u64 {
+            42
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn interner_hash_collisions() {
+        let mut interner: Interner<BuildHasherDefault<CollidingHasher>> = Interner::default();
+        let indices: Vec<u32> = ["x", "y", "z"].iter().map(|s| interner.intern(s)).collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(interner.intern("y"), 1);
+        assert_eq!(interner.intern_string("z".to_owned()), 2);
+        assert_eq!(interner.get("w"), None);
+        assert_eq!(interner.lookup(1), "y");
+        assert_eq!(interner.hash_of(2), 42);
+    }
+
+    #[test]
+    fn multi_line_statement_errors() {
+        let mut vm = VM::new();
+        match vm.interpret("var n; print n +\n  1 +\n  2;") {
+            Err(LoxError::Runtime(error)) => assert_eq!(error.trace[0].line, Some(1)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn multi_line_if_lines() {
+        let mut vm = VM::new();
+        assert_eq!(
+            vm.interpret("var a = 1;\nif (\n  a == 1\n)\n  print a;")
+                .err(),
+            None
+        );
+        let chunk = &vm.functions.last().unwrap().chunk;
+        let jump = chunk
+            .code()
+            .iter()
+            .position(|op| matches!(op, OpCode::JumpIfFalse(_)))
+            .unwrap();
+        assert_eq!(chunk.line(jump), Some(2));
+        // the comparison stays on the line of its operator
+        let equal = chunk
+            .code()
+            .iter()
+            .position(|op| matches!(op, OpCode::Equal))
+            .unwrap();
+        assert_eq!(chunk.line(equal), Some(3));
+    }
+
+    #[test]
+    fn inspect_values() {
+        let mut vm = VM::new();
+        let source = "fun add(a, b) {\n  return a + b;\n}\nvar s = \"two\nlines\";";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(
+            repl::command(&mut vm, "inspect add"),
+            "<fn add>\n  arity: 2\n  chunk: 6 instructions, 0 constants\n"
+        );
+        assert_eq!(repl::command(&mut vm, "inspect s"), "\"two\\nlines\"\n");
+        assert_eq!(
+            repl::command(&mut vm, "inspect math.sqrt"),
+            "<native fn math.sqrt>\n  arity: 1\n"
+        );
+        assert_eq!(repl::command(&mut vm, "inspect 1 + 2"), "3\n");
+        assert_eq!(repl::command(&mut vm, "inspect 1 +"), "");
+        assert!(vm.stack.is_empty());
+    }
+
+    // Function constants of a chunk, as indices into vm.functions
+    fn function_constants(function: &Function) -> Vec<usize> {
+        let constants = function.chunk.constants();
+        (0..constants.len())
+            .filter_map(|idx| match constants.get(idx) {
+                Some(Value::Function(f_idx)) => Some(*f_idx),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nested_function_chunks() {
+        let mut vm = VM::new();
+        let source = "fun outer() {\n  fun inner() { print 1; }\n  return inner;\n}";
+        assert_eq!(vm.interpret(source).err(), None);
+
+        let script = vm.functions.last().unwrap();
+        let outer_idx = function_constants(script)[0];
+        let outer = &vm.functions[outer_idx];
+        assert_eq!(vm.interner.lookup(outer.name.unwrap()), "outer");
+        let inner_idx = function_constants(outer)[0];
+        let inner = &vm.functions[inner_idx];
+        assert_eq!(vm.interner.lookup(inner.name.unwrap()), "inner");
+
+        // the body of inner is only in its own chunk
+        assert!(matches!(
+            inner.chunk.code(),
+            [OpCode::One, OpCode::Print, OpCode::Nil, OpCode::Return]
+        ));
+        assert!(!outer
+            .chunk
+            .code()
+            .iter()
+            .any(|op| matches!(op, OpCode::Print)));
+        assert!(function_constants(inner).is_empty());
+    }
+
+    #[test]
+    fn print_and_print_err_are_captured_separately() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert_eq!(
+            vm.interpret("print \"data\"; printErr(\"oops\"); print 1 + 2;")
+                .err(),
+            None
+        );
+        assert_eq!(vm.output.out.captured(), "data\n3\n");
+        assert_eq!(vm.output.err.captured(), "oops\n");
+    }
+
+    #[test]
+    fn recursion_through_slot_zero() {
+        let mut vm = VM::new();
+        let source = "fun count(n) {\n  if (n > 0) return count(n - 1) + 1;\n  return 0;\n}\nvar c = count;\ncount = nil;\nvar r = c(3);";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(values_equal(global(&vm, "r").unwrap(), Value::Number(3.0)));
+
+        let count_idx = function_constants(&vm.functions[vm.functions.len() - 1])[0];
+        let count = &vm.functions[count_idx];
+        assert!(count
+            .chunk
+            .code()
+            .iter()
+            .any(|op| matches!(op, OpCode::GetLocal(0))));
+        assert!(!count
+            .chunk
+            .code()
+            .iter()
+            .any(|op| matches!(op, OpCode::GetGlobal(_))));
+    }
+
+    #[test]
+    fn slot_zero_hidden_from_scripts() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("{ var a = 1; print a; }").err(), None);
+        let code = script_code(&vm);
+        assert!(code.iter().any(|op| matches!(op, OpCode::GetLocal(1))));
+        assert!(!code
+            .iter()
+            .any(|op| matches!(op, OpCode::GetLocal(0) | OpCode::SetLocal(0))));
+    }
+
+    #[test]
+    fn compilation_units() {
+        let source = "fun greet(name) { return \"hello \" + name; }\n\
+                      class Pair { init(a) { this.a = a; } }\n\
+                      print greet(\"unit\"); print Pair(\"x\").a; print math.sqrt(16); \
+                      var answer = greet(\"again\");";
+        let namespaces = VM::new().namespace_names();
+        let options = CompileOptions::default();
+        let unit = std::thread::spawn(move || {
+            CompilationUnit::compile(source, Some("unit.lox"), options, &namespaces)
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+
+        let expected = "hello unit\nx\n4\n";
+        // one VM with state of its own, so the strings and functions have other ids there
+        let mut other = VM::new();
+        other.output = Output::capture();
+        other
+            .interpret("fun before() {} var a = \"x\"; var b = \"hello \";")
+            .unwrap();
+        for vm in [&mut VM::new(), &mut other] {
+            vm.output = Output::capture();
+            let functions_before = vm.functions.len();
+            let f_idx = vm.install(&unit).unwrap();
+            // `greet` and `init`, then the script
+            assert_eq!(f_idx, functions_before + 2);
+            vm.run_compiled(f_idx).unwrap();
+            assert_eq!(vm.output.out.captured(), expected);
+            let answer = global(vm, "answer").unwrap();
+            assert_eq!(vm.format_value(&answer), "hello again");
+            assert_eq!(vm.functions[f_idx].display_name(&vm.interner), "<script>");
+            let origin = vm.functions[f_idx - 1].origin.unwrap();
+            assert_eq!(vm.interner.lookup(origin), "unit.lox");
+        }
+
+        // installing twice gives two copies of the functions
+        let mut vm = VM::new();
+        let first = vm.install(&unit).unwrap();
+        assert_eq!(vm.install(&unit).unwrap(), first + 3);
+
+        match CompilationUnit::compile("print ;", None, options, &[]) {
+            Err(LoxError::Compile(errors)) => assert_eq!(errors.len(), 1),
+            _ => panic!("expected a compile error"),
+        }
+        // warnings are reported, or denied, by the VM installing the unit
+        let unit = CompilationUnit::compile("if (false) print 1;", None, options, &[]).unwrap();
+        assert_eq!(unit.warnings.len(), 1);
+        let mut vm = VM::new();
+        vm.warning_levels.deny_all(true);
+        assert!(matches!(vm.install(&unit), Err(LoxError::Compile(_))));
+    }
+
+    #[test]
+    fn compile_cache() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.enable_compile_cache(2);
+        let script = "fun twice(x) { return 2 * x; }\nprint twice(n);";
+        vm.set_global("n", Value::Number(1.0));
+        assert_eq!(vm.interpret(script).err(), None);
+        let compiled = vm.functions.len();
+        // a hit reuses the compiled functions
+        vm.set_global("n", Value::Number(2.0));
+        assert_eq!(vm.interpret(script).err(), None);
+        assert_eq!(vm.functions.len(), compiled);
+        assert_eq!(vm.output.out.captured(), "2\n4\n");
+
+        // different sources, origins or options don't share an entry
+        assert_eq!(vm.interpret("print 1;").err(), None);
+        assert_eq!(vm.interpret_from("print 1;", "a.lox").err(), None);
+        assert_eq!(vm.functions.len(), compiled + 2);
+        vm.compile_options.optimize = true;
+        assert_eq!(vm.interpret("print 1;").err(), None);
+        assert_eq!(vm.functions.len(), compiled + 3);
+        assert_eq!(vm.compile_cache_len(), 2);
+
+        // the least recently used source was evicted
+        vm.compile_options.optimize = false;
+        assert_eq!(vm.interpret(script).err(), None);
+        assert_eq!(vm.functions.len(), compiled * 2 + 3);
+
+        // compile errors are not cached
+        let before = vm.functions.len();
+        assert!(vm.interpret("print ;").is_err());
+        assert!(vm.interpret("print ;").is_err());
+        assert_eq!(vm.diagnostics.errors.len(), 1);
+        assert_eq!(vm.functions.len(), before);
+
+        // warnings are reported on every run
+        vm.compile_options.lang_level = LangLevel::Classic;
+        assert_eq!(vm.interpret("var switch = 1;").err(), None);
+        assert_eq!(vm.interpret("var switch = 1;").err(), None);
+        assert_eq!(vm.diagnostics.warnings.len(), 1);
+
+        vm.reset_user_state();
+        assert_eq!(vm.compile_cache_len(), 0);
+    }
+
+    #[test]
+    fn host_data_for_natives() {
+        #[derive(Default)]
+        struct Calls(usize);
+        fn count(vm: &mut VM, _args: &[Value]) -> NativeResult {
+            vm.host_data::<Calls>().0 += 1;
+            Ok(Value::Nil)
+        }
+        let mut vm = VM::new();
+        vm.define_native("count", 0, count);
+        assert_eq!(vm.interpret("count(); count();").err(), None);
+        // kept by a soft reset, like the natives using it
+        vm.reset_user_state();
+        assert_eq!(vm.interpret("count();").err(), None);
+        assert_eq!(vm.host_data::<Calls>().0, 3);
+        assert_eq!(vm.take_host_data::<Calls>().0, 3);
+        assert_eq!(vm.host_data::<Calls>().0, 0);
+    }
+
+    #[test]
+    fn prelude_files() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/prelude");
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        repl::load_rc(&mut vm, &fixtures.join("helpers.lox"));
+        assert_eq!(
+            vm.interpret("var mine = double(21); print mine;").err(),
+            None
+        );
+        assert_eq!(vm.output.out.captured(), "42\n");
+        // prelude definitions survive a soft reset, like natives
+        vm.reset_user_state();
+        assert!(global(&vm, "mine").is_none());
+        assert_eq!(vm.interpret("print greeting;").err(), None);
+
+        // a broken rc file keeps what it defined before the error, a missing one is skipped
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        repl::load_rc(&mut vm, &fixtures.join("broken.lox"));
+        repl::load_rc(&mut vm, &fixtures.join("missing.lox"));
+        assert_eq!(vm.interpret("print one();").err(), None);
+        assert!(global(&vm, "two").is_none());
+        assert_eq!(vm.last_error, None);
+
+        let mut vm = VM::new();
+        match vm.run_prelude(&fixtures.join("broken.lox")) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Undefined variable 'undefinedInPrelude'.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repl_soft_reset() {
+        let mut vm = VM::new();
+        let source = "var user = 1;\nfun twice(x) { return x * 2; }\nclock = nil;";
+        assert_eq!(vm.interpret(source).err(), None);
+        let twice = vm.evaluate("twice").unwrap();
+
+        assert_eq!(
+            repl::command(&mut vm, "reset soft"),
+            "Global variables cleared.\n"
+        );
+        assert!(global(&vm, "user").is_none());
+        assert!(global(&vm, "twice").is_none());
+        assert_eq!(
+            vm.interpret("var t = clock(); var s = math.sqrt(9);").err(),
+            None
+        );
+        assert!(values_equal(global(&vm, "s").unwrap(), Value::Number(3.0)));
+
+        // a function value held by the host still runs
+        vm.set_global("held", twice);
+        assert_eq!(vm.interpret("var r = held(4);").err(), None);
+        assert!(values_equal(global(&vm, "r").unwrap(), Value::Number(8.0)));
+        assert_eq!(repl::command(&mut vm, "reset"), "Usage: :reset soft\n");
+    }
+
+    #[test]
+    fn scope_exit_pops_batched() {
+        let mut vm = VM::new();
+        let source =
+            "var r;\n{\n  var a = 1; var b = 2; var c = 3;\n  { var d = 4; r = a + b + c + d; }\n}";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(values_equal(global(&vm, "r").unwrap(), Value::Number(10.0)));
+        let pops: Vec<OpCode> = script_code(&vm)
+            .into_iter()
+            .filter(|op| matches!(op, OpCode::Pop | OpCode::PopN(_)))
+            .collect();
+        // the expression statement, d, then a, b and c at once
+        assert!(matches!(
+            pops.as_slice(),
+            [OpCode::Pop, OpCode::Pop, OpCode::PopN(3)]
+        ));
+    }
+
+    #[test]
+    fn scope_exit_pops_elided_after_return() {
+        let mut vm = VM::new();
+        let source = "fun f(x) {\n  if (x) { var a = 1; var b = 2; return a + b; }\n  { var c = 3; return c; }\n}\nvar r1 = f(true);\nvar r2 = f(false);";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(values_equal(global(&vm, "r1").unwrap(), Value::Number(3.0)));
+        assert!(values_equal(global(&vm, "r2").unwrap(), Value::Number(3.0)));
+        let f_idx = function_constants(&vm.functions[vm.functions.len() - 1])[0];
+        let code = &vm.functions[f_idx].chunk.code();
+        assert!(!code.iter().any(|op| matches!(op, OpCode::PopN(_))));
+        // only the two pops of the if condition remain
+        assert_eq!(
+            code.iter().filter(|op| matches!(op, OpCode::Pop)).count(),
+            2
+        );
+
+        // an else branch that returns doesn't make the pops after the if unreachable
+        let source = "fun g(x) {\n  { var a = 1; var b = 2; if (x) a = b; else return a; }\n  var c = 5;\n  return c;\n}\nvar r3 = g(true);";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(values_equal(global(&vm, "r3").unwrap(), Value::Number(5.0)));
+
+        // neither does a loop whose body is a `return`, the loop can still end
+        let source =
+            "fun f(){ for (var i = 0; i < 0; i = i + 1) return i; var x = \"x\"; return x; }\n\
+                      print f();\n\
+                      fun g(){ for (var x in 0..0) return x; var y = \"y\"; return y; }\n\
+                      print g();";
+        assert_eq!(run_output(source).unwrap(), "x\ny\n");
+    }
+
+    #[test]
+    fn object_census() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let census = |vm: &VM, kind: &str| vm.live_object_census()[kind];
+        assert_eq!(census(&vm, "strings"), vm.interner.len());
+        assert_eq!(census(&vm, "functions"), 0);
+
+        let script = "fun greet(name) { return \"hi \" + name; } print greet(\"bob\");";
+        vm.interpret(script).unwrap();
+        let after_first = vm.live_object_census();
+        // the script and `greet`
+        assert_eq!(after_first["functions"], 2);
+
+        // the same strings are interned again, but every run keeps its compiled functions:
+        // without a garbage collector the census can't return to the baseline
+        vm.interpret(script).unwrap();
+        assert_eq!(census(&vm, "strings"), after_first["strings"]);
+        assert_ne!(census(&vm, "functions"), after_first["functions"]);
+
+        // a global holding a big string keeps it alive, as expected
+        let strings = census(&vm, "strings");
+        vm.interpret("var big = \"x\"; for (var i = 0; i < 10; i = i + 1) big = big + big;")
+            .unwrap();
+        assert!(census(&vm, "strings") > strings + 10);
+    }
+
+    #[test]
+    fn gas_accounting() {
+        let build = |n: usize, cost_model: CostModel| {
+            let mut vm = VM::new();
+            vm.cost_model = cost_model;
+            let source = format!(
+                "var s = \"\"; for (var i = 0; i < {}; i = i + 1) s = s + \"x\";",
+                n
+            );
+            vm.interpret(&source).unwrap();
+            vm.gas_used()
+        };
+        // by default gas is the number of instructions, which grows linearly
+        let (short, long) = (
+            build(100, CostModel::default()),
+            build(200, CostModel::default()),
+        );
+        assert!(short > 0);
+        assert!(long < 2 * short + 20, "{} {}", short, long);
+        // charging the copied bytes makes building a string quadratic
+        let (short, long) = (
+            build(100, CostModel::weighted()),
+            build(200, CostModel::weighted()),
+        );
+        assert!(long > 3 * short, "{} {}", short, long);
+        // deterministic
+        assert_eq!(build(100, CostModel::weighted()), short);
+
+        // calls cost extra, natives more
+        let extra = |source: &str| {
+            let mut vm = VM::new();
+            vm.interpret(source).unwrap();
+            let instructions = vm.gas_used();
+            vm.cost_model = CostModel::weighted();
+            vm.interpret(source).unwrap();
+            vm.gas_used() - instructions
+        };
+        assert_eq!(extra("var x = 1;"), 0);
+        assert_eq!(extra("fun f() { return 1; } var x = f();"), 10);
+        assert_eq!(extra("var x = len(\"a\");"), 20);
+        // also natives called back by a native: the warm-up call and the 2 timed ones
+        assert_eq!(extra("var x = benchmark(clock, 2);"), 4 * 20);
+
+        // running out is a runtime error, and gas is counted per run
+        let mut vm = VM::with_limits(Limits {
+            max_gas: 50,
+            ..Limits::default()
+        });
+        match vm.interpret("var i = 0; while (true) i = i + 1;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Gas limit (50) exhausted.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.gas_used(), 51);
+        assert_eq!(vm.interpret("var x = 1;").err(), None);
+        assert_eq!(vm.gas_used(), 4);
+        // so is a call by the host
+        vm.interpret("fun f() { return 1; }").unwrap();
+        let f = vm.get_global("f").unwrap();
+        vm.call_function(f, &[]).unwrap();
+        assert_eq!(vm.gas_used(), 2);
+    }
+
+    #[test]
+    fn time_sliced_runs() {
+        // a loop in a function, so pauses happen inside calls and with locals on the stack
+        let source =
+            "fun sum(n) { var s = 0; for (var i = 0; i < n; i = i + 1) s = s + i; return s; }\n\
+                      var total = sum(1000); print total;";
+        let mut whole = VM::new();
+        whole.output = Output::capture();
+        whole.interpret(source).unwrap();
+        let instructions = whole.gas_used();
+        assert!(instructions > 10_000, "{}", instructions);
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let f_idx = vm.compile_source(source, None, false).unwrap();
+        let mut state = vm.run_sliced(f_idx, 1000).unwrap();
+        let mut slices = 1;
+        while let RunState::Paused = state {
+            assert!(vm.is_paused());
+            assert!(vm.gas_used() >= slices * 1000);
+            assert_eq!(vm.output.out.captured(), "");
+            state = vm.resume(1000).unwrap();
+            slices += 1;
+        }
+        assert!(matches!(state, RunState::Finished(Value::Nil)));
+        assert!(!vm.is_paused());
+        assert_eq!(slices, instructions.div_ceil(1000));
+        assert_eq!(vm.gas_used(), instructions);
+        assert_eq!(vm.output.out.captured(), whole.output.out.captured());
+        assert!(values_equal(
+            global(&vm, "total").unwrap(),
+            global(&whole, "total").unwrap()
+        ));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // while paused, the host can call functions but not start other runs
+        let f_idx = vm.compile_source(source, None, false).unwrap();
+        assert!(matches!(vm.run_sliced(f_idx, 100), Ok(RunState::Paused)));
+        let sum = vm.get_global("sum").unwrap();
+        let result = vm.call_function(sum, &[Value::Number(4.0)]).unwrap();
+        assert!(values_equal(result, Value::Number(6.0)));
+        match vm.interpret("print 1;") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Cannot start a run while another one is paused."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(vm.is_paused());
+        while let RunState::Paused = vm.resume(5000).unwrap() {}
+        assert_eq!(vm.output.out.captured(), "499500\n499500\n");
+
+        // a cancelled run leaves nothing behind, and there is nothing left to resume
+        let f_idx = vm.compile_source(source, None, false).unwrap();
+        assert!(matches!(vm.run_sliced(f_idx, 100), Ok(RunState::Paused)));
+        vm.cancel();
+        assert!(!vm.is_paused() && vm.stack.is_empty() && vm.frames.is_empty());
+        match vm.resume(100) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "There is no paused run to resume.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.interpret("var x = 1;").err(), None);
+
+        // the gas limit counts the whole run
+        let mut vm = VM::with_limits(Limits {
+            max_gas: 50,
+            ..Limits::default()
+        });
+        let f_idx = vm
+            .compile_source("var i = 0; while (true) i = i + 1;", None, false)
+            .unwrap();
+        let mut state = vm.run_sliced(f_idx, 20);
+        while let Ok(RunState::Paused) = state {
+            state = vm.resume(20);
+        }
+        match state {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Gas limit (50) exhausted.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!vm.is_paused());
+    }
+
+    #[test]
+    fn resource_limits() {
+        let mut vm = VM::with_limits(Limits {
+            max_call_depth: 3,
+            max_string_length: 5,
+            ..Limits::default()
+        });
+        assert_eq!(vm.interpret("var s = \"ab\" + \"cde\";").err(), None);
+        match vm.interpret("var t = s + \"f\";") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "String length limit (5) exceeded.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the script and two calls fit, a third call doesn't
+        let source = "fun f(n) { if (n > 0) f(n - 1); }\nf(1);";
+        assert_eq!(vm.interpret(source).err(), None);
+        match vm.interpret("f(2);") {
+            Err(LoxError::Runtime(error)) => assert_eq!(error.message, "Stack overflow."),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the defaults don't get in the way of normal programs
+        let mut vm = VM::new();
+        let source = "var s = \"\";\nfor (var i = 0; i < 100; i = i + 1) s = s + \"abcdefghij\";\nfun f(n) { if (n > 0) return f(n - 1); return 0; }\nf(60);";
+        assert_eq!(vm.interpret(source).err(), None);
+    }
+
+    #[test]
+    fn trace_names_origin_files() {
+        let mut vm = VM::new();
+        let util = "fun helper(x) {\n  return -x;\n}";
+        assert_eq!(vm.interpret_from(util, "util.lox").err(), None);
+        match vm.interpret_from("print 1;\nhelper(\"a\");", "main.lox") {
+            Err(LoxError::Runtime(error)) => {
+                let origins: Vec<Option<&str>> = error
+                    .trace
+                    .iter()
+                    .map(|frame| frame.origin.as_deref())
+                    .collect();
+                assert_eq!(origins, vec![Some("util.lox"), Some("main.lox")]);
+                assert_eq!(
+                    error.to_string(),
+                    "Operand to '-' must be a number, got string (\"a\").\n[file util.lox, line 2] in helper()\n[file main.lox, line 2] in <script>"
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // the path is interned once
+        let interned = vm.interner.get("util.lox").unwrap();
+        assert_eq!(vm.interner.intern("util.lox"), interned);
+    }
+
+    #[test]
+    fn function_display_names() {
+        let mut vm = VM::new();
+        let util = "fun helper() {\n  return 1;\n}";
+        assert_eq!(vm.interpret_from(util, "util.lox").err(), None);
+        let helper = vm
+            .functions
+            .iter()
+            .find(|f| f.name.is_some())
+            .expect("helper is compiled");
+        assert_eq!(helper.display_name(&vm.interner), "helper");
+        assert_eq!(
+            debug::chunk_title(helper, &vm.interner),
+            "helper (util.lox)"
+        );
+        let script = vm.functions.iter().find(|f| f.name.is_none()).unwrap();
+        assert_eq!(script.display_name(&vm.interner), "<script>");
+        assert_eq!(
+            debug::chunk_title(script, &vm.interner),
+            "<script> (util.lox)"
+        );
+    }
+
+    #[test]
+    fn index_and_integer_coercion() {
+        let mut vm = VM::new();
+        let s = Value::string(&mut vm.interner, "s");
+        let cases = [
+            (Value::Number(2.0), Ok(2), Ok(2)),
+            (Value::Number(0.0), Ok(0), Ok(0)),
+            (Value::Number(-0.0), Ok(0), Ok(0)),
+            (
+                Value::Number(3.0),
+                Err("List index 3 is out of bounds for length 3."),
+                Ok(3),
+            ),
+            (
+                Value::Number(-1.0),
+                Err("List index must be a non-negative integer, got -1."),
+                Ok(-1),
+            ),
+            (
+                Value::Number(1.5),
+                Err("List index must be a non-negative integer, got 1.5."),
+                Err("Shift amount must be an integer, got 1.5."),
+            ),
+            (
+                Value::Number(1e300),
+                Err("List index must be a non-negative integer, got 1e300."),
+                Err("Shift amount must be an integer, got 1e300."),
+            ),
+            (
+                Value::Number(f64::NAN),
+                Err("List index must be a non-negative integer, got nan."),
+                Err("Shift amount must be an integer, got nan."),
+            ),
+            (
+                Value::Number(f64::NEG_INFINITY),
+                Err("List index must be a non-negative integer, got -inf."),
+                Err("Shift amount must be an integer, got -inf."),
+            ),
+            (
+                Value::Nil,
+                Err("List index must be a number, got nil."),
+                Err("Shift amount must be a number, got nil."),
+            ),
+            (
+                s,
+                Err("List index must be a number, got string."),
+                Err("Shift amount must be a number, got string."),
+            ),
+        ];
+        for (value, index, integer) in cases.iter() {
+            let expected_index = index.map_err(|e: &str| e.to_owned());
+            assert_eq!(as_index(value, "List index", 3), expected_index);
+            let expected_integer = integer.map_err(|e: &str| e.to_owned());
+            assert_eq!(as_i64(value, "Shift amount"), expected_integer);
+        }
+    }
+
+    // Evaluates `expression` with integer literals, and formats the result with its type
+    fn eval_int_mode(vm: &mut VM, expression: &str) -> String {
+        match vm.evaluate(expression) {
+            Ok(Value::Int(n)) => format!("int {}", n),
+            Ok(Value::Number(n)) => format!("float {:?}", n),
+            Ok(Value::Bool(b)) => b.to_string(),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn max_compile_errors() {
+        let broken = "var = 1;\n".repeat(150);
+        let mut vm = VM::new();
+        match vm.interpret(&broken) {
+            Err(LoxError::Compile(errors)) => {
+                assert_eq!(errors.len(), DEFAULT_MAX_ERRORS);
+                assert_eq!(errors.last().unwrap().line, DEFAULT_MAX_ERRORS);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            vm.diagnostics.notes,
+            vec![format!(
+                "Too many errors ({}), stopping.",
+                DEFAULT_MAX_ERRORS
+            )]
+        );
+        vm.compile_options.max_errors = 3;
+        match vm.interpret(&broken) {
+            Err(LoxError::Compile(errors)) => assert_eq!(errors.len(), 3),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.diagnostics.notes, vec!["Too many errors (3), stopping."]);
+        // under the cap there's no notice
+        assert!(vm.interpret("var = 1;").is_err());
+        assert!(vm.diagnostics.notes.is_empty());
+        // the cap also stops inside of functions and blocks
+        match vm.interpret(&format!("fun f() {{ {} }} {{ {} }}", broken, broken)) {
+            Err(LoxError::Compile(errors)) => assert_eq!(errors.len(), 3),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a large valid file still compiles to a runnable program
+        let source = format!(
+            "{{ var n = 0;\n{}print n; }}\n",
+            "n = n + 1;\n".repeat(20_000)
+        );
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret(&source).unwrap();
+        assert_eq!(vm.output.out.captured(), "20000\n");
+    }
+
+    #[test]
+    fn max_nesting() {
+        let nested_parens =
+            |depth: usize| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        let nested_blocks =
+            |depth: usize| format!("{}print 1;{}", "{".repeat(depth), "}".repeat(depth));
+        let nested_ifs = |depth: usize| format!("{}print 1;", "if (true) ".repeat(depth));
+        let nested_functions =
+            |depth: usize| format!("{}{}", "fun f() {".repeat(depth), "}".repeat(depth));
+        let negations = |depth: usize| format!("print {}1;", "-".repeat(depth));
+
+        // deep code that still compiles
+        // (the bodies of more ifs are too much code to jump over)
+        for source in [nested_parens(200), nested_blocks(200), nested_ifs(40)] {
+            assert_eq!(run_output(&source).unwrap(), "1\n");
+        }
+        assert_eq!(run_output(&negations(200)).unwrap(), "1\n");
+        assert!(run_output(&nested_functions(200)).is_ok());
+
+        // a single clean error instead of a stack overflow, however deep the code is
+        for depth in [300, 10_000] {
+            for source in [
+                nested_parens(depth),
+                nested_blocks(depth),
+                nested_ifs(depth),
+                nested_functions(depth),
+                negations(depth),
+            ] {
+                match VM::new().interpret(&source) {
+                    Err(LoxError::Compile(errors)) => {
+                        assert_eq!(errors.len(), 1);
+                        assert_eq!(errors[0].message, "Expression too deeply nested.");
+                    }
+                    other => panic!("unexpected result {:?}", other),
+                }
+            }
+        }
+
+        let mut vm = VM::new();
+        vm.compile_options.max_nesting = 10;
+        assert!(matches!(
+            vm.interpret(&nested_parens(10)),
+            Err(LoxError::Compile(_))
+        ));
+        assert_eq!(vm.interpret(&nested_parens(5)).err(), None);
+    }
+
+    #[test]
+    fn strict_bool_conditions() {
+        let scripts = [
+            ("if (5) print \"then\"; else print \"else\";", Ok("then")),
+            ("print !\"s\";", Ok("false")),
+            ("print nil or \"default\";", Ok("default")),
+            (
+                "var n = 0; while (n) { n = nil; } print \"done\";",
+                Ok("done"),
+            ),
+            ("if (bool(5)) print \"then\";", Ok("then")),
+            ("if (1 < 2) print \"then\";", Ok("then")),
+            ("while (nil) {} print \"done\";", Ok("done")),
+            ("if (true) print \"then\";", Ok("then")),
+        ];
+        let strict = [
+            Err("Condition must be a boolean, got number (5)."),
+            Err("Condition must be a boolean, got string (\"s\")."),
+            Err("Condition must be a boolean, got nil."),
+            Err("Condition must be a boolean, got number (0)."),
+            Ok("then"),
+            Ok("then"),
+            Err("Condition must be a boolean, got nil."),
+            Ok("then"),
+        ];
+        // the optimizer only removes conditions that are boolean literals
+        let modes = [(false, false), (false, true), (true, false), (true, true)];
+        for ((source, classic), strict) in scripts.iter().zip(strict) {
+            for (strict_bool, optimize) in modes {
+                let expected = if strict_bool { strict } else { *classic };
+                let mut vm = VM::new();
+                vm.output = Output::capture();
+                vm.compile_options.strict_bool = strict_bool;
+                vm.compile_options.optimize = optimize;
+                let result = match vm.interpret(source) {
+                    Ok(_) => Ok(vm.output.out.captured().trim_end().to_owned()),
+                    Err(LoxError::Runtime(error)) => Err(error.message),
+                    Err(error) => panic!("unexpected error {:?}", error),
+                };
+                assert_eq!(
+                    result,
+                    expected.map(str::to_owned).map_err(str::to_owned),
+                    "{} (strict: {}, optimize: {})",
+                    source,
+                    strict_bool,
+                    optimize
+                );
+            }
+        }
+
+        // only conditions that aren't known to be booleans are checked, with `IsType`
+        let mut vm = VM::builder().strict_bool(true).build().unwrap();
+        let text = vm
+            .disassemble_source("var x; if (x) x = 1; if (x < 1) x = 2;", None)
+            .unwrap();
+        assert_eq!(text.matches("OP_IS_TYPE bool\n").count(), 1, "{}", text);
+        assert!(text.contains("OP_IS_TYPE bool\n   | OP_CHECK_CONDITION\n   | OP_JUMP_IF_FALSE"));
+        let text = VM::new()
+            .disassemble_source("var x; if (x) x = 1;", None)
+            .unwrap();
+        assert!(!text.contains("OP_IS_TYPE"), "{}", text);
+    }
+
+    #[test]
+    fn runtime_warnings() {
+        let source = "\
+            var total = 0;\n\
+            for (var i = 0; i < 3; i = i + 1) {\n\
+            \x20 if (\"3\" == 3) print \"never\";\n\
+            \x20 if (i) total = total + 1;\n\
+            \x20 if (10.1 * 3 == 30.3) print \"never\";\n\
+            \x20 if (i == nil or 2 == 2) total = total + 1;\n\
+            }\n\
+            fun f(x) { return !x; }\n\
+            f(\"a\"); f(\"b\");\n\
+            print total;\n";
+        let run = |runtime_warnings: bool| {
+            let mut vm = VM::new();
+            vm.output = Output::capture();
+            vm.runtime_warnings = runtime_warnings;
+            vm.interpret(source).unwrap();
+            assert_eq!(vm.output.out.captured(), "6\n");
+            vm.diagnostics
+                .warnings
+                .iter()
+                .map(|warning| warning.to_string())
+                .collect::<Vec<_>>()
+        };
+        // once per instruction, also in loops and functions called more than once
+        assert_eq!(
+            run(true),
+            [
+                "[line 3] Warning W0004: Comparing a string with a number using '==' is always false.",
+                "[line 4] Warning W0005: Condition is a number, which is always true.",
+                "[line 5] Warning W0006: Comparing fractional numbers with '==' \
+                 (30.299999999999997 == 30.3) is unreliable, compare their difference with a \
+                 tolerance instead.",
+                "[line 8] Warning W0005: Condition is a string, which is always true.",
+            ]
+        );
+        assert!(run(false).is_empty());
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.runtime_warnings = true;
+        vm.warning_levels
+            .set(warnings::MIXED_EQUALITY, warnings::Level::Error);
+        vm.warning_levels
+            .set(warnings::TRUTHY_CONDITION, warnings::Level::Off);
+        let error = vm
+            .interpret("var one = 1; if (one) print one == \"1\";")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Comparing a number with a string using '==' is always false.\n[line 1] in <script>"
+        );
+        assert!(vm.diagnostics.warnings.is_empty());
+    }
+
+    #[test]
+    fn operand_errors() {
+        let runtime_error = |source: &str| match run_output(source) {
+            Err(LoxError::Runtime(error)) => error.message,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(
+            runtime_error("var a = \"a\"; print a + nil;"),
+            "Operands to '+' must be two numbers or two strings, got string (\"a\") and nil."
+        );
+        assert_eq!(
+            runtime_error("var n = 1; print \"n = \" + n;"),
+            "Operands to '+' must be two numbers or two strings, got string (\"n = \") and number (1)."
+        );
+        assert_eq!(
+            runtime_error("var t = true; print 1 < t;"),
+            "Operands to '<' must be two numbers, got number (1) and bool (true)."
+        );
+        assert_eq!(
+            runtime_error("var s = \"s\"; print s >= 2;"),
+            "Operands to '>=' must be two numbers, got string (\"s\") and number (2)."
+        );
+        assert_eq!(
+            runtime_error("var s = \"s\"; print s <= 2;"),
+            "Operands to '<=' must be two numbers, got string (\"s\") and number (2)."
+        );
+        // a negated comparison names the operator that was written
+        assert_eq!(
+            runtime_error("var s = \"s\"; print !(s < 2);"),
+            "Operands to '<' must be two numbers, got string (\"s\") and number (2)."
+        );
+        assert_eq!(
+            runtime_error("fun f() {} print f * 2;"),
+            "Operands to '*' must be two numbers, got function (<fn>) and number (2)."
+        );
+        assert_eq!(
+            runtime_error("var s = \"abc\"; print -s;"),
+            "Operand to '-' must be a number, got string (\"abc\")."
+        );
+        // long values are cut
+        assert_eq!(
+            runtime_error(&format!("var s = \"{}\"; print -s;", "x".repeat(40))),
+            format!(
+                "Operand to '-' must be a number, got string (\"{}... 8 more bytes\").",
+                "x".repeat(32)
+            )
+        );
+    }
+
+    #[test]
+    fn expression_types() {
+        // operands of known types the operator rejects are compile errors
+        for (source, message) in [
+            (
+                "print -\"hello\";",
+                "Operand to '-' must be a number, got string.",
+            ),
+            (
+                "print true + 1;",
+                "Operands to '+' must be two numbers or two strings, got bool and number.",
+            ),
+            (
+                "print (1 < 2) * 3;",
+                "Operands to '*' must be two numbers, got bool and number.",
+            ),
+            (
+                "print \"a\" + (\"b\" == nil);",
+                "Operands to '+' must be two numbers or two strings, got string and bool.",
+            ),
+            (
+                "print -(nil or nil);",
+                "Operand to '-' must be a number, got nil.",
+            ),
+        ] {
+            match run_output(source) {
+                Err(error @ LoxError::Compile(_)) => {
+                    assert!(error.to_string().contains(message), "{}", error)
+                }
+                other => panic!("expected a compile error, got {:?}", other),
+            }
+        }
+        // unknown types are left to the VM
+        let mut vm = VM::new();
+        let source = "var s = \"a\"; fun f() { return 1; } print s + f();";
+        assert!(matches!(vm.interpret(source), Err(LoxError::Runtime(_))));
+
+        // `AddNumbers` where both operands are numbers, with the same results as `Add`
+        let source = "var x = 4; print (1 + 2) + 3 * 4; print x + 1; print \"a\" + \"b\";";
+        let text = VM::new().disassemble_source(source, None).unwrap();
+        assert_eq!(text.matches("OP_ADD_NUMBERS").count(), 2, "{}", text);
+        assert_eq!(text.matches("OP_ADD\n").count(), 2, "{}", text);
+        assert_eq!(run_output(source).unwrap(), "15\n5\nab\n");
+        let mut vm = VM::new();
+        vm.compile_options.integers = true;
+        vm.output = Output::capture();
+        assert_eq!(vm.interpret("print 1 + 2.5; print 1 + 2;").err(), None);
+        assert_eq!(vm.output.out.captured(), "3.5\n3\n");
+
+        // conditions of a type that is always true or false
+        let mut vm = VM::new();
+        let res =
+            vm.interpret("var n = 1;\nif (\"a\" + \"b\") n = 2;\nwhile (n == 3 and nil) n = 4;");
+        assert_eq!(res.err(), None);
+        let warnings: Vec<(usize, &str)> = vm
+            .diagnostics
+            .warnings
+            .iter()
+            .map(|w| (w.line, w.message.as_str()))
+            .collect();
+        assert_eq!(warnings, vec![(2, "Condition is always true.")]);
+        assert_eq!(vm.interpret("while (nil or nil) {}").err(), None);
+        assert_eq!(vm.diagnostics.warnings.len(), 1);
+    }
+
+    #[test]
+    fn modulo() {
+        // truncated: the result has the sign of the dividend
+        let source =
+            "print 7 % 3; print -7 % 3; print 7 % -3; print 7.5 % 2; print 1 + 6 % 4 * 2; \
+                      print 5 % 0;";
+        assert_eq!(run_output(source).unwrap(), "1\n-1\n1\n1.5\n5\nnan\n");
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.compile_options.integers = true;
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), "1\n-1\n1\n1.5\n5\nnan\n");
+
+        match run_output("var s = \"a\"; print s % 2;") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Operands to '%' must be two numbers, got string (\"a\") and number (2)."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let error = run_output("print true % 2;").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Operands to '%' must be two numbers, got bool and number."));
+        let text = VM::new()
+            .disassemble_source("var a = 7; print a % 2;", None)
+            .unwrap();
+        assert!(text.contains("   | OP_MODULO\n"), "{}", text);
+    }
+
+    #[test]
+    fn power() {
+        // right-associative, above `*` and binding the operand of a unary minus
+        let source = "print 2 ** 10; print 2 ** 3 ** 2; print -2 ** 2; print (-2) ** 2; \
+                      print 2 * 3 ** 2; print 2 ** -1; print 4 ** 0.5; print 8 ** (1 / 3); \
+                      print 2 ** - 2 ** 2; print !true == false;";
+        let expected = "1024\n512\n-4\n4\n18\n0.5\n2\n2\n0.0625\ntrue\n";
+        assert_eq!(run_output(source).unwrap(), expected);
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.compile_options.integers = true;
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), expected);
+        assert_eq!(eval_int_mode(&mut vm, "2 ** 10"), "int 1024");
+        assert_eq!(eval_int_mode(&mut vm, "2 ** -1"), "float 0.5");
+        assert_eq!(
+            eval_int_mode(&mut vm, "3 ** 40"),
+            format!("float {:?}", 3f64.powf(40.0))
+        );
+        // a negative exponent isn't an overflow, unlike a too large power
+        vm.checked_arithmetic = true;
+        assert_eq!(eval_int_mode(&mut vm, "2 ** -1"), "float 0.5");
+        match vm.evaluate("3 ** 40") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Arithmetic overflow in '**' (3 ** 40).")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        match run_output("var s = \"a\"; print 2 ** s;") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Operands to '**' must be two numbers, got number (2) and string (\"a\")."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let error = run_output("print \"a\" ** 2;").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Operands to '**' must be two numbers, got string and number."));
+        let text = VM::new()
+            .disassemble_source("var a = 2; print a ** 2;", None)
+            .unwrap();
+        assert!(text.contains("   | OP_POWER\n"), "{}", text);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let source = "print 6 & 3; print 6 | 3; print 6 ^ 3; print ~5; print ~-1; \
+                      print 1 << 4; print -16 >> 2; print 1 << 64; print 1 << 65; \
+                      print 1 | 2 ^ 3 & 4; print 6 & 3 == 2; print 1 << 2 + 1; print -1 >> 70;";
+        let expected = "2\n7\n5\n-6\n0\n16\n-4\n1\n2\n3\ntrue\n8\n-1\n";
+        assert_eq!(run_output(source).unwrap(), expected);
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.compile_options.integers = true;
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), expected);
+        assert_eq!(eval_int_mode(&mut vm, "12 & 10"), "int 8");
+        assert_eq!(eval_int_mode(&mut vm, "~0"), "int -1");
+        assert_eq!(
+            eval_int_mode(&mut vm, "1 << 62"),
+            format!("int {}", 1i64 << 62)
+        );
+
+        // fractional numbers aren't truncated
+        match run_output("var x = 5.5; print x & 1;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand of '&' must be an integer, got 5.5.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match run_output("var x = 0.5; print ~x;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand of '~' must be an integer, got 0.5.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match run_output("var s = \"a\"; print 1 << s;") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Operands to '<<' must be two numbers, got number (1) and string (\"a\")."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match run_output("var b = true; print ~b;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(
+                    error.message,
+                    "Operand to '~' must be a number, got bool (true)."
+                )
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let error = run_output("print ~\"a\";").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Operand to '~' must be a number, got string."));
+
+        let text = VM::new()
+            .disassemble_source("var a = 1; print ~(a & 2 | a ^ 3) << a >> 1;", None)
+            .unwrap();
+        for name in [
+            "OP_BIT_AND",
+            "OP_BIT_OR",
+            "OP_BIT_XOR",
+            "OP_SHIFT_LEFT",
+            "OP_SHIFT_RIGHT",
+            "OP_BIT_NOT",
+        ] {
+            assert!(text.contains(&format!("   | {}\n", name)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn literal_equality_lowered() {
+        // the literals held in variables aren't lowered, so they compare with OP_EQUAL
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let setup = "var x; var n = nil; var t = true; var f = false; fun g() {}";
+        assert_eq!(vm.interpret(setup).err(), None);
+        let patterns = [
+            ("x == nil", "x == n"),
+            ("nil == x", "n == x"),
+            ("x != nil", "x != n"),
+            ("x == true", "x == t"),
+            ("true == x", "t == x"),
+            ("x != true", "x != t"),
+            ("x == false", "x == f"),
+            ("false == x", "f == x"),
+            ("x != false", "x != f"),
+        ];
+        let values = [
+            "nil", "true", "false", "0", "1", "\"\"", "\"a\"", "g", "clock", "1..3",
+        ];
+        for value in values {
+            assert_eq!(vm.interpret(&format!("x = {};", value)).err(), None);
+            for (lowered, equal) in patterns {
+                assert_eq!(
+                    eval_str(&mut vm, lowered),
+                    eval_str(&mut vm, equal),
+                    "{} with x = {}",
+                    lowered,
+                    value
+                );
+            }
+        }
+
+        let text = VM::new()
+            .disassemble_source(
+                "var x = 1; print x == nil; print true == x; print x != false;",
+                None,
+            )
+            .unwrap();
+        for name in ["OP_IS_NIL", "OP_IS_TRUE", "OP_IS_FALSE"] {
+            assert!(text.contains(&format!("   | {}\n", name)), "{}", text);
+        }
+        assert!(!text.contains("OP_EQUAL"), "{}", text);
+        // a jump to the removed literal lands on the other operand
+        let source = "var x; print x or nil == x; print x and false != x;";
+        assert_eq!(run_output(source).unwrap(), "true\nnil\n");
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        // there are no exponent literals
+        let big = format!("var big = 1{};", "0".repeat(308));
+        let cases = [
+            ("1 / 0", "inf", "Division by zero in '/' (1 / 0)."),
+            ("0 / 0", "nan", "Division by zero in '/' (0 / 0)."),
+            ("5 % 0", "nan", "Division by zero in '%' (5 % 0)."),
+            (
+                "big * 10",
+                "inf",
+                "Arithmetic overflow in '*' (1e308 * 10).",
+            ),
+            (
+                "-big - big",
+                "-inf",
+                "Arithmetic overflow in '-' (-1e308 - 1e308).",
+            ),
+            (
+                "big * 10 - big * 10",
+                "nan",
+                "Arithmetic overflow in '*' (1e308 * 10).",
+            ),
+        ];
+        for (expression, unchecked, error) in cases {
+            for checked in [false, true] {
+                let mut vm = VM::new();
+                vm.output = Output::capture();
+                vm.checked_arithmetic = checked;
+                let result = vm.interpret(&format!("{} print {};", big, expression));
+                match result {
+                    Ok(_) if !checked => {
+                        assert_eq!(vm.output.out.captured(), format!("{}\n", unchecked))
+                    }
+                    Err(LoxError::Runtime(e)) if checked => assert_eq!(e.message, error),
+                    other => panic!("{} (checked: {}): {:?}", expression, checked, other),
+                }
+            }
+        }
+
+        // results that are fine stay the same
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.checked_arithmetic = true;
+        vm.interpret(&format!(
+            "{} print 1 / 4; print big + 1; print 7 - 7.5;",
+            big
+        ))
+        .unwrap();
+        assert_eq!(vm.output.out.captured(), "0.25\n1e308\n-0.5\n");
+        // NaN from a native is only reported once arithmetic uses it
+        match vm.interpret("var n = math.sqrt(-1); print n + 1;") {
+            Err(LoxError::Runtime(e)) => assert_eq!(
+                e.message,
+                "Undefined arithmetic result (NaN) in '+' (nan + 1)."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the int type overflows instead of becoming a float
+        let int_cases = [
+            (
+                "9223372036854775807 + 1",
+                "9223372036854776000",
+                "Arithmetic overflow in '+' (9223372036854775807 + 1).",
+            ),
+            (
+                "4611686018427387904 * 2",
+                "9223372036854776000",
+                "Arithmetic overflow in '*' (4611686018427387904 * 2).",
+            ),
+            ("7 / 0", "inf", "Division by zero in '/' (7 / 0)."),
+            ("7 % 0", "nan", "Division by zero in '%' (7 % 0)."),
+            (
+                "-9223372036854775807 - 1 - 1",
+                "-9223372036854776000",
+                "Arithmetic overflow in '-' (-9223372036854775808 - 1).",
+            ),
+        ];
+        for (expression, unchecked, error) in int_cases {
+            for checked in [false, true] {
+                let mut vm = VM::new();
+                vm.output = Output::capture();
+                vm.compile_options.integers = true;
+                vm.checked_arithmetic = checked;
+                let result = vm.interpret(&format!("print {};", expression));
+                match result {
+                    Ok(_) if !checked => {
+                        assert_eq!(vm.output.out.captured(), format!("{}\n", unchecked))
+                    }
+                    Err(LoxError::Runtime(e)) if checked => assert_eq!(e.message, error),
+                    other => panic!("{} (checked: {}): {:?}", expression, checked, other),
+                }
+            }
+        }
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.compile_options.integers = true;
+        vm.checked_arithmetic = true;
+        vm.interpret("print 7 / 2; print 8 / 2;").unwrap();
+        assert_eq!(vm.output.out.captured(), "3.5\n4\n");
+    }
+
+    #[test]
+    fn number_formatting() {
+        let cases = [
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (3.0, "3"),
+            (-12.0, "-12"),
+            (0.1, "0.1"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (123.456, "123.456"),
+            (1e20, "100000000000000000000"),
+            (1e21, "1e21"),
+            (-1.5e300, "-1.5e300"),
+            (1e-7, "0.0000001"),
+            (9.9e-8, "9.9e-8"),
+            (5e-324, "5e-324"),
+            (f64::MAX, "1.7976931348623157e308"),
+            (9007199254740991.0, "9007199254740991"),
+            (9007199254740992.0, "9007199254740992"),
+            // 2^53 + 1 isn't representable and rounds to 2^53
+            (9007199254740993.0, "9007199254740992"),
+            (9007199254740994.0, "9007199254740994"),
+            (f64::NAN, "nan"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (n, expected) in cases {
+            let formatted = format_number(n);
+            assert_eq!(formatted, expected);
+            if n.is_finite() {
+                let parsed: f64 = formatted.parse().unwrap();
+                assert_eq!(parsed.to_bits(), n.to_bits(), "{} round-trips", formatted);
+                assert_eq!(format_json_number(n), Ok(formatted));
+            }
+        }
+        assert_eq!(
+            format_json_number(f64::NAN),
+            Err("JSON can't represent the number nan.".to_owned())
+        );
+        assert!(format_json_number(f64::NEG_INFINITY).is_err());
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert_eq!(
+            vm.interpret("print 0.1 + 0.2;\nprint 2 * 3;\nprint -(0);")
+                .err(),
+            None
+        );
+        assert_eq!(vm.output.out.captured(), "0.30000000000000004\n6\n-0\n");
+    }
+
+    #[test]
+    fn statement_ranges() {
+        let mut vm = VM::new();
+        vm.compile_options.statement_ranges = true;
+        let source = "var a = 1;\nif (a > 0) {\n  print \"pos\";\n} else {\n  print \"neg\";\n}\nfun f() {\n  return a;\n}\nprint f();";
+        assert_eq!(vm.interpret(source).err(), None);
+        let script = vm.functions.last().unwrap();
+        let ranges = script.statement_ranges();
+        let lines: Vec<usize> = ranges.iter().map(|range| range.line).collect();
+        assert_eq!(lines, vec![1, 2, 2, 3, 4, 5, 7, 10]);
+
+        // top-level statements partition the chunk, except for the implicit `return nil`
+        let top_level: Vec<_> = [0, 1, 6, 7].iter().map(|&i| ranges[i]).collect();
+        assert_eq!(top_level[0].start, 0);
+        for pair in top_level.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert_eq!(top_level[3].end, script.chunk.len() - 2);
+
+        // the branches are disjoint, and nested in the `if`
+        let (if_range, then_block, then_print, else_block, else_print) =
+            (ranges[1], ranges[2], ranges[3], ranges[4], ranges[5]);
+        assert!(if_range.start < then_block.start && else_block.end <= if_range.end);
+        assert!(then_block.end < else_block.start);
+        assert!(then_block.start <= then_print.start && then_print.end <= then_block.end);
+        assert!(else_block.start <= else_print.start && else_print.end <= else_block.end);
+        assert!(matches!(
+            script.chunk.op(then_print.end - 1),
+            Some(OpCode::Print)
+        ));
+
+        // the function body has its own ranges
+        let f = vm.functions.iter().find(|f| f.name.is_some()).unwrap();
+        let body: Vec<usize> = f
+            .statement_ranges()
+            .iter()
+            .map(|range| range.line)
+            .collect();
+        assert_eq!(body, vec![8]);
+
+        // eliminated branches leave no ranges behind
+        let mut vm = VM::new();
+        vm.compile_options.statement_ranges = true;
+        vm.compile_options.optimize = true;
+        assert_eq!(
+            vm.interpret("if (false) {\n  print 1;\n} else {\n  print 2;\n}")
+                .err(),
+            None
+        );
+        let script = vm.functions.last().unwrap();
+        let lines: Vec<usize> = script.statement_ranges().iter().map(|r| r.line).collect();
+        assert_eq!(lines, vec![1, 3, 4]);
+        assert_eq!(script.statement_ranges()[0].end, script.chunk.len() - 2);
+
+        // nothing is recorded by default
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("print 1;").err(), None);
+        assert!(vm.functions.last().unwrap().statement_ranges().is_empty());
+    }
+
+    #[test]
+    fn values_are_small_copy_handles() {
+        fn assert_copy<T: Copy>() {}
+        assert_copy::<Value>();
+
+        let mut vm = VM::new();
+        let a = Value::string(&mut vm.interner, "lox");
+        let b = Value::string(&mut vm.interner, "lox");
+        assert!(matches!((a, b), (Value::StringObj(x), Value::StringObj(y)) if x == y));
+        assert_eq!(format_value(&a, &vm.interner), "lox");
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        let mut vm = VM::new();
+        vm.compile_options.integers = true;
+        let cases = [
+            ("1 + 2", "int 3"),
+            ("10000000000000000 + 1", "int 10000000000000001"),
+            ("7 - 10", "int -3"),
+            ("6 * 7", "int 42"),
+            ("6 / 3", "int 2"),
+            ("7 / 2", "float 3.5"),
+            ("1 / 0", "float inf"),
+            ("-9223372036854775807 - 1", "int -9223372036854775808"),
+            // overflow promotes to float
+            ("9223372036854775807 + 1", "float 9.223372036854776e18"),
+            ("-9223372036854775807 - 2", "float -9.223372036854776e18"),
+            ("9223372036854775807 * 2", "float 1.8446744073709552e19"),
+            ("-(-9223372036854775807 - 1)", "float 9.223372036854776e18"),
+            (
+                "(-9223372036854775807 - 1) / -1",
+                "float 9.223372036854776e18",
+            ),
+            // literals that don't fit stay floats
+            ("9223372036854775808", "float 9.223372036854776e18"),
+            // mixed operands are floats
+            ("1 + 0.5", "float 1.5"),
+            ("2.0 * 3", "float 6.0"),
+            ("-4", "int -4"),
+            // comparisons and equality across the two
+            ("9007199254740993 > 9007199254740992", "true"),
+            ("1 < 1.5", "true"),
+            ("2 == 2.0", "true"),
+            ("3 == 3", "true"),
+            ("3 != 4", "true"),
+        ];
+        for (expression, expected) in cases.iter() {
+            assert_eq!(
+                &eval_int_mode(&mut vm, expression),
+                expected,
+                "{}",
+                expression
+            );
+        }
+
+        vm.output = Output::capture();
+        assert_eq!(
+            vm.interpret("print 4 / 2; print 1.5 + 1; print math.sqrt(16);")
+                .err(),
+            None
+        );
+        assert_eq!(vm.output.out.captured(), "2\n2.5\n4\n");
+
+        // off by default
+        let mut vm = VM::new();
+        assert_eq!(
+            eval_int_mode(&mut vm, "10000000000000000 + 1"),
+            "float 1e16"
+        );
+    }
+
+    #[test]
+    fn benchmark_native() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let source = "var count = 0;\nfun f() { count = count + 1; }\nvar mean = benchmark(f, 5);\nbenchmark(clock, 2);";
+        assert_eq!(vm.interpret(source).err(), None);
+        // one warmup call
+        assert!(values_equal(
+            global(&vm, "count").unwrap(),
+            Value::Number(6.0)
+        ));
+        assert!(matches!(global(&vm, "mean"), Some(Value::Number(ms)) if ms >= 0.0));
+        let lines: Vec<&str> = vm.output.out.captured().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("benchmark: 5 iterations, min "));
+        for stat in ["max", "mean", "median"] {
+            assert!(lines[0].contains(&format!(", {} ", stat)));
+        }
+        assert!(lines[1].starts_with("benchmark: 2 iterations"));
+
+        // a builtin method bound to its receiver is a callback like any other
+        let source = "benchmark(\"abc\".len, 2); var after = \"abc\".len();";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(values_equal(
+            global(&vm, "after").unwrap(),
+            Value::Number(3.0)
+        ));
+        assert!(vm
+            .output
+            .out
+            .captured()
+            .lines()
+            .nth(2)
+            .unwrap()
+            .starts_with("benchmark: 2 iterations"));
+        assert_eq!(vm.interpret("var len = \"abc\".len;").err(), None);
+        let len = global(&vm, "len").unwrap();
+        assert!(values_equal(
+            vm.call_function(len, &[]).unwrap(),
+            Value::Number(3.0)
+        ));
+        match vm.call_function(len, &[Value::Nil]) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Expected 0 arguments but got 1.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // errors of the callback are propagated with the full trace
+        let source = "var n; fun g() { return -n; }\nbenchmark(g, 3);";
+        match vm.interpret(source) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand to '-' must be a number, got nil.");
+                let functions: Vec<Option<&str>> = error
+                    .trace
+                    .iter()
+                    .map(|frame| frame.function.as_deref())
+                    .collect();
+                assert_eq!(functions, vec![Some("g"), None]);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(vm.stack.is_empty());
+
+        match vm.interpret("benchmark(f, 0);") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "benchmark() expects a positive iteration count."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.interpret("var after = 1;").err(), None);
+    }
+
+    #[test]
+    fn long_line_snippets() {
+        let mut vm = VM::new();
+        let filler = "x".repeat(5000);
+        let source = format!("var a = \"{}\"; var = 2; var b = \"{}\";", filler, filler);
+        assert!(source.len() > 10_000);
+        let errors = match vm.interpret(&source) {
+            Err(LoxError::Compile(errors)) => errors,
+            other => panic!("unexpected result {:?}", other),
+        };
+        let snippet = errors[0].snippet.as_deref().unwrap();
+        let lines: Vec<&str> = snippet.lines().collect();
+        // 120 chars of source plus the two '…' markers, after the indentation
+        assert_eq!(lines[0].chars().count(), 4 + 122);
+        assert!(lines[0].starts_with("    …"));
+        assert!(lines[0].ends_with('…'));
+        assert!(lines[0].contains("var = 2;"));
+        // the caret is under the '=' the parser complained about
+        let caret = lines[1].chars().count() - 1;
+        assert_eq!(lines[1].chars().nth(caret), Some('^'));
+        assert_eq!(lines[0].chars().nth(caret), Some('='));
+        assert_eq!(lines[0].chars().nth(caret - 4).unwrap(), 'v');
+
+        // short lines are shown whole
+        let errors = match vm.interpret("print 1;\nvar x = ;") {
+            Err(LoxError::Compile(errors)) => errors,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert_eq!(
+            errors[0].snippet.as_deref(),
+            Some("    var x = ;\n            ^")
+        );
+    }
+
+    #[test]
+    fn long_lexemes_truncated() {
+        let long = "x".repeat(1000);
+        assert_eq!(truncate_lexeme(&long), format!("{}…", "x".repeat(40)));
+        assert_eq!(truncate_lexeme("short"), "short");
+        let snippet = render_snippet(&format!("\"{}\" +;", long), 1004);
+        assert!(snippet.starts_with("    …"));
+        assert!(snippet.lines().next().unwrap().ends_with("\" +;"));
+    }
+
+    #[test]
+    fn path_normalization() {
+        let cases = [
+            ("a/b/../c", "a/c"),
+            ("./a/./b/", "a/b"),
+            ("../a/../../b", "../../b"),
+            ("", "."),
+            ("a/..", "."),
+            ("/a/../../b", "/b"),
+            (r"lib\util.lox", "lib/util.lox"),
+            (r"lib\sub\..\util.lox", "lib/util.lox"),
+            (r"c:\Users\lox\..\main.lox", "C:/Users/main.lox"),
+            (r"C:\..\x", "C:/x"),
+            // drive-relative: relative to the current directory of drive D
+            (r"d:lib\..\..\x", "D:../x"),
+            (r"\\server\share\a\..\b", "//server/share/b"),
+            (r"\\server\share\..", "//server/share/"),
+            (r"\\?\C:\lox\.\main.lox", "C:/lox/main.lox"),
+            (r"\\?\UNC\server\share\x", "//server/share/x"),
+            ("a//b", "a/b"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(paths::normalize(path), expected, "{}", path);
+        }
+        // mixed separators name the same file
+        assert_eq!(
+            paths::normalize(r"C:/lox\lib/../main.lox"),
+            paths::normalize(r"c:\lox\main.lox")
+        );
+    }
+
+    #[test]
+    fn file_keys() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let direct = paths::file_key(&dir.join("tests/fixtures/cli/streams.lox"));
+        let detour = paths::file_key(&dir.join("tests/fixtures/./scanner/../cli/streams.lox"));
+        assert_eq!(direct, detour);
+        assert!(direct.ends_with("tests/fixtures/cli/streams.lox"));
+        // missing files fall back to the normalized path
+        assert_eq!(
+            paths::file_key(Path::new("missing/../also_missing.lox")),
+            "also_missing.lox"
+        );
+    }
+
+    #[test]
+    fn interpret_file_errors() {
+        let mut vm = VM::new();
+        let missing = Path::new("tests/fixtures/does_not_exist.lox");
+        match vm.interpret_file(missing) {
+            Err(error @ LoxError::Io { .. }) => {
+                assert!(error
+                    .to_string()
+                    .starts_with("Could not open file 'tests/fixtures/does_not_exist.lox': "));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // a directory can't be read as a file either
+        assert!(matches!(
+            vm.interpret_file(Path::new("tests/fixtures")),
+            Err(LoxError::Io { .. })
+        ));
+
+        vm.output = Output::capture();
+        let path = Path::new("tests/fixtures/cli/streams.lox");
+        assert_eq!(vm.interpret_file(path).err(), None);
+        assert_eq!(vm.output.out.captured(), "to stdout\n");
+    }
+
+    fn script_code(vm: &VM) -> Vec<OpCode> {
+        vm.functions.last().unwrap().chunk.code().to_vec()
+    }
+
+    #[test]
+    fn constant_if_condition_optimized() {
+        let mut vm = VM::new();
+        vm.compile_options.optimize = true;
+        let res = vm.interpret("var a; if (false) a = 1; else a = 2;");
+        assert_eq!(res.err(), None);
+        assert!(vm.diagnostics.warnings.is_empty());
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(2.0)));
+        let code = script_code(&vm);
+        assert!(!code
+            .iter()
+            .any(|op| matches!(op, OpCode::JumpIfFalse(_) | OpCode::Jump(_) | OpCode::One)));
+
+        let res = vm.interpret("var b; if (1) { var c = 3; b = c; } else b = 4;");
+        assert_eq!(res.err(), None);
+        assert!(values_equal(global(&vm, "b").unwrap(), Value::Number(3.0)));
+        assert!(!script_code(&vm)
+            .iter()
+            .any(|op| matches!(op, OpCode::JumpIfFalse(_) | OpCode::Jump(_))));
+    }
+
+    #[test]
+    fn constant_while_condition_optimized() {
+        let mut vm = VM::new();
+        vm.compile_options.optimize = true;
+        assert_eq!(vm.interpret("var a = 0; while (nil) a = 1;").err(), None);
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(0.0)));
+        assert!(!script_code(&vm)
+            .iter()
+            .any(|op| matches!(op, OpCode::Loop(_))));
+
+        // still an infinite loop, just without testing the condition
+        assert_eq!(
+            vm.interpret("fun f() { while (true) { return 1; } }").err(),
+            None
+        );
+        let body = &vm.functions[vm.functions.len() - 2].chunk.code();
+        assert!(body.iter().any(|op| matches!(op, OpCode::Loop(_))));
+        assert!(!body.iter().any(|op| matches!(op, OpCode::JumpIfFalse(_))));
+    }
+
+    #[test]
+    fn constant_condition_warnings() {
+        let mut vm = VM::new();
+        let res = vm.interpret("var a;\nif (false) a = 1; else a = 2;\nwhile (false) a = 3;");
+        assert_eq!(res.err(), None);
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(2.0)));
+        let warnings: Vec<(usize, &str)> = vm
+            .diagnostics
+            .warnings
+            .iter()
+            .map(|w| (w.line, w.message.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                (2, "Condition is always false."),
+                (3, "Condition is always false.")
+            ]
+        );
+        // not a literal, no warning
+        assert_eq!(vm.interpret("if (a) a = 1;").err(), None);
+        assert!(vm.diagnostics.warnings.is_empty());
+        assert!(script_code(&vm)
+            .iter()
+            .any(|op| matches!(op, OpCode::JumpIfFalse(_))));
+    }
+
+    fn error_lines(vm: &VM) -> Vec<usize> {
+        vm.diagnostics.errors.iter().map(|e| e.line).collect()
+    }
+
+    #[test]
+    fn error_recovery_in_block() {
+        let mut vm = VM::new();
+        let res = vm.interpret("{\n  print 1\n  var a = 2;\n  print a;\n}\nprint 3;");
+        assert!(matches!(res, Err(LoxError::Compile(_))));
+        assert_eq!(error_lines(&vm), vec![3]);
+        assert_eq!(vm.diagnostics.errors[0].message, "Expect ';' after value.");
+    }
+
+    #[test]
+    fn error_recovery_in_function() {
+        let mut vm = VM::new();
+        let res = vm.interpret(
+            "fun a() {\n  var x = 1;\n  x +;\n  return x;\n}\nfun b() { return 2; }\nprint a() + b();",
+        );
+        assert!(matches!(res, Err(LoxError::Compile(_))));
+        assert_eq!(error_lines(&vm), vec![3]);
+
+        // a missing ';' right before '}' doesn't swallow the end of the function
+        let res = vm.interpret("fun a() { print 1 }\nfun b() { print 2; }\nb();");
+        assert!(matches!(res, Err(LoxError::Compile(_))));
+        assert_eq!(error_lines(&vm), vec![1]);
+    }
+
+    #[test]
+    fn error_recovery_two_functions() {
+        let mut vm = VM::new();
+        let res =
+            vm.interpret("fun a() {\n  print 1\n}\nfun b() {\n  var = 2;\n}\nfun c() { print 3; }");
+        assert!(matches!(res, Err(LoxError::Compile(_))));
+        assert_eq!(error_lines(&vm), vec![3, 5]);
+    }
+
+    fn crafted_function(code: &[OpCode], constants: &[Value]) -> Function {
+        let mut function = Function::new();
+        for op in code {
+            function.chunk.write(*op, 1);
+        }
+        for constant in constants {
+            function.chunk.add_constant(*constant);
+        }
+        function.chunk.freeze();
+        function
+    }
+
+    #[test]
+    fn compiled_chunks_are_frozen() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("fun f() { return 1; }").err(), None);
+        assert!(vm.functions.iter().all(|f| f.chunk.is_frozen()));
+        let f = &vm.functions[0].chunk;
+        assert!(f.constant(f.constants().len()).is_none());
+        assert!(f.op(f.len()).is_none());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Chunk is frozen after compilation.")]
+    fn frozen_chunk_rejects_writes() {
+        let mut function = crafted_function(&[OpCode::Nil, OpCode::Return], &[]);
+        function.chunk.add_constant(Value::Nil);
+    }
+
+    // Output of running `function` on a fresh VM
+    fn crafted_output(function: Function) -> String {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert_eq!(vm.run_function(function).err(), None);
+        vm.output.out.captured().to_owned()
+    }
+
+    #[test]
+    fn nops_are_skipped() {
+        use OpCode::*;
+        let plain = [One, Print, True, JumpIfFalse(2), Zero, Print, Nil, Return];
+        let with_nops = [
+            Nop,
+            One,
+            Nop,
+            Print,
+            True,
+            JumpIfFalse(3),
+            Nop,
+            Zero,
+            Print,
+            Nop,
+            Nil,
+            Return,
+        ];
+        let expected = crafted_output(crafted_function(&plain, &[]));
+        assert_eq!(expected, "1\n0\n");
+        assert_eq!(crafted_output(crafted_function(&with_nops, &[])), expected);
+
+        // nopped out instructions keep their slot, so the jump over them is still valid
+        let mut function = Function::new();
+        for op in [True, JumpIfFalse(2), One, Print, Zero, Print, Nil, Return] {
+            function.chunk.write(op, 1);
+        }
+        function.chunk.nop_out(2..4);
+        function.chunk.freeze();
+        assert_eq!(function.chunk.len(), 8);
+        assert!(matches!(function.chunk.op(3), Some(Nop)));
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert_eq!(vm.run_function(function).err(), None);
+        assert_eq!(vm.output.out.captured(), "0\n");
+        let script = Value::Function(vm.functions.len() - 1);
+        assert!(vm.inspect(&script).contains("chunk: 8 instructions"));
+    }
+
+    #[test]
+    fn is_type_peeks() {
+        use OpCode::*;
+        let cases = [
+            (Nil, TypeTag::Nil),
+            (True, TypeTag::Bool),
+            (One, TypeTag::Number),
+            (Constant(0), TypeTag::String),
+            (Constant(1), TypeTag::Function),
+        ];
+        let tags = [
+            TypeTag::Nil,
+            TypeTag::Bool,
+            TypeTag::Number,
+            TypeTag::String,
+            TypeTag::Function,
+        ];
+        for (literal, literal_tag) in cases {
+            for tag in tags {
+                // the operand stays below the result
+                let code = [literal, IsType(tag), Print, Print, Nil, Return];
+                let mut vm = VM::new();
+                let s = Value::string(&mut vm.interner, "s");
+                let function = crafted_function(&code, &[s, Value::Native(0)]);
+                assert_eq!(function.chunk.literal_type(0), Some(literal_tag));
+                vm.output = Output::capture();
+                assert_eq!(vm.run_function(function).err(), None);
+                let output = vm.output.out.captured().to_owned();
+                let first = output.lines().next().unwrap();
+                assert_eq!(first, (tag == literal_tag).to_string(), "{:?}", tag);
+                assert_eq!(output.lines().count(), 2);
+            }
+        }
+        // integers are numbers too, and only literals have a known type
+        let function = crafted_function(&[Constant(0), GetGlobal(1)], &[Value::Int(1)]);
+        assert_eq!(function.chunk.literal_type(0), Some(TypeTag::Number));
+        assert_eq!(function.chunk.literal_type(1), None);
+    }
+
+    #[test]
+    fn is_operator() {
+        let values = ["nil", "true", "1", "\"s\"", "f", "0..2", "P", "P()"];
+        let types = [
+            "Nil", "Bool", "Number", "String", "Function", "Range", "Class", "Instance",
+        ];
+        for optimize in [false, true] {
+            let mut vm = VM::new();
+            vm.compile_options.optimize = optimize;
+            vm.output = Output::capture();
+            assert_eq!(vm.interpret("class P {} fun f() {}").err(), None);
+            for (i, value) in values.iter().enumerate() {
+                assert_eq!(vm.interpret(&format!("var x = {};", value)).err(), None);
+                for (j, ty) in types.iter().enumerate() {
+                    let expected = (i == j).to_string();
+                    // a literal, a variable and an expression of the value
+                    for operand in [
+                        value.to_string(),
+                        "x".to_owned(),
+                        format!("(x = {})", value),
+                    ] {
+                        let expression = format!("{} is {}", operand, ty);
+                        assert_eq!(eval_str(&mut vm, &expression), expected, "{}", expression);
+                    }
+                }
+            }
+            assert_eq!(
+                eval_str(&mut vm, "1 + 2 is Number and !(nil is Bool)"),
+                "true"
+            );
+        }
+
+        // the optimizer checks operands of a known type at compile time
+        let mut vm = VM::new();
+        vm.compile_options.optimize = true;
+        vm.output = Output::capture();
+        assert_eq!(vm.interpret("print 1 is Number;").err(), None);
+        assert!(matches!(
+            script_code(&vm)[..2],
+            [OpCode::True, OpCode::Print]
+        ));
+        assert_eq!(
+            vm.interpret("var s = \"a\"; print \"a\" + \"b\" is Nil;")
+                .err(),
+            None
+        );
+        let code = script_code(&vm);
+        assert!(!code.iter().any(|op| matches!(op, OpCode::IsType(_))));
+        assert!(code
+            .windows(2)
+            .any(|ops| matches!(ops, [OpCode::Pop, OpCode::False])));
+        assert_eq!(vm.interpret("print s is String;").err(), None);
+        assert!(script_code(&vm)
+            .iter()
+            .any(|op| matches!(op, OpCode::IsType(TypeTag::String))));
+        assert_eq!(vm.output.out.captured(), "true\nfalse\ntrue\n");
+
+        for (source, message) in [
+            ("1 is Integer;", "Unknown type 'Integer'."),
+            ("1 is;", "Expect type name after 'is'."),
+            ("1 < 2 is Bool;", "Chained comparisons are not supported"),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn warning_levels() {
+        let mut vm = VM::new();
+        vm.compile_options.lang_level = LangLevel::Classic;
+        let source = "var switch = 1;\nif (false) print 2;";
+        assert_eq!(vm.interpret(source).err(), None);
+        let reported: Vec<_> = vm
+            .diagnostics
+            .all()
+            .iter()
+            .map(|d| (d.severity, d.code, d.line, d.column))
+            .collect();
+        assert_eq!(
+            reported,
+            vec![
+                (Severity::Warning, Some(warnings::RESERVED_WORD), 1, Some(5)),
+                (
+                    Severity::Warning,
+                    Some(warnings::CONSTANT_CONDITION),
+                    2,
+                    Some(10)
+                ),
+            ]
+        );
+        assert_eq!(
+            vm.diagnostics.warnings[1].to_string(),
+            "[line 2] Warning W0001: Condition is always false."
+        );
+
+        // disabled
+        assert!(vm.warning_levels.set_from_str("W0002=off"));
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.diagnostics.warnings.len(), 1);
+        assert_eq!(vm.diagnostics.warnings[0].code, Some("W0001"));
+
+        // denied: the script doesn't run
+        assert!(vm.warning_levels.set_from_str("W0001=error"));
+        assert_eq!(vm.interpret("var a = 1;").err(), None);
+        match vm.interpret("a = 2;\nif (false) a = 3;") {
+            Err(LoxError::Compile(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].severity, Severity::Error);
+                assert_eq!(
+                    errors[0].to_string(),
+                    "[line 2] Error W0001: Condition is always false."
+                );
+            }
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+        assert!(values_equal(global(&vm, "a").unwrap(), Value::Number(1.0)));
+
+        // explicit levels win over --deny-warnings
+        vm.warning_levels.deny_all(true);
+        assert!(vm.warning_levels.set_from_str("W0001=warn"));
+        assert_eq!(vm.interpret(source).err(), None);
+        assert!(vm.warning_levels.set_from_str("W0002=error"));
+        assert!(vm.interpret(source).is_err());
+
+        assert!(!vm.warning_levels.set_from_str("W9999=off"));
+        assert!(!vm.warning_levels.set_from_str("W0001=loud"));
+        assert!(!vm.warning_levels.set_from_str("W0001"));
+    }
+
+    #[test]
+    fn repl_session_positions() {
+        let mut vm = VM::new();
+        vm.enable_source_map();
+        vm.output = Output::capture();
+        repl::eval(&mut vm, "fun check(x) {\n  return -x;\n}\n");
+        repl::eval(&mut vm, "var a = 1;\n");
+        repl::eval(&mut vm, "print a;\ncheck(\"one\");\n");
+        assert_eq!(vm.source_map().map(|map| map.len()), Some(3));
+
+        let error = vm.last_error.clone().unwrap();
+        assert_eq!(error.trace.len(), 2);
+        let (inner, outer) = (&error.trace[0], &error.trace[1]);
+        assert_eq!(inner.line, Some(2));
+        assert_eq!(
+            inner.position,
+            Some(SessionPosition {
+                submission: 1,
+                session_line: 2,
+                text: "  return -x;".to_owned()
+            })
+        );
+        assert_eq!(outer.line, Some(2));
+        assert_eq!(outer.position.as_ref().map(|p| p.session_line), Some(6));
+        assert_eq!(
+            error.to_string(),
+            "Operand to '-' must be a number, got string (\"one\").\n\
+             [file <repl>, line 2] in check() (submission 1, session line 2)\n      return -x;\n\
+             [file <repl>, line 2] in <script> (submission 3, session line 6)\n    check(\"one\");"
+        );
+
+        // without the source map, traces only have the line in the submission
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        repl::eval(&mut vm, "var n; fun f() {\n  return -n;\n}\nf();");
+        let error = vm.last_error.clone().unwrap();
+        assert_eq!(error.trace[0].line, Some(2));
+        assert!(error.trace[0].position.is_none());
+    }
+
+    #[test]
+    fn function_redefinition() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        repl::eval(&mut vm, "fun f() { return 1; }");
+        repl::eval(&mut vm, "fun g() { return f(); }\nvar h = f;");
+        repl::eval(&mut vm, "print g(); print h();");
+        // recursion in the new body calls the new body
+        repl::eval(
+            &mut vm,
+            "fun f(n) { if (n < 1) return 0; return 10 + f(n - 1); }",
+        );
+        let warnings: Vec<String> = vm
+            .diagnostics
+            .warnings
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec!["[line 1] Warning W0003: Redefining 'f' changes its arity from 0 to 1."]
+        );
+        repl::eval(&mut vm, "print h(2); print f == h;");
+        assert_eq!(vm.output.out.captured(), "1\n1\n20\ntrue\n");
+        assert_eq!(vm.last_error, None);
+
+        // a redefinition with another arity can be an error, the old function stays
+        assert!(vm.warning_levels.set_from_str("W0003=error"));
+        let res = vm.interpret("fun f() { return 3; }");
+        match res {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Redefining 'f' changes its arity from 1 to 0."
+            ),
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+        assert_eq!(vm.interpret("print h(1);").err(), None);
+        assert_eq!(vm.output.out.captured(), "1\n1\n20\ntrue\n10\n");
+
+        // other values are just replaced
+        assert_eq!(
+            vm.interpret("var h = 1; fun h() { return 2; } print h();")
+                .err(),
+            None
+        );
+        assert_eq!(
+            vm.interpret("fun k() {} var k = f; print k(0);").err(),
+            None
+        );
+        assert!(vm.output.out.captured().ends_with("2\n0\n"));
+
+        // compiled scripts can run again, each run defines its own body
+        let mut vm = VM::new();
+        let a = vm
+            .compile_source("fun f() { return \"A\"; }", None, false)
+            .unwrap();
+        let b = vm
+            .compile_source("fun f() { return \"B\"; }", None, false)
+            .unwrap();
+        for (script, expected) in [(a, "A"), (b, "B"), (b, "B"), (a, "A"), (a, "A")] {
+            vm.run_compiled(script).unwrap();
+            assert_eq!(eval_str(&mut vm, "f()"), expected);
+        }
+    }
+
+    // `run(source)` runs a script in the VM that calls it
+    fn run_native(vm: &mut VM, args: &[Value]) -> NativeResult {
+        let source = format_value(&args[0], &vm.interner);
+        vm.interpret(&source)?;
+        Ok(Value::Nil)
+    }
+
+    #[test]
+    fn script_run_by_a_native() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.define_native("run", 1, run_native);
+        // the caller's locals and the rest of its code are left alone
+        let source = "fun f(a) { var b = a + 1; run(\"var c = 3; print c;\"); return a + b; }\n\
+                      print f(1); print c;";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), "3\n3\n3\n");
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        // an error in the script is an error of the native's caller
+        let error = vm
+            .interpret("fun g() { run(\"nil();\"); } g();")
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Can only call functions"),
+            "{}",
+            error
+        );
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+    }
+
+    #[test]
+    fn crash_report() {
+        use OpCode::*;
+        let mut vm = VM::new();
+        vm.enable_crash_reports();
+        vm.output = Output::capture();
+        assert_eq!(vm.interpret("fun f(a) { return a; }").err(), None);
+        // reads a local far past the end of the stack, which the VM doesn't check
+        let function = crafted_function(
+            &[One, Constant(0), GetLocal(200), Return],
+            &[Value::Number(7.5)],
+        );
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crash_report::run_reporting_crashes(&mut vm, |vm| vm.run_function(function))
+        }));
+        assert!(res.is_err());
+
+        let report = vm.crash_report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "== VM state ==");
+        assert_eq!(lines[1], "function: <script>");
+        assert_eq!(lines[2], "ip: 2");
+        assert_eq!(lines[3], "instruction: 2 1 OP_GET_LOCAL 200");
+        assert!(lines.contains(&"  [2] number: 7.5"));
+        assert!(lines.contains(&"  [1] number: 1"));
+        assert!(lines.contains(&"  <script> at ip 2, line 1, slots from 0"));
+        let history = lines.iter().position(|l| l.starts_with("last ")).unwrap();
+        assert_eq!(lines[history], "last 7 instructions (oldest first):");
+        assert_eq!(lines.last(), Some(&"  <script>: 2 OP_GET_LOCAL 200"));
+    }
+
+    #[test]
+    fn op_history_ring_buffer() {
+        let mut history = OpHistory::new();
+        assert!(history.is_empty());
+        for ip in 0..crash_report::HISTORY_LEN + 5 {
+            history.record(0, ip, OpCode::Nop);
+        }
+        assert_eq!(history.len(), crash_report::HISTORY_LEN);
+
+        let mut vm = VM::new();
+        vm.enable_crash_reports();
+        vm.output = Output::capture();
+        assert_eq!(
+            vm.interpret("var i = 0;\nwhile (i < 20) i = i + 1;").err(),
+            None
+        );
+        let report = vm.crash_report();
+        let recorded: Vec<&str> = report
+            .lines()
+            .skip_while(|l| !l.starts_with("last "))
+            .collect();
+        assert_eq!(recorded[0], "last 32 instructions (oldest first):");
+        assert_eq!(recorded.len(), 33);
+        // the most recent ones are the end of the script
+        assert!(recorded[32].ends_with("OP_RETURN"));
+        assert!(recorded[31].ends_with("OP_NIL"));
+    }
+
+    fn run_output(source: &str) -> Result<String, LoxError> {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret(source)?;
+        Ok(vm.output.out.captured().to_owned())
+    }
+
+    #[test]
+    fn closures() {
+        let source = "fun outer(){ var x = 1; fun inner(){ print x; } inner(); } outer();";
+        assert_eq!(run_output(source).unwrap(), "1\n");
+
+        // closures assign to the captured variable itself, not to a copy,
+        // also through a function in between that doesn't use it
+        let source = "\
+            fun counter() {\n\
+              var n = 0;\n\
+              fun inc() { n = n + 1; return n; }\n\
+              fun twice() { fun again() { inc(); } again(); return inc(); }\n\
+              print inc();\n\
+              print twice();\n\
+              n = n * 10;\n\
+              print inc();\n\
+            }\n\
+            counter();\n\
+            { var block = \"top\"; fun show() { print block; } show(); }\n";
+        assert_eq!(run_output(source).unwrap(), "1\n3\n31\ntop\n");
+
+        // the innermost declaration is captured
+        let source = "\
+            fun f() {\n\
+              var x = \"outer\";\n\
+              { var x = \"inner\"; fun g() { return x; } print g(); }\n\
+              fun h() { return x; }\n\
+              print h();\n\
+            }\n\
+            f();";
+        assert_eq!(run_output(source).unwrap(), "inner\nouter\n");
+
+        // an enclosing local shadows a namespace, like a local of the function itself
+        let source = "fun f() { var math = 2; fun g() { return math * 3; } print g(); } f();";
+        assert_eq!(run_output(source).unwrap(), "6\n");
+
+        // functions that capture nothing stay plain functions
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let text = vm
+            .disassemble_source(
+                "fun f() { var a = 1; fun g() { return a; } fun h() { return 2; } }",
+                None,
+            )
+            .unwrap();
+        assert!(text.contains("OP_CLOSURE 0 'Function id:"), "{}", text);
+        assert!(text.contains("OP_CONSTANT 1 'Function id:"), "{}", text);
+        assert!(text.contains("== g/0 [local 1] =="), "{}", text);
+        assert!(text.contains("== h/0 ==\n"), "{}", text);
+        assert!(text.contains("OP_GET_UPVALUE 0"), "{}", text);
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret("fun f() { var a = 1; fun g() { return a; } return g; }")
+            .unwrap();
+        let g = vm.evaluate("f()").unwrap();
+        assert!(matches!(g, Value::Closure(_)));
+        assert_eq!(format_value(&g, &vm.interner), "<fn>");
+        assert!(vm.inspect(&g).ends_with("  upvalues: 1\n"));
+        assert_eq!(vm.live_object_census()["closures"], 1);
+    }
+
+    #[test]
+    fn closed_upvalues() {
+        let source = "fun makeCounter(){ var i=0; fun inc(){ i = i + 1; print i; } return inc; } \
+                      var c = makeCounter(); c(); c();";
+        assert_eq!(run_output(source).unwrap(), "1\n2\n");
+
+        // every call has its own variable, shared by the closures created by that call
+        let source = "\
+            var get; var set;\n\
+            fun pair(value) {\n\
+              fun getter() { return value; }\n\
+              fun setter(v) { value = v; }\n\
+              get = getter; set = setter;\n\
+            }\n\
+            pair(1); var get1 = get; var set1 = set;\n\
+            pair(2);\n\
+            set1(10);\n\
+            print get1(); print get();\n";
+        assert_eq!(run_output(source).unwrap(), "10\n2\n");
+
+        // a variable declared in a loop body is a new one in every iteration,
+        // closed over when the body's scope ends
+        let source = "\
+            var first; var second;\n\
+            for (var i = 0; i < 2; i = i + 1) {\n\
+              var j = i * 10;\n\
+              var after = \"popped\";\n\
+              fun show() { print j; }\n\
+              if (i == 0) first = show; else second = show;\n\
+            }\n\
+            first(); second();\n";
+        assert_eq!(run_output(source).unwrap(), "0\n10\n");
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let text = vm
+            .disassemble_source("{ var a = 1; var b = 2; fun f() { return a; } }", None)
+            .unwrap();
+        // `f` and `b` are popped, then `a` is closed over
+        assert!(
+            text.contains("   | OP_POP_N 2\n   | OP_CLOSE_UPVALUE\n"),
+            "{}",
+            text
+        );
+
+        // a closure outlives the run that failed while its variable was on the stack
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let error = vm.interpret(
+            "var get; fun f() { var x = \"kept\"; fun g() { return x; } get = g; nil(); } f();",
+        );
+        assert!(error.is_err());
+        assert_eq!(
+            format_value(&vm.evaluate("get()").unwrap(), &vm.interner),
+            "kept"
+        );
+    }
+
+    #[test]
+    fn classes() {
+        let source = "class Pair {} var p = Pair(); print p;";
+        assert_eq!(run_output(source).unwrap(), "Pair instance\n");
+
+        // every call creates a new instance; classes are local or global like functions
+        let source = "\
+            class Pair {}\n\
+            var a = Pair(); var b = Pair();\n\
+            print Pair; print a == a; print a == b; print Pair == Pair;\n\
+            fun make() { class Local {} return Local(); }\n\
+            print make(); print make() == make();\n\
+            printErr(a);\n";
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret(source).unwrap();
+        assert_eq!(
+            vm.output.out.captured(),
+            "Pair\ntrue\nfalse\ntrue\nLocal instance\nfalse\n"
+        );
+        assert_eq!(vm.output.err.captured(), "Pair instance\n");
+        let census = vm.live_object_census();
+        // a class declaration creates a new class every time it runs
+        assert_eq!((census["classes"], census["instances"]), (4, 5));
+        let a = vm.get_global("a").unwrap();
+        assert_eq!(type_name(&a), "instance");
+        assert_eq!(type_name(&vm.get_global("Pair").unwrap()), "class");
+        assert!(matches!(a, Value::Instance(idx) if vm.instances[idx].fields.is_empty()));
+        let pair = vm.get_global("Pair").unwrap();
+        let instance = vm.call_function(pair, &[]).unwrap();
+        assert_eq!(vm.format_value(&instance), "Pair instance");
+
+        let error = run_output("class Pair {} Pair(1, 2);").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Expected 0 arguments but got 2."));
+        let error = run_output("class {}").unwrap_err();
+        assert!(
+            error.to_string().contains("Expect class name."),
+            "{}",
+            error
+        );
+        let error = run_output("class A { var x; }").unwrap_err();
+        assert!(
+            error.to_string().contains("Expect method name."),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn properties() {
+        let source = "class Point {} var p = Point();\n\
+                      p.x = 1; p.y = 2; print p.x + p.y;\n\
+                      p.x = p.y = 3; print p.x; print p.y;\n\
+                      print p.x = 4; p.x = p.x + 1; print p.x;\n\
+                      var q = Point(); q.next = p; q.next.x = 10; print p.x;\n\
+                      fun point() { return p; } point().y = 20; print p.y;\n\
+                      p.f = clock; print p.f() > 0;";
+        assert_eq!(run_output(source).unwrap(), "3\n3\n3\n4\n5\n10\n20\ntrue\n");
+
+        // the name is a constant, like the name of a global variable
+        let mut vm = VM::new();
+        let f_idx = vm.compile_source("a.b = a.c;", None, false).unwrap();
+        let chunk = &vm.functions[f_idx].chunk;
+        let names: Vec<String> = chunk
+            .code()
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, OpCode::GetProperty(_) | OpCode::SetProperty(_)))
+            .map(|(offset, op)| debug::instruction(chunk, offset, op, &vm.interner))
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("OP_GET_PROPERTY") && names[0].ends_with(": c'"));
+        assert!(names[1].starts_with("OP_SET_PROPERTY") && names[1].ends_with(": b'"));
+
+        for (source, message) in [
+            ("class A {} A().missing;", "Undefined property 'missing'."),
+            (
+                "var n = 1; n.x = 2;",
+                "Only instances have fields, got number (1).",
+            ),
+            (
+                "\"s\".x = 2;",
+                "Only instances have fields, got string (\"s\").",
+            ),
+            ("nil.x = 2;", "Only instances have fields, got nil."),
+            (
+                "print (1..3).start;",
+                "Only instances have properties, got range (1..3).",
+            ),
+            (
+                "class A {} A.x = 1;",
+                "Only instances have fields, got class (A).",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().starts_with(message), "{}", error);
+        }
+        for (source, message) in [
+            ("var a; a.;", "Expect property name after '.'."),
+            (
+                "var a; a.b + 1 = 2;",
+                "Cannot assign to the result of an expression.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn assignment_targets() {
+        let source = "class P {} var p = P(); var a; var b;\n\
+                      { var l; fun f() { l = a = p.x = 1; } f(); print l; }\n\
+                      b = p.y = a + 1; print b; print p.y; print a;";
+        assert_eq!(run_output(source).unwrap(), "1\n2\n2\n1\n");
+
+        // the error points at the `=`, and no code is run
+        for (target, message) in [
+            ("f()", "Cannot assign to a function call."),
+            ("p.m()", "Cannot assign to a function call."),
+            (
+                "r[0]",
+                "Cannot assign to an indexed element, ranges are immutable.",
+            ),
+            ("(a)", "Cannot assign to a parenthesized expression."),
+            ("(p.x)", "Cannot assign to a parenthesized expression."),
+            ("a + b", "Cannot assign to the result of an expression."),
+            ("r[0] + 1", "Cannot assign to the result of an expression."),
+            ("-a", "Cannot assign to the result of an expression."),
+            ("!a", "Cannot assign to the result of an expression."),
+            ("a == b", "Cannot assign to the result of an expression."),
+            ("a < b", "Cannot assign to the result of an expression."),
+            ("a or b", "Cannot assign to the result of an expression."),
+            ("1", "Cannot assign to the result of an expression."),
+            ("nil", "Cannot assign to the result of an expression."),
+        ] {
+            let source = format!(
+                "print 0; fun f() {{}} class P {{ m() {{}} }} var p = P(); var r = 0..3;\n\
+                 var a = 1; var b = 2; {} = 3;",
+                target
+            );
+            let mut vm = VM::new();
+            vm.output = Output::capture();
+            match vm.interpret(&source) {
+                Err(LoxError::Compile(errors)) => {
+                    assert_eq!(errors.len(), 1, "{}: {:?}", target, errors);
+                    assert_eq!(errors[0].message, message, "{}", target);
+                    // line 2 starts with 22 characters before the target
+                    assert_eq!(errors[0].column, Some(24 + target.len()), "{}", target);
+                }
+                other => panic!("unexpected result {:?} for {}", other, target),
+            }
+            assert_eq!(vm.output.out.captured(), "");
+        }
+    }
+
+    #[test]
+    fn methods_and_initializers() {
+        let source = "class Point { init(x, y) { this.x = x; this.y = y; } } print Point(1,2).x;";
+        assert_eq!(run_output(source).unwrap(), "1\n");
+
+        let source = "\
+            class Counter {\n\
+              init(start) { this.n = start; if (start > 5) return; this.n = this.n * 10; }\n\
+              inc() { this.n = this.n + 1; return this; }\n\
+              adder() { fun add(k) { this.n = this.n + k; } return add; }\n\
+            }\n\
+            var c = Counter(1); print c.n; print Counter(9).n;\n\
+            print c.inc().inc().n;\n\
+            var inc = c.inc; inc(); print c.n;\n\
+            c.adder()(100); print c.n;\n\
+            print c.init(2) == c; print c.n;\n\
+            c.inc = \"field\"; print c.inc;\n\
+            print inc; print c.adder == c.adder;\n\
+            print arity(Counter); print arity(c.adder); print name(c.adder);";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "10\n9\n12\n13\n113\ntrue\n20\nfield\n<fn>\nfalse\n1\n0\nCounter.adder\n"
+        );
+
+        // methods are named after their class in stack traces
+        let error = run_output("class A {\n  m() { return -this.n; }\n  n = nil;\n}\nA().m();")
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("[line 2] in A.m()\n[line 5] in <script>"),
+            "{}",
+            error
+        );
+
+        // the host calls classes and bound methods like functions
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret("class Pair { init(a, b) { this.a = a; this.b = b; } sum() { return this.a + this.b; } }")
+            .unwrap();
+        let pair = vm.get_global("Pair").unwrap();
+        let instance = vm
+            .call_function(pair, &[Value::Number(1.0), Value::Number(2.0)])
+            .unwrap();
+        assert_eq!(vm.format_value(&instance), "Pair instance");
+        assert!(values_equal(
+            vm.get_field(instance, "b").unwrap(),
+            Value::Number(2.0)
+        ));
+        assert!(vm.get_field(instance, "c").is_none() && vm.get_field(pair, "a").is_none());
+        vm.set_global("p", instance);
+        let sum = vm.evaluate("p.sum").unwrap();
+        assert_eq!(type_name(&sum), "function");
+        assert!(values_equal(
+            vm.call_function(sum, &[]).unwrap(),
+            Value::Number(3.0)
+        ));
+        assert!(vm.call_function(pair, &[]).is_err());
+
+        for (source, message) in [
+            (
+                "class A { init(a) {} } A();",
+                "Expected 1 arguments but got 0.",
+            ),
+            (
+                "class A { m() {} } A().m(1);",
+                "Expected 0 arguments but got 1.",
+            ),
+            ("class A { m() {} } A().n();", "Undefined property 'n'."),
+            (
+                "class A {} A.m();",
+                "Only instances have properties, got class (A).",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().starts_with(message), "{}", error);
+        }
+        for (source, message) in [
+            (
+                "class A { init() { return 1; } }",
+                "Can't return a value from an initializer.",
+            ),
+            ("print this;", "Can't use 'this' outside of a class."),
+            (
+                "fun f() { return this; }",
+                "Can't use 'this' outside of a class.",
+            ),
+            (
+                "class A { m() { this = 1; } }",
+                "Invalid assignment target.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn field_declarations() {
+        let source = "class Point { x = 0; y = 0; init() {} } print Point().x;";
+        assert_eq!(run_output(source).unwrap(), "0\n");
+
+        // defaults are computed for each instance, in order and before `init`
+        let source = "\
+            var made = 0;\n\
+            class Box {\n\
+              id = made = made + 1;\n\
+              size = this.id * 10;\n\
+              init(extra) { this.size = this.size + extra; }\n\
+            }\n\
+            var a = Box(1); var b = Box(2);\n\
+            print a.id; print b.id; print b.size;\n\
+            class Bare { label = \"bare\"; }\n\
+            print Bare().label;";
+        assert_eq!(run_output(source).unwrap(), "1\n2\n22\nbare\n");
+
+        // subclasses get the fields of their superclass first
+        let source = "\
+            class A { a = 1; }\n\
+            class B < A { b = this.a + 1; }\n\
+            var b = B(); print b.a; print b.b;";
+        assert_eq!(run_output(source).unwrap(), "1\n2\n");
+
+        for (source, message) in [
+            (
+                "class A { x = 1; x() {} }",
+                "A field can't have the same name as a method.",
+            ),
+            (
+                "class A { x() {} x = 1; }",
+                "A field can't have the same name as a method.",
+            ),
+            ("class A { x = 1 }", "Expect ';' after field value."),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+        // errors in a default value have a stack trace through the call of the class
+        let error = run_output("var n; class A {\n  x = n + 1;\n}\nA();").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .ends_with("[line 2] in A.x()\n[line 4] in <script>"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn inheritance() {
+        let source = "\
+            class A {\n\
+              init(n) { this.n = n; }\n\
+              method() { return \"A method of \" + this.who(); }\n\
+              who() { return \"A\"; }\n\
+              inherited() { return \"inherited\"; }\n\
+            }\n\
+            class B < A {\n\
+              init(n) { super.init(n * 2); }\n\
+              method() { return \"B, \" + super.method(); }\n\
+              who() { return \"B\"; }\n\
+            }\n\
+            class C < B {\n\
+              method() { var m = super.method; return \"C, \" + m(); }\n\
+              later() { fun f() { return super.who(); } return f; }\n\
+            }\n\
+            print B(1).n; print B(1).method(); print C(3).method(); print C(3).n;\n\
+            print C(1).inherited(); print C(1).later()();\n\
+            { class Local < A {} print Local(5).who(); print Local(5).n; }";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "2\nB, A method of B\nC, B, A method of B\n6\ninherited\nB\nA\n5\n"
+        );
+
+        // a subclass with an empty body has the methods of its superclass
+        let source = "class A { m() { return 1; } } class B < A {} print B().m();";
+        assert_eq!(run_output(source).unwrap(), "1\n");
+
+        for (source, message) in [
+            (
+                "var x = 3; class A < x {}",
+                "Superclass must be a class, got number (3).",
+            ),
+            (
+                "fun f() {} class A < f {}",
+                "Superclass must be a class, got function (<fn>).",
+            ),
+            (
+                "class A {} class B < A { m() { return super.missing(); } } B().m();",
+                "Undefined property 'missing'.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().starts_with(message), "{}", error);
+            assert!(error.to_string().contains("in <script>"), "{}", error);
+        }
+        for (source, message) in [
+            ("print super.m;", "Can't use 'super' outside of a class."),
+            (
+                "fun f() { super.m(); }",
+                "Can't use 'super' outside of a class.",
+            ),
+            (
+                "class A { m() { super.m(); } }",
+                "Can't use 'super' in a class with no superclass.",
+            ),
+            ("class A < A {}", "A class can't inherit from itself."),
+            ("class A < 3 {}", "Expect superclass name."),
+            (
+                "class A {} class B < A { m() { super; } }",
+                "Expect '.' after 'super'.",
+            ),
+            (
+                "class A {} class B < A { m() { super.1; } }",
+                "Expect superclass method name.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn implicit_return_is_nil() {
+        let source =
+            "fun empty() {} fun bare() { return; } fun statements() { 1 + 2; var a = 3; }\n\
+                      print empty(); print bare(); print statements();\n\
+                      class A { m() {} } print A().m();\n\
+                      fun outer() { fun inner() {} return inner(); } print outer() == nil;";
+        assert_eq!(run_output(source).unwrap(), "nil\nnil\nnil\nnil\ntrue\n");
+
+        // functions and scripts end with an explicit nil, and runs leave the stack empty
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let f_idx = vm
+            .compile_source(
+                "fun empty() {} fun bare() { return; } empty();",
+                None,
+                false,
+            )
+            .unwrap();
+        let script = vm.functions[f_idx].chunk.code();
+        assert!(matches!(
+            script[script.len() - 2..],
+            [OpCode::Nil, OpCode::Return]
+        ));
+        vm.run_compiled(f_idx).unwrap();
+        assert!(vm.stack.is_empty());
+        for name in ["empty", "bare"] {
+            let f = vm.get_global(name).unwrap();
+            let code = match f {
+                Value::Function(f_idx) => vm.functions[f_idx].chunk.code(),
+                other => panic!("unexpected value {:?}", other),
+            };
+            assert!(
+                matches!(code, [OpCode::Nil, OpCode::Return, ..]),
+                "{:?}",
+                code
+            );
+            assert!(matches!(vm.call_function(f, &[]).unwrap(), Value::Nil));
+        }
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn call_expressions() {
+        let source = "\
+            fun add(a, b) { return a + b; }\n\
+            fun getFn() { return curry; }\n\
+            fun curry(a) { return inc; }\n\
+            fun inc(x) { return x + 1; }\n\
+            print add(1, 2);\n\
+            print getFn()(1)(2);\n\
+            print add(add(1, 2), add(3, 4));\n";
+        assert_eq!(run_output(source).unwrap(), "3\n3\n10\n");
+
+        let error = run_output("var x = 1; x(2);").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Can only call functions and classes.\n[line 1] in <script>"
+        );
+        // `getFn()(1)` returns `inc`, which takes one argument
+        let error = run_output(&format!(
+            "{}getFn()(1)();",
+            &source[..source.find("print").unwrap()]
+        ))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Expected 1 arguments but got 0."));
+
+        let args = |n: usize| vec!["nil"; n].join(", ");
+        let error = run_output(&format!("fun f() {{}} f({});", args(256))).unwrap_err();
+        assert!(
+            matches!(&error, LoxError::Compile(errors) if errors.len() == 1
+                && errors[0].message.contains("Cannot have more than 255 arguments.")),
+            "{:?}",
+            error
+        );
+        let error = run_output(&format!("fun f() {{}} f({});", args(255))).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Expected 0 arguments but got 255."));
+    }
+
+    #[test]
+    fn global_slot_cache() {
+        // the cached slot sees every assignment, on the very next call
+        let source = "\
+            fun a() { return \"a\"; }\n\
+            fun b() { return \"b\"; }\n\
+            var f = a;\n\
+            for (var i = 0; i < 4; i = i + 1) {\n\
+            \x20 print f();\n\
+            \x20 if (i == 1) f = b;\n\
+            }\n\
+            fun a() { return \"new a\"; }\n\
+            f = a;\n\
+            for (var i = 0; i < 2; i = i + 1) print f();\n";
+        assert_eq!(run_output(source).unwrap(), "a\na\nb\nb\nnew a\nnew a\n");
+
+        // resetting renumbers the slots, code that ran before must not read the old ones
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.interpret("var x = \"x\"; var y = \"y\";").unwrap();
+        let read_y = vm.compile_source("print y;", None, false).unwrap();
+        vm.run_compiled(read_y).unwrap();
+        vm.reset_user_state();
+        vm.interpret("var y = \"new y\"; var z = \"z\";").unwrap();
+        vm.run_compiled(read_y).unwrap();
+        vm.reset_user_state();
+        let error = vm.run_compiled(read_y).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Undefined variable 'y'.\n[line 1] in <script>"
+        );
+        assert_eq!(vm.output.out.captured(), "y\nnew y\n");
+    }
+
+    #[test]
+    fn ranges() {
+        let source = "var r = 0..n;\nprint r; print len(r); print r[0]; print r[4];";
+        let out = run_output(&format!("var n = 5;\n{}", source)).unwrap();
+        assert_eq!(out, "0..5\n5\n0\n4\n");
+        // `..` binds looser than arithmetic, tighter than comparisons
+        assert_eq!(run_output("print 1 + 1..2 * 3;").unwrap(), "2..6\n");
+        assert_eq!(run_output("print 1..3 == 1..3;").unwrap(), "true\n");
+        assert_eq!(run_output("print 1..3 == 1..4;").unwrap(), "false\n");
+
+        // half-open: the end isn't in the range
+        let membership = "var r = 2..5;\nprint 1 in r; print 2 in r; print 4 in r; print 5 in r;\n\
+                          print 2.5 in r; print \"a\" in r;";
+        assert_eq!(
+            run_output(membership).unwrap(),
+            "false\ntrue\ntrue\nfalse\nfalse\nfalse\n"
+        );
+
+        // empty and reversed ranges have no elements
+        let empty = "print len(3..3); print len(5..0); print 0 in 5..0; print 5..0;\n\
+                     for (var x in 5..0) print x;";
+        assert_eq!(run_output(empty).unwrap(), "0\n0\nfalse\n5..0\n");
+
+        // ranges are values: passed to functions and iterated there
+        let sum = "fun sum(range) { var total = 0; for (var x in range) total = total + x; return total; }\n\
+                   print sum(1..5); print sum(-2..1);";
+        assert_eq!(run_output(sum).unwrap(), "10\n-3\n");
+        let nested = "for (var i in 0..3) for (var j in i..3) if (j == 2) print i;";
+        assert_eq!(run_output(nested).unwrap(), "0\n1\n2\n");
+        assert_eq!(run_output("print len(\"héllo\");").unwrap(), "5\n");
+
+        let runtime_error = |source: &str| match run_output(source) {
+            Err(LoxError::Runtime(error)) => error.message,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(
+            runtime_error("print (0..3)[3];"),
+            "Range index 3 is out of bounds for length 3."
+        );
+        assert_eq!(
+            runtime_error("print 0..1.5;"),
+            "Range bound must be an integer, got 1.5."
+        );
+        assert_eq!(
+            runtime_error("print 0..3000000000;"),
+            "Range bound 3000000000 is out of range."
+        );
+        assert_eq!(
+            runtime_error("print 1 in 3;"),
+            "Right operand of 'in' must be a range, got number (3)."
+        );
+        assert_eq!(
+            runtime_error("for (var x in nil) print x;"),
+            "Can only iterate over a range, got nil."
+        );
+        assert_eq!(
+            runtime_error("print len(nil);"),
+            "Can't take the length of a nil."
+        );
+        assert_eq!(
+            runtime_error("var s = \"ab\"; print s[0];"),
+            "Can only index ranges, got string (\"ab\")."
+        );
+
+        // the loop variable can't be used in the range expression
+        let mut vm = VM::new();
+        assert!(vm.interpret("{ for (var x in 0..x) print x; }").is_err());
+        assert_eq!(
+            vm.diagnostics.errors[0].message,
+            "Cannot read local variable in its own initializer."
+        );
+    }
+
+    #[test]
+    fn copy_and_freeze() {
+        let source = "var s = \"text\"; var r = 1..3; fun f() {}\n\
+                      print copy(s) == s; print clone(r) == r; print freeze(f) == f;\n\
+                      print copy(clock) == clock; print freeze(nil); print copy(1.5);";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\ntrue\ntrue\ntrue\nnil\n1.5\n"
+        );
+
+        // instances: `clone` shares the values of the fields, `copy` copies them too
+        let source = "class P {} var inner = P(); inner.n = 1;\n\
+                      var a = P(); a.inner = inner; a.self = a;\n\
+                      var c = clone(a); var d = copy(a);\n\
+                      print c == a; print c.inner == inner; print c.self == a;\n\
+                      print d == a; print d.inner == inner; print d.inner.n; print d.self == d;\n\
+                      c.inner = nil; d.inner.n = 2; print a.inner == inner; print inner.n;";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "false\ntrue\ntrue\nfalse\nfalse\n1\ntrue\ntrue\n1\n"
+        );
+
+        // a frozen instance can't be changed, its copies can
+        let source = "class P { init() { this.x = 1; } } var p = freeze(P());\n\
+                      var c = clone(p); c.x = 2; print c.x; var d = copy(p); d.x = 3; print d.x;\n\
+                      p.x = 4;";
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        match vm.interpret(source) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(
+                    error.message,
+                    "Cannot set field 'x' of a frozen P instance."
+                )
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(vm.output.out.captured(), "2\n3\n");
+        assert_eq!(eval_str(&mut vm, "p.x"), "1");
+    }
+
+    #[test]
+    fn deep_equals_and_compare() {
+        let source = "fun f() {} var nan = 0 / 0;\n\
+                      print deepEquals(1..3, 1..3); print deepEquals(1..3, 1..4);\n\
+                      print deepEquals(\"a\" + \"b\", \"ab\"); print deepEquals(nil, false);\n\
+                      print deepEquals(nan, nan); print nan == nan; print deepEquals(nan, 1);\n\
+                      print deepEquals(f, f); print deepEquals(f, clock); print deepEquals(1, \"1\");";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\nfalse\ntrue\nfalse\ntrue\nfalse\nfalse\ntrue\nfalse\nfalse\n"
+        );
+
+        // instances compare by their fields, also nested and cyclic ones
+        let source = "class A { init() { this.a = 1; } } class B { init() { this.a = 1; } }\n\
+                      var a = A(); print deepEquals(a, copy(a)); print a == copy(a);\n\
+                      print deepEquals(a, B()); var b = A(); b.extra = nil; print deepEquals(a, b);\n\
+                      a.inner = A(); b = copy(a); print deepEquals(a, b);\n\
+                      b.inner.a = 2; print deepEquals(a, b);\n\
+                      a.self = a; b = copy(a); print deepEquals(a, b);\n\
+                      b.self = a; print deepEquals(a, b);\n\
+                      var n = A(); n.a = 0 / 0; print deepEquals(n, copy(n));\n\
+                      print deepEquals(n, 0 / 0); print deepEquals(A, A);";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\nfalse\nfalse\nfalse\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\ntrue\n"
+        );
+
+        let source = "print compare(1, 2); print compare(2, 1); print compare(0.1 + 0.2, 0.3);\n\
+                      print compare(\"apple\", \"banana\"); print compare(\"b\", \"a\");\n\
+                      print compare(\"\", \"\"); print compare(\"Z\", \"a\");";
+        assert_eq!(run_output(source).unwrap(), "-1\n1\n0\n-1\n1\n0\n-1\n");
+
+        let error = run_output("compare(1, \"1\");").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("compare() expects two numbers or two strings, got number and string."));
+        let error = run_output("compare(nil, nil);").unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("compare() expects two numbers or two strings, got nil and nil."));
+        let error = run_output("compare(0 / 0, 1);").unwrap_err();
+        assert!(error.to_string().starts_with("compare() can't order nan."));
+
+        // a sort written in Lox
+        let source = "var a = \"pear\"; var b = \"fig\"; var c = \"apple\";\n\
+                      if (compare(a, b) > 0) { var t = a; a = b; b = t; }\n\
+                      if (compare(b, c) > 0) { var t = b; b = c; c = t; }\n\
+                      if (compare(a, b) > 0) { var t = a; a = b; b = t; }\n\
+                      print a; print b; print c;";
+        assert_eq!(run_output(source).unwrap(), "apple\nfig\npear\n");
+    }
+
+    #[test]
+    fn arity_and_name() {
+        let source = "fun none() {} fun two(a, b) { return a + b; }\n\
+                      fun outer(x) { fun inner(y) { return x + y; } return inner; }\n\
+                      class Pair {}\n\
+                      print arity(none); print arity(two); print arity(outer(1));\n\
+                      print arity(clock); print arity(compare); print arity(math.sqrt); print arity(Pair);\n\
+                      print name(two); print name(outer(1)); print name(len); print name(math.floor);\n\
+                      print name(Pair); print name(two) == \"two\";";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "0\n2\n1\n0\n2\n1\n0\ntwo\ninner\nlen\nmath.floor\nPair\ntrue\n"
+        );
+
+        // a test runner written in Lox, calling only the functions that take no arguments
+        let source = "fun check() { print \"checked\"; } fun helper(x) {}\n\
+                      fun run(f) { if (arity(f) == 0) { print name(f) + \": \"; f(); } }\n\
+                      run(check); run(helper);";
+        assert_eq!(run_output(source).unwrap(), "check: \nchecked\n");
+
+        // own and inherited methods count, fields don't
+        let source = "class A { area() { return 1; } }\n\
+                      class B < A { init() { this.f = clock; } scale() {} }\n\
+                      var b = B();\n\
+                      print hasMethod(b, \"scale\"); print hasMethod(b, \"area\");\n\
+                      print hasMethod(b, \"init\"); print hasMethod(A(), \"scale\");\n\
+                      print hasMethod(b, \"f\"); print hasMethod(b, \"missing\");";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\ntrue\ntrue\nfalse\nfalse\nfalse\n"
+        );
+
+        for (source, message) in [
+            ("arity(1);", "arity() expects a function, got number."),
+            ("arity(\"f\");", "arity() expects a function, got string."),
+            ("name(nil);", "name() expects a function, got nil."),
+            (
+                "class A {} name(A());",
+                "name() expects a function, got instance.",
+            ),
+            (
+                "class A {} hasMethod(A, \"init\");",
+                "hasMethod() expects an instance, got class.",
+            ),
+            (
+                "hasMethod(1, \"abs\");",
+                "hasMethod() expects an instance, got number.",
+            ),
+            (
+                "class A {} hasMethod(A(), nil);",
+                "hasMethod() expects a string, got nil.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().starts_with(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn encoding_natives() {
+        // multibyte strings round-trip through every encoder and decoder
+        let source = "var s = \"h\u{e9}llo, \u{4e16}\u{754c} \u{1f389}\";\n\
+                      print toHex(s); print fromHex(toHex(s)) == s;\n\
+                      print toBase64(s); print fromBase64(toBase64(s)) == s;\n\
+                      var chars = \"\"; for (var i in 0..len(s)) chars = chars + fromCharCode(charCodeAt(s, i));\n\
+                      print chars == s; print charCodeAt(s, 1); print charCodeAt(s, 10);\n\
+                      print toBase64(\"\") + \"|\" + toBase64(\"f\") + \"|\" + toBase64(\"fo\") + \"|\" + toBase64(\"foo\");\n\
+                      print fromBase64(\"Zm9vYg==\"); print fromHex(\"48656C6c6F\"); print fromHex(\"\") == \"\";";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "68c3a96c6c6f2c20e4b896e7958c20f09f8e89\ntrue\n\
+             aMOpbGxvLCDkuJbnlYwg8J+OiQ==\ntrue\n\
+             true\n233\n127881\n\
+             |Zg==|Zm8=|Zm9v\n\
+             foob\nHello\ntrue\n"
+        );
+
+        for (source, message) in [
+            (
+                "fromHex(\"c3\");",
+                "fromHex() decoded invalid UTF-8 at byte 0.",
+            ),
+            (
+                "fromHex(\"41ff\");",
+                "fromHex() decoded invalid UTF-8 at byte 1.",
+            ),
+            ("fromHex(\"4g\");", "Invalid hex digit 'g' at index 1."),
+            (
+                "fromHex(\"414\");",
+                "Hex string needs an even number of digits, got 3.",
+            ),
+            (
+                "fromBase64(\"/w==\");",
+                "fromBase64() decoded invalid UTF-8 at byte 0.",
+            ),
+            (
+                "fromBase64(\"Zm9\");",
+                "Base64 string needs a multiple of 4 characters, got 3.",
+            ),
+            (
+                "fromBase64(\"Zm*v\");",
+                "Invalid base64 character '*' at index 2.",
+            ),
+            (
+                "fromBase64(\"Z=9v\");",
+                "Invalid base64 character '=' at index 1.",
+            ),
+            (
+                "fromBase64(\"Zg=v\");",
+                "Invalid base64 character 'v' at index 3.",
+            ),
+            (
+                "fromBase64(\"Zg==Zg==\");",
+                "Invalid base64 character '=' at index 2.",
+            ),
+            (
+                "fromCharCode(55296);",
+                "fromCharCode() expects a Unicode scalar value, got 55296.",
+            ),
+            (
+                "fromCharCode(-1);",
+                "fromCharCode() expects a Unicode scalar value, got -1.",
+            ),
+            (
+                "fromCharCode(1.5);",
+                "fromCharCode() argument must be an integer, got 1.5.",
+            ),
+            (
+                "charCodeAt(\"ab\", 2);",
+                "charCodeAt() index 2 is out of bounds for length 2.",
+            ),
+            ("toHex(1);", "toHex() expects a string, got number."),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().starts_with(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn audit_log() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        // nothing is recorded unless enabled
+        assert_eq!(vm.interpret("var a = 1;").err(), None);
+        assert!(vm.take_audit_log().is_empty());
+
+        vm.enable_audit();
+        let source = "var a = 2;\nvar b = \"x\";\na = math.sqrt(16) + len(b);\n{ var local = 5; local = 6; }";
+        assert_eq!(vm.interpret(source).err(), None);
+        let log: Vec<String> = vm.take_audit_log().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            log,
+            vec![
+                "define a = 2 (was 1)",
+                "define b = x",
+                "call math.sqrt(16)",
+                "call len(x)",
+                "assign a = 5 (was 2)",
+            ]
+        );
+        assert_eq!(
+            vm.take_audit_log(),
+            Vec::<AuditEvent>::new(),
+            "taking the log empties it"
+        );
+
+        // natives called back by a native are recorded too
+        assert_eq!(vm.interpret("benchmark(clock, 1);").err(), None);
+        let log: Vec<String> = vm.take_audit_log().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            log,
+            vec![
+                "call benchmark(<native fn>, 1)",
+                "call clock()",
+                "call clock()"
+            ]
+        );
+
+        assert_eq!(vm.interpret("fun f() {}").err(), None);
+        assert_eq!(
+            vm.take_audit_log(),
+            vec![AuditEvent::GlobalDefined {
+                name: "f".to_owned(),
+                old: None,
+                new: "<fn>".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let matches = |pattern: &str, text: &str| Pattern::parse(pattern).unwrap().matches(text);
+        // `*` at either end, or both
+        assert!(matches("*.lox", "main.lox"));
+        assert!(matches("*.lox", ".lox"));
+        assert!(!matches("*.lox", "main.lox.bak"));
+        assert!(matches("test_*", "test_"));
+        assert!(matches("*mid*", "a mid b"));
+        assert!(matches("*", ""));
+        assert!(matches("a**b", "ab"));
+        // the empty pattern only matches the empty text
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        // `?` is one char, also a multibyte one
+        assert!(matches("h?llo", "héllo"));
+        assert!(!matches("h??llo", "héllo"));
+        assert!(matches("??", "日本"));
+        // classes
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[^0-9_]", "é"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("v[0-9][0-9]", "v42"));
+        // escapes
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+
+        let error = |pattern: &str| Pattern::parse(pattern).err().unwrap();
+        assert_eq!(error("[abc"), "Unterminated character class in pattern.");
+        assert_eq!(error("[z-a]"), "Invalid range 'z-a' in pattern.");
+        assert_eq!(error("abc\\"), "Pattern ends with '\\'.");
+
+        let find = |pattern: &str, text: &str| Pattern::parse(pattern).unwrap().find(text);
+        assert_eq!(find("b?d", "abcde"), Some(1));
+        assert_eq!(find("d", "日本d"), Some(2));
+        assert_eq!(find("", "abc"), Some(0));
+        assert_eq!(find("*z", "abc"), None);
+        assert_eq!(find("x*", "abx"), Some(2));
+
+        // backtracking is limited to the last `*`, this doesn't take exponential time
+        let text = "a".repeat(5000);
+        let start = std::time::Instant::now();
+        assert!(!matches("*a*a*a*a*a*a*a*a*b", &text));
+        assert_eq!(find("a*a*a*a*a*a*b", &text), None);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn string_pattern_natives() {
+        let source = "print startsWith(\"rust-lox\", \"rust\"); print endsWith(\"rust-lox\", \"x\");\n\
+                      print startsWith(\"\", \"a\"); print endsWith(\"abc\", \"\");\n\
+                      print match(\"main.lox\", \"*.lox\"); print find(\"héllo\", \"l?o\"); print find(\"abc\", \"z\");";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "true\ntrue\nfalse\ntrue\ntrue\n2\nnil\n"
+        );
+        match run_output("print match(\"a\", 1);") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "match() expects a string, got number.")
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+        match run_output("print find(\"a\", \"[a\");") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Unterminated character class in pattern.")
+            }
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locals_per_frame() {
+        // both functions read and write slot 1 (their parameter) and slot 2, in their own frames
+        let source = "fun inner(a) { var b = a * 10; a = b; return a; }\n\
+                      fun outer(a) { var b = inner(a + 1); return a + b; }\n\
+                      print outer(1); print outer(inner(1));";
+        assert_eq!(run_output(source).unwrap(), "21\n120\n");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn slots_outside_the_frame() {
+        use OpCode::*;
+        let function = crafted_function(&[Nil, GetLocal(2), Return], &[]);
+        let mut vm = VM::new();
+        let payload =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run_function(function)))
+                .err()
+                .unwrap();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("Slot 2 is outside of the frame's window of 2 values.")
+        );
+    }
+
+    // Effects happen left to right: operands, arguments (after the callee), assignment targets
+    // before their values. Optimizations must keep this order.
+    #[test]
+    fn evaluation_order() {
+        let prelude = "fun t(x) { print x; return x; }\n\
+                       fun f(a, b, c) { return a; }\n\
+                       var g = 0;\n";
+        let cases = [
+            ("t(1) + t(2) * t(3);", "1 2 3"),
+            ("t(1) * t(2) + t(3);", "1 2 3"),
+            ("t(1) - (t(2) - t(3));", "1 2 3"),
+            ("-t(1) / t(2);", "1 2"),
+            ("t(1) < t(2) == t(3) > t(4);", "1 2 3 4"),
+            ("t(1) <= t(2); t(3) >= t(4); t(5) != t(6);", "1 2 3 4 5 6"),
+            ("f(t(1), t(2), t(3));", "1 2 3"),
+            ("f(t(1), f(t(2), t(3), t(4)), t(5));", "1 2 3 4 5"),
+            ("t(f)(t(1), t(2), t(3));", "<fn> 1 2 3"),
+            ("t(\"a\") + t(\"b\") + t(\"c\");", "a b c"),
+            ("t(0)..t(3);", "0 3"),
+            ("t(1) in t(0)..t(3);", "1 0 3"),
+            ("t(t(0)..t(5))[t(2)];", "0 5 0..5 2"),
+            ("g = t(1) + (g = t(2));", "1 2"),
+            ("print g = t(1) + t(2);", "1 2 3"),
+            ("{ var l = 0; l = t(l + 1) + t(l + 2); print l; }", "1 2 3"),
+            // short-circuit: the right operand runs only when needed, and once
+            ("t(false) and t(1);", "false"),
+            ("t(true) and t(1);", "true 1"),
+            ("t(nil) or t(2);", "nil 2"),
+            ("t(1) or t(2);", "1"),
+            ("t(false) or t(false) or t(3);", "false false 3"),
+            ("t(1) and t(false) or t(3);", "1 false 3"),
+            ("t(nil) or t(false) and t(3);", "nil false"),
+            ("if (t(1) and t(nil)) t(2); else t(3);", "1 nil 3"),
+            ("if (t(nil) or t(2)) t(3); else t(4);", "nil 2 3"),
+            ("while (t(false) or t(false)) t(1);", "false false"),
+            ("for (var i in t(0)..t(2)) t(i);", "0 2 0 1"),
+            (
+                "for (var i = t(0); t(i < 2); i = t(i + 1)) t(\"body\");",
+                "0 true body 1 true body 2 false",
+            ),
+        ];
+        for (optimize, paranoid) in [(false, false), (true, false), (false, true)] {
+            for (source, expected) in cases {
+                let mut vm = VM::new();
+                vm.output = Output::capture();
+                vm.compile_options.optimize = optimize;
+                vm.compile_options.paranoid = paranoid;
+                let result = vm.interpret(&format!("{}{}", prelude, source));
+                assert_eq!(result.err(), None, "{}", source);
+                let effects: Vec<&str> = vm.output.out.captured().lines().collect();
+                assert_eq!(
+                    effects.join(" "),
+                    expected,
+                    "{} (optimize: {}, paranoid: {})",
+                    source,
+                    optimize,
+                    paranoid
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn strip_lines() {
+        let source = "fun f(x) {\n  return -x;\n}\nf(nil);";
+        let run = |strip_lines: bool| {
+            let mut vm = VM::builder().strip_lines(strip_lines).build().unwrap();
+            vm.interpret(source).unwrap_err().to_string()
+        };
+        assert_eq!(
+            run(false),
+            "Operand to '-' must be a number, got nil.\n[line 2] in f()\n[line 4] in <script>"
+        );
+        assert_eq!(
+            run(true),
+            "Operand to '-' must be a number, got nil.\n[unknown line] in f()\n[unknown line] in <script>"
+        );
+
+        // the disassembly shows `?` for the lines, and reads back without them
+        let fixture =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts/classes/inheritance.lox");
+        let source = fs::read_to_string(fixture).unwrap();
+        let disassemble = |strip_lines: bool| {
+            let mut vm = VM::builder().strip_lines(strip_lines).build().unwrap();
+            let text = vm.disassemble_source(&source, None).unwrap();
+            let f_idx = vm.assemble(&text).unwrap();
+            assert_eq!(vm.functions[f_idx].chunk.has_lines(), !strip_lines);
+            text
+        };
+        let (full, stripped) = (disassemble(false), disassemble(true));
+        assert!(stripped.lines().any(|line| line.starts_with("   ? OP_")));
+        assert!(!stripped.contains("   | "));
+        assert_eq!(stripped.lines().count(), full.lines().count());
+
+        // each instruction saves the bytes of its line
+        let memory = |strip_lines: bool| {
+            let mut vm = VM::builder().strip_lines(strip_lines).build().unwrap();
+            let f_idx = vm.compile_source(&source, None, false).unwrap();
+            let functions = &vm.functions[f_idx..];
+            let instructions: usize = functions.iter().map(|f| f.chunk.len()).sum();
+            let bytes: usize = functions.iter().map(|f| f.chunk.memory_size()).sum();
+            (instructions, bytes)
+        };
+        let ((instructions, full), (_, stripped)) = (memory(false), memory(true));
+        assert_eq!(full - stripped, instructions * std::mem::size_of::<usize>());
+
+        let error = VM::new()
+            .assemble("== <script> ==\n   ? OP_NIL\n   1 OP_RETURN")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[asm line 3] Either every instruction of a function has a line number or none ('?')."
+        );
+    }
+
+    #[test]
+    fn labeled_disassembly() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disasm/loop.lox");
+        let source = fs::read_to_string(path).unwrap();
+        let disassemble = |optimize: bool| {
+            let mut vm = VM::new();
+            vm.compile_options.optimize = optimize;
+            let text = vm.disassemble_source(&source, None).unwrap();
+            (text, vm)
+        };
+        let (plain, vm) = disassemble(false);
+        let i = string_operand(&vm, "Identifier", "i");
+        let never = string_operand(&vm, "StringObj", "never");
+        assert!(plain.contains(
+            "== f/1 ==\n\
+             \x20  7 OP_NIL\n\
+             \x20  | OP_ZERO\n\
+             \x20  | OP_GET_LOCAL 1\n\
+             \x20  | OP_RANGE\n\
+             \x20  | OP_ZERO\n\
+             L7:\n\
+             \x20  | OP_FOR_IN 2 -> L7.2\n\
+             \x20  | OP_GET_LOCAL 2\n\
+             \x20  | OP_PRINT\n\
+             \x20  | OP_LOOP -> L7\n\
+             L7.2:\n\
+             \x20  | OP_POP_N 3\n"
+        ));
+        assert!(plain.starts_with("== <script> ==\n"));
+        assert!(plain.contains(&format!("   3 OP_GET_GLOBAL 2 {}\n", i)));
+        assert!(plain.contains("   3 OP_LOOP -> L3\nL3.2:\n"));
+
+        assert_eq!(diff::unified(&plain, &disassemble(false).0), "");
+        // the optimizer only removed the `if (false)`, the labels of the loop stay the same
+        assert_eq!(
+            diff::unified(&plain, &disassemble(true).0),
+            format!(
+                "@@ -2,12 +2,4 @@\n\
+                 \x20   1 OP_ZERO\n\
+                 \x20   | OP_DEFINE_GLOBAL 0 {i}\n\
+                 -   2 OP_FALSE\n\
+                 -   | OP_JUMP_IF_FALSE -> L2\n\
+                 -   | OP_POP\n\
+                 -   | OP_CONSTANT 1 {never}\n\
+                 -   | OP_PRINT\n\
+                 -   | OP_JUMP -> L3\n\
+                 -L2:\n\
+                 -   | OP_POP\n\
+                 \x20L3:\n\
+                 \x20   3 OP_GET_GLOBAL 2 {i}\n"
+            )
+        );
+    }
+
+    // A string constant as the disassembler shows it, e.g. 'Identifier: 39: i', with the id
+    // the string has in the VM
+    fn string_operand(vm: &VM, kind: &str, string: &str) -> String {
+        let idx = vm.interner.get(string).unwrap();
+        format!("'{}: {}: {}'", kind, idx, string)
+    }
+
+    #[test]
+    fn switch_statement() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/disasm/switch.lox");
+        let source = fs::read_to_string(path).unwrap();
+        // the local declared after the switch has the right slot, whichever case ran
+        assert_eq!(run_output(&source).unwrap(), "one!\neven\ntwo!\nmany!\n");
+        let mut vm = VM::new();
+        let text = vm.disassemble_source(&source, None).unwrap();
+        assert!(
+            text.contains(&format!(
+                "   3 OP_GET_LOCAL 1\n\
+                 \x20  4 OP_GET_LOCAL 3\n\
+                 \x20  | OP_ONE\n\
+                 \x20  | OP_EQUAL\n\
+                 \x20  | OP_JUMP_IF_FALSE -> L4\n\
+                 \x20  | OP_POP\n\
+                 \x20  5 OP_CONSTANT 1 {}\n\
+                 \x20  | OP_SET_LOCAL 2\n\
+                 \x20  | OP_POP\n\
+                 \x20  4 OP_JUMP -> L11\n\
+                 L4:\n\
+                 \x20  | OP_POP\n",
+                string_operand(&vm, "StringObj", "one")
+            )),
+            "{}",
+            text
+        );
+        // the value is popped once at the end, after the default case
+        assert!(
+            text.contains("   | OP_POP\nL11:\n  11 OP_POP\n  12 OP_GET_LOCAL 2\n"),
+            "{}",
+            text
+        );
+
+        // no fallthrough, no case matches without a default, returns from a case
+        let source = "\
+            fun f(x) { switch (x) { case \"a\": return 1; case \"b\": return 2; } return 0; }\n\
+            print f(\"a\"); print f(\"b\"); print f(\"c\");\n\
+            for (var i = 0; i < 3; i = i + 1) {\n\
+              var before = i * 10;\n\
+              switch (i) { case 0: print \"zero\"; case 1: { var one = 1; print one; } }\n\
+              print before;\n\
+            }\n\
+            switch (nil) { default: print \"default\"; }";
+        assert_eq!(
+            run_output(source).unwrap(),
+            "1\n2\n0\nzero\n0\n1\n10\n20\ndefault\n"
+        );
+
+        for (source, message) in [
+            (
+                "switch (1) { default: print 1; case 1: print 2; }",
+                "Can't have a case after the default case.",
+            ),
+            (
+                "switch (1) { default: print 1; default: print 2; }",
+                "Can't have more than one default case.",
+            ),
+            (
+                "switch (1) { print 1; }",
+                "Expect 'case' or 'default' in switch.",
+            ),
+            (
+                "switch (1) { case 1 print 1; }",
+                "Expect ':' after case value.",
+            ),
+        ] {
+            let error = run_output(source).unwrap_err();
+            assert!(error.to_string().contains(message), "{}", error);
+        }
+        // the keywords are reserved by the extended language level only
+        let mut vm = VM::new();
+        vm.compile_options.lang_level = LangLevel::Classic;
+        assert_eq!(vm.interpret("var switch = 1; var case = 2;").err(), None);
+    }
+
+    // compile -> disassemble -> assemble gives the same code, which runs like the original
+    #[test]
+    fn assembler_round_trip() {
+        let mut fixtures = Vec::new();
+        let mut dirs = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "lox") {
+                    fixtures.push(path);
+                }
+            }
+        }
+        assert!(fixtures.len() > 10);
+
+        // the assembled functions have new ids
+        let without_ids = |text: &str| -> Vec<String> {
+            text.lines()
+                .map(|line| match line.find("'Function id: ") {
+                    Some(idx) => line[..idx].to_owned(),
+                    None => line.to_owned(),
+                })
+                .collect()
+        };
+        let mut round_trips = 0;
+        for path in fixtures {
+            let source = fs::read_to_string(&path).unwrap();
+            for (optimize, strip_lines) in [(false, false), (true, false), (false, true)] {
+                let mut expected = VM::new();
+                expected.compile_options.optimize = optimize;
+                expected.compile_options.strip_lines = strip_lines;
+                expected.output = Output::capture();
+                let expected_result = expected.interpret(&source);
+                if let Err(LoxError::Compile(_)) = expected_result {
+                    continue;
+                }
+
+                let mut vm = VM::new();
+                vm.compile_options.optimize = optimize;
+                vm.compile_options.strip_lines = strip_lines;
+                vm.output = Output::capture();
+                let text = vm.disassemble_source(&source, None).unwrap();
+                let f_idx = vm
+                    .assemble(&text)
+                    .unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+                assert_eq!(
+                    without_ids(&vm.disassemble_function(f_idx)),
+                    without_ids(&text),
+                    "{}",
+                    path.display()
+                );
+                let result = vm.run_compiled(f_idx);
+                assert_eq!(
+                    result.map(|_| ()).map_err(|error| error.to_string()),
+                    expected_result.map_err(|error| error.to_string()),
+                    "{}",
+                    path.display()
+                );
+                assert_eq!(vm.output.out.captured(), expected.output.out.captured());
+                assert_eq!(vm.output.err.captured(), expected.output.err.captured());
+                round_trips += 1;
+            }
+        }
+        assert!(round_trips >= 16);
+    }
+
+    #[test]
+    fn assembler() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let text = "\
+            ; every instruction form\n\
+            == <script> (hand.lox) ==\n\
+            \x20  1 OP_CONSTANT 0 'Function id: 42'\n\
+            \x20  | OP_DEFINE_FUNCTION 1 'Identifier: 0: count'\n\
+            \x20  2 OP_GET_GLOBAL 1 'Identifier: 0: count'\n\
+            \x20  | OP_CONSTANT 2 'number: 2.5'\n\
+            \x20  | OP_CALL 1\n\
+            \x20  | OP_PRINT\n\
+            \x20  3 OP_CONSTANT 3 'StringObj: 0: a\\tb\\\\'\n\
+            \x20  | OP_IS_TYPE string\n\
+            \x20  | OP_PRINT\n\
+            \x20  | OP_POP\n\
+            \x20  | OP_TRUE\n\
+            \x20  | OP_IS_TYPE bool\n\
+            \x20  | OP_CHECK_CONDITION\n\
+            \x20  | OP_IS_TYPE number\n\
+            \x20  | OP_POP_BELOW\n\
+            \x20  | OP_PRINT\n\
+            \x20  4 OP_CONSTANT 4 'range: 1..3'\n\
+            \x20  | OP_ONE\n\
+            \x20  | OP_INDEX\n\
+            \x20  | OP_PRINT\n\
+            \x20  5 OP_NIL\n\
+            \x20  | OP_RETURN\n\
+            == count/1 ==\n\
+            \x20  7 OP_CHECK_ARITY 1\n\
+            \x20  | OP_ZERO\n\
+            L7:\n\
+            \x20  8 OP_CHECK_SLOT 2\n\
+            \x20  | OP_GET_LOCAL 2\n\
+            \x20  | OP_GET_LOCAL 1\n\
+            \x20  | OP_LESS\n\
+            \x20  | OP_JUMP_IF_FALSE -> L9\n\
+            \x20  | OP_POP\n\
+            \x20  | OP_GET_LOCAL 2\n\
+            \x20  | OP_ONE\n\
+            \x20  | OP_ADD\n\
+            \x20  | OP_SET_LOCAL 2\n\
+            \x20  | OP_POP\n\
+            \x20  | OP_LOOP -> L7\n\
+            L9:\n\
+            \x20  9 OP_POP\n\
+            \x20  | OP_NOP\n\
+            \x20  | OP_RETURN\n";
+        let f_idx = vm.assemble(text).unwrap();
+        assert_eq!(vm.functions[f_idx + 1].arity, 1);
+        assert_eq!(vm.functions[f_idx].chunk.line(2), Some(2));
+        assert!(matches!(
+            vm.functions[f_idx + 1].chunk.op(6),
+            Some(OpCode::JumpIfFalse(7))
+        ));
+        assert!(matches!(
+            vm.functions[f_idx + 1].chunk.op(13),
+            Some(OpCode::Loop(11))
+        ));
+        vm.run_compiled(f_idx).unwrap();
+        assert_eq!(vm.output.out.captured(), "3\ntrue\nfalse\n2\n");
+        assert!(vm
+            .disassemble_function(f_idx)
+            .starts_with("== <script> (hand.lox) ==\n"));
+
+        let error = |text: &str| VM::new().assemble(text).unwrap_err().to_string();
+        assert_eq!(
+            error("   1 OP_NIL\n"),
+            "[asm line 1] Expected a section title like '== <script> =='."
+        );
+        assert_eq!(
+            error("== <script> ==\n   1 OP_NIL\n   | OP_JUMP -> L2\n"),
+            "[asm line 3] Undefined label 'L2'."
+        );
+        assert_eq!(
+            error("== <script> ==\nL1:\n   1 OP_JUMP -> L1\n"),
+            "[asm line 3] Only OP_LOOP can jump backwards."
+        );
+        assert_eq!(
+            error("== <script> ==\n   1 OP_POP_N 300\n"),
+            "[asm line 2] OP_POP_N expects a number from 0 to 255, got '300'."
+        );
+        assert_eq!(
+            error("== <script> ==\n   | OP_NIL\n"),
+            "[asm line 2] The first instruction needs a line number."
+        );
+        assert_eq!(
+            error("== <script> ==\n   1 OP_FROB\n"),
+            "[asm line 2] Unknown instruction 'OP_FROB'."
+        );
+        assert_eq!(
+            error("== <script> ==\n   1 OP_CONSTANT 0 'number: 1'\n   | OP_CONSTANT 0 'nil'\n"),
+            "[asm line 3] Constant 0 is 'number: 1', it can't also be 'nil'."
+        );
+        assert_eq!(
+            error("== <script> ==\n   1 OP_CONSTANT 0 'Function id: 3'\n"),
+            "[asm line 2] Function constants refer to 1 functions, but there are only 0 after the script."
+        );
+        assert_eq!(
+            error("== f/0 [local 1, global 2] ==\n"),
+            "[asm line 1] Invalid capture 'global 2', expected 'local N' or 'upvalue N'."
+        );
+    }
+
+    #[test]
+    fn unified_diff() {
+        assert_eq!(diff::unified("a\nb\n", "a\nb\n"), "");
+        assert_eq!(
+            diff::unified("a\nb\nc\n", "a\nx\nc\n"),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n"
+        );
+        assert_eq!(diff::unified("", "a\n"), "@@ -0,0 +1 @@\n+a\n");
+        // changes far apart get their own hunks
+        let old: String = (1..=10).map(|i| format!("{}\n", i)).collect();
+        let new = old.replace("2\n", "two\n").replace("9\n", "nine\n");
+        assert_eq!(
+            diff::unified(&old, &new),
+            "@@ -1,4 +1,4 @@\n 1\n-2\n+two\n 3\n 4\n\
+             @@ -7,4 +7,4 @@\n 7\n 8\n-9\n+nine\n 10\n"
+        );
+    }
+
+    #[test]
+    fn paranoid_mode() {
+        let source = "fun add(a, b) { var sum = a + b; return sum; }\n\
+                      fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+                      var total = 0;\n\
+                      for (var i in 0..5) { total = total + add(i, i * 2); }\n\
+                      print total; print fib(10); print \"a\" + \"b\"; print 7 / 2 >= 3;";
+        let run = |paranoid: bool| {
+            let mut vm = VM::new();
+            vm.output = Output::capture();
+            vm.compile_options.paranoid = paranoid;
+            vm.interpret(source).unwrap();
+            let code_size: usize = vm.functions.iter().map(|f| f.chunk.len()).sum();
+            (vm.output.out.captured().to_owned(), code_size)
+        };
+        let (output, size) = run(false);
+        let (paranoid_output, paranoid_size) = run(true);
+        assert_eq!(output, "30\n55\nab\ntrue\n");
+        assert_eq!(paranoid_output, output);
+        assert!(paranoid_size > size);
+
+        // type errors are still the ones of the instructions, the checks only differ in the message
+        let mut vm = VM::new();
+        vm.compile_options.paranoid = true;
+        match vm.interpret("var a = 1; var b = nil;\nprint (a + 1) * -b;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand to '-' must be a number, got nil.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match vm.interpret("var a = 1; var b = nil;\nprint (a + 1) * b;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(
+                    error.message,
+                    "Paranoid check failed: operands of '(a + 1) * b' must be two numbers, got number (2) and nil."
+                );
+                assert_eq!(error.trace[0].line, Some(2));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a corrupted chunk: the constants 2.5 and \"x\" are swapped
+        let mut vm = VM::new();
+        vm.compile_options.paranoid = true;
+        let f_idx = vm
+            .compile_source("var s = \"x\"; print 2.5 - 1.5;", None, false)
+            .unwrap();
+        let chunk = &vm.functions[f_idx].chunk;
+        let mut constants: Vec<Value> = (0..chunk.constants().len())
+            .map(|idx| *chunk.constant(idx).unwrap())
+            .collect();
+        let x = constants
+            .iter()
+            .position(|c| matches!(c, Value::StringObj(s) if vm.interner.lookup(*s) == "x"));
+        let n = constants
+            .iter()
+            .position(|c| values_equal(*c, Value::Number(2.5)));
+        constants.swap(x.unwrap(), n.unwrap());
+        let function = crafted_function(chunk.code(), &constants);
+        match vm.run_function(function) {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Paranoid check failed: operands of '2.5 - 1.5' must be two numbers, got string (\"x\") and number (1.5)."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a frame that doesn't match the arity, and a slot outside of it
+        use OpCode::*;
+        let mut vm = VM::new();
+        let function = crafted_function(&[Nil, CheckArity(2), Return], &[]);
+        match vm.run_function(function) {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Paranoid check failed: <script> takes 2 arguments, but its frame holds 2 values."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let function = crafted_function(&[CheckSlot(1), GetLocal(1), Return], &[]);
+        match vm.run_function(function) {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Paranoid check failed: slot 1 is outside of the frame's window of 1 values."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repl_display_settings() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let echo = |vm: &mut VM, line: &str| {
+            let before = vm.output.out.captured().len();
+            repl::eval(vm, line);
+            vm.output.out.captured()[before..].to_owned()
+        };
+        assert_eq!(
+            repl::command(&mut vm, "set"),
+            "precision auto\nshowtypes off\nmaxlength 4096\n"
+        );
+        assert_eq!(echo(&mut vm, "0.1 + 0.2"), "0.30000000000000004\n");
+        assert_eq!(echo(&mut vm, "0.1"), "0.1\n");
+        // statements aren't echoed
+        assert_eq!(echo(&mut vm, "var x = 0.1;"), "");
+        assert_eq!(echo(&mut vm, "print x"), "");
+
+        assert_eq!(repl::command(&mut vm, "set precision 17"), "");
+        assert_eq!(echo(&mut vm, "0.1 + 0.2"), "0.30000000000000004\n");
+        assert_eq!(echo(&mut vm, "x"), "0.10000000000000001\n");
+        assert_eq!(echo(&mut vm, "1 / 3 * 3"), "1\n");
+        assert_eq!(echo(&mut vm, "\"text\""), "text\n");
+        // program output keeps the shortest digits
+        assert_eq!(echo(&mut vm, "print x;"), "0.1\n");
+        assert_eq!(repl::command(&mut vm, "set precision 3"), "");
+        assert_eq!(echo(&mut vm, "2 / 3"), "0.667\n");
+        assert_eq!(echo(&mut vm, "123456"), "123000\n");
+        assert_eq!(echo(&mut vm, "99999 * 100000000000000000"), "1e22\n");
+
+        assert_eq!(repl::command(&mut vm, "set showtypes on"), "");
+        assert_eq!(echo(&mut vm, "1 == 1"), "bool: true\n");
+        assert_eq!(echo(&mut vm, "0.1 + 0.2"), "number: 0.3\n");
+        assert_eq!(
+            repl::command(&mut vm, "set"),
+            "precision 3\nshowtypes on\nmaxlength 4096\n"
+        );
+        assert_eq!(repl::command(&mut vm, "set precision auto"), "");
+        assert_eq!(echo(&mut vm, "0.1 + 0.2"), "number: 0.30000000000000004\n");
+
+        assert_eq!(
+            repl::command(&mut vm, "set precision 18"),
+            "Usage: :set precision <1-17|auto>\n"
+        );
+        assert_eq!(
+            repl::command(&mut vm, "set showtypes yes"),
+            "Usage: :set showtypes <on|off>\n"
+        );
+        assert_eq!(
+            repl::command(&mut vm, "set colors on"),
+            "Unknown setting 'colors'.\n"
+        );
+        assert_eq!(
+            repl::command(&mut vm, "set"),
+            "precision auto\nshowtypes on\nmaxlength 4096\n"
+        );
+    }
+
+    #[test]
+    fn repl_truncates_long_values() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        // 2^14 = 16384 characters
+        let source = "var s = \"é\"; for (var i = 0; i < 14; i = i + 1) s = s + s;";
+        repl::eval(&mut vm, source);
+        repl::eval(&mut vm, "s");
+        let echo = vm.output.out.captured().to_owned();
+        assert!(echo.len() < repl::DEFAULT_MAX_LENGTH + 50);
+        // "é" is 2 bytes, the cut doesn't split it
+        assert!(echo.ends_with(&format!("é... {} more bytes\n", 2 * 16384 - 4096)));
+
+        assert_eq!(repl::command(&mut vm, "set maxlength 5"), "");
+        repl::eval(&mut vm, "s");
+        repl::eval(&mut vm, "12345");
+        repl::eval(&mut vm, "123456");
+        assert!(vm.output.out.captured().ends_with(&format!(
+            "éé... {} more bytes\n12345\n12345... 1 more bytes\n",
+            2 * 16384 - 4
+        )));
+
+        // program output isn't cut
+        repl::eval(&mut vm, "print s;");
+        assert!(vm
+            .output
+            .out
+            .captured()
+            .ends_with(&format!("{}\n", "é".repeat(16384))));
+        assert_eq!(repl::command(&mut vm, "set maxlength off"), "");
+        repl::eval(&mut vm, "s + \"!\"");
+        assert!(vm.output.out.captured().ends_with("é!\n"));
+        assert_eq!(
+            repl::command(&mut vm, "set maxlength 0"),
+            "Usage: :set maxlength <bytes|off>\n"
+        );
+    }
+
+    #[test]
+    fn repl_type_and_disasm_commands() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        assert_eq!(
+            repl::command(&mut vm, "disasm-last"),
+            "Nothing was evaluated yet.\n"
+        );
+        repl::eval(&mut vm, "var x = 1;\n");
+        assert_eq!(repl::command(&mut vm, "type x + 1"), "number\n");
+        assert_eq!(repl::command(&mut vm, "type \"a\""), "string\n");
+        assert_eq!(repl::command(&mut vm, "type 0..x"), "range\n");
+        assert_eq!(repl::command(&mut vm, "type clock"), "function\n");
+        assert_eq!(
+            repl::command(&mut vm, "type"),
+            "Usage: :type <expression>\n"
+        );
+        // refused, and compile errors only print the error
+        assert_eq!(
+            repl::command(&mut vm, "type x = 5"),
+            ":type doesn't evaluate assignments to global variables or fields.\n"
+        );
+        assert!(values_equal(global(&vm, "x").unwrap(), Value::Number(1.0)));
+        assert_eq!(repl::command(&mut vm, "type 1 +"), "");
+        assert_eq!(repl::command(&mut vm, "type undefinedVariable"), "");
+
+        // the last line of code, not the `:type` expressions
+        let x = string_operand(&vm, "Identifier", "x");
+        assert_eq!(
+            repl::command(&mut vm, "disasm-last"),
+            format!(
+                "== <script> (<repl>) ==\n\
+                 0 1 OP_ONE\n\
+                 1    | OP_DEFINE_GLOBAL 0 {}\n\
+                 2 2 OP_NIL\n\
+                 3    | OP_RETURN\n",
+                x
+            )
+        );
+        repl::eval(&mut vm, "print x;\n");
+        assert_eq!(
+            repl::command(&mut vm, "disasm-last"),
+            format!(
+                "== <script> (<repl>) ==\n\
+                 0 1 OP_GET_GLOBAL 0 {}\n\
+                 1    | OP_PRINT\n\
+                 2 2 OP_NIL\n\
+                 3    | OP_RETURN\n",
+                x
+            )
+        );
+
+        // fields of instances in the session are left as they are too
+        repl::eval(&mut vm, "class A {} var a = A(); a.f = 1;\n");
+        assert_eq!(
+            repl::command(&mut vm, "type a.f = \"s\""),
+            ":type doesn't evaluate assignments to global variables or fields.\n"
+        );
+        assert_eq!(repl::command(&mut vm, "type a.f"), "number\n");
+
+        // so are calls, also of methods and classes, which could change anything
+        repl::eval(&mut vm, "var n = 0; fun bump() { n = n + 1; return n; }\n");
+        for expression in ["bump()", "1 + bump()", "A()", "\"abc\".len()"] {
+            assert_eq!(
+                repl::command(&mut vm, &format!("type {}", expression)),
+                ":type doesn't evaluate calls.\n"
+            );
+        }
+        assert!(values_equal(global(&vm, "n").unwrap(), Value::Number(0.0)));
+        assert_eq!(repl::command(&mut vm, "type bump"), "function\n");
+    }
+
+    #[test]
+    fn chunk_walking() {
+        use OpCode::*;
+        // the forward jump skips `One`, so only 0 is printed
+        let function = crafted_function(&[Jump(1), One, Zero, Print, Nil, Return], &[]);
+        let offsets: Vec<usize> = function.chunk.instructions().map(|(i, _)| i).collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4, 5]);
+        let (offset, jump) = function.chunk.instructions().next().unwrap();
+        assert_eq!(function.chunk.jump_target(offset, &jump), Some(2));
+        assert_eq!(crafted_output(function), "0\n");
+
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        let source = "var i = 0;\nwhile (i < 2) {\n  i = i + 1;\n}\nprint i;";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), "2\n");
+        let chunk = &vm.functions.last().unwrap().chunk;
+        let (loop_offset, loop_op) = chunk
+            .instructions()
+            .find(|(_, op)| matches!(op, Loop(_)))
+            .unwrap();
+        // the loop goes back to the condition, which starts by reading `i`
+        let condition = chunk.jump_target(loop_offset, &loop_op).unwrap();
+        assert!(matches!(chunk.op(condition), Some(GetGlobal(_))));
+        // and the exit jump lands right after the loop
+        let (exit_offset, exit_op) = chunk
+            .instructions()
+            .find(|(_, op)| matches!(op, JumpIfFalse(_)))
+            .unwrap();
+        assert_eq!(
+            chunk.jump_target(exit_offset, &exit_op),
+            Some(loop_offset + 1)
+        );
+        assert_eq!(chunk.jump_target(0, &Add), None);
+
+        let listing = debug::disassemble(chunk, "<script>", &vm.interner);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "== <script> ==");
+        assert_eq!(lines[1], "0 1 OP_ZERO");
+        assert!(lines[2].starts_with("1    | OP_DEFINE_GLOBAL 0 'Identifier: "));
+        let loop_line = format!("OP_LOOP {} -> {}", loop_offset, condition);
+        assert!(lines[loop_offset + 1].ends_with(&loop_line));
+        assert_eq!(lines.len(), chunk.len() + 1);
+    }
+
+    #[test]
+    fn invalid_constant_index() {
+        let crafted = [
+            crafted_function(&[OpCode::Constant(3), OpCode::Return], &[]),
+            crafted_function(&[OpCode::Nil, OpCode::DefineGlobal(1)], &[Value::Nil]),
+            crafted_function(&[OpCode::GetGlobal(0)], &[]),
+            crafted_function(&[OpCode::Nil, OpCode::SetGlobal(7)], &[]),
+            // a constant that is not an identifier
+            crafted_function(&[OpCode::GetGlobal(0)], &[Value::Number(1.0)]),
+            // calling a function that doesn't exist
+            crafted_function(
+                &[OpCode::Constant(0), OpCode::Call(0)],
+                &[Value::Function(99)],
+            ),
+            // no Return at the end
+            crafted_function(&[OpCode::Nil], &[]),
+        ];
+        for function in crafted {
+            let mut vm = VM::new();
+            assert!(matches!(
+                vm.run_function(function),
+                Err(LoxError::Runtime(_))
+            ));
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn call_main() {
+        let path = Path::new("tests/fixtures/cli/main.lox");
+        let mut vm = VM::builder()
+            .output(Output::capture())
+            .args(vec!["7".to_owned()])
+            .build()
+            .unwrap();
+        // without the option, only the top-level code runs
+        assert_eq!(vm.run_script(path), Ok(0));
+        assert_eq!(vm.output.out.captured(), "");
+
+        vm.call_main = true;
+        assert_eq!(vm.run_script(path), Ok(7));
+        assert_eq!(vm.output.out.captured(), "main 7\n");
+        assert!(vm.stack.is_empty());
+
+        // the arguments must match the parameters, unless main takes none
+        vm.set_args(Vec::new());
+        match vm.run_script(path) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Expected 1 arguments but got 0.")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        vm.interpret("fun main() { return 2.9; }").unwrap();
+        vm.set_args(vec!["ignored".to_owned()]);
+        let result = vm.run_main().unwrap();
+        assert!(values_equal(result, Value::Number(2.9)));
+        vm.interpret("fun main() {}").unwrap();
+        assert!(values_equal(vm.run_main().unwrap(), Value::Nil));
+
+        // `main`'s result must be a valid exit code
+        let dir = std::env::temp_dir().join(format!("rust-lox-main-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("main.lox");
+        let mut exit_code = |result: &str| {
+            fs::write(&script, format!("fun main() {{ return {}; }}", result)).unwrap();
+            vm.run_script(&script).map_err(|error| match error {
+                LoxError::Runtime(error) => error.message,
+                other => panic!("unexpected error {:?}", other),
+            })
+        };
+        assert_eq!(exit_code("0"), Ok(0));
+        assert_eq!(exit_code("255"), Ok(255));
+        assert_eq!(exit_code("2.0"), Ok(2));
+        assert_eq!(exit_code("\"3\""), Ok(0));
+        for (result, got) in [
+            ("300", "number (300)"),
+            ("-1", "number (-1)"),
+            ("-1.5", "number (-1.5)"),
+            ("0/0", "number (nan)"),
+        ] {
+            assert_eq!(
+                exit_code(result),
+                Err(format!(
+                    "'main' must return an exit code from 0 to 255, got {}.",
+                    got
+                ))
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut vm = VM::builder().call_main(true).build().unwrap();
+        vm.interpret("var main = 1;").unwrap();
+        match vm.run_main() {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "'main' must be a function, got number (1).")
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let mut vm = VM::builder()
+            .call_main(true)
+            .output(Output::capture())
+            .build()
+            .unwrap();
+        let missing = Path::new("tests/fixtures/cli/main_missing.lox");
+        match vm.run_script(missing) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Undefined function 'main'.");
+                assert!(error.trace.is_empty());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    fn eval_str(vm: &mut VM, expression: &str) -> String {
+        let value = vm.evaluate(expression).unwrap();
+        vm.format_value(&value)
+    }
+
+    #[test]
+    fn program_args_and_environment() {
+        let mut vm = VM::new();
+        vm.output = Output::capture();
+        vm.set_args(vec!["a b".to_owned(), "".to_owned()]);
+        let source = "print argCount(); print arg(0); print arg(1) == \"\";";
+        assert_eq!(vm.interpret(source).err(), None);
+        assert_eq!(vm.output.out.captured(), "2\na b\ntrue\n");
+        match vm.interpret("arg(2);") {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "arg() index 2 is out of bounds for length 2."
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // a variable of the test process, which only the process environment shows
+        let name = format!("RUST_LOX_ENV_TEST_{}", std::process::id());
+        std::env::set_var(&name, "real");
+        let read = |vm: &mut VM| eval_str(vm, &format!("env(\"{}\")", name));
+        let mut vm = VM::new();
+        assert_eq!(read(&mut vm), "real");
+        // the map replaces the environment, also for variables it doesn't have
+        vm.set_env_map(HashMap::from([("HOME".to_owned(), "/lox".to_owned())]));
+        assert_eq!(read(&mut vm), "nil");
+        assert_eq!(eval_str(&mut vm, "env(\"HOME\")"), "/lox");
+        vm.environment = Environment::Empty;
+        assert_eq!(eval_str(&mut vm, "env(\"HOME\")"), "nil");
+        // a VM without the standard library is a sandbox: no variables or arguments
+        let sandbox = VM::builder().stdlib(false).build().unwrap();
+        assert_eq!(sandbox.environment, Environment::Empty);
+        assert!(sandbox.args.is_empty());
+        let mut vm = VM::builder()
+            .environment(Environment::Empty)
+            .build()
+            .unwrap();
+        assert_eq!(read(&mut vm), "nil");
+        std::env::remove_var(&name);
+    }
+
+    #[test]
+    fn structured_errors() {
+        let mut vm = VM::new();
+        match vm.interpret("var n; fun f() {\n  return -n;\n}\nf();") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand to '-' must be a number, got nil.");
+                let trace: Vec<(Option<&str>, Option<usize>)> = error
+                    .trace
+                    .iter()
+                    .map(|frame| (frame.function.as_deref(), frame.line))
+                    .collect();
+                assert_eq!(trace, vec![(Some("f"), Some(2)), (None, Some(4))]);
+                assert_eq!(
+                    error.to_string(),
+                    "Operand to '-' must be a number, got nil.\n[line 2] in f()\n[line 4] in <script>"
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        match vm.interpret("print 1 +;\nprint 2") {
+            Err(LoxError::Compile(errors)) => {
+                let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+                assert_eq!(lines, vec![1, 2]);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
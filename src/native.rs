@@ -0,0 +1,539 @@
+// Native functions are implemented in Rust and called from Lox like any other function.
+// Like `Function`, they are saved to a list in the VM, while `Value::Native` stores the index.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    class::Instance,
+    encoding,
+    error::LoxError,
+    function::Function,
+    glob::Pattern,
+    interner::StringObjIdx,
+    value::{
+        as_f64, as_i64, as_index, is_falsey, range_len, type_name, values_equal, TypeTag, Value,
+    },
+    vm::VM,
+};
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> NativeResult;
+pub type NativeResult = Result<Value, NativeError>;
+
+pub enum NativeError {
+    Message(String),     // reported as a runtime error at the call site
+    Propagate(LoxError), // already reported, e.g. by a callback called with `VM::call_function`
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> NativeError {
+        NativeError::Message(message)
+    }
+}
+
+impl From<LoxError> for NativeError {
+    fn from(error: LoxError) -> NativeError {
+        NativeError::Propagate(error)
+    }
+}
+
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: u8, // # of parameters
+    pub function: NativeFn,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: u8, function: NativeFn) -> NativeFunction {
+        NativeFunction {
+            name: name.to_owned(),
+            arity,
+            function,
+        }
+    }
+}
+
+// The natives every VM starts with
+pub fn define_stdlib(vm: &mut VM) {
+    vm.define_native("clock", 0, clock);
+    vm.define_native("lastError", 0, last_error);
+    vm.define_native("printErr", 1, print_err);
+    vm.define_native("benchmark", 2, benchmark);
+    vm.define_native("bool", 1, bool_native);
+    vm.define_native("len", 1, len);
+    vm.define_native("copy", 1, copy);
+    vm.define_native("clone", 1, clone);
+    vm.define_native("freeze", 1, freeze);
+    vm.define_native("deepEquals", 2, deep_equals);
+    vm.define_native("compare", 2, compare);
+    vm.define_native("startsWith", 2, starts_with);
+    vm.define_native("endsWith", 2, ends_with);
+    vm.define_native("match", 2, match_native);
+    vm.define_native("find", 2, find);
+    vm.define_native("charCodeAt", 2, char_code_at);
+    vm.define_native("fromCharCode", 1, from_char_code);
+    vm.define_native("toHex", 1, to_hex);
+    vm.define_native("fromHex", 1, from_hex);
+    vm.define_native("toBase64", 1, to_base64);
+    vm.define_native("fromBase64", 1, from_base64);
+    vm.define_native("arity", 1, arity);
+    vm.define_native("name", 1, name);
+    vm.define_native("hasMethod", 2, has_method);
+    vm.define_native("env", 1, env);
+    vm.define_native("argCount", 0, arg_count);
+    vm.define_native("arg", 1, arg);
+    vm.define_namespace(
+        "math",
+        &[
+            ("sqrt", 1, math_sqrt),
+            ("floor", 1, math_floor),
+            ("ceil", 1, math_ceil),
+            ("abs", 1, math_abs),
+        ],
+    );
+    define_builtin_methods(vm);
+}
+
+// Methods of strings, numbers and ranges, e.g. `"abc".len()` or `(3.7).floor()`. They are the
+// natives of the same name, called with the receiver as the first argument.
+fn define_builtin_methods(vm: &mut VM) {
+    let methods: [(TypeTag, &str, u8, NativeFn); 12] = [
+        (TypeTag::String, "len", 0, len),
+        (TypeTag::String, "startsWith", 1, starts_with),
+        (TypeTag::String, "endsWith", 1, ends_with),
+        (TypeTag::String, "match", 1, match_native),
+        (TypeTag::String, "find", 1, find),
+        (TypeTag::String, "charCodeAt", 1, char_code_at),
+        (TypeTag::String, "toHex", 0, to_hex),
+        (TypeTag::Number, "abs", 0, math_abs),
+        (TypeTag::Number, "floor", 0, math_floor),
+        (TypeTag::Number, "ceil", 0, math_ceil),
+        (TypeTag::Number, "sqrt", 0, math_sqrt),
+        (TypeTag::Range, "len", 0, len),
+    ];
+    for (tag, name, arity, function) in methods {
+        vm.define_method(tag, name, arity, function);
+    }
+}
+
+fn number_arg(args: &[Value], idx: usize, fn_name: &str) -> Result<f64, String> {
+    as_f64(&args[idx]).ok_or_else(|| format!("{}() expects a number.", fn_name))
+}
+
+fn string_arg<'vm>(
+    vm: &'vm VM,
+    args: &[Value],
+    idx: usize,
+    fn_name: &str,
+) -> Result<&'vm str, String> {
+    match args[idx] {
+        Value::StringObj(s) => Ok(vm.interner.lookup(s)),
+        other => Err(format!(
+            "{}() expects a string, got {}.",
+            fn_name,
+            type_name(&other)
+        )),
+    }
+}
+
+fn clock(_vm: &mut VM, _args: &[Value]) -> NativeResult {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+// Truthiness of any value, e.g. `if (bool(name))` under --strict-bool
+fn bool_native(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Bool(!is_falsey(&args[0])))
+}
+
+// `copy(v)`: a new instance with copies of the fields, which are copied the same way.
+// Instances reachable twice, e.g. in a cycle, are copied once. Instances are the only mutable
+// values, the others are returned as they are.
+fn copy(vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(copy_value(vm, args[0], &mut HashMap::new()))
+}
+
+// `copies` maps the instances copied so far to their copy
+fn copy_value(vm: &mut VM, value: Value, copies: &mut HashMap<usize, usize>) -> Value {
+    let instance = match value {
+        Value::Instance(instance) => instance,
+        _ => return value,
+    };
+    if let Some(&copied) = copies.get(&instance) {
+        return Value::Instance(copied);
+    }
+    let class = vm.instances[instance].class;
+    vm.instances.push(Instance::new(class));
+    let copied = vm.instances.len() - 1;
+    copies.insert(instance, copied);
+    for (name, value) in fields_of(vm, instance) {
+        let value = copy_value(vm, value, copies);
+        vm.instances[copied].fields.insert(name, value);
+    }
+    Value::Instance(copied)
+}
+
+// The fields of an instance, for the walks of `copy_value` and `deep_equal` through the
+// instances reachable from a value
+fn fields_of(vm: &VM, instance: usize) -> Vec<(StringObjIdx, Value)> {
+    vm.instances[instance]
+        .fields
+        .iter()
+        .map(|(&name, &value)| (name, value))
+        .collect()
+}
+
+// `clone(v)`: a new instance with the same fields, which aren't copied. Other values are
+// returned as they are, like with `copy`.
+fn clone(vm: &mut VM, args: &[Value]) -> NativeResult {
+    match args[0] {
+        Value::Instance(instance) => {
+            let mut cloned = vm.instances[instance].clone();
+            cloned.frozen = false;
+            vm.instances.push(cloned);
+            Ok(Value::Instance(vm.instances.len() - 1))
+        }
+        other => Ok(other),
+    }
+}
+
+// `freeze(v)`: setting a field of the instance becomes a runtime error. The values of its fields
+// aren't frozen. Returns the value, every other value is already immutable.
+fn freeze(vm: &mut VM, args: &[Value]) -> NativeResult {
+    if let Value::Instance(instance) = args[0] {
+        vm.instances[instance].frozen = true;
+    }
+    Ok(args[0])
+}
+
+// `deepEquals(a, b)`: structural equality for tests, see `deep_equal`
+fn deep_equals(vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Bool(deep_equal(
+        vm,
+        args[0],
+        args[1],
+        &mut HashMap::new(),
+    )))
+}
+
+// Like `values_equal`, but NaN equals NaN, so results of the same computation compare equal in
+// tests, and instances of the same class are equal if their fields are. Functions, closures,
+// natives and classes fall back to identity.
+// `pairs` maps the instances of `a` compared so far to their counterpart in `b`, so cycles end
+// and an instance reachable twice must be so in both values, like in a `copy`.
+fn deep_equal(vm: &VM, a: Value, b: Value, pairs: &mut HashMap<usize, usize>) -> bool {
+    match (a, b) {
+        (Value::Instance(x), Value::Instance(y)) => {
+            if let Some(&paired) = pairs.get(&x) {
+                return paired == y;
+            }
+            let (x_instance, y_instance) = (&vm.instances[x], &vm.instances[y]);
+            if x_instance.class != y_instance.class
+                || x_instance.fields.len() != y_instance.fields.len()
+            {
+                return false;
+            }
+            pairs.insert(x, y);
+            fields_of(vm, x).into_iter().all(|(name, value)| {
+                match vm.instances[y].fields.get(&name) {
+                    Some(&other) => deep_equal(vm, value, other, pairs),
+                    None => false,
+                }
+            })
+        }
+        _ => match (as_f64(&a), as_f64(&b)) {
+            (Some(x), Some(y)) if x.is_nan() || y.is_nan() => x.is_nan() && y.is_nan(),
+            _ => values_equal(a, b),
+        },
+    }
+}
+
+// `compare(a, b)`: -1, 0 or 1 as `a` orders before, like or after `b`, e.g. for sorts written
+// in Lox. Numbers equal by `==` compare as 0, strings compare by their characters.
+fn compare(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let (a, b) = (args[0], args[1]);
+    let ordering = match (a, b) {
+        (Value::StringObj(x), Value::StringObj(y)) => {
+            vm.interner.lookup(x).cmp(vm.interner.lookup(y))
+        }
+        _ => match (as_f64(&a), as_f64(&b)) {
+            (Some(_), Some(_)) if values_equal(a, b) => Ordering::Equal,
+            (Some(x), Some(y)) => x
+                .partial_cmp(&y)
+                .ok_or_else(|| "compare() can't order nan.".to_owned())?,
+            _ => {
+                let msg = format!(
+                    "compare() expects two numbers or two strings, got {} and {}.",
+                    type_name(&a),
+                    type_name(&b)
+                );
+                return Err(msg.into());
+            }
+        },
+    };
+    Ok(Value::Number(match ordering {
+        Ordering::Less => -1.0,
+        Ordering::Equal => 0.0,
+        Ordering::Greater => 1.0,
+    }))
+}
+
+// Number of elements of a range, or chars of a string
+fn len(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let len = match args[0] {
+        Value::Range(start, end) => range_len(start, end),
+        Value::StringObj(s) => vm.interner.lookup(s).chars().count(),
+        other => {
+            return Err(format!("Can't take the length of a {}.", type_name(&other)).into());
+        }
+    };
+    Ok(Value::Number(len as f64))
+}
+
+fn starts_with(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = string_arg(vm, args, 0, "startsWith")?;
+    let prefix = string_arg(vm, args, 1, "startsWith")?;
+    Ok(Value::Bool(s.starts_with(prefix)))
+}
+
+fn ends_with(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = string_arg(vm, args, 0, "endsWith")?;
+    let suffix = string_arg(vm, args, 1, "endsWith")?;
+    Ok(Value::Bool(s.ends_with(suffix)))
+}
+
+// `match(s, pattern)`: whether the glob pattern (see `glob.rs`) matches all of `s`
+fn match_native(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = string_arg(vm, args, 0, "match")?;
+    let pattern = Pattern::parse(string_arg(vm, args, 1, "match")?)?;
+    Ok(Value::Bool(pattern.matches(s)))
+}
+
+// `find(s, pattern)`: index (in chars) of the first match of the glob pattern in `s`, or nil
+fn find(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = string_arg(vm, args, 0, "find")?;
+    let pattern = Pattern::parse(string_arg(vm, args, 1, "find")?)?;
+    Ok(pattern
+        .find(s)
+        .map_or(Value::Nil, |idx| Value::Number(idx as f64)))
+}
+
+// `charCodeAt(s, i)`: the Unicode scalar value of the char at index `i`
+fn char_code_at(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let s = string_arg(vm, args, 0, "charCodeAt")?;
+    let idx = as_index(&args[1], "charCodeAt() index", s.chars().count())?;
+    let c = s.chars().nth(idx).unwrap();
+    Ok(Value::Number(f64::from(u32::from(c))))
+}
+
+// `fromCharCode(n)`: the one-char string of a Unicode scalar value, e.g. not a surrogate
+fn from_char_code(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let n = as_i64(&args[0], "fromCharCode() argument")?;
+    let c = u32::try_from(n)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("fromCharCode() expects a Unicode scalar value, got {}.", n))?;
+    let s = vm.interner.intern_string(c.to_string());
+    Ok(Value::StringObj(s))
+}
+
+// A string of the decoded bytes, which have to be UTF-8 like every string
+fn decoded_string(vm: &mut VM, bytes: Vec<u8>, fn_name: &str) -> NativeResult {
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(Value::StringObj(vm.interner.intern_string(s))),
+        Err(e) => Err(format!(
+            "{}() decoded invalid UTF-8 at byte {}.",
+            fn_name,
+            e.utf8_error().valid_up_to()
+        )
+        .into()),
+    }
+}
+
+// `toHex(s)`: the UTF-8 bytes of `s` as lowercase hex digits
+fn to_hex(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let hex = encoding::to_hex(string_arg(vm, args, 0, "toHex")?.as_bytes());
+    Ok(Value::StringObj(vm.interner.intern_string(hex)))
+}
+
+fn from_hex(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let bytes = encoding::from_hex(string_arg(vm, args, 0, "fromHex")?)?;
+    decoded_string(vm, bytes, "fromHex")
+}
+
+fn to_base64(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let base64 = encoding::to_base64(string_arg(vm, args, 0, "toBase64")?.as_bytes());
+    Ok(Value::StringObj(vm.interner.intern_string(base64)))
+}
+
+fn from_base64(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let bytes = encoding::from_base64(string_arg(vm, args, 0, "fromBase64")?)?;
+    decoded_string(vm, bytes, "fromBase64")
+}
+
+// The function behind a function, closure or bound method value
+fn lox_function(vm: &VM, value: Value) -> Option<&Function> {
+    match value {
+        Value::Function(f_idx) => Some(&vm.functions[f_idx]),
+        Value::Closure(c_idx) => Some(&vm.functions[vm.closures[c_idx].f_idx]),
+        Value::BoundMethod(b_idx) => lox_function(vm, vm.bound_methods[b_idx].method),
+        _ => None,
+    }
+}
+
+fn not_callable(fn_name: &str, value: Value) -> NativeError {
+    format!(
+        "{}() expects a function, got {}.",
+        fn_name,
+        type_name(&value)
+    )
+    .into()
+}
+
+// `arity(f)`: # of parameters of a function, closure, bound method, native or class (the ones
+// of its `init` method)
+fn arity(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let arity = match args[0] {
+        Value::Native(n_idx) => vm.natives[n_idx].arity,
+        Value::Class(class) => match vm.classes[class].initializer(&vm.interner) {
+            Some(init) => lox_function(vm, init).map_or(0, |init| init.arity),
+            None => 0,
+        },
+        other => match lox_function(vm, other) {
+            Some(function) => function.arity,
+            None => return Err(not_callable("arity", other)),
+        },
+    };
+    Ok(Value::Number(arity as f64))
+}
+
+// `name(f)`: declared name of a function, closure, bound method, native (e.g. "math.sqrt") or
+// class
+fn name(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let name = match args[0] {
+        Value::Native(n_idx) => {
+            let name = vm.natives[n_idx].name.clone();
+            Some(vm.interner.intern(&name))
+        }
+        Value::Class(class) => Some(vm.classes[class].name),
+        other => match lox_function(vm, other) {
+            Some(function) => function.name,
+            None => return Err(not_callable("name", other)),
+        },
+    };
+    Ok(name.map_or(Value::Nil, Value::StringObj))
+}
+
+// `hasMethod(obj, "m")`: whether the class of an instance has a method `m`, its own or an
+// inherited one (they are copied to the subclass). Fields holding functions don't count.
+fn has_method(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let class = match args[0] {
+        Value::Instance(instance) => vm.instances[instance].class,
+        other => {
+            return Err(format!(
+                "hasMethod() expects an instance, got {}.",
+                type_name(&other)
+            )
+            .into())
+        }
+    };
+    let name = string_arg(vm, args, 1, "hasMethod")?;
+    let found = match vm.interner.get(name) {
+        Some(name) => vm.classes[class].methods.contains_key(&name),
+        None => false,
+    };
+    Ok(Value::Bool(found))
+}
+
+// Like `print`, but to the error stream, e.g. for diagnostics of a script used as a filter
+fn print_err(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let line = vm.format_value(&args[0]);
+    vm.output.err.write_line(&line);
+    Ok(Value::Nil)
+}
+
+// benchmark(f, iterations) calls `f` once to warm up, then `iterations` times,
+// prints the min/max/mean/median duration of the timed calls and returns the mean, in milliseconds
+fn benchmark(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let f = args[0];
+    let iterations = match as_i64(&args[1], "Iteration count") {
+        Ok(n) if n > 0 => n as usize,
+        Ok(_) => {
+            return Err("benchmark() expects a positive iteration count."
+                .to_owned()
+                .into())
+        }
+        Err(msg) => return Err(msg.into()),
+    };
+
+    vm.call_function(f, &[])?;
+    let mut times = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        vm.call_function(f, &[])?;
+        times.push(start.elapsed().as_secs_f64() * 1000.0);
+    }
+
+    times.sort_by(|a, b| a.total_cmp(b));
+    let mean = times.iter().sum::<f64>() / iterations as f64;
+    let median = if iterations % 2 == 0 {
+        (times[iterations / 2 - 1] + times[iterations / 2]) / 2.0
+    } else {
+        times[iterations / 2]
+    };
+    let line = format!(
+        "benchmark: {} iterations, min {:.6} ms, max {:.6} ms, mean {:.6} ms, median {:.6} ms",
+        iterations,
+        times[0],
+        times[iterations - 1],
+        mean,
+        median
+    );
+    vm.output.out.write_line(&line);
+    Ok(Value::Number(mean))
+}
+
+// Message of the runtime error that ended the previous run, or nil
+fn last_error(vm: &mut VM, _args: &[Value]) -> NativeResult {
+    Ok(vm.last_error_value())
+}
+
+// `env(name)`: the value of an environment variable, or nil if it isn't set.
+// Reads `VM::environment`, which can be a fixed map instead of the process environment.
+fn env(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let name = string_arg(vm, args, 0, "env")?;
+    Ok(match vm.environment.get(name) {
+        Some(value) => Value::string(&mut vm.interner, &value),
+        None => Value::Nil,
+    })
+}
+
+// `argCount()` and `arg(i)`: the program arguments, see `VM::set_args`
+fn arg_count(vm: &mut VM, _args: &[Value]) -> NativeResult {
+    Ok(Value::Number(vm.args.len() as f64))
+}
+
+fn arg(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let idx = as_index(&args[0], "arg() index", vm.args.len())?;
+    let arg = vm.args[idx].clone();
+    Ok(Value::string(&mut vm.interner, &arg))
+}
+
+fn math_sqrt(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number_arg(args, 0, "math.sqrt")?.sqrt()))
+}
+
+fn math_floor(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number_arg(args, 0, "math.floor")?.floor()))
+}
+
+fn math_ceil(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number_arg(args, 0, "math.ceil")?.ceil()))
+}
+
+fn math_abs(_vm: &mut VM, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number_arg(args, 0, "math.abs")?.abs()))
+}
@@ -0,0 +1,59 @@
+// Where scripts write to: `print` goes to `out`, the `printErr` native to `err`.
+// Embedders (and tests) can capture either stream instead of writing to the process's stdout/stderr.
+use std::io::{self, Write};
+
+pub enum Sink {
+    Stdout,
+    Stderr,
+    Capture(String), // everything written so far
+}
+
+impl Sink {
+    pub fn write_line(&mut self, line: &str) {
+        match self {
+            // a closed pipe shouldn't crash the VM, e.g. `rust-lox script.lox | head -1`
+            Sink::Stdout => {
+                let _ = writeln!(io::stdout(), "{}", line);
+            }
+            Sink::Stderr => {
+                let _ = writeln!(io::stderr(), "{}", line);
+            }
+            Sink::Capture(buffer) => {
+                buffer.push_str(line);
+                buffer.push('\n');
+            }
+        }
+    }
+
+    // Captured text, empty if the sink writes to the process's streams
+    pub fn captured(&self) -> &str {
+        match self {
+            Sink::Capture(buffer) => buffer,
+            _ => "",
+        }
+    }
+}
+
+pub struct Output {
+    pub out: Sink,
+    pub err: Sink,
+}
+
+impl Output {
+    // Both streams are kept in memory
+    pub fn capture() -> Output {
+        Output {
+            out: Sink::Capture(String::new()),
+            err: Sink::Capture(String::new()),
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        Output {
+            out: Sink::Stdout,
+            err: Sink::Stderr,
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// Path handling that behaves the same for Windows-style and Unix-style spellings,
+// e.g. to recognize a file that is reached through different paths.
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+
+// Lexically normalizes a path: both '/' and '\' separate components, '.' components are
+// dropped and '..' removes the previous component (but never goes above the root).
+// The result uses '/' as separator. Drive letters are upper case, and the verbatim prefix of
+// canonicalized Windows paths is removed where that doesn't change the meaning, i.e.
+// `\\?\C:\x` becomes `C:/x` and `\\?\UNC\server\share` becomes `//server/share`.
+pub fn normalize(path: &str) -> String {
+    let path = strip_verbatim(path);
+    let path = path.as_ref();
+    let is_sep = |c: char| c == '/' || c == '\\';
+
+    // the part that `..` can't remove: a drive, a UNC share and/or a root separator
+    let mut prefix = String::new();
+    let mut rest = path;
+    if rest.starts_with("//") || rest.starts_with("\\\\") {
+        // \\server\share
+        let mut parts = rest[2..].splitn(3, is_sep);
+        let server = parts.next().unwrap_or("");
+        let share = parts.next().unwrap_or("");
+        prefix = format!("//{}/{}", server, share);
+        rest = parts.next().unwrap_or("");
+        if !rest.is_empty() {
+            prefix.push('/');
+        }
+    } else {
+        let bytes = rest.as_bytes();
+        if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic() {
+            prefix.push((bytes[0] as char).to_ascii_uppercase());
+            prefix.push(':');
+            rest = &rest[2..];
+        }
+        if rest.starts_with(is_sep) {
+            prefix.push('/');
+        }
+    }
+    let rooted = prefix.ends_with('/');
+
+    let mut components: Vec<&str> = Vec::new();
+    for component in rest.split(is_sep) {
+        match component {
+            "" | "." => {}
+            ".." => match components.last() {
+                Some(&last) if last != ".." => {
+                    components.pop();
+                }
+                // `/..` is `/`, but a relative path keeps its leading `..`
+                _ if rooted => {}
+                _ => components.push(".."),
+            },
+            _ => components.push(component),
+        }
+    }
+    let joined = components.join("/");
+    if prefix.is_empty() && joined.is_empty() {
+        ".".to_owned()
+    } else {
+        prefix + &joined
+    }
+}
+
+fn strip_verbatim(path: &str) -> Cow<'_, str> {
+    if let Some(rest) = path.strip_prefix(r"\\?\") {
+        if let Some(unc) = rest.strip_prefix(r"UNC\") {
+            return Cow::Owned(format!("//{}", unc));
+        }
+        let bytes = rest.as_bytes();
+        if bytes.len() >= 2 && bytes[1] == b':' {
+            return Cow::Borrowed(rest);
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+// Identity of a source file, e.g. for a cache of already loaded files:
+// the same file reached through different paths gets the same key.
+// Falls back to the normalized path if the file can't be resolved (e.g. it doesn't exist).
+pub fn file_key(path: &Path) -> String {
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let key = normalize(&resolved.to_string_lossy());
+    // NTFS paths are case-insensitive
+    if cfg!(windows) {
+        key.to_lowercase()
+    } else {
+        key
+    }
+}
@@ -0,0 +1,257 @@
+// The interactive prompt.
+// Lines starting with ':' are REPL commands (e.g. `:globals`, `:inspect expr`, `:type expr`,
+// `:disasm-last`, `:set`) instead of Lox code.
+// A line that is a single expression without ';' is evaluated and its value shown, e.g. `1 + 2`.
+// After every line, `_lastError` holds the message of its runtime error, or nil.
+// Before the first prompt, the rc file (`~/.loxrc`, or `LOX_RC`) runs if there is one.
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    chunk::OpCode,
+    debug::{chunk_title, disassemble},
+    error::LoxError,
+    value::{format_value_truncated, type_name, Precision, Value},
+    vm::VM,
+};
+
+// How the REPL shows the values of expressions, changed with `:set` for the session.
+// Output of the program itself (`print`) isn't affected. Kept in `VM::host_data`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub precision: Precision,
+    pub show_types: bool, // e.g. `number: 3` instead of `3`
+    // longer values are cut, so a huge string doesn't flood the terminal; None shows everything
+    pub max_length: Option<usize>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            precision: Precision::Auto,
+            show_types: false,
+            max_length: Some(DEFAULT_MAX_LENGTH),
+        }
+    }
+}
+
+pub const DEFAULT_MAX_LENGTH: usize = 4096; // bytes
+
+// Statements that could also start an expression statement, e.g. `print 1` without ';'
+const STATEMENT_KEYWORDS: &[&str] = &["print", "var", "fun", "if", "while", "for", "return"];
+
+pub fn run(vm: &mut VM) {
+    vm.enable_source_map();
+    if let Some(path) = rc_path() {
+        load_rc(vm, &path);
+    }
+    // char line[1024];
+    let mut buffer = String::new();
+    let stdin = io::stdin();
+
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("Could not flush stdout");
+        buffer.clear();
+        match stdin.read_line(&mut buffer) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {
+                let line = buffer.trim_start();
+                if let Some(cmd) = line.strip_prefix(':') {
+                    print!("{}", command(vm, cmd.trim()));
+                } else {
+                    eval(vm, &buffer);
+                }
+            }
+        }
+    }
+}
+
+// The file named by the LOX_RC environment variable, or `.loxrc` in the home directory
+pub fn rc_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("LOX_RC") {
+        return Some(PathBuf::from(path));
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".loxrc"))
+}
+
+// Runs the rc file, if it exists, as a prelude (see `VM::run_prelude`).
+// Its errors are reported, but the REPL still starts.
+pub fn load_rc(vm: &mut VM, path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+    // compile and runtime errors are already reported by the VM
+    if let Err(error @ LoxError::Io { .. }) = vm.run_prelude(path) {
+        eprintln!("{}", error);
+    }
+}
+
+// Interprets a line of Lox code, or evaluates an expression and shows its value
+pub fn eval(vm: &mut VM, source: &str) {
+    // errors are already reported by the VM, keep the REPL running
+    if is_expression(source) {
+        if let Ok(value) = vm.evaluate(source) {
+            let settings = *vm.host_data::<Settings>();
+            let max_length = settings.max_length.unwrap_or(usize::MAX);
+            let mut echo = match value {
+                // named by the VM, and short
+                Value::Class(_) | Value::Instance(_) => vm.format_value(&value),
+                _ => format_value_truncated(&value, &vm.interner, settings.precision, max_length),
+            };
+            if settings.show_types {
+                echo = format!("{}: {}", type_name(&value), echo);
+            }
+            vm.output.out.write_line(&echo);
+        }
+    } else {
+        let _ = vm.interpret_from(source, "<repl>");
+    }
+    let last_error = vm.last_error_value();
+    vm.set_global("_lastError", last_error);
+}
+
+// Statements end with ';' or '}', anything else is taken as an expression to show.
+// Statements that don't start an expression are still compiled as such, for their error messages.
+fn is_expression(source: &str) -> bool {
+    let source = source.trim();
+    let first_word = source
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or("");
+    !source.is_empty()
+        && !source.ends_with(';')
+        && !source.ends_with('}')
+        && !source.starts_with('{')
+        && !STATEMENT_KEYWORDS.contains(&first_word)
+}
+
+// Runs a REPL command (without the leading ':') and returns its output
+pub fn command(vm: &mut VM, cmd: &str) -> String {
+    let (name, argument) = cmd.split_once(char::is_whitespace).unwrap_or((cmd, ""));
+    match name {
+        "globals" => globals(vm),
+        "inspect" => inspect(vm, argument.trim()),
+        "type" => type_of(vm, argument.trim()),
+        "disasm-last" => disasm_last(vm),
+        "reset" => reset(vm, argument.trim()),
+        "set" => set(vm, argument.trim()),
+        "" => "Expect a command after ':'.\n".to_owned(),
+        other => format!("Unknown command ':{}'.\n", other),
+    }
+}
+
+// `:inspect expr` evaluates the expression and shows its value in detail
+fn inspect(vm: &mut VM, expression: &str) -> String {
+    match vm.evaluate(expression) {
+        Ok(value) => vm.inspect(&value),
+        Err(_) => String::new(), // already reported by the VM
+    }
+}
+
+// `:type expr` evaluates the expression and shows the type of its value.
+// Expressions that assign a global variable or a field, or that call a function (which could do
+// anything), are refused, to keep the session unchanged.
+fn type_of(vm: &mut VM, expression: &str) -> String {
+    if expression.is_empty() {
+        return "Usage: :type <expression>\n".to_owned();
+    }
+    let f_idx = match vm.compile_source(expression, Some("<repl>"), true) {
+        Ok(f_idx) => f_idx,
+        Err(_) => return String::new(), // already reported by the VM
+    };
+    let refused = vm.functions[f_idx]
+        .chunk
+        .code()
+        .iter()
+        .find_map(|op| match op {
+            OpCode::SetGlobal(_) | OpCode::SetProperty(_) => {
+                Some("assignments to global variables or fields")
+            }
+            OpCode::Call(_) => Some("calls"),
+            _ => None,
+        });
+    if let Some(refused) = refused {
+        return format!(":type doesn't evaluate {}.\n", refused);
+    }
+    match vm.run_compiled(f_idx) {
+        Ok(value) => format!("{}\n", type_name(&value)),
+        Err(_) => String::new(), // already reported by the VM
+    }
+}
+
+// `:disasm-last` shows the bytecode of the last line of Lox code (not of commands like `:type`)
+fn disasm_last(vm: &VM) -> String {
+    match vm.last_script {
+        Some(f_idx) => {
+            let function = &vm.functions[f_idx];
+            let title = chunk_title(function, &vm.interner);
+            disassemble(&function.chunk, &title, &vm.interner)
+        }
+        None => "Nothing was evaluated yet.\n".to_owned(),
+    }
+}
+
+// `:set precision <1-17|auto>`, `:set showtypes <on|off>` and `:set maxlength <bytes|off>`
+// change how values are shown, `:set` lists the settings
+fn set(vm: &mut VM, argument: &str) -> String {
+    let settings = vm.host_data::<Settings>();
+    let (name, value) = argument
+        .split_once(char::is_whitespace)
+        .map_or((argument, ""), |(name, value)| (name, value.trim()));
+    match (name, value) {
+        ("", _) => {
+            let precision = match settings.precision {
+                Precision::Auto => "auto".to_owned(),
+                Precision::Significant(digits) => digits.to_string(),
+            };
+            let show_types = if settings.show_types { "on" } else { "off" };
+            let max_length = match settings.max_length {
+                Some(bytes) => bytes.to_string(),
+                None => "off".to_owned(),
+            };
+            return format!(
+                "precision {}\nshowtypes {}\nmaxlength {}\n",
+                precision, show_types, max_length
+            );
+        }
+        ("precision", "auto") => settings.precision = Precision::Auto,
+        ("precision", digits) => match digits.parse() {
+            Ok(digits @ 1..=17) => settings.precision = Precision::Significant(digits),
+            _ => return "Usage: :set precision <1-17|auto>\n".to_owned(),
+        },
+        ("showtypes", "on") => settings.show_types = true,
+        ("showtypes", "off") => settings.show_types = false,
+        ("showtypes", _) => return "Usage: :set showtypes <on|off>\n".to_owned(),
+        ("maxlength", "off") => settings.max_length = None,
+        ("maxlength", bytes) => match bytes.parse() {
+            Ok(bytes) if bytes > 0 => settings.max_length = Some(bytes),
+            _ => return "Usage: :set maxlength <bytes|off>\n".to_owned(),
+        },
+        (other, _) => return format!("Unknown setting '{}'.\n", other),
+    }
+    String::new()
+}
+
+// `:reset soft` removes the globals defined in the session, natives and preludes stay defined
+fn reset(vm: &mut VM, mode: &str) -> String {
+    match mode {
+        "soft" => {
+            vm.reset_user_state();
+            "Global variables cleared.\n".to_owned()
+        }
+        _ => "Usage: :reset soft\n".to_owned(),
+    }
+}
+
+// One `name = value` line per global, in definition order
+fn globals(vm: &VM) -> String {
+    vm.globals_iter()
+        .map(|(name, value)| format!("{} = {}\n", name, vm.format_value(value)))
+        .collect()
+}
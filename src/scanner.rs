@@ -1,22 +1,188 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::warnings;
+
+// Keywords are looked up for most identifiers, with a hash that is cheap for short words
+type KeywordMap = HashMap<&'static str, TokenType, BuildHasherDefault<FnvHasher>>;
+
+// 64-bit FNV-1a
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Language levels, from oldest to newest. Each level adds keywords to the previous one.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum LangLevel {
+    Classic,  // the keywords of the book
+    Extended, // reserves keywords for the control flow extensions
+}
+
+impl LangLevel {
+    pub fn newest() -> LangLevel {
+        LangLevel::Extended
+    }
+
+    pub fn parse(name: &str) -> Option<LangLevel> {
+        match name {
+            "classic" => Some(LangLevel::Classic),
+            "extended" => Some(LangLevel::Extended),
+            _ => None,
+        }
+    }
+
+    fn next(self) -> Option<LangLevel> {
+        match self {
+            LangLevel::Classic => Some(LangLevel::Extended),
+            LangLevel::Extended => None,
+        }
+    }
+
+    // keywords introduced by this level only
+    fn added_keywords(self) -> &'static [(&'static str, TokenType)] {
+        match self {
+            LangLevel::Classic => &[
+                ("and", TokenType::And),
+                ("class", TokenType::Class),
+                ("else", TokenType::Else),
+                ("false", TokenType::False),
+                ("for", TokenType::For),
+                ("fun", TokenType::Fun),
+                ("if", TokenType::If),
+                ("nil", TokenType::Nil),
+                ("or", TokenType::Or),
+                ("print", TokenType::Print),
+                ("return", TokenType::Return),
+                ("super", TokenType::Super),
+                ("this", TokenType::This),
+                ("true", TokenType::True),
+                ("var", TokenType::Var),
+                ("while", TokenType::While),
+            ],
+            LangLevel::Extended => &[
+                ("break", TokenType::Break),
+                ("case", TokenType::Case),
+                ("continue", TokenType::Continue),
+                ("default", TokenType::Default),
+                ("in", TokenType::In),
+                ("is", TokenType::Is),
+                ("switch", TokenType::Switch),
+            ],
+        }
+    }
+
+    // all keywords of this level
+    fn keywords(self) -> KeywordMap {
+        let mut keywords = KeywordMap::default();
+        let mut level = Some(LangLevel::Classic);
+        while let Some(l) = level.filter(|l| *l <= self) {
+            keywords.extend(l.added_keywords().iter().copied());
+            level = l.next();
+        }
+        keywords
+    }
+}
+
+impl Default for LangLevel {
+    fn default() -> Self {
+        LangLevel::newest()
+    }
+}
+
+// A comment, kept when the scanner collects trivia (see `Scanner::enable_trivia`).
+// It belongs to the token that follows it, e.g. the `fun` of a documented function,
+// and comments at the end of the source belong to the Eof token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia<'src> {
+    pub token: usize, // index of the token in the scanned order, starting at 0
+    pub kind: TriviaKind,
+    pub line: usize,
+    pub text: &'src str, // including the slashes, without the line ending
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriviaKind {
+    Comment, // `// ...`
+    Doc,     // `/// ...`, documents the declaration that follows
+}
+
+pub struct ScanWarning {
+    pub code: &'static str, // see `warnings::CODES`
+    pub line: usize,
+    pub message: String,
+}
+
 pub struct Scanner<'src> {
     start: usize, // beginning of the current lexeme being scanned
     current: usize,
     src: &'src str,
     line: usize,
+    keywords: KeywordMap,
+    // keywords of the next level, which are still identifiers at this level
+    reserved: KeywordMap,
+    // longest word in `keywords` or `reserved`, longer identifiers skip the lookups
+    max_keyword_len: usize,
+    pub warnings: Vec<ScanWarning>,
+    trivia: Option<Vec<Trivia<'src>>>, // comments, only collected if enabled
+    tokens_scanned: usize,
 }
 impl<'src> Scanner<'src> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn with_level(source: &str, level: LangLevel) -> Scanner<'_> {
+        let reserved = match level.next() {
+            Some(next) => next.added_keywords().iter().copied().collect(),
+            None => KeywordMap::default(),
+        };
+        let keywords = level.keywords();
+        let max_keyword_len = keywords
+            .keys()
+            .chain(reserved.keys())
+            .map(|k| k.len())
+            .max();
         Scanner {
             start: 0,
             current: 0,
             src: source,
             line: 1,
+            keywords,
+            reserved,
+            max_keyword_len: max_keyword_len.unwrap_or(0),
+            warnings: Vec::new(),
+            trivia: None,
+            tokens_scanned: 0,
         }
     }
 
+    // Collects the comments instead of only skipping them, for tools that need them
+    // (e.g. a formatter). The compiler doesn't look at them.
+    pub fn enable_trivia(&mut self) {
+        self.trivia.get_or_insert_with(Vec::new);
+    }
+
+    // The comments collected so far, in source order
+    pub fn take_trivia(&mut self) -> Vec<Trivia<'src>> {
+        self.trivia.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
     pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.tokens_scanned += 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -36,20 +202,32 @@ impl<'src> Scanner<'src> {
             b')' => self.make_token(TokenType::RightParen),
             b'{' => self.make_token(TokenType::LeftBrace),
             b'}' => self.make_token(TokenType::RightBrace),
+            b'[' => self.make_token(TokenType::LeftBracket),
+            b']' => self.make_token(TokenType::RightBracket),
             b';' => self.make_token(TokenType::Semicolon),
+            b':' => self.make_token(TokenType::Colon),
             b',' => self.make_token(TokenType::Comma),
+            b'.' if self.check_next(b'.') => self.make_token(TokenType::DotDot),
             b'.' => self.make_token(TokenType::Dot),
             b'-' => self.make_token(TokenType::Minus),
             b'+' => self.make_token(TokenType::Plus),
             b'/' => self.make_token(TokenType::Slash),
+            b'*' if self.check_next(b'*') => self.make_token(TokenType::StarStar),
             b'*' => self.make_token(TokenType::Star),
+            b'%' => self.make_token(TokenType::Percent),
+            b'&' => self.make_token(TokenType::Ampersand),
+            b'|' => self.make_token(TokenType::Pipe),
+            b'^' => self.make_token(TokenType::Caret),
+            b'~' => self.make_token(TokenType::Tilde),
             b'!' if self.check_next(b'=') => self.make_token(TokenType::BangEqual),
             b'!' => self.make_token(TokenType::Bang),
             b'=' if self.check_next(b'=') => self.make_token(TokenType::EqualEqual),
             b'=' => self.make_token(TokenType::Equal),
             b'<' if self.check_next(b'=') => self.make_token(TokenType::LessEqual),
+            b'<' if self.check_next(b'<') => self.make_token(TokenType::LessLess),
             b'<' => self.make_token(TokenType::Less),
             b'>' if self.check_next(b'=') => self.make_token(TokenType::GreaterEqual),
+            b'>' if self.check_next(b'>') => self.make_token(TokenType::GreaterGreater),
             b'>' => self.make_token(TokenType::Greater),
             b'"' => self.string(),
             _ => self.error_token("Unexpected character."),
@@ -75,13 +253,20 @@ impl<'src> Scanner<'src> {
     }
 
     fn peek_next(&self) -> u8 {
-        if self.current > self.src.len() - 2 {
+        if self.current + 1 >= self.src.len() {
             b'\0'
         } else {
             self.src.as_bytes()[self.current + 1]
         }
     }
 
+    // Moves `current` past the run of bytes matching `pred` in one step, instead of a peek/advance
+    // per byte. `pred` must not match line endings, as they wouldn't be counted.
+    fn skip_while(&mut self, pred: impl Fn(u8) -> bool) {
+        let rest = &self.src.as_bytes()[self.current..];
+        self.current += rest.iter().position(|&c| !pred(c)).unwrap_or(rest.len());
+    }
+
     fn check_next(&mut self, expected: u8) -> bool {
         if self.is_at_end() || self.src.as_bytes()[self.current] != expected {
             false
@@ -113,112 +298,109 @@ impl<'src> Scanner<'src> {
         }
     }
 
+    // Consumes a line ending if there is one at `current`.
+    // "\r\n", "\n" and a lone "\r" (classic Mac) all count as a single newline.
+    fn newline(&mut self) -> bool {
+        match self.peek() {
+            b'\r' => {
+                self.advance();
+                self.check_next(b'\n');
+                self.line += 1;
+                true
+            }
+            b'\n' => {
+                self.advance();
+                self.line += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
+            self.skip_while(|c| c == b' ' || c == b'\t');
             match self.peek() {
-                b' ' | b'\r' | b'\t' => {
-                    self.advance();
+                b'\r' | b'\n' => {
+                    self.newline();
                 }
-                b'\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    }
-                    break;
+                b'/' if self.peek_next() == b'/' => {
+                    // A comment goes until the end of the line.
+                    let start = self.current;
+                    self.skip_while(|c| !is_eol(c));
+                    self.add_trivia(start);
                 }
                 _ => return,
             }
         }
     }
 
-    fn check_keyword(
-        &self,
-        start: usize,
-        length: usize,
-        rest: &str,
-        token_type: TokenType,
-    ) -> TokenType {
-        if self.current - self.start == start + length
-            && &self.src[self.start + start..self.start + start + length] == rest
-        {
-            return token_type;
+    fn add_trivia(&mut self, start: usize) {
+        if let Some(trivia) = &mut self.trivia {
+            let text = &self.src[start..self.current];
+            // `////` is a plain comment, e.g. a separator line
+            let kind = if text.starts_with("///") && !text.starts_with("////") {
+                TriviaKind::Doc
+            } else {
+                TriviaKind::Comment
+            };
+            trivia.push(Trivia {
+                token: self.tokens_scanned,
+                kind,
+                line: self.line,
+                text,
+            });
         }
-        TokenType::Identifier
     }
 
-    fn identifier_type(&self) -> TokenType {
-        match self.src.as_bytes()[self.start] {
-            b'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            b'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            b'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            b'f' if self.current - self.start > 1 => {
-                // lexeme is more than 2 char
-                match self.src.as_bytes()[self.start + 1] {
-                    b'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                    b'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                    b'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
-                    _ => TokenType::Identifier,
-                }
-            }
-            b'i' => self.check_keyword(1, 1, "f", TokenType::If),
-            b'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-            b'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-            b'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            b'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            b's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-            b't' if self.current - self.start > 1 => {
-                // lexeme is more than 2 char
-                match self.src.as_bytes()[self.start + 1] {
-                    b'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                    b'r' => self.check_keyword(2, 2, "ue", TokenType::True),
-                    _ => TokenType::Identifier,
-                }
-            }
-            b'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            b'w' => self.check_keyword(1, 4, "hile", TokenType::While),
-            _ => TokenType::Identifier,
+    fn identifier_type(&mut self) -> TokenType {
+        let src = self.src;
+        let lexeme = &src[self.start..self.current];
+        // keywords are lowercase letters only
+        if lexeme.len() > self.max_keyword_len || !lexeme.bytes().all(|c| c.is_ascii_lowercase()) {
+            return TokenType::Identifier;
+        }
+        if let Some(&token_type) = self.keywords.get(lexeme) {
+            return token_type;
         }
+        if self.reserved.contains_key(lexeme) {
+            // still an identifier at this level, but not for long
+            self.warnings.push(ScanWarning {
+                code: warnings::RESERVED_WORD,
+                line: self.line,
+                message: format!("'{}' will become a reserved word.", lexeme),
+            });
+        }
+        TokenType::Identifier
     }
 
     fn identifier(&mut self) -> Token<'src> {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
-            self.advance();
-        }
-        self.make_token(self.identifier_type())
+        self.skip_while(|c| is_alpha(c) || is_digit(c));
+        let token_type = self.identifier_type();
+        self.make_token(token_type)
     }
 
     fn number(&mut self) -> Token<'src> {
-        while is_digit(self.peek()) {
-            self.advance();
-        }
+        self.skip_while(is_digit);
 
         // Look for a fractional part.
         if self.peek() == b'.' && is_digit(self.peek_next()) {
             // Consume the ".".
             self.advance();
-
-            while is_digit(self.peek()) {
-                self.advance();
-            }
+            self.skip_while(is_digit);
         }
 
         self.make_token(TokenType::Number)
     }
 
+    // Line endings inside a string literal are kept verbatim (including any '\r'),
+    // since the lexeme is a slice of the source. Only the line count is normalized.
     fn string(&mut self) -> Token<'src> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1
-            };
-            self.advance();
+        loop {
+            self.skip_while(|c| c != b'"' && !is_eol(c));
+            if !self.newline() {
+                break;
+            }
         }
 
         if self.is_at_end() {
@@ -256,23 +438,35 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
     Plus,
     Semicolon,
+    Colon,
     Slash,
     Star,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
 
     // One or two character tokens.
+    DotDot,
+    StarStar,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
 
     // Literals.
     Identifier,
@@ -296,6 +490,14 @@ pub enum TokenType {
     True,
     Var,
     While,
+    // Keywords of LangLevel::Extended.
+    Break,
+    Case,
+    Continue,
+    Default,
+    In,
+    Is,
+    Switch,
 
     Error,
     Eof,
@@ -308,3 +510,7 @@ fn is_alpha(c: u8) -> bool {
 fn is_digit(c: u8) -> bool {
     c.is_ascii_digit()
 }
+
+fn is_eol(c: u8) -> bool {
+    c == b'\n' || c == b'\r'
+}
@@ -0,0 +1,64 @@
+// Every source compiled in a session (e.g. each line typed in the REPL), so lines of code compiled
+// earlier can be shown with their position in the whole session and their text.
+// Chunks keep the line in their own submission, the map translates when rendering.
+
+struct Submission {
+    first_line: usize, // session line of its line 1
+    source: String,
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    submissions: Vec<Submission>,
+}
+
+// A line of a submission, see `SourceMap::position`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionPosition {
+    pub submission: usize,   // starting at 1
+    pub session_line: usize, // counting the lines of all submissions, starting at 1
+    pub text: String,        // the line as it was typed
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    // Adds the source after the previous ones, returns its index
+    pub fn register(&mut self, source: &str) -> usize {
+        let first_line = match self.submissions.last() {
+            Some(last) => last.first_line + line_count(&last.source),
+            None => 1,
+        };
+        self.submissions.push(Submission {
+            first_line,
+            source: source.to_owned(),
+        });
+        self.submissions.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.submissions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.submissions.is_empty()
+    }
+
+    // Where `line` (starting at 1) of the submission is in the session
+    pub fn position(&self, submission: usize, line: usize) -> Option<SessionPosition> {
+        let registered = self.submissions.get(submission)?;
+        let text = registered.source.lines().nth(line.checked_sub(1)?)?;
+        Some(SessionPosition {
+            submission: submission + 1,
+            session_line: registered.first_line + line - 1,
+            text: text.trim_end_matches('\r').to_owned(),
+        })
+    }
+}
+
+// A source without a trailing newline still takes up its last line
+fn line_count(source: &str) -> usize {
+    source.lines().count().max(1)
+}
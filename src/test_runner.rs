@@ -0,0 +1,184 @@
+// `rust-lox test <path>`: a built-in runner for tests written in Lox.
+// Every `*_test.lox` file under <path> runs in a fresh VM with the stdlib plus an
+// `expect(actual, expected)` native, which records failures instead of aborting the script.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::LoxError,
+    native::NativeResult,
+    output::Output,
+    value::{values_equal, Value},
+    vm::VM,
+};
+
+const TEST_FILE_SUFFIX: &str = "_test.lox";
+
+pub struct ExpectFailure {
+    pub line: usize, // line of the `expect` call site
+    pub message: String,
+}
+
+// Filled in by the `expect` native while a test file runs, kept in `VM::host_data`
+#[derive(Default)]
+pub struct ExpectReport {
+    pub passed: usize,
+    pub failures: Vec<ExpectFailure>,
+}
+
+pub struct FileResult {
+    pub path: PathBuf,
+    pub report: ExpectReport,
+    pub error: Option<LoxError>, // the file failed to compile or hit a runtime error
+    pub output: Output,          // what the file printed, shown if it failed
+}
+
+impl FileResult {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.report.failures.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct TestSummary {
+    pub files: Vec<FileResult>,
+}
+
+impl TestSummary {
+    pub fn passed(&self) -> usize {
+        self.files.iter().map(|f| f.report.passed).sum()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.files.iter().map(|f| f.report.failures.len()).sum()
+    }
+
+    pub fn errored(&self) -> usize {
+        self.files.iter().filter(|f| f.error.is_some()).count()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.files.iter().all(FileResult::is_success)
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        if self.is_success() {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+// Runs a single test file if `path` is a file, otherwise every test file below the directory
+pub fn run_tests(path: &Path) -> io::Result<TestSummary> {
+    let mut summary = TestSummary::default();
+    for file in discover(path)? {
+        let result = run_test_file(&file)?;
+        print_file_result(&result);
+        summary.files.push(result);
+    }
+
+    println!(
+        "{} files, {} assertions passed, {} failed, {} files with errors",
+        summary.files.len(),
+        summary.passed(),
+        summary.failed(),
+        summary.errored()
+    );
+    Ok(summary)
+}
+
+pub fn run_test_file(path: &Path) -> io::Result<FileResult> {
+    let source = fs::read_to_string(path)?;
+    let mut vm = VM::new();
+    vm.define_native("expect", 2, expect);
+    vm.output = Output::capture();
+    let error = vm.interpret(&source).err();
+
+    Ok(FileResult {
+        path: path.to_owned(),
+        report: vm.take_host_data::<ExpectReport>(),
+        error,
+        output: std::mem::take(&mut vm.output),
+    })
+}
+
+// Test files sorted by path, so the run order is stable
+fn discover(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if entry_path.is_dir() {
+            files.extend(discover(&entry_path)?);
+        } else if entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(TEST_FILE_SUFFIX))
+        {
+            files.push(entry_path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn print_file_result(result: &FileResult) {
+    let status = if result.is_success() { "ok" } else { "FAILED" };
+    println!(
+        "test {} ... {} ({} passed, {} failed)",
+        result.path.display(),
+        status,
+        result.report.passed,
+        result.report.failures.len()
+    );
+    for failure in &result.report.failures {
+        println!("    [line {}] {}", failure.line, failure.message);
+    }
+    match &result.error {
+        Some(LoxError::Compile(_)) => println!("    compile error"),
+        Some(LoxError::Runtime(error)) => println!("    runtime error: {}", error.message),
+        Some(error @ LoxError::Io { .. }) => println!("    {}", error),
+        None => {}
+    }
+    if !result.is_success() {
+        print_captured("stdout", result.output.out.captured());
+        print_captured("stderr", result.output.err.captured());
+    }
+}
+
+fn print_captured(stream: &str, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    println!("    ---- {} ----", stream);
+    for line in text.lines() {
+        println!("    {}", line);
+    }
+}
+
+// expect(actual, expected)
+fn expect(vm: &mut VM, args: &[Value]) -> NativeResult {
+    let (actual, expected) = (args[0], args[1]);
+    let is_equal = values_equal(actual, expected);
+    if is_equal {
+        vm.host_data::<ExpectReport>().passed += 1;
+    } else {
+        let message = format!(
+            "expected {} but got {}",
+            vm.format_value(&expected),
+            vm.format_value(&actual)
+        );
+        let line = vm.current_line();
+        vm.host_data::<ExpectReport>()
+            .failures
+            .push(ExpectFailure { line, message });
+    }
+    Ok(Value::Bool(is_equal))
+}
@@ -0,0 +1,94 @@
+// Execution trace: one line per executed instruction, e.g. to find where a long run goes wrong.
+// `--trace-file`, `--trace-filter` and `--trace-last` of the CLI, see `VM::enable_trace`.
+//
+// A line is the function, the offset and source line of the instruction, and the instruction:
+//     count 7 3 OP_GET_LOCAL 1
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::{
+    chunk::OpCode, crash_report::OpHistory, debug, function::Function, interner::Interner,
+};
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    function: Option<String>, // only instructions run in frames of this function
+    // only the last instructions, written by `flush` (e.g. on a runtime error)
+    last: Option<OpHistory>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Tracer {
+        Tracer {
+            out,
+            function: None,
+            last: None,
+        }
+    }
+
+    pub fn to_file(path: &Path) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file))))
+    }
+
+    // Only traces the frames of the function with this name (`Function::display_name`,
+    // "<script>" for the top-level code)
+    pub fn filter(mut self, function: &str) -> Tracer {
+        self.function = Some(function.to_owned());
+        self
+    }
+
+    // Keeps the last `count` instructions in a ring buffer, instead of writing every one
+    pub fn last(mut self, count: usize) -> Tracer {
+        self.last = Some(OpHistory::with_capacity(count));
+        self
+    }
+
+    pub fn traces(&self, function_name: &str) -> bool {
+        self.function
+            .as_ref()
+            .is_none_or(|function| function == function_name)
+    }
+
+    pub fn record(
+        &mut self,
+        (f_idx, ip, op): (usize, usize, OpCode),
+        functions: &[Function],
+        interner: &Interner,
+    ) {
+        match &mut self.last {
+            Some(last) => last.record(f_idx, ip, op),
+            // a full disk shouldn't stop the program that is traced
+            None => {
+                let _ = writeln!(self.out, "{}", line(&functions[f_idx], ip, &op, interner));
+            }
+        }
+    }
+
+    // Writes the kept instructions, oldest first, and everything buffered so far
+    pub fn flush(&mut self, functions: &[Function], interner: &Interner) {
+        if let Some(last) = &mut self.last {
+            for executed in last.iter() {
+                let function = &functions[executed.f_idx];
+                let text = line(function, executed.ip, &executed.op, interner);
+                let _ = writeln!(self.out, "{}", text);
+            }
+            last.clear();
+        }
+        let _ = self.out.flush();
+    }
+}
+
+fn line(function: &Function, ip: usize, op: &OpCode, interner: &Interner) -> String {
+    format!(
+        "{} {} {} {}",
+        function.display_name(interner),
+        ip,
+        function
+            .chunk
+            .line(ip)
+            .map_or("?".to_owned(), |line| line.to_string()),
+        debug::instruction(&function.chunk, ip, op, interner)
+    )
+}
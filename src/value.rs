@@ -1,24 +1,57 @@
-use crate::interner::Interner;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+use crate::interner::{Interner, StringObjIdx};
 
 static ERR_MARGIN: f64 = f64::EPSILON;
 
 // Enum = tagged union in Rust
 // Ref: http://patshaughnessy.net/2018/3/15/how-rust-implements-tagged-unions
+//
+// Values are Copy handles: a variant holds a scalar or an index into a table owned by the VM
+// (interner, functions, natives), never owned data. The VM relies on this everywhere,
+// e.g. pushing a value copies it and `*self.peek(0)` reads one without popping it.
+// Heap types (lists, instances, ...) must follow the same rule: store the object in a VM-side
+// table and put its index in the variant. The size check below keeps variants small.
 #[derive(Clone, Copy, Debug)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
+    Int(i64), // only with `CompileOptions::integers`, see `numeric_op` in the VM
     // enum and the ref to String are on the stack,
     // while the actual String is stored on the heap
-    StringObj(u32),  // u32 = idx in string intern vec
-    Identifier(u32), // u32 = idx in string intern vec
-    Function(usize), // = idx in the function list in VM
+    StringObj(StringObjIdx),  // idx in string intern vec
+    Identifier(StringObjIdx), // idx in string intern vec
+    Function(usize),          // = idx in the function list in VM
+    Native(usize),            // = idx in the native list in VM
+    Closure(usize),           // = idx in the closure list in VM
+    Class(usize),             // = idx in the class list in VM
+    Instance(usize),          // = idx in the instance list in VM
+    BoundMethod(usize),       // = idx in the bound method list in VM
+    Range(i32, i32),          // start..end, without end; empty if end <= start (also `5..0`)
+}
+
+// A tag and one word of payload
+const _: () = assert!(std::mem::size_of::<Value>() <= 16);
+
+impl Value {
+    // Interns `s` and returns the string value
+    pub fn string(interner: &mut Interner, s: &str) -> Value {
+        Value::StringObj(interner.intern(s))
+    }
 }
 
 // The constant pool is an array of values. The instruction to load a constant looks up the value by index in that array.
+#[derive(Clone)]
 pub struct ValueArray {
-    pub values: Vec<Value>,
+    values: Vec<Value>,
+}
+
+impl Default for ValueArray {
+    fn default() -> ValueArray {
+        ValueArray::new()
+    }
 }
 
 impl ValueArray {
@@ -26,20 +59,334 @@ impl ValueArray {
         ValueArray { values: Vec::new() }
     }
 
-    pub fn write(&mut self, v: Value) {
+    pub fn get(&self, idx: usize) -> Option<&Value> {
+        self.values.get(idx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // Returns the index of the new value
+    pub fn push(&mut self, v: Value) -> usize {
         self.values.push(v);
+        self.values.len() - 1
+    }
+
+    // Replaces every value, keeping the indices
+    pub fn map_in_place(&mut self, f: impl Fn(Value) -> Value) {
+        for value in &mut self.values {
+            *value = f(*value);
+        }
     }
 }
 
 pub fn print_value(value: &Value, interner: &Interner) {
+    print!("{}", debug_value(value, interner));
+}
+
+// Representation of a value with its variant, for the disassembler and execution traces
+pub fn debug_value(value: &Value, interner: &Interner) -> String {
+    match value {
+        Value::Bool(n) => format!("bool: {:?}", n),
+        Value::Nil => "nil".to_owned(),
+        Value::Number(n) => format!("number: {}", format_number(*n)),
+        Value::Int(n) => format!("int: {:?}", n),
+        Value::StringObj(s) => format!("StringObj: {:?}: {}", s, escape(interner.lookup(*s))),
+        Value::Identifier(s) => format!("Identifier: {:?}: {}", s, escape(interner.lookup(*s))),
+        Value::Function(s) => format!("Function id: {:?}", s),
+        Value::Native(s) => format!("Native id: {:?}", s),
+        Value::Closure(s) => format!("Closure id: {:?}", s),
+        Value::Class(s) => format!("Class id: {:?}", s),
+        Value::Instance(s) => format!("Instance id: {:?}", s),
+        Value::BoundMethod(s) => format!("BoundMethod id: {:?}", s),
+        Value::Range(start, end) => format!("range: {}..{}", start, end),
+    }
+}
+
+// Keeps a string on one line, `asm` reverts it
+fn escape(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\\', '\n', '\r', '\t']) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// User-facing representation of a value, e.g. for messages shown to script authors
+pub fn format_value(value: &Value, interner: &Interner) -> String {
+    format_value_with(value, interner, Precision::Auto)
+}
+
+// Like `format_value_with`, cut to at most `max_bytes` (on a char boundary) followed by
+// "... N more bytes". Long strings are cut before they are copied.
+pub fn format_value_truncated(
+    value: &Value,
+    interner: &Interner,
+    precision: Precision,
+    max_bytes: usize,
+) -> String {
+    let text = match value {
+        Value::StringObj(s) | Value::Identifier(s) => Cow::Borrowed(interner.lookup(*s)),
+        _ => Cow::Owned(format_value_with(value, interner, precision)),
+    };
+    if text.len() <= max_bytes {
+        return text.into_owned();
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... {} more bytes", &text[..end], text.len() - end)
+}
+
+// Like `format_value`, with the digits of numbers chosen by the rendering context,
+// e.g. the REPL's `:set precision`
+pub fn format_value_with(value: &Value, interner: &Interner, precision: Precision) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::Nil => "nil".to_owned(),
+        Value::Number(n) => format_number_with(*n, precision),
+        Value::Int(n) => n.to_string(),
+        Value::StringObj(s) | Value::Identifier(s) => interner.lookup(*s).to_owned(),
+        Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) => "<fn>".to_owned(),
+        Value::Native(_) => "<native fn>".to_owned(),
+        Value::Range(start, end) => format!("{}..{}", start, end),
+        // their names are kept by the VM, see `VM::format_value`
+        Value::Class(_) => "<class>".to_owned(),
+        Value::Instance(_) => "<instance>".to_owned(),
+    }
+}
+
+// Value of a number, whether it is a float or an integer
+pub fn as_f64(value: &Value) -> Option<f64> {
+    match *value {
+        Value::Number(n) => Some(n),
+        Value::Int(n) => Some(n as f64),
+        _ => None,
+    }
+}
+
+// Classic Lox truthiness: only nil and false are falsey
+pub fn is_falsey(value: &Value) -> bool {
     match value {
-        Value::Bool(n) => print!("bool: {:?}", n),
-        Value::Nil => print!("nil"),
-        Value::Number(n) => print!("number: {:?}", n),
-        Value::StringObj(s) => print!("StringObj: {:?}: {}", s, interner.lookup(*s)),
-        Value::Identifier(s) => print!("Identifier: {:?}: {}", s, interner.lookup(*s)),
-        Value::Function(s) => print!("Function id: {:?}", s),
+        Value::Bool(b) => !b,
+        Value::Nil => true,
+        _ => false,
+    }
+}
+
+// The types scripts can tell apart, e.g. for `OpCode::IsType`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TypeTag {
+    Nil,
+    Bool,
+    Number, // floats and integers
+    String,
+    Function, // functions, closures, bound methods and natives
+    Range,
+    Class,
+    Instance,
+}
+
+impl TypeTag {
+    pub fn of(value: &Value) -> TypeTag {
+        match value {
+            Value::Bool(_) => TypeTag::Bool,
+            Value::Nil => TypeTag::Nil,
+            Value::Number(_) | Value::Int(_) => TypeTag::Number,
+            Value::StringObj(_) | Value::Identifier(_) => TypeTag::String,
+            Value::Function(_) | Value::Native(_) | Value::Closure(_) | Value::BoundMethod(_) => {
+                TypeTag::Function
+            }
+            Value::Range(..) => TypeTag::Range,
+            Value::Class(_) => TypeTag::Class,
+            Value::Instance(_) => TypeTag::Instance,
+        }
+    }
+
+    // The type as scripts spell it after `is`, e.g. "Number" in `x is Number`
+    pub fn from_type_name(name: &str) -> Option<TypeTag> {
+        match name {
+            "Nil" => Some(TypeTag::Nil),
+            "Bool" => Some(TypeTag::Bool),
+            "Number" => Some(TypeTag::Number),
+            "String" => Some(TypeTag::String),
+            "Function" => Some(TypeTag::Function),
+            "Range" => Some(TypeTag::Range),
+            "Class" => Some(TypeTag::Class),
+            "Instance" => Some(TypeTag::Instance),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TypeTag::Nil => "nil",
+            TypeTag::Bool => "bool",
+            TypeTag::Number => "number",
+            TypeTag::String => "string",
+            TypeTag::Function => "function",
+            TypeTag::Range => "range",
+            TypeTag::Class => "class",
+            TypeTag::Instance => "instance",
+        }
+    }
+}
+
+// Name of the value's type for error messages, e.g. "Index must be a number, got string."
+pub fn type_name(value: &Value) -> &'static str {
+    TypeTag::of(value).name()
+}
+
+// The one rendering of floats, for `print`, error messages and the disassembler.
+// The shortest digits that parse back to the same float: integers without a decimal point,
+// fixed notation in [1e-7, 1e21) (like JavaScript), exponent notation outside of it,
+// e.g. "3", "0.1", "1e21", "5e-324". NaN and infinities are "nan", "inf" and "-inf".
+pub fn format_number(n: f64) -> String {
+    format_number_with(n, Precision::Auto)
+}
+
+// How many digits `format_number_with` shows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Precision {
+    #[default]
+    Auto, // the shortest digits that parse back to the same float
+    Significant(u8), // rounded to this many significant digits (1 to 17), trailing zeros removed
+}
+
+pub fn format_number_with(n: f64, precision: Precision) -> String {
+    let digits = match precision {
+        Precision::Significant(digits) if n.is_finite() && n != 0.0 => usize::from(digits.max(1)),
+        _ => return format_shortest(n),
+    };
+    // rounding can carry into the next power of ten, the exponent of the rounded number counts
+    let scientific = format!("{:.*e}", digits - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-7..21).contains(&exponent) {
+        // digits left of the point beyond the precision are zeros, e.g. 123000
+        let rounded: f64 = scientific.parse().unwrap();
+        let decimals = (digits as i32 - 1 - exponent).max(0) as usize;
+        trim_fraction(&format!("{:.*}", decimals, rounded)).to_owned()
+    } else {
+        format!("{}e{}", trim_fraction(mantissa), exponent)
+    }
+}
+
+// "0.2500" -> "0.25", "3.000" -> "3"
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+fn format_shortest(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_owned()
+    } else if n.is_infinite() {
+        if n > 0.0 { "inf" } else { "-inf" }.to_owned()
+    } else if n == 0.0 || (1e-7..1e21).contains(&n.abs()) {
+        // Display never uses an exponent, and prints -0.0 as "-0"
+        n.to_string()
+    } else {
+        format!("{:e}", n)
+    }
+}
+
+// A float as a JSON number, which can't be NaN or infinite
+pub fn format_json_number(n: f64) -> Result<String, String> {
+    if n.is_finite() {
+        Ok(format_number(n))
+    } else {
+        Err(format!(
+            "JSON can't represent the number {}.",
+            format_number(n)
+        ))
+    }
+}
+
+// Largest integer below which every integer is exactly representable as f64
+const MAX_EXACT_INTEGER: f64 = 9007199254740992.0; // 2^53
+
+// Converts `value` to an index into something of length `len`, e.g. a list or a string.
+// `what` names the operand in the message, e.g. "List index".
+// Used by `OpCode::Index`.
+pub fn as_index(value: &Value, what: &str, len: usize) -> Result<usize, String> {
+    let n = match as_f64(value) {
+        Some(n) => n,
+        None => {
+            return Err(format!(
+                "{} must be a number, got {}.",
+                what,
+                type_name(value)
+            ))
+        }
+    };
+    if !(n >= 0.0 && n.fract() == 0.0 && n < MAX_EXACT_INTEGER) {
+        return Err(format!(
+            "{} must be a non-negative integer, got {}.",
+            what,
+            format_number(n)
+        ));
+    }
+    let idx = n as usize;
+    if idx >= len {
+        return Err(format!(
+            "{} {} is out of bounds for length {}.",
+            what, idx, len
+        ));
+    }
+    Ok(idx)
+}
+
+// Number of values in `start..end`
+pub fn range_len(start: i32, end: i32) -> usize {
+    (i64::from(end) - i64::from(start)).max(0) as usize
+}
+
+// Converts `value` to a bound of a range, which are 32-bit integers
+pub fn as_range_bound(value: &Value) -> Result<i32, String> {
+    let n = as_i64(value, "Range bound")?;
+    i32::try_from(n).map_err(|_| format!("Range bound {} is out of range.", n))
+}
+
+// Converts `value` to an integer, e.g. for shifts and bitwise operators
+pub fn as_i64(value: &Value, what: &str) -> Result<i64, String> {
+    let n = match *value {
+        Value::Int(n) => return Ok(n),
+        Value::Number(n) => n,
+        _ => {
+            return Err(format!(
+                "{} must be a number, got {}.",
+                what,
+                type_name(value)
+            ))
+        }
+    };
+    if !(n.fract() == 0.0 && n.abs() < MAX_EXACT_INTEGER) {
+        return Err(format!(
+            "{} must be an integer, got {}.",
+            what,
+            format_number(n)
+        ));
     }
+    Ok(n as i64)
 }
 
 pub fn values_equal(av: Value, bv: Value) -> bool {
@@ -47,8 +394,23 @@ pub fn values_equal(av: Value, bv: Value) -> bool {
         (Value::Bool(a), Value::Bool(b)) => a == b,
         (Value::Nil, Value::Nil) => true,
         (Value::Number(a), Value::Number(b)) => (a - b).abs() < ERR_MARGIN,
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+            (a as f64 - b).abs() < ERR_MARGIN
+        }
+        // every string is interned, also the results of concatenations and natives,
+        // so equal contents have the same index and comparing the indices is exact
         (Value::StringObj(a), Value::StringObj(b)) => a == b,
         (Value::Function(a), Value::Function(b)) => a == b,
+        (Value::Native(a), Value::Native(b)) => a == b,
+        (Value::Closure(a), Value::Closure(b)) => a == b,
+        (Value::Class(a), Value::Class(b)) => a == b,
+        (Value::Instance(a), Value::Instance(b)) => a == b,
+        // every access of a method binds it anew, so `a.m == a.m` is false like in clox
+        (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+        (Value::Range(a_start, a_end), Value::Range(b_start, b_end)) => {
+            a_start == b_start && a_end == b_end
+        }
         _ => false,
     }
 }
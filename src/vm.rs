@@ -1,81 +1,967 @@
-use crate::compiler::Parser;
+use crate::asm::{self, AsmError};
+use crate::audit::AuditEvent;
+use crate::builder::VmBuilder;
+use crate::class::{BoundMethod, Class, Instance};
+use crate::compilation_unit::CompilationUnit;
+use crate::compile_cache::{CompileCache, CompileSettings};
 use crate::compiler::USIZE_COUNT;
-use crate::function::Function;
-use crate::interner::Interner;
+use crate::compiler::{
+    checks_operands, CompileDiagnostic, CompileOptions, Diagnostics, Parser, Severity,
+};
+use crate::crash_report::{self, OpHistory};
+use crate::environment::Environment;
+use crate::error::{LoxError, RuntimeError, TraceFrame};
+use crate::function::{Closure, Function, Upvalue};
+use crate::gas::CostModel;
+use crate::globals::{GlobalSlot, Globals};
+use crate::interner::{Interner, StringObjIdx};
+use crate::limits::Limits;
+use crate::native::{NativeError, NativeFn, NativeFunction};
+use crate::output::Output;
+use crate::source_map::{SessionPosition, SourceMap};
+use crate::trace::Tracer;
+use crate::warnings::{self, Level, WarningLevels};
 use crate::{
     chunk::OpCode,
-    value::{print_value, values_equal, Value},
+    debug,
+    value::{
+        as_f64, as_i64, as_index, as_range_bound, format_number, format_value_truncated,
+        format_value_with, is_falsey, range_len, type_name, values_equal, Precision, TypeTag,
+        Value,
+    },
 };
-use std::collections::HashMap;
+use std::any::{Any, TypeId};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::mem;
+use std::path::Path;
 
 const STACK_SIZE: usize = FRAMES_MAX * USIZE_COUNT;
-const FRAMES_MAX: usize = 64;
+pub const FRAMES_MAX: usize = 64;
+// Values in runtime type errors are cut to this many bytes, see `describe_value`
+const ERROR_VALUE_WIDTH: usize = 32;
 
 #[derive(Clone, Copy)]
 pub struct CallFrame {
     pub f_idx: usize,
     pub ip: usize,          // ip of the caller (local frame index, not VM index)
-    pub slot_offset: usize, // offset of slots, i.e. starting position of this CallFrame's stack
+    slot_offset: usize,     // offset of slots, i.e. starting position of this CallFrame's stack
+    closure: Option<usize>, // closure being called, whose upvalues the function reads
 }
 
+// Locals are only accessed through the frame's window of the stack, which starts at slot zero
+// (the called function) followed by the arguments and the other locals
 impl CallFrame {
     fn new(f_idx: usize, current_slot: usize) -> Self {
         CallFrame {
             f_idx,
             ip: 0,
             slot_offset: current_slot,
+            closure: None,
         }
     }
+
+    pub fn slot_offset(&self) -> usize {
+        self.slot_offset
+    }
+
+    pub fn slots<'a>(&self, stack: &'a [Value]) -> &'a [Value] {
+        &stack[self.slot_offset..]
+    }
+
+    pub fn slots_mut<'a>(&self, stack: &'a mut [Value]) -> &'a mut [Value] {
+        &mut stack[self.slot_offset..]
+    }
+
+    pub fn slot(&self, stack: &[Value], idx: usize) -> Value {
+        self.check_slot(stack, idx);
+        self.slots(stack)[idx]
+    }
+
+    pub fn set_slot(&self, stack: &mut [Value], idx: usize, value: Value) {
+        self.check_slot(stack, idx);
+        self.slots_mut(stack)[idx] = value;
+    }
+
+    // Discards the frame's window, e.g. when the function returns
+    pub fn close(&self, stack: &mut Vec<Value>) {
+        debug_assert!(
+            self.slot_offset <= stack.len(),
+            "Frame starts at slot {} above the stack of {} values.",
+            self.slot_offset,
+            stack.len()
+        );
+        stack.truncate(self.slot_offset);
+    }
+
+    // Compiled code never reads past its locals, so a slot outside the window is a VM bug
+    fn check_slot(&self, stack: &[Value], idx: usize) {
+        debug_assert!(
+            self.slot_offset + idx < stack.len(),
+            "Slot {} is outside of the frame's window of {} values.",
+            idx,
+            stack.len().saturating_sub(self.slot_offset)
+        );
+    }
+}
+
+// Result of a time-sliced run, see `VM::run_sliced`
+#[derive(Clone, Copy, Debug)]
+pub enum RunState {
+    Finished(Value), // what the top-level function returned
+    Paused,          // out of budget, `VM::resume` continues
 }
 
 pub struct VM {
     pub frames: Vec<CallFrame>,
     pub interner: Interner,
     pub stack: Vec<Value>,
-    pub globals: HashMap<u32, Value>, // u32 is interner idx
+    pub globals: Globals,
     pub functions: Vec<Function>,
+    // compiled bodies of the functions a redefinition replaced, by index, see `redefine_function`
+    replaced_bodies: HashMap<usize, Function>,
+    pub natives: Vec<NativeFunction>,
+    // natives called as methods of values that aren't instances, e.g. `"abc".len()`, by the
+    // receiver's type and the method name, see `define_method`
+    pub builtin_methods: HashMap<(TypeTag, StringObjIdx), usize>,
+    pub closures: Vec<Closure>,
+    pub classes: Vec<Class>,
+    pub instances: Vec<Instance>,
+    pub bound_methods: Vec<BoundMethod>,
+    pub upvalues: Vec<Upvalue>, // captured by the closures
+    // upvalues still pointing at the stack, by ascending slot, so closures capturing
+    // the same variable share one upvalue
+    open_upvalues: Vec<usize>,
+    pub compile_options: CompileOptions,
+    pub diagnostics: Diagnostics, // reported by the last compilation
+    // `math.sqrt` is compiled to the global "math.sqrt" when `math` is a namespace
+    pub namespaces: HashSet<StringObjIdx>,
+    // also define namespace members as bare globals, e.g. `sqrt` for `math.sqrt`
+    compat_bare_natives: bool,
+    // runtime error of the previous `interpret`, returned by the `lastError` native
+    pub last_error: Option<RuntimeError>,
+    pub output: Output, // written by `print` and `printErr`
+    pub limits: Limits,
+    // division by zero, NaN or infinite results and integer overflow are runtime errors
+    pub checked_arithmetic: bool,
+    // report suspicious operations while running (warnings W0004 to W0006), see `runtime_warning`
+    pub runtime_warnings: bool,
+    warned_sites: HashSet<(usize, usize)>, // function and ip of the runtime warnings reported
+    pub cost_model: CostModel,             // prices the gas of `Limits::max_gas`
+    gas_used: u64,
+    // time-sliced runs (see `run_sliced`): the gas at which the run pauses, and whether the
+    // frames and the stack are those of a paused run
+    pause_at: Option<u64>,
+    paused: bool,
+    run_depth: usize, // nested `run` loops, e.g. of a callback called by a native
+    compile_cache: Option<CompileCache>, // see `enable_compile_cache`
+    pub warning_levels: WarningLevels, // which warnings are reported, and which are errors
+    source_map: Option<SourceMap>, // see `enable_source_map`
+    op_history: Option<OpHistory>, // see `enable_crash_reports`
+    tracer: Option<Tracer>, // see `enable_trace`
+    audit_log: Option<Vec<AuditEvent>>, // see `enable_audit`
+    // top-level function of the last source passed to `interpret` (or a variant), e.g. for the REPL
+    pub last_script: Option<usize>,
+    // state of the program embedding the VM, by type, see `host_data`
+    host_data: HashMap<TypeId, Box<dyn Any>>,
+    // `run_script` calls the script's `main` function after its top-level code
+    pub call_main: bool,
+    pub args: Vec<String>, // program arguments, read by `arg`/`argCount` and passed to `main`
+    pub environment: Environment, // read by `env`
 }
 
-#[derive(PartialEq, Debug)]
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
+    // Every option at its default, see `VmBuilder` for the others
     pub fn new() -> VM {
+        VM::builder()
+            .build()
+            .expect("The default options are valid.")
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::new()
+    }
+
+    // Panics if the limits are invalid, see `VmBuilder::build`
+    pub fn with_limits(limits: Limits) -> VM {
+        match VM::builder().limits(limits).build() {
+            Ok(vm) => vm,
+            Err(msg) => panic!("{}", msg),
+        }
+    }
+
+    // A VM without natives, which `VmBuilder::build` configures
+    pub(crate) fn empty(limits: Limits) -> VM {
         VM {
             frames: Vec::with_capacity(FRAMES_MAX),
-            interner: Interner::default(),
+            interner: Interner::with_common_strings(),
             stack: Vec::with_capacity(STACK_SIZE), // = reset stack
-            globals: HashMap::with_capacity(STACK_SIZE),
+            globals: Globals::with_capacity(STACK_SIZE),
             functions: Vec::new(),
+            replaced_bodies: HashMap::new(),
+            natives: Vec::new(),
+            builtin_methods: HashMap::new(),
+            closures: Vec::new(),
+            classes: Vec::new(),
+            instances: Vec::new(),
+            bound_methods: Vec::new(),
+            upvalues: Vec::new(),
+            open_upvalues: Vec::new(),
+            compile_options: CompileOptions::default(),
+            diagnostics: Diagnostics::default(),
+            namespaces: HashSet::new(),
+            compat_bare_natives: false,
+            last_error: None,
+            output: Output::default(),
+            limits,
+            checked_arithmetic: false,
+            runtime_warnings: false,
+            warned_sites: HashSet::new(),
+            cost_model: CostModel::default(),
+            gas_used: 0,
+            pause_at: None,
+            paused: false,
+            run_depth: 0,
+            compile_cache: None,
+            warning_levels: WarningLevels::default(),
+            source_map: None,
+            op_history: None,
+            tracer: None,
+            audit_log: None,
+            last_script: None,
+            host_data: HashMap::new(),
+            call_main: false,
+            args: Vec::new(),
+            environment: Environment::Process,
+        }
+    }
+
+    // Saves the native to the natives Vec, and binds it to a global variable
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        self.natives
+            .push(NativeFunction::new(name, arity, function));
+        let name_idx = self.interner.intern(name);
+        self.globals
+            .insert_host(name_idx, Value::Native(self.natives.len() - 1));
+    }
+
+    // Registers a native as the method `name` of every value of the type, e.g. `len` of strings.
+    // The receiver is the native's first argument, followed by the `arity` arguments of the call.
+    pub fn define_method(&mut self, tag: TypeTag, name: &str, arity: u8, function: NativeFn) {
+        // named like a namespace member, for traces and the audit log
+        let full_name = format!("{}.{}", tag.name(), name);
+        self.natives
+            .push(NativeFunction::new(&full_name, arity + 1, function));
+        let name_idx = self.interner.intern(name);
+        self.builtin_methods
+            .insert((tag, name_idx), self.natives.len() - 1);
+    }
+
+    // Registers natives under a namespace, callable as `namespace.name(...)`
+    pub fn define_namespace(&mut self, namespace: &str, natives: &[(&str, u8, NativeFn)]) {
+        self.namespaces.insert(self.interner.intern(namespace));
+        // cached code may have compiled `namespace.name` as a property access
+        self.clear_compile_cache();
+        for &(name, arity, function) in natives {
+            self.define_native(&format!("{}.{}", namespace, name), arity, function);
+            if self.compat_bare_natives {
+                self.define_native(name, arity, function);
+            }
+        }
+    }
+
+    // Set by `VmBuilder::compat_bare_natives` before any namespace is defined, so every
+    // namespace member gets its alias and no alias outlives the option
+    pub(crate) fn enable_compat_bare_natives(&mut self) {
+        self.compat_bare_natives = true;
+    }
+
+    // Global variables with their names, in definition order
+    pub fn globals_iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals
+            .iter()
+            .map(move |(name, value)| (self.interner.lookup(*name), value))
+    }
+
+    // Source line of the instruction being executed in the current frame,
+    // i.e. the call site when asked from inside a native
+    pub fn current_line(&self) -> usize {
+        match self.frames.last() {
+            Some(frame) => self.functions[frame.f_idx]
+                .chunk
+                .line(frame.ip)
+                .unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    pub fn interpret(&mut self, source: &str) -> Result<(), LoxError> {
+        self.compile_and_run(source, None, false).map(|_| ())
+    }
+
+    // Like `interpret`, and stack traces name `origin` (a file path, "<repl>", ...) as the source
+    pub fn interpret_from(&mut self, source: &str, origin: &str) -> Result<(), LoxError> {
+        self.compile_and_run(source, Some(origin), false)
+            .map(|_| ())
+    }
+
+    // Runs a script file, stack traces name its path
+    pub fn interpret_file(&mut self, path: &Path) -> Result<(), LoxError> {
+        let source = fs::read_to_string(path).map_err(|e| LoxError::Io {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })?;
+        self.interpret_from(&source, &path.to_string_lossy())
+    }
+
+    // Runs a script file like `interpret_file` and, with `call_main`, then its `main` function
+    // (see `run_main`). Returns the exit code: what `main` returned if it's a number, else 0.
+    // Numbers that aren't a valid exit code, i.e. an integer from 0 to 255, are a runtime error
+    // rather than being wrapped by the OS.
+    pub fn run_script(&mut self, path: &Path) -> Result<i32, LoxError> {
+        self.interpret_file(path)?;
+        if !self.call_main {
+            return Ok(0);
+        }
+        match self.run_main()? {
+            Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(&n) => Ok(n as i32),
+            Value::Int(n) if (0..=255).contains(&n) => Ok(n as i32),
+            value @ (Value::Number(_) | Value::Int(_)) => {
+                let msg = format!(
+                    "'main' must return an exit code from 0 to 255, got {}.",
+                    self.describe_value(&value)
+                );
+                self.runtime_error(&msg)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    // Calls the global function `main` of the script that ran last, with one string argument
+    // per element of `args`, or without arguments if `main` takes none, and returns its result.
+    // A missing `main` is a runtime error.
+    pub fn run_main(&mut self) -> Result<Value, LoxError> {
+        let main = self.get_global("main");
+        let f_idx = match main {
+            Some(Value::Function(f_idx)) => f_idx,
+            Some(Value::Closure(closure)) => self.closures[closure].f_idx,
+            Some(other) => {
+                let msg = format!(
+                    "'main' must be a function, got {}.",
+                    self.describe_value(&other)
+                );
+                return self.runtime_error(&msg);
+            }
+            None => return self.runtime_error("Undefined function 'main'."),
+        };
+        let args: Vec<Value> = match self.functions[f_idx].arity {
+            0 => Vec::new(),
+            _ => {
+                let interner = &mut self.interner;
+                self.args
+                    .iter()
+                    .map(|arg| Value::string(interner, arg))
+                    .collect()
+            }
+        };
+        let result = self.call_function(main.unwrap(), &args);
+        self.last_error = match &result {
+            Err(LoxError::Runtime(error)) => Some(error.clone()),
+            _ => None,
+        };
+        result
+    }
+
+    // The program arguments, e.g. the arguments after the script in the CLI
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    // `env` only reads the variables of `map` instead of the process environment
+    pub fn set_env_map(&mut self, map: HashMap<String, String>) {
+        self.environment = Environment::Map(map);
+    }
+
+    // Runs a file of definitions for every later script, e.g. the REPL's rc file or `--prelude`.
+    // Its globals are kept by `reset_user_state`, also the ones defined before an error.
+    pub fn run_prelude(&mut self, path: &Path) -> Result<(), LoxError> {
+        let result = self.interpret_file(path);
+        self.globals.keep_on_reset();
+        result
+    }
+
+    // Evaluates a single expression, e.g. `1 + 2` or `f`, and returns its value
+    pub fn evaluate(&mut self, source: &str) -> Result<Value, LoxError> {
+        self.compile_and_run(source, None, true)
+    }
+
+    fn compile_and_run(
+        &mut self,
+        source: &str,
+        origin: Option<&str>,
+        expression: bool,
+    ) -> Result<Value, LoxError> {
+        match self.compile_source(source, origin, expression) {
+            Ok(f_idx) => {
+                if !expression {
+                    self.last_script = Some(f_idx);
+                }
+                self.run_compiled(f_idx)
+            }
+            Err(error) => {
+                self.last_error = None;
+                Err(error)
+            }
+        }
+    }
+
+    // Compiles `source` (a single expression if `expression`) without running it, e.g. to check the
+    // code first, and returns the index of its top-level function for `run_compiled`
+    pub fn compile_source(
+        &mut self,
+        source: &str,
+        origin: Option<&str>,
+        expression: bool,
+    ) -> Result<usize, LoxError> {
+        self.diagnostics.clear();
+        let origin = origin.map(|origin| self.interner.intern(origin));
+        let settings = CompileSettings {
+            origin,
+            options: self.compile_options,
+            expression,
+        };
+        let cached = self
+            .compile_cache
+            .as_mut()
+            .and_then(|cache| cache.get(source, settings))
+            .map(|(f_idx, warnings)| (f_idx, warnings.to_vec()));
+        let compiled = match cached {
+            Some((f_idx, warnings)) => {
+                self.diagnostics.warnings = warnings;
+                Some(f_idx)
+            }
+            None => self.compile(source, settings),
+        };
+        let errors = self.diagnostics.errors.len();
+        self.diagnostics.apply_levels(&self.warning_levels);
+        // the compiler printed its own errors already, these are the denied warnings
+        for diagnostic in &self.diagnostics.errors[errors..] {
+            eprintln!("{}", diagnostic);
+        }
+        for warning in &self.diagnostics.warnings {
+            eprintln!("{}", warning);
+        }
+
+        match compiled {
+            Some(f_idx) if self.diagnostics.errors.is_empty() => Ok(f_idx),
+            _ => Err(LoxError::Compile(self.diagnostics.errors.clone())),
+        }
+    }
+
+    // Adds the functions of a unit compiled for this VM (see `CompilationUnit`), e.g. on another
+    // thread, and returns the index of its top-level function for `run_compiled`. Its strings
+    // are interned in this VM and its function ids moved past the existing functions. Reports
+    // the unit's warnings like `compile_source`, and fails if one of them is denied.
+    pub fn install(&mut self, unit: &CompilationUnit) -> Result<usize, LoxError> {
+        self.diagnostics.clear();
+        self.diagnostics.warnings = unit.warnings.clone();
+        self.diagnostics.apply_levels(&self.warning_levels);
+        for diagnostic in self
+            .diagnostics
+            .errors
+            .iter()
+            .chain(&self.diagnostics.warnings)
+        {
+            eprintln!("{}", diagnostic);
+        }
+        if !self.diagnostics.errors.is_empty() {
+            return Err(LoxError::Compile(self.diagnostics.errors.clone()));
+        }
+
+        let strings: Vec<StringObjIdx> = (0..unit.interner().len())
+            .map(|idx| {
+                let s = unit.interner().lookup(idx as StringObjIdx);
+                self.interner.intern(s)
+            })
+            .collect();
+        let functions = unit.remap(&strings, self.functions.len());
+        self.functions.extend(functions);
+        Ok(self.functions.len() - 1)
+    }
+
+    // Names of the namespaces, e.g. "math", for compiling a `CompilationUnit` outside of the VM
+    pub fn namespace_names(&self) -> Vec<String> {
+        self.namespaces
+            .iter()
+            .map(|&name| self.interner.lookup(name).to_owned())
+            .collect()
+    }
+
+    // Compiles `source` without running it, and returns the labeled disassembly
+    // (see `debug::disassemble_labeled`) of the script and then the functions declared in it
+    pub fn disassemble_source(
+        &mut self,
+        source: &str,
+        origin: Option<&str>,
+    ) -> Result<String, LoxError> {
+        let f_idx = self.compile_source(source, origin, false)?;
+        Ok(self.disassemble_function(f_idx))
+    }
+
+    // Labeled disassembly of a function and every function it declares, in the order that
+    // `asm::assemble` expects: breadth first, in the order the constants are loaded
+    pub fn disassemble_function(&self, f_idx: usize) -> String {
+        let mut declared = vec![f_idx];
+        let mut i = 0;
+        while i < declared.len() {
+            let chunk = &self.functions[declared[i]].chunk;
+            for (_, op) in chunk.instructions() {
+                if let OpCode::Constant(idx) | OpCode::Closure(idx) = op {
+                    if let Some(&Value::Function(nested)) = chunk.constant(idx.into()) {
+                        if !declared.contains(&nested) {
+                            declared.push(nested);
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+        declared
+            .iter()
+            .map(|&f_idx| {
+                let function = &self.functions[f_idx];
+                let title = debug::section_title(function, &self.interner);
+                debug::disassemble_labeled(&function.chunk, &title, &self.interner)
+            })
+            .collect()
+    }
+
+    // Assembles the text of a labeled disassembly (see `asm`) and returns the index of the
+    // script, to run with `run_compiled`
+    pub fn assemble(&mut self, text: &str) -> Result<usize, AsmError> {
+        let first_id = self.functions.len();
+        let functions = asm::assemble(text, &mut self.interner, first_id)?;
+        self.functions.extend(functions);
+        Ok(first_id)
+    }
+
+    // Runs a top-level function returned by `compile_source` for at most `budget` gas (see
+    // `cost_model`, 1 per instruction by default), e.g. a slice of every frame of a game.
+    // If the function doesn't finish in time, the VM keeps its frames and stack and returns
+    // `RunState::Paused`, and `resume` continues exactly where it stopped. Other runs can't
+    // start while one is paused, but the host can still call functions (`call_function`).
+    // `Limits::max_gas` applies to the whole run, across the slices.
+    pub fn run_sliced(&mut self, f_idx: usize, budget: u64) -> Result<RunState, LoxError> {
+        self.pause_at = Some(budget);
+        let result = self.run_top_level(f_idx);
+        self.finish_slice(result)
+    }
+
+    // Runs the paused run for at most `budget` more gas, see `run_sliced`
+    pub fn resume(&mut self, budget: u64) -> Result<RunState, LoxError> {
+        if !self.paused {
+            return self.runtime_error("There is no paused run to resume.");
+        }
+        self.paused = false;
+        self.pause_at = Some(self.gas_used.saturating_add(budget));
+        let result = self.run(0);
+        self.finish_slice(result)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Abandons the paused run, like a runtime error would. Does nothing if no run is paused.
+    pub fn cancel(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.close_upvalues(0);
+            self.stack.clear();
+            self.frames.clear();
+        }
+    }
+
+    fn finish_slice(&mut self, result: Result<Value, LoxError>) -> Result<RunState, LoxError> {
+        self.pause_at = None;
+        if self.paused {
+            return Ok(RunState::Paused);
+        }
+        self.last_error = match &result {
+            Err(LoxError::Runtime(error)) => Some(error.clone()),
+            _ => None,
+        };
+        result.map(RunState::Finished)
+    }
+
+    // Runs a top-level function returned by `compile_source`
+    pub fn run_compiled(&mut self, f_idx: usize) -> Result<Value, LoxError> {
+        let result = self.run_top_level(f_idx);
+        // the script could still read the previous error, now replace it with this run's
+        self.last_error = match &result {
+            Err(LoxError::Runtime(error)) => Some(error.clone()),
+            _ => None,
+        };
+        result
+    }
+
+    // Compiles `source` and pushes the top-level function to the functions Vec,
+    // returns its index or None if there were compile errors
+    fn compile(&mut self, source: &str, settings: CompileSettings) -> Option<usize> {
+        // registered even if it doesn't compile, the lines were still typed
+        let submission = self.source_map.as_mut().map(|map| map.register(source));
+        let first_new = self.functions.len();
+        let parser = Parser::new(
+            source,
+            settings.options,
+            &self.namespaces,
+            &mut self.interner,
+            first_new,
+            &mut self.diagnostics,
+            settings.origin,
+        );
+        let compiled = if settings.expression {
+            parser.compile_expression()
+        } else {
+            parser.compile()
+        }?;
+        // only now, so failed submissions leave no functions behind
+        self.functions.extend(compiled.functions);
+        self.functions.push(compiled.script);
+        let f_idx = self.functions.len() - 1;
+        // the script and the functions declared in it
+        for function in &mut self.functions[first_new..] {
+            function.submission = submission;
+        }
+        if let Some(cache) = &mut self.compile_cache {
+            cache.insert(source, settings, f_idx, self.diagnostics.warnings.clone());
+        }
+        Some(f_idx)
+    }
+
+    // Remembers every compiled source, so stack traces also show where a line is in the whole
+    // session and its text, e.g. for functions defined in earlier lines of the REPL.
+    // Sources that are already in the compile cache aren't compiled, or registered, again.
+    pub fn enable_source_map(&mut self) {
+        self.source_map.get_or_insert_with(SourceMap::new);
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    // Records the globals scripts define or assign and the natives they call, see `take_audit_log`
+    pub fn enable_audit(&mut self) {
+        self.audit_log.get_or_insert_with(Vec::new);
+    }
+
+    // The events recorded since the last call, in order. Recording continues.
+    pub fn take_audit_log(&mut self) -> Vec<AuditEvent> {
+        self.audit_log.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    // Number of live heap objects per kind, to check in tests that runs don't leave objects behind.
+    // There is no garbage collector yet: strings, functions, closures, classes, instances and
+    // bound methods are kept until the VM is dropped, so the census only grows. Values like
+    // numbers and ranges aren't objects.
+    pub fn live_object_census(&self) -> BTreeMap<&'static str, usize> {
+        BTreeMap::from([
+            ("strings", self.interner.len()),
+            ("functions", self.functions.len()),
+            ("closures", self.closures.len()),
+            ("upvalues", self.upvalues.len()),
+            ("classes", self.classes.len()),
+            ("instances", self.instances.len()),
+            ("bound methods", self.bound_methods.len()),
+        ])
+    }
+
+    // Records the last executed instructions for `crash_report`, which costs a little on every one
+    pub fn enable_crash_reports(&mut self) {
+        self.op_history.get_or_insert_with(OpHistory::new);
+    }
+
+    pub fn op_history(&self) -> Option<&OpHistory> {
+        self.op_history.as_ref()
+    }
+
+    // Writes every executed instruction to the tracer, see `trace.rs`.
+    // Runtime errors flush the trace; call `flush_trace` when the program exits.
+    pub fn enable_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn flush_trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush(&self.functions, &self.interner);
         }
     }
 
-    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretResult> {
-        let parser = Parser::new(source, &mut self.interner, &mut self.functions);
+    // Dump of the VM state for bug reports, e.g. after a panic, see `crash_report::run_reporting_crashes`
+    pub fn crash_report(&self) -> String {
+        crash_report::report(self)
+    }
 
-        match parser.compile() {
-            Some(function) => {
-                // push top-level script to the functions Vec
-                // at this point, the functions Vec is empty
-                self.functions.push(function);
-                let top_level_f_idx = self.functions.len() - 1;
-                self.frames.push(CallFrame::new(top_level_f_idx, 0));
+    // Keeps the compiled code of the last `capacity` distinct sources passed to `interpret`
+    // (and its variants), so running one of them again skips compilation.
+    // Compile options and the origin are part of the key. Evicted functions stay in `functions`.
+    pub fn enable_compile_cache(&mut self, capacity: usize) {
+        self.compile_cache = Some(CompileCache::new(capacity));
+    }
+
+    pub fn clear_compile_cache(&mut self) {
+        if let Some(cache) = &mut self.compile_cache {
+            cache.clear();
+        }
+    }
+
+    // Number of cached sources, 0 if the cache is disabled
+    pub fn compile_cache_len(&self) -> usize {
+        self.compile_cache.as_ref().map_or(0, CompileCache::len)
+    }
+
+    // Message of `last_error` as a Lox string, or nil if the previous run succeeded
+    pub fn last_error_value(&mut self) -> Value {
+        match &self.last_error {
+            Some(error) => Value::string(&mut self.interner, &error.message),
+            None => Value::Nil,
+        }
+    }
+
+    // Value of a global variable, without interning the name
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name_idx = self.interner.get(name)?;
+        self.globals.get(&name_idx).copied()
+    }
+
+    // Value of a field of an instance, e.g. of an object returned to the host. None for values
+    // that aren't instances.
+    pub fn get_field(&self, instance: Value, name: &str) -> Option<Value> {
+        let name_idx = self.interner.get(name)?;
+        match instance {
+            Value::Instance(instance) => {
+                self.instances.get(instance)?.fields.get(&name_idx).copied()
             }
-            None => return Err(InterpretResult::CompileError),
+            _ => None,
         }
+    }
 
-        self.run()
+    // Forgets what scripts defined (globals, the last error) but keeps the natives, namespaces,
+    // interned strings and compiled functions, so function values held by the host still work
+    pub fn reset_user_state(&mut self) {
+        self.globals.reset_user_state();
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.paused = false;
+        self.last_error = None;
+        self.clear_compile_cache();
+    }
+
+    // State the embedding program keeps with the VM, e.g. for its natives, which only get the VM:
+    // one value of each type, created with its default value when it is first used.
+    // It outlives `reset_user_state`.
+    pub fn host_data<T: Any + Default>(&mut self) -> &mut T {
+        self.host_data
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut()
+            .expect("Host data is stored under its own type.")
+    }
+
+    // Removes the value of `host_data`, e.g. to report it after a run
+    pub fn take_host_data<T: Any + Default>(&mut self) -> T {
+        match self.host_data.remove(&TypeId::of::<T>()) {
+            Some(data) => *data
+                .downcast()
+                .expect("Host data is stored under its own type."),
+            None => T::default(),
+        }
+    }
+
+    // Defines or overwrites a global variable
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name_idx = self.interner.intern(name);
+        self.globals.insert(name_idx, value);
+    }
+
+    // Multi-line rendering of a value for the REPL's `:inspect`, more detailed than `print`
+    pub fn inspect(&self, value: &Value) -> String {
+        match *value {
+            Value::StringObj(s) => format!("{:?}\n", self.interner.lookup(s)),
+            Value::Function(f_idx) => {
+                let function = &self.functions[f_idx];
+                let name = match function.name {
+                    Some(_) => format!("<fn {}>", function.display_name(&self.interner)),
+                    None => function.display_name(&self.interner).to_owned(),
+                };
+                format!(
+                    "{}\n  arity: {}\n  chunk: {} instructions, {} constants\n",
+                    name,
+                    function.arity,
+                    function.chunk.len(),
+                    function.chunk.constants().len()
+                )
+            }
+            Value::Closure(closure) => {
+                let closure = &self.closures[closure];
+                format!(
+                    "{}  upvalues: {}\n",
+                    self.inspect(&Value::Function(closure.f_idx)),
+                    closure.upvalues.len()
+                )
+            }
+            Value::Native(native_idx) => {
+                let native = &self.natives[native_idx];
+                format!("<native fn {}>\n  arity: {}\n", native.name, native.arity)
+            }
+            _ => format!("{}\n", self.format_value(value)),
+        }
+    }
+
+    // Like `value::format_value`, with the names of classes, which are kept by the VM,
+    // e.g. "Pair" for a class and "Pair instance" for its instances
+    pub fn format_value(&self, value: &Value) -> String {
+        self.format_value_with(value, Precision::Auto)
+    }
+
+    // A value in a runtime type error: its type and the value, cut to ERROR_VALUE_WIDTH bytes,
+    // e.g. `string ("a")` or `number (3)`. Only `nil` is shown alone.
+    pub fn describe_value(&self, value: &Value) -> String {
+        let text = match value {
+            Value::Nil => return "nil".to_owned(),
+            Value::Class(_) | Value::Instance(_) => self.format_value(value),
+            _ => format_value_truncated(value, &self.interner, Precision::Auto, ERROR_VALUE_WIDTH),
+        };
+        match value {
+            Value::StringObj(_) => format!("{} (\"{}\")", type_name(value), text),
+            _ => format!("{} ({})", type_name(value), text),
+        }
+    }
+
+    pub fn format_value_with(&self, value: &Value, precision: Precision) -> String {
+        match *value {
+            Value::Class(class) => self.interner.lookup(self.classes[class].name).to_owned(),
+            Value::Instance(instance) => {
+                let class = &self.classes[self.instances[instance].class];
+                format!("{} instance", self.interner.lookup(class.name))
+            }
+            _ => format_value_with(value, &self.interner, precision),
+        }
+    }
+
+    // Runs a compiled top-level function, e.g. a script, and returns the value it returned
+    pub fn run_function(&mut self, function: Function) -> Result<Value, LoxError> {
+        self.functions.push(function);
+        self.run_top_level(self.functions.len() - 1)
+    }
+
+    fn run_top_level(&mut self, top_level_f_idx: usize) -> Result<Value, LoxError> {
+        debug_assert!(
+            self.functions[top_level_f_idx].chunk.is_frozen(),
+            "Only compiled chunks can be run."
+        );
+        if self.paused {
+            // it would run on top of the paused frames, and an error would abandon them
+            self.pause_at = None;
+            let error = RuntimeError {
+                message: "Cannot start a run while another one is paused.".to_owned(),
+                trace: Vec::new(),
+            };
+            eprintln!("{}", error);
+            return Err(LoxError::Runtime(error));
+        }
+        if self.frames.is_empty() {
+            // a new run, not a script run by a native of a running one
+            self.gas_used = 0;
+        }
+        // Like any other call, slot zero of the frame holds the function being called.
+        // This matches the dummy local the compiler reserves for slot zero.
+        // A script run by a native starts above the frames of the running one, and returns
+        // to the native like `call_function`.
+        let base_depth = self.frames.len();
+        self.frames
+            .push(CallFrame::new(top_level_f_idx, self.stack.len()));
+        self.stack.push(Value::Function(top_level_f_idx));
+
+        self.run(base_depth)
+    }
+
+    // Calls a function or native with `args` from Rust, e.g. a callback passed to a native,
+    // and returns its result. Can be used while the VM is running.
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Result<Value, LoxError> {
+        if self.frames.is_empty() {
+            // a new run, like in `run_top_level`
+            self.gas_used = 0;
+        }
+        if let Value::Native(n_idx) = callee {
+            let native = &self.natives[n_idx];
+            if args.len() != native.arity as usize {
+                let msg = format!(
+                    "Expected {} arguments but got {}.",
+                    native.arity,
+                    args.len()
+                );
+                return self.runtime_error(&msg);
+            }
+            return self.invoke_native(n_idx, args);
+        }
+        // a builtin method, e.g. `"abc".len`, is a native taking the receiver first
+        if let Value::BoundMethod(bound) = callee {
+            if let Some(&BoundMethod {
+                receiver,
+                method: Value::Native(n_idx),
+            }) = self.bound_methods.get(bound)
+            {
+                let arity = self.natives[n_idx].arity - 1;
+                if args.len() != arity as usize {
+                    let msg = format!("Expected {} arguments but got {}.", arity, args.len());
+                    return self.runtime_error(&msg);
+                }
+                let args: Vec<Value> = std::iter::once(receiver)
+                    .chain(args.iter().copied())
+                    .collect();
+                return self.invoke_native(n_idx, &args);
+            }
+        }
+
+        // an initializer runs in a frame like any other call
+        if let Value::Class(class) = callee {
+            if self.classes[class].initializer(&self.interner).is_none() {
+                self.check_class_arity(args.len() as u8)?;
+                return self.instantiate(class);
+            }
+        }
+
+        let base_depth = self.frames.len();
+        self.stack.push(callee);
+        self.stack.extend_from_slice(args);
+        self.call_value(callee, args.len() as u8)?;
+        self.run(base_depth)
+    }
+
+    // Runs until the frame count drops back to `base_depth`, i.e. the function called last returns,
+    // or a time-sliced run pauses
+    fn run(&mut self, base_depth: usize) -> Result<Value, LoxError> {
+        self.run_depth += 1;
+        let result = self.execute(base_depth);
+        self.run_depth -= 1;
+        result
     }
 
     // We run every single instruction here, so this is the most performance critical part of the VM.
     // TODO: look up “direct threaded code”, “jump table”, and “computed goto” for optimization techniques
-    fn run(&mut self) -> Result<(), InterpretResult> {
+    fn execute(&mut self, base_depth: usize) -> Result<Value, LoxError> {
         // wrap in Result, so that we can use the question mark operator to:
-        // 1. *Return* InterpretResult if error
+        // 1. *Return* LoxError if error
         // 2. Unpacks the Result ((), i.e. do nothing) if no error
 
         // let mut frame = self.frames.last_mut().unwrap();
@@ -86,159 +972,563 @@ impl VM {
 
         // TODO: refactor self.frames.last().unwrap() and self.frames.last_mut().unwrap() into a single function
         loop {
-            let op = self.functions[self.frames.last().unwrap().f_idx].chunk.code
-                [self.frames.last().unwrap().ip];
+            // Only the outermost loop pauses, between two instructions: a nested one returns
+            // to Rust code (e.g. a native) which can't be resumed
+            if self.run_depth == 1 && self.pause_at.is_some_and(|at| self.gas_used >= at) {
+                self.paused = true;
+                return Ok(Value::Nil);
+            }
+            self.charge(self.cost_model.instruction)?;
+            let frame = self.frames.last().unwrap();
+            let op = match self.functions[frame.f_idx].chunk.op(frame.ip) {
+                Some(op) => op,
+                None => {
+                    let msg = format!(
+                        "Instruction pointer {} out of bounds in {}.",
+                        frame.ip,
+                        self.function_name(frame.f_idx)
+                    );
+                    return self.runtime_error(&msg);
+                }
+            };
+            if let Some(history) = &mut self.op_history {
+                history.record(frame.f_idx, frame.ip, op);
+            }
+            if let Some(tracer) = &mut self.tracer {
+                let function = &self.functions[frame.f_idx];
+                if tracer.traces(function.display_name(&self.interner)) {
+                    tracer.record((frame.f_idx, frame.ip, op), &self.functions, &self.interner);
+                }
+            }
             match op {
                 OpCode::Constant(idx) => {
-                    let constant = self.functions[self.frames.last().unwrap().f_idx]
-                        .chunk
-                        .constants
-                        .values[idx as usize];
-                    print_value(&constant, &self.interner);
+                    let constant = self.read_constant(idx)?;
                     self.stack.push(constant);
-                    println!();
                 }
+                OpCode::Zero => self.stack.push(Value::Number(0.0)),
+                OpCode::One => self.stack.push(Value::Number(1.0)),
+                OpCode::MinusOne => self.stack.push(Value::Number(-1.0)),
                 OpCode::Nil => self.stack.push(Value::Nil),
                 OpCode::True => self.stack.push(Value::Bool(true)),
                 OpCode::False => self.stack.push(Value::Bool(false)),
                 OpCode::Pop => {
                     self.stack.pop();
                 }
+                OpCode::Nop => {}
+                OpCode::CheckSlot(idx) => {
+                    let window = self.stack.len() - frame.slot_offset();
+                    if idx as usize >= window {
+                        let msg = format!(
+                            "Paranoid check failed: slot {} is outside of the frame's window of {} values.",
+                            idx, window
+                        );
+                        return self.runtime_error(&msg);
+                    }
+                }
+                OpCode::CheckArity(arity) => {
+                    let window = self.stack.len() - frame.slot_offset();
+                    if window != arity as usize + 1 {
+                        let msg = format!(
+                            "Paranoid check failed: {} takes {} arguments, but its frame holds {} values.",
+                            self.function_name(frame.f_idx),
+                            arity,
+                            window
+                        );
+                        return self.runtime_error(&msg);
+                    }
+                }
+                OpCode::CheckOperands(idx) => {
+                    let next = self.functions[frame.f_idx].chunk.op(frame.ip + 1);
+                    self.check_operands(idx, next)?;
+                }
+                OpCode::IsType(tag) => {
+                    let is_type = TypeTag::of(self.peek(0)) == tag;
+                    self.stack.push(Value::Bool(is_type));
+                }
+                OpCode::PopBelow => {
+                    let top = self.pop();
+                    self.pop();
+                    self.stack.push(top);
+                }
+                OpCode::CheckCondition => {
+                    if let Value::Bool(false) = self.pop() {
+                        let msg = format!(
+                            "Condition must be a boolean, got {}.",
+                            self.describe_value(self.peek(0))
+                        );
+                        return self.runtime_error(&msg);
+                    }
+                }
+                OpCode::PopN(n) => {
+                    let len = self.stack.len().saturating_sub(n.into());
+                    self.stack.truncate(len);
+                }
                 OpCode::DefineGlobal(idx) => {
-                    let constant = self.functions[self.frames.last().unwrap().f_idx]
-                        .chunk
-                        .constants
-                        .values[idx as usize];
-                    if let Value::Identifier(name) = constant {
-                        self.globals.insert(name, *self.peek(0));
-                        self.stack.pop(); //TODO: pop wat?
-                    } else {
-                        return self.runtime_error("constant is not Value::Identifier!");
+                    let name = self.read_identifier(idx)?;
+                    if self.audit_log.is_some() {
+                        self.audit_define(name, *self.peek(0));
                     }
+                    self.globals.insert(name, *self.peek(0));
+                    self.stack.pop(); //TODO: pop wat?
+                }
+                OpCode::DefineFunction(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let mut value = *self.peek(0);
+                    if let (Some(&Value::Function(old)), Value::Function(new)) =
+                        (self.globals.get(&name), value)
+                    {
+                        self.redefine_function(old, new)?;
+                        value = Value::Function(old);
+                    }
+                    if self.audit_log.is_some() {
+                        self.audit_define(name, value);
+                    }
+                    self.globals.insert(name, value);
+                    self.stack.pop();
                 }
                 OpCode::GetGlobal(idx) => {
-                    let constant = self.functions[self.frames.last().unwrap().f_idx]
-                        .chunk
-                        .constants
-                        .values[idx as usize];
-                    if let Value::Identifier(name) = constant {
-                        if let Some(v) = self.globals.get(&name) {
-                            self.stack.push(v.to_owned());
-                        } else {
-                            let msg = format!("Undefined variable {}.", name);
-                            return self.runtime_error(&msg);
-                        }
+                    // the slot found by the last run of this instruction, if it's still valid
+                    let (f_idx, ip) = (frame.f_idx, frame.ip);
+                    let cached = self.functions[f_idx]
+                        .global_cache
+                        .get(ip)
+                        .copied()
+                        .flatten();
+                    if let Some(&value) = cached.and_then(|slot| self.globals.get_at(slot)) {
+                        self.stack.push(value);
                     } else {
-                        return self.runtime_error("constant is not Value::Identifier!");
+                        let name = self.read_identifier(idx)?;
+                        match self.globals.slot(&name) {
+                            Some(slot) => {
+                                self.stack.push(*self.globals.get_at(slot).unwrap());
+                                self.cache_global_slot(f_idx, ip, slot);
+                            }
+                            None => {
+                                let msg =
+                                    format!("Undefined variable '{}'.", self.interner.lookup(name));
+                                return self.runtime_error(&msg);
+                            }
+                        }
                     }
                 }
                 OpCode::SetGlobal(idx) => {
-                    let constant = self.functions[self.frames.last().unwrap().f_idx]
-                        .chunk
-                        .constants
-                        .values[idx as usize];
-                    if let Value::Identifier(name) = constant {
-                        if self.globals.contains_key(&name) {
-                            self.globals.insert(name, *self.peek(0));
-                            // no pop -> in case the assignment is nested inside some larger expression
-                        } else {
-                            let msg = format!("Cannot assign to undefined variable {}.", name);
-                            return self.runtime_error(&msg);
+                    let name = self.read_identifier(idx)?;
+                    if let Some(&old) = self.globals.get(&name) {
+                        if self.audit_log.is_some() {
+                            self.audit_assign(name, old, *self.peek(0));
                         }
+                        self.globals.insert(name, *self.peek(0));
+                        // no pop -> in case the assignment is nested inside some larger expression
                     } else {
-                        return self.runtime_error("constant is not Value::Identifier!");
+                        let msg = format!(
+                            "Cannot assign to undefined variable '{}'.",
+                            self.interner.lookup(name)
+                        );
+                        return self.runtime_error(&msg);
                     }
                 }
                 OpCode::GetLocal(idx) => {
-                    let idx = self.frames.last().unwrap().slot_offset + idx as usize;
-                    self.stack.push(self.stack[idx]);
+                    let value = frame.slot(&self.stack, idx as usize);
+                    self.stack.push(value);
                 }
                 OpCode::SetLocal(idx) => {
-                    let idx = self.frames.last().unwrap().slot_offset + idx as usize;
-                    self.stack[idx] = *self.peek(0);
+                    let value = *self.peek(0);
+                    frame.set_slot(&mut self.stack, idx as usize, value);
+                }
+                OpCode::GetUpvalue(idx) => {
+                    let upvalue = self.upvalue(idx)?;
+                    let value = match self.upvalues[upvalue] {
+                        Upvalue::Open(slot) => self.stack[slot],
+                        Upvalue::Closed(value) => value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(idx) => {
+                    let value = *self.peek(0);
+                    let upvalue = self.upvalue(idx)?;
+                    match &mut self.upvalues[upvalue] {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::Class(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    self.classes.push(Class::new(name));
+                    self.stack.push(Value::Class(self.classes.len() - 1));
+                }
+                OpCode::Method(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let method = self.pop();
+                    match *self.peek(0) {
+                        Value::Class(class) => {
+                            self.classes[class].methods.insert(name, method);
+                        }
+                        _ => return self.runtime_error("Methods can only be added to classes."),
+                    }
+                }
+                OpCode::Field(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let default = self.pop();
+                    match *self.peek(0) {
+                        Value::Class(class) => self.classes[class].fields.push((name, default)),
+                        _ => return self.runtime_error("Fields can only be added to classes."),
+                    }
+                }
+                OpCode::Inherit => {
+                    let superclass = match *self.peek(1) {
+                        Value::Class(class) => class,
+                        other => {
+                            let msg = format!(
+                                "Superclass must be a class, got {}.",
+                                self.describe_value(&other)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                    };
+                    let subclass = match self.pop() {
+                        Value::Class(class) => class,
+                        _ => return self.runtime_error("Can only inherit into a class."),
+                    };
+                    // copied before the subclass' own methods are added, which override them
+                    let methods = self.classes[superclass].methods.clone();
+                    self.classes[subclass].methods.extend(methods);
+                    let fields = self.classes[superclass].fields.clone();
+                    self.classes[subclass].fields.extend(fields);
+                }
+                OpCode::GetProperty(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let target = self.pop();
+                    let bound = match target {
+                        // fields shadow methods
+                        Value::Instance(instance) => {
+                            match self.instances[instance].fields.get(&name) {
+                                Some(&value) => value,
+                                None => {
+                                    let class = self.instances[instance].class;
+                                    self.bind_method(class, name, target)?
+                                }
+                            }
+                        }
+                        _ => self.bind_builtin_method(target, name)?,
+                    };
+                    self.stack.push(bound);
+                }
+                OpCode::GetSuper(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let superclass = match self.pop() {
+                        Value::Class(class) => class,
+                        _ => return self.runtime_error("constant is not Value::Class!"),
+                    };
+                    let receiver = self.pop();
+                    let bound = self.bind_method(superclass, name, receiver)?;
+                    self.stack.push(bound);
+                }
+                OpCode::SetProperty(idx) => {
+                    let name = self.read_identifier(idx)?;
+                    let value = self.pop();
+                    let target = self.pop();
+                    match target {
+                        Value::Instance(instance) if self.instances[instance].frozen => {
+                            let msg = format!(
+                                "Cannot set field '{}' of a frozen {}.",
+                                self.interner.lookup(name),
+                                self.format_value(&target)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                        Value::Instance(instance) => {
+                            self.instances[instance].fields.insert(name, value);
+                        }
+                        _ => {
+                            let msg = format!(
+                                "Only instances have fields, got {}.",
+                                self.describe_value(&target)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                    }
+                    // an assignment is an expression, e.g. `p.x = p.y = 3`
+                    self.stack.push(value);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Closure(idx) => {
+                    let f_idx = match self.read_constant(idx)? {
+                        Value::Function(f_idx) if f_idx < self.functions.len() => f_idx,
+                        _ => return self.runtime_error("constant is not Value::Function!"),
+                    };
+                    let closure = self.new_closure(f_idx)?;
+                    self.stack.push(Value::Closure(closure));
                 }
                 OpCode::Equal => {
                     let b = self.pop();
                     let a = self.pop();
+                    if self.runtime_warnings {
+                        self.check_equality(a, b)?;
+                    }
                     self.stack.push(Value::Bool(values_equal(a, b)));
                 }
+                OpCode::IsNil | OpCode::IsTrue | OpCode::IsFalse => {
+                    let literal = match op {
+                        OpCode::IsNil => Value::Nil,
+                        OpCode::IsTrue => Value::Bool(true),
+                        _ => Value::Bool(false),
+                    };
+                    let value = self.pop();
+                    if self.runtime_warnings {
+                        self.check_equality(value, literal)?;
+                    }
+                    self.stack.push(Value::Bool(values_equal(value, literal)));
+                }
                 OpCode::Greater => {
-                    self.binary_op(|x, y| x > y, Value::Bool)?;
+                    self.comparison_op(">", |x, y| x > y, |x, y| x > y)?;
                 }
                 OpCode::Less => {
-                    self.binary_op(|x, y| x < y, Value::Bool)?;
+                    self.comparison_op("<", |x, y| x < y, |x, y| x < y)?;
+                }
+                OpCode::GreaterEqual => {
+                    self.comparison_op(
+                        ">=",
+                        |x, y| x >= y,
+                        |x, y| x.partial_cmp(&y) != Some(Ordering::Less),
+                    )?;
+                }
+                OpCode::LessEqual => {
+                    self.comparison_op(
+                        "<=",
+                        |x, y| x <= y,
+                        |x, y| x.partial_cmp(&y) != Some(Ordering::Greater),
+                    )?;
                 }
                 OpCode::Add => match (self.peek(0), self.peek(1)) {
-                    (Value::Number(_), Value::Number(_)) => {
-                        self.binary_op(|x, y| x + y, Value::Number)?;
-                    }
                     (Value::StringObj(_), Value::StringObj(_)) => {
                         self.concatenate()?;
                     }
-                    _ => return self.runtime_error("Operand must be a number."),
+                    (b, a) if as_f64(a).is_some() && as_f64(b).is_some() => {
+                        self.numeric_op("+", i64::checked_add, |x, y| x + y)?;
+                    }
+                    _ => return self.operands_error("+", "two numbers or two strings"),
+                },
+                OpCode::AddNumbers => match (*self.peek(1), *self.peek(0)) {
+                    // skips the type dispatch of `Add`; checked arithmetic still checks the sum
+                    (Value::Number(a), Value::Number(b)) if !self.checked_arithmetic => {
+                        self.pop();
+                        self.pop();
+                        self.stack.push(Value::Number(a + b));
+                    }
+                    _ => self.numeric_op("+", i64::checked_add, |x, y| x + y)?,
                 },
                 OpCode::Subtract => {
-                    self.binary_op(|x, y| x - y, Value::Number)?;
+                    self.numeric_op("-", i64::checked_sub, |x, y| x - y)?;
                 }
                 OpCode::Multiply => {
-                    self.binary_op(|x, y| x * y, Value::Number)?;
+                    self.numeric_op("*", i64::checked_mul, |x, y| x * y)?;
                 }
                 OpCode::Divide => {
-                    self.binary_op(|x, y| x / y, Value::Number)?;
+                    // stays an integer only if there is no remainder
+                    self.numeric_op(
+                        "/",
+                        |x, y| match x.checked_rem(y) {
+                            Some(0) => x.checked_div(y),
+                            _ => None,
+                        },
+                        |x, y| x / y,
+                    )?;
+                }
+                // truncated like Rust's `%` (and C's `fmod`), by zero it's NaN, or a float
+                // for integers
+                OpCode::Modulo => {
+                    self.numeric_op("%", i64::checked_rem, |x, y| x % y)?;
+                }
+                // a negative exponent of an integer gives a float, e.g. 2 ** -1 is 0.5
+                OpCode::Power => {
+                    self.numeric_op(
+                        "**",
+                        |x, y| x.checked_pow(u32::try_from(y).ok()?),
+                        f64::powf,
+                    )?;
+                }
+                OpCode::BitAnd => self.bitwise_op("&", |x, y| x & y)?,
+                OpCode::BitOr => self.bitwise_op("|", |x, y| x | y)?,
+                OpCode::BitXor => self.bitwise_op("^", |x, y| x ^ y)?,
+                // only the low 6 bits of the count are used, so shifting by 64 is shifting by 0
+                OpCode::ShiftLeft => self.bitwise_op("<<", |x, y| x << (y & 63))?,
+                OpCode::ShiftRight => self.bitwise_op(">>", |x, y| x >> (y & 63))?,
+                OpCode::BitNot => {
+                    let value = self.pop();
+                    let result = match value {
+                        Value::Int(n) => Ok(Value::Int(!n)),
+                        Value::Number(_) => {
+                            as_i64(&value, "Operand of '~'").map(|n| Value::Number(!n as f64))
+                        }
+                        other => Err(format!(
+                            "Operand to '~' must be a number, got {}.",
+                            self.describe_value(&other)
+                        )),
+                    };
+                    match result {
+                        Ok(result) => self.stack.push(result),
+                        Err(msg) => {
+                            self.stack.push(value);
+                            return self.runtime_error(&msg);
+                        }
+                    }
                 }
                 OpCode::Not => {
                     let val = self.pop();
-                    self.stack.push(Value::Bool(self.is_falsey(&val)))
+                    let falsey = self.is_falsey(val)?;
+                    self.stack.push(Value::Bool(falsey))
                 }
-                OpCode::Negate => {
-                    if let Value::Number(val) = self.peek(0) {
-                        let neg_val = -val;
+                OpCode::Negate => match *self.peek(0) {
+                    Value::Number(val) => {
                         self.pop();
-                        self.stack.push(Value::Number(neg_val));
-                    } else {
-                        return self.runtime_error("Operand must be a number.");
+                        self.stack.push(Value::Number(-val));
                     }
-                }
+                    Value::Int(val) => {
+                        self.pop();
+                        let neg_val = match val.checked_neg() {
+                            Some(neg_val) => Value::Int(neg_val),
+                            None if self.checked_arithmetic => {
+                                let msg = format!("Arithmetic overflow in '-' (-{}).", val);
+                                return self.runtime_error(&msg);
+                            }
+                            None => Value::Number(-(val as f64)),
+                        };
+                        self.stack.push(neg_val);
+                    }
+                    other => {
+                        let msg = format!(
+                            "Operand to '-' must be a number, got {}.",
+                            self.describe_value(&other)
+                        );
+                        return self.runtime_error(&msg);
+                    }
+                },
                 OpCode::Print => {
-                    print!("OpCode::Print: ");
-                    print_value(&self.pop(), &self.interner);
-                    println!();
+                    let value = self.pop();
+                    let line = self.format_value(&value);
+                    self.output.out.write_line(&line);
                 }
                 OpCode::Jump(offset) => {
                     self.frames.last_mut().unwrap().ip += offset;
                 }
                 OpCode::JumpIfFalse(offset) => {
-                    if self.is_falsey(self.peek(0)) {
+                    if self.is_falsey(*self.peek(0))? {
                         self.frames.last_mut().unwrap().ip += offset;
                     }
                 }
                 OpCode::Loop(offset) => {
-                    self.frames.last_mut().unwrap().ip -= offset + 1;
+                    // jump straight to the target, `ip - offset - 1` underflows for a loop at ip 0
+                    self.frames.last_mut().unwrap().ip -= offset;
+                    continue;
+                }
+                OpCode::Range => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    match (as_range_bound(&start), as_range_bound(&end)) {
+                        (Ok(start), Ok(end)) => self.stack.push(Value::Range(start, end)),
+                        (Err(msg), _) | (_, Err(msg)) => return self.runtime_error(&msg),
+                    }
+                }
+                OpCode::In => {
+                    let range = self.pop();
+                    let value = self.pop();
+                    let (start, end) = match range {
+                        Value::Range(start, end) => (start, end),
+                        _ => {
+                            let msg = format!(
+                                "Right operand of 'in' must be a range, got {}.",
+                                self.describe_value(&range)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                    };
+                    // e.g. `1.5 in 0..3` and `"a" in 0..3` are false
+                    let contains = as_f64(&value).is_some_and(|n| {
+                        n.fract() == 0.0 && n >= f64::from(start) && n < f64::from(end)
+                    });
+                    self.stack.push(Value::Bool(contains));
+                }
+                OpCode::Index => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    match target {
+                        Value::Range(start, end) => {
+                            match as_index(&index, "Range index", range_len(start, end)) {
+                                Ok(i) => {
+                                    self.stack.push(Value::Number(f64::from(start) + i as f64))
+                                }
+                                Err(msg) => return self.runtime_error(&msg),
+                            }
+                        }
+                        _ => {
+                            let msg = format!(
+                                "Can only index ranges, got {}.",
+                                self.describe_value(&target)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                    }
+                }
+                OpCode::ForIn(slot, offset) => {
+                    // x, the range and the index of the next element are consecutive locals
+                    let slot = slot as usize;
+                    let (start, end) = match frame.slot(&self.stack, slot + 1) {
+                        Value::Range(start, end) => (start, end),
+                        other => {
+                            let msg = format!(
+                                "Can only iterate over a range, got {}.",
+                                self.describe_value(&other)
+                            );
+                            return self.runtime_error(&msg);
+                        }
+                    };
+                    // starts at the Zero emitted by the compiler, only ForIn writes it
+                    let index = as_f64(&frame.slot(&self.stack, slot + 2)).unwrap_or(f64::INFINITY)
+                        as usize;
+                    if index < range_len(start, end) {
+                        let value = Value::Number(f64::from(start) + index as f64);
+                        frame.set_slot(&mut self.stack, slot, value);
+                        frame.set_slot(
+                            &mut self.stack,
+                            slot + 2,
+                            Value::Number((index + 1) as f64),
+                        );
+                    } else {
+                        self.frames.last_mut().unwrap().ip += offset;
+                    }
                 }
                 OpCode::Return => {
                     // When a function returns a value, that value will be on top of the stack.
                     // We’re about to discard the called function’s entire stack window,
                     // so we pop that return value off and hang on to it.
                     let ret_val = self.pop();
-                    // Then we discard the CallFrame for the current returning function.
-                    self.frames.pop();
-                    // If that was the very last CallFrame, it means we’ve finished executing the top-level code.
-                    // The entire program is done, so we pop the main script function from the stack and then exit the interpreter.
-                    if self.frames.is_empty() {
-                        self.stack.pop();
-                        return Ok(());
+                    // Then we discard the CallFrame for the current returning function,
+                    // and all of the slots the callee was using for its parameters and local variables.
+                    let returning_frame = self.frames.pop().unwrap();
+                    self.close_upvalues(returning_frame.slot_offset);
+                    returning_frame.close(&mut self.stack);
+                    // If that was the very last CallFrame, it means we’ve finished executing the top-level code
+                    // (or the function called by `call_function`), so we exit the interpreter.
+                    if self.frames.len() == base_depth {
+                        // the implicit `nil` return of a script leaves the stack empty, while a
+                        // function called by a native returns onto the stack of its caller
+                        debug_assert!(
+                            base_depth > 0 || self.stack.is_empty(),
+                            "Top-level code returned with {} values left on the stack.",
+                            self.stack.len()
+                        );
+                        return Ok(ret_val);
                     }
-                    // Otherwise, we discard all of the slots the callee was using for its parameters and local variables.
-                    // Then we push the return value back onto the stack, where the caller can find it.
-                    self.stack.truncate(self.frames.last().unwrap().slot_offset); // TODO: check if correct
+                    // Otherwise, we push the return value back onto the stack, where the caller can find it.
                     self.stack.push(ret_val);
                     // frame = *self.frames.last().unwrap(); // switch back to caller
                     // no need, because we will always get the last frame in the next iteration, and we just popped the last one
                 }
                 OpCode::Call(arg_count) => {
-                    if !self.call_value(*self.peek(arg_count.into()), arg_count) {
-                        return Err(InterpretResult::RuntimeError);
-                    }
+                    self.call_value(*self.peek(arg_count.into()), arg_count)?;
                     // frame = *self.frames.last().unwrap(); // switch to new CallFrame
                     // no need, because we will always get the last frame in the next iteration, and we just pushed the new one
                     continue; // don't increment self.frames.last().unwrap().ip if this is a new call
@@ -248,61 +1538,501 @@ impl VM {
         }
     }
 
+    // The constant at `idx` in the chunk of the current frame.
+    // A bad index (from a compiler bug or corrupted bytecode) is a runtime error, not a panic.
+    // `OpCode::CheckOperands` before the binary instruction `op`
+    fn check_operands(&mut self, idx: u8, op: Option<OpCode>) -> Result<(), LoxError> {
+        let (a, b) = (*self.peek(1), *self.peek(0));
+        let numbers = as_f64(&a).is_some() && as_f64(&b).is_some();
+        let expected = match op {
+            Some(OpCode::Add) => {
+                let strings = matches!((a, b), (Value::StringObj(_), Value::StringObj(_)));
+                if numbers || strings {
+                    return Ok(());
+                }
+                "two numbers or two strings"
+            }
+            Some(op) if checks_operands(&op) => {
+                if numbers {
+                    return Ok(());
+                }
+                "two numbers"
+            }
+            _ => {
+                let msg = format!(
+                    "Paranoid check failed: no binary instruction after the operand check, got {:?}.",
+                    op
+                );
+                return self.runtime_error(&msg);
+            }
+        };
+        let expression = match self.read_constant(idx)? {
+            Value::StringObj(text) => self.interner.lookup(text).to_owned(),
+            other => self.format_value(&other),
+        };
+        let msg = format!(
+            "Paranoid check failed: operands of '{}' must be {}, got {} and {}.",
+            expression,
+            expected,
+            self.describe_value(&a),
+            self.describe_value(&b)
+        );
+        self.runtime_error(&msg)
+    }
+
+    fn read_constant(&mut self, idx: u8) -> Result<Value, LoxError> {
+        let frame = self.frames.last().unwrap();
+        match self.functions[frame.f_idx].chunk.constant(idx as usize) {
+            Some(constant) => Ok(*constant),
+            None => {
+                let msg = format!(
+                    "Invalid constant index {} at ip {} in {}.",
+                    idx,
+                    frame.ip,
+                    self.function_name(frame.f_idx)
+                );
+                self.runtime_error(&msg)
+            }
+        }
+    }
+
+    // The name of a global variable, stored in the constant table
+    fn read_identifier(&mut self, idx: u8) -> Result<StringObjIdx, LoxError> {
+        match self.read_constant(idx)? {
+            Value::Identifier(name) => Ok(name),
+            _ => self.runtime_error("constant is not Value::Identifier!"),
+        }
+    }
+
+    // Creates a closure of the function, capturing its variables from the current frame
+    fn new_closure(&mut self, f_idx: usize) -> Result<usize, LoxError> {
+        let frame = *self.frames.last().unwrap();
+        let mut upvalues = Vec::with_capacity(self.functions[f_idx].upvalues.len());
+        for i in 0..self.functions[f_idx].upvalues.len() {
+            let capture = self.functions[f_idx].upvalues[i];
+            let index = capture.index as usize;
+            if capture.is_local {
+                upvalues.push(self.capture_upvalue(frame.slot_offset + index));
+                continue;
+            }
+            // a variable of a function further out, which the enclosing closure captured already
+            match frame
+                .closure
+                .and_then(|closure| self.closures[closure].upvalues.get(index))
+            {
+                Some(&upvalue) => upvalues.push(upvalue),
+                None => {
+                    let msg = format!(
+                        "Invalid upvalue index {} in {}.",
+                        index,
+                        self.function_name(frame.f_idx)
+                    );
+                    return self.runtime_error(&msg);
+                }
+            }
+        }
+        self.closures.push(Closure { f_idx, upvalues });
+        Ok(self.closures.len() - 1)
+    }
+
+    // The upvalue of the stack slot, shared by every closure that captures the variable
+    fn capture_upvalue(&mut self, slot: usize) -> usize {
+        let open_slot = |upvalue: &Upvalue| match *upvalue {
+            Upvalue::Open(slot) => slot,
+            Upvalue::Closed(_) => unreachable!("closed upvalues leave the open list"),
+        };
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| open_slot(&self.upvalues[upvalue]) < slot);
+        match self.open_upvalues.get(position) {
+            Some(&upvalue) if open_slot(&self.upvalues[upvalue]) == slot => upvalue,
+            _ => {
+                self.upvalues.push(Upvalue::Open(slot));
+                self.open_upvalues.insert(position, self.upvalues.len() - 1);
+                self.upvalues.len() - 1
+            }
+        }
+    }
+
+    // Moves the values of the captured variables in slots from `first_slot` on into their
+    // upvalues, before the slots are popped
+    fn close_upvalues(&mut self, first_slot: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            match self.upvalues[upvalue] {
+                Upvalue::Open(slot) if slot >= first_slot => {
+                    self.upvalues[upvalue] = Upvalue::Closed(self.stack[slot]);
+                    self.open_upvalues.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // The upvalue captured as `idx` by the closure of the current frame
+    fn upvalue(&mut self, idx: u8) -> Result<usize, LoxError> {
+        let frame = self.frames.last().unwrap();
+        let upvalue = frame
+            .closure
+            .and_then(|closure| self.closures[closure].upvalues.get(idx as usize));
+        match upvalue {
+            Some(&upvalue) => Ok(upvalue),
+            None => {
+                let msg = format!(
+                    "Invalid upvalue index {} in {}.",
+                    idx,
+                    self.function_name(frame.f_idx)
+                );
+                self.runtime_error(&msg)
+            }
+        }
+    }
+
+    fn cache_global_slot(&mut self, f_idx: usize, ip: usize, slot: GlobalSlot) {
+        let function = &mut self.functions[f_idx];
+        if function.global_cache.is_empty() {
+            function.global_cache = vec![None; function.chunk.len()];
+        }
+        if let Some(entry) = function.global_cache.get_mut(ip) {
+            *entry = Some(slot);
+        }
+    }
+
+    // `fun f` redefines the global function `f`: its index gets a copy of the new body, so
+    // references to the old function call the new one. The compiled body it had is kept in
+    // `replaced_bodies`, so running its script again defines it again.
+    fn redefine_function(&mut self, old: usize, new: usize) -> Result<(), LoxError> {
+        let body = match self.replaced_bodies.get(&new) {
+            Some(body) => body.clone(),
+            // the script defines `f` again, which still has its compiled body
+            None if old == new => return Ok(()),
+            None => self.functions[new].clone(),
+        };
+        let (old_arity, new_arity) = (self.functions[old].arity, body.arity);
+        if old_arity != new_arity {
+            let msg = format!(
+                "Redefining '{}' changes its arity from {} to {}.",
+                self.function_name(old),
+                old_arity,
+                new_arity
+            );
+            self.runtime_warning(warnings::ARITY_CHANGE, &msg)?;
+        }
+        if !self.replaced_bodies.contains_key(&old) {
+            self.replaced_bodies
+                .insert(old, self.functions[old].clone());
+        }
+        self.functions[old] = body;
+        Ok(())
+    }
+
+    fn function_name(&self, f_idx: usize) -> &str {
+        self.functions[f_idx].display_name(&self.interner)
+    }
+
     // helper function for popping stack
     fn pop(&mut self) -> Value {
         self.stack.pop().expect("Empty stack")
     }
 
     fn peek(&self, distance: usize) -> &Value {
-        return self
-            .stack
+        self.stack
             .get(self.stack.len() - 1 - distance)
-            .expect("Failed to peek");
+            .expect("Failed to peek")
     }
 
-    fn call(&mut self, f_idx: usize, arg_count: u8) -> bool {
+    fn call(&mut self, f_idx: usize, arg_count: u8) -> Result<(), LoxError> {
         if arg_count != self.functions[f_idx].arity {
             let msg = format!(
                 "Expected {} arguments but got {}.",
                 self.functions[f_idx].arity, arg_count
             );
-            self.runtime_error(&msg);
-            return false;
+            return self.runtime_error(&msg);
         }
-        if self.frames.len() == FRAMES_MAX {
-            self.runtime_error("Stack overflow.");
-            return false;
+        if self.frames.len() >= self.limits.max_call_depth {
+            return self.runtime_error("Stack overflow.");
         }
+        self.charge(self.cost_model.call)?;
         let frame = CallFrame::new(f_idx, self.stack.len() - arg_count as usize - 1);
         self.frames.push(frame);
-        true
+        Ok(())
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), LoxError> {
         match callee {
-            Value::Function(f_idx) => self.call(f_idx, arg_count),
-            _ => {
-                self.runtime_error("Can only call functions and classes.");
-                false
+            Value::Function(f_idx) if f_idx < self.functions.len() => self.call(f_idx, arg_count),
+            Value::Function(f_idx) => {
+                let msg = format!("Invalid function index {}.", f_idx);
+                self.runtime_error(&msg)
+            }
+            Value::Native(n_idx) => self.call_native(n_idx, arg_count),
+            Value::Class(class) if class < self.classes.len() => {
+                // the instance replaces the class in the callee's slot, where a call's result goes,
+                // which is slot zero (`this`) of the initializer's frame
+                let instance = self.instantiate(class)?;
+                let callee_slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[callee_slot] = instance;
+                if let Some(init) = self.classes[class].initializer(&self.interner) {
+                    return self.call_value(init, arg_count);
+                }
+                self.check_class_arity(arg_count)?;
+                // unlike a function call, no new frame will move past the Call instruction
+                self.frames.last_mut().unwrap().ip += 1;
+                Ok(())
             }
+            Value::BoundMethod(bound) if bound < self.bound_methods.len() => {
+                let BoundMethod { receiver, method } = self.bound_methods[bound];
+                let callee_slot = self.stack.len() - arg_count as usize - 1;
+                if let Value::Native(n_idx) = method {
+                    return self.call_builtin_method(n_idx, receiver, arg_count);
+                }
+                self.stack[callee_slot] = receiver;
+                self.call_value(method, arg_count)
+            }
+            Value::Closure(closure) if closure < self.closures.len() => {
+                self.call(self.closures[closure].f_idx, arg_count)?;
+                self.frames.last_mut().unwrap().closure = Some(closure);
+                Ok(())
+            }
+            _ => self.runtime_error("Can only call functions and classes."),
+        }
+    }
+
+    // The method of the class bound to the receiver, e.g. for `instance.method`
+    fn bind_method(
+        &mut self,
+        class: usize,
+        name: StringObjIdx,
+        receiver: Value,
+    ) -> Result<Value, LoxError> {
+        let method = match self.classes[class].methods.get(&name) {
+            Some(&method) => method,
+            None => {
+                let msg = format!("Undefined property '{}'.", self.interner.lookup(name));
+                return self.runtime_error(&msg);
+            }
+        };
+        self.bound_methods.push(BoundMethod { receiver, method });
+        Ok(Value::BoundMethod(self.bound_methods.len() - 1))
+    }
+
+    // The builtin method of the value's type bound to it, e.g. for `"abc".len`
+    fn bind_builtin_method(
+        &mut self,
+        receiver: Value,
+        name: StringObjIdx,
+    ) -> Result<Value, LoxError> {
+        let method = match self.builtin_methods.get(&(TypeTag::of(&receiver), name)) {
+            Some(&n_idx) => Value::Native(n_idx),
+            None => {
+                let msg = format!(
+                    "Only instances have properties, got {}.",
+                    self.describe_value(&receiver)
+                );
+                return self.runtime_error(&msg);
+            }
+        };
+        self.bound_methods.push(BoundMethod { receiver, method });
+        Ok(Value::BoundMethod(self.bound_methods.len() - 1))
+    }
+
+    // Classes without an `init` method take no arguments
+    fn check_class_arity(&mut self, arg_count: u8) -> Result<(), LoxError> {
+        if arg_count == 0 {
+            return Ok(());
+        }
+        let msg = format!("Expected 0 arguments but got {}.", arg_count);
+        self.runtime_error(&msg)
+    }
+
+    // A new instance of the class, with the default values of its declared fields
+    fn instantiate(&mut self, class: usize) -> Result<Value, LoxError> {
+        self.instances.push(Instance::new(class));
+        let instance = self.instances.len() - 1;
+        for i in 0..self.classes[class].fields.len() {
+            let (name, default) = self.classes[class].fields[i];
+            // the method runs in a frame of its own, with the instance in slot zero (`this`)
+            let base_depth = self.frames.len();
+            self.stack.push(Value::Instance(instance));
+            self.call_value(default, 0)?;
+            let value = self.run(base_depth)?;
+            self.instances[instance].fields.insert(name, value);
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    fn audit_define(&mut self, name: StringObjIdx, value: Value) {
+        let event = AuditEvent::GlobalDefined {
+            name: self.interner.lookup(name).to_owned(),
+            old: self.globals.get(&name).map(|old| self.format_value(old)),
+            new: self.format_value(&value),
+        };
+        self.audit_log.as_mut().unwrap().push(event);
+    }
+
+    fn audit_assign(&mut self, name: StringObjIdx, old: Value, new: Value) {
+        let event = AuditEvent::GlobalAssigned {
+            name: self.interner.lookup(name).to_owned(),
+            old: self.format_value(&old),
+            new: self.format_value(&new),
+        };
+        self.audit_log.as_mut().unwrap().push(event);
+    }
+
+    fn audit_native_call(&mut self, n_idx: usize, args: &[Value]) {
+        let event = AuditEvent::NativeCalled {
+            name: self.natives[n_idx].name.clone(),
+            args: args.iter().map(|arg| self.format_value(arg)).collect(),
+        };
+        self.audit_log.as_mut().unwrap().push(event);
+    }
+
+    // Natives run to completion right away, no CallFrame is needed
+    fn call_native(&mut self, n_idx: usize, arg_count: u8) -> Result<(), LoxError> {
+        let native = &self.natives[n_idx];
+        if arg_count != native.arity {
+            let msg = format!("Expected {} arguments but got {}.", native.arity, arg_count);
+            return self.runtime_error(&msg);
         }
+        let args_start = self.stack.len() - arg_count as usize;
+        let args: Vec<Value> = self.stack[args_start..].to_vec();
+        let result = self.invoke_native(n_idx, &args)?;
+        // discard the args and the native itself, then push the result
+        self.stack.truncate(args_start - 1);
+        self.stack.push(result);
+        // unlike a function call, no new frame will move past the Call instruction
+        self.frames.last_mut().unwrap().ip += 1;
+        Ok(())
     }
 
-    fn is_falsey(&self, value: &Value) -> bool {
+    // Every call of a native goes through here, from a script or from Rust (e.g. a callback
+    // of another native), so each is charged and audited. The arity is already checked.
+    fn invoke_native(&mut self, n_idx: usize, args: &[Value]) -> Result<Value, LoxError> {
+        let function = self.natives[n_idx].function;
+        self.charge(self.cost_model.native_call)?;
+        if self.audit_log.is_some() {
+            self.audit_native_call(n_idx, args);
+        }
+        match function(self, args) {
+            Ok(result) => Ok(result),
+            Err(NativeError::Message(msg)) => self.runtime_error(&msg),
+            Err(NativeError::Propagate(error)) => Err(error),
+        }
+    }
+
+    // A builtin method takes its receiver as the first argument of the native, it goes
+    // below the arguments of the call
+    fn call_builtin_method(
+        &mut self,
+        n_idx: usize,
+        receiver: Value,
+        arg_count: u8,
+    ) -> Result<(), LoxError> {
+        let arity = self.natives[n_idx].arity - 1;
+        if arg_count != arity {
+            let msg = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return self.runtime_error(&msg);
+        }
+        let args_start = self.stack.len() - arg_count as usize;
+        self.stack.insert(args_start, receiver);
+        self.call_native(n_idx, arg_count + 1)
+    }
+
+    // Gas used by the current (or last) run, see `cost_model`
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    fn charge(&mut self, cost: u64) -> Result<(), LoxError> {
+        self.gas_used = self.gas_used.saturating_add(cost);
+        if self.gas_used > self.limits.max_gas {
+            let msg = format!("Gas limit ({}) exhausted.", self.limits.max_gas);
+            return self.runtime_error(&msg);
+        }
+        Ok(())
+    }
+
+    fn is_falsey(&mut self, value: Value) -> Result<bool, LoxError> {
         match value {
-            Value::Bool(b) => !b,
-            Value::Nil => true,
-            _ => false,
+            Value::Bool(b) => Ok(!b),
+            Value::Nil => Ok(true),
+            _ => {
+                if self.runtime_warnings {
+                    let msg = format!(
+                        "Condition is a {}, which is always true.",
+                        type_name(&value)
+                    );
+                    self.runtime_warning(warnings::TRUTHY_CONDITION, &msg)?;
+                }
+                Ok(is_falsey(&value))
+            }
         }
     }
 
-    fn concatenate(&mut self) -> Result<(), InterpretResult> {
+    // `==` on operands that are probably a mistake, with `runtime_warnings`
+    fn check_equality(&mut self, a: Value, b: Value) -> Result<(), LoxError> {
+        let (a_type, b_type) = (TypeTag::of(&a), TypeTag::of(&b));
+        // comparing with nil is how optional values are checked
+        if a_type != b_type && a_type != TypeTag::Nil && b_type != TypeTag::Nil {
+            let msg = format!(
+                "Comparing a {} with a {} using '==' is always false.",
+                a_type.name(),
+                b_type.name()
+            );
+            return self.runtime_warning(warnings::MIXED_EQUALITY, &msg);
+        }
+        if let (Value::Number(x), Value::Number(y)) = (a, b) {
+            if x.fract() != 0.0 || y.fract() != 0.0 {
+                let msg = format!(
+                    "Comparing fractional numbers with '==' ({} == {}) is unreliable, \
+                     compare their difference with a tolerance instead.",
+                    format_number(x),
+                    format_number(y)
+                );
+                return self.runtime_warning(warnings::FRACTIONAL_EQUALITY, &msg);
+            }
+        }
+        Ok(())
+    }
+
+    // Reports a warning of the running instruction, once per instruction: in a loop only the
+    // first iteration warns. It's added to `diagnostics` like the compiler's warnings, and
+    // stops the script like a runtime error if the warning is denied.
+    fn runtime_warning(&mut self, code: &'static str, msg: &str) -> Result<(), LoxError> {
+        let frame = self.frames.last().unwrap();
+        if !self.warned_sites.insert((frame.f_idx, frame.ip)) {
+            return Ok(());
+        }
+        match self.warning_levels.level(Some(code)) {
+            Level::Off => Ok(()),
+            Level::Warn => {
+                let warning = CompileDiagnostic {
+                    severity: Severity::Warning,
+                    code: Some(code),
+                    line: self.current_line(),
+                    column: None,
+                    message: msg.to_owned(),
+                    snippet: None,
+                };
+                eprintln!("{}", warning);
+                self.diagnostics.warnings.push(warning);
+                Ok(())
+            }
+            Level::Error => self.runtime_error(msg),
+        }
+    }
+
+    fn concatenate(&mut self) -> Result<(), LoxError> {
         match (self.pop(), self.pop()) {
             // note: the first pop returns the right operand
             (Value::StringObj(b), Value::StringObj(a)) => {
-                let b_str = self.interner.lookup(b);
-                let a_str = self.interner.lookup(a);
-                let res = a_str.to_owned() + b_str;
+                let max_length = self.limits.max_string_length;
+                let length = self.interner.lookup(a).len() + self.interner.lookup(b).len();
+                if length > max_length {
+                    let msg = format!("String length limit ({}) exceeded.", max_length);
+                    return self.runtime_error(&msg);
+                }
+                self.charge(self.cost_model.concatenation(length))?;
+                let res = self.interner.lookup(a).to_owned() + self.interner.lookup(b);
                 let res_idx = self.interner.intern_string(res);
                 self.stack.push(Value::StringObj(res_idx));
                 Ok(())
@@ -312,32 +2042,171 @@ impl VM {
                 // TODO: Unnecessary? Runtime failure will crash program anyway
                 self.stack.push(a);
                 self.stack.push(b);
-                self.runtime_error("Operands must be two strings.")
+                self.operands_error("+", "two numbers or two strings")
             }
         }
     }
 
-    fn binary_op<T>(
+    // Arithmetic on two numbers. Two integers give an integer, unless `int_op` returns None
+    // (e.g. on overflow), in which case the result is the float one, like for mixed operands.
+    fn numeric_op(
+        &mut self,
+        op: &str,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<(), LoxError> {
+        // note: the first pop returns the right operand
+        let (b, a) = (self.pop(), self.pop());
+        if self.checked_arithmetic {
+            self.check_arithmetic(op, a, b, int_op, float_op)?;
+        }
+        match (b, a) {
+            (Value::Int(b), Value::Int(a)) => {
+                let result = match int_op(a, b) {
+                    Some(int) => Value::Int(int),
+                    None => Value::Number(float_op(a as f64, b as f64)),
+                };
+                self.stack.push(result);
+                Ok(())
+            }
+            (b, a) => self.float_op(op, a, b, |x, y| Value::Number(float_op(x, y))),
+        }
+    }
+
+    // For `checked_arithmetic`: fails where `numeric_op` would divide by zero, give NaN or
+    // an infinity, or turn an overflowing integer operation into a float
+    fn check_arithmetic(
+        &mut self,
+        op: &str,
+        a: Value,
+        b: Value,
+        int_op: fn(i64, i64) -> Option<i64>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<(), LoxError> {
+        let (x, y) = match (as_f64(&a), as_f64(&b)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Ok(()), // not numbers, `numeric_op` reports it
+        };
+        let problem = if (op == "/" || op == "%") && y == 0.0 {
+            "Division by zero"
+        } else {
+            match (a, b) {
+                // a division with a remainder or a negative exponent is a float, not an overflow
+                (Value::Int(a), Value::Int(b))
+                    if int_op(a, b).is_none()
+                        && match op {
+                            "/" => a.checked_div(b).is_none(),
+                            "**" => b >= 0,
+                            _ => true,
+                        } =>
+                {
+                    "Arithmetic overflow"
+                }
+                _ => {
+                    let result = float_op(x, y);
+                    if result.is_nan() {
+                        "Undefined arithmetic result (NaN)"
+                    } else if result.is_infinite() {
+                        "Arithmetic overflow"
+                    } else {
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        let msg = format!(
+            "{} in '{}' ({} {} {}).",
+            problem,
+            op,
+            self.format_value(&a),
+            op,
+            self.format_value(&b)
+        );
+        // keep the operands on the stack, like the other operand errors
+        self.stack.push(a);
+        self.stack.push(b);
+        self.runtime_error(&msg)
+    }
+
+    // Bitwise operators work on whole numbers (see `as_i64`), e.g. `5.5 & 1` is an error.
+    // Like `numeric_op`, two integers give an integer and other numbers a float.
+    fn bitwise_op(&mut self, op: &str, f: fn(i64, i64) -> i64) -> Result<(), LoxError> {
+        let (b, a) = (self.pop(), self.pop());
+        let what = format!("Operand of '{}'", op);
+        let operands = match (as_f64(&a), as_f64(&b)) {
+            (Some(_), Some(_)) => as_i64(&a, &what).and_then(|x| Ok((x, as_i64(&b, &what)?))),
+            _ => {
+                self.stack.push(a);
+                self.stack.push(b);
+                return self.operands_error(op, "two numbers");
+            }
+        };
+        let result = match operands {
+            Ok((x, y)) => f(x, y),
+            Err(msg) => {
+                self.stack.push(a);
+                self.stack.push(b);
+                return self.runtime_error(&msg);
+            }
+        };
+        self.stack.push(match (a, b) {
+            (Value::Int(_), Value::Int(_)) => Value::Int(result),
+            _ => Value::Number(result as f64),
+        });
+        Ok(())
+    }
+
+    // Integers are compared exactly, mixed operands as floats
+    fn comparison_op(
         &mut self,
-        f: fn(f64, f64) -> T,
-        convert: fn(T) -> Value,
-    ) -> Result<(), InterpretResult> {
+        op: &str,
+        int_op: fn(i64, i64) -> bool,
+        float_op: fn(f64, f64) -> bool,
+    ) -> Result<(), LoxError> {
         match (self.pop(), self.pop()) {
-            // note: the first pop returns the right operand
-            (Value::Number(b), Value::Number(a)) => {
-                self.stack.push(convert(f(a, b)));
+            (Value::Int(b), Value::Int(a)) => {
+                self.stack.push(Value::Bool(int_op(a, b)));
                 Ok(())
             }
-            (b, a) => {
+            (b, a) => self.float_op(op, a, b, |x, y| Value::Bool(float_op(x, y))),
+        }
+    }
+
+    fn float_op(
+        &mut self,
+        op: &str,
+        a: Value,
+        b: Value,
+        f: impl Fn(f64, f64) -> Value,
+    ) -> Result<(), LoxError> {
+        match (as_f64(&a), as_f64(&b)) {
+            (Some(x), Some(y)) => {
+                self.stack.push(f(x, y));
+                Ok(())
+            }
+            _ => {
                 // Push them back on the stack
                 // TODO: Unnecessary? Runtime failure will crash program anyway
                 self.stack.push(a);
                 self.stack.push(b);
-                self.runtime_error("Operands must be two numbers.")
+                self.operands_error(op, "two numbers")
             }
         }
     }
 
+    // The error of the binary instruction `op` whose operands, still on the stack, have the wrong
+    // types, e.g. "Operands to '<' must be two numbers, got string ("a") and nil."
+    fn operands_error<T>(&mut self, op: &str, expected: &str) -> Result<T, LoxError> {
+        let msg = format!(
+            "Operands to '{}' must be {}, got {} and {}.",
+            op,
+            expected,
+            self.describe_value(self.peek(1)),
+            self.describe_value(self.peek(0))
+        );
+        self.runtime_error(&msg)
+    }
+
     // pub fn debug_trace_execution(&self) {
     //     print!("          ");
     //     for slot in &self.stack {
@@ -349,23 +2218,48 @@ impl VM {
     //     disassemble_instruction(&self.chunk, self.ip);
     // }
 
+    fn session_position(&self, f_idx: usize, ip: usize) -> Option<SessionPosition> {
+        let function = &self.functions[f_idx];
+        let line = function.chunk.line(ip)?;
+        self.source_map
+            .as_ref()?
+            .position(function.submission?, line)
+    }
+
     // Note: All errors are fatal and immediately halt the interpreter.
     // No variadic functions in rust
-    fn runtime_error(&self, msg: &str) -> Result<(), InterpretResult> {
-        eprintln!("{}", msg);
-
-        for frame in self.frames.iter().rev() {
-            let instruction = frame.ip - 1;
-            let line = self.functions[frame.f_idx].chunk.lines[instruction];
-            if self.functions[frame.f_idx].name.is_some() {
-                let name = self
-                    .interner
-                    .lookup(self.functions[frame.f_idx].name.unwrap());
-                eprintln!("[line {}] in {}()", line, name);
-            } else {
-                eprintln!("[line {}] in script", line);
-            }
-        }
-        Err(InterpretResult::RuntimeError)
+    fn runtime_error<T>(&mut self, msg: &str) -> Result<T, LoxError> {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| TraceFrame {
+                function: self.functions[frame.f_idx]
+                    .name
+                    .map(|_| self.function_name(frame.f_idx).to_owned()),
+                origin: self.functions[frame.f_idx]
+                    .origin
+                    .map(|origin| self.interner.lookup(origin).to_owned()),
+                // ip of every frame points at the instruction being executed (e.g. the Call of a caller)
+                line: self.functions[frame.f_idx].chunk.line(frame.ip),
+                position: self.session_position(frame.f_idx, frame.ip),
+            })
+            .collect();
+        let error = RuntimeError {
+            message: msg.to_owned(),
+            trace,
+        };
+        eprintln!("{}", error);
+
+        // with `Tracer::last`, the trace ends at the failing instruction
+        self.flush_trace();
+
+        // reset the stack, so the VM can be reused (e.g. by the REPL).
+        // Closures that escaped keep the last values of their variables.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.paused = false;
+        Err(LoxError::Runtime(error))
     }
 }
@@ -0,0 +1,93 @@
+// Codes of the warnings reported by the compiler (and the VM), and how each of them is treated
+// (`--warn <code>=off|warn|error` and `--deny-warnings` in the CLI, `VM::warning_levels` for embedders)
+use std::collections::HashMap;
+
+pub const CONSTANT_CONDITION: &str = "W0001"; // e.g. `if (false)`, `while (false)`
+pub const RESERVED_WORD: &str = "W0002"; // identifier that is a keyword at the next lang level
+pub const ARITY_CHANGE: &str = "W0003"; // a global function is redefined with a different arity
+
+// Reported by the VM with `VM::runtime_warnings`, once per instruction
+pub const MIXED_EQUALITY: &str = "W0004"; // `==` on values of different types, e.g. `"1" == 1`
+pub const TRUTHY_CONDITION: &str = "W0005"; // a condition is a string, number, ..., always true
+pub const FRACTIONAL_EQUALITY: &str = "W0006"; // `==` on fractional numbers, e.g. `0.1 + 0.2 == 0.3`
+
+// Every warning code with a short description, new warnings register their code here
+pub const CODES: &[(&str, &str)] = &[
+    (CONSTANT_CONDITION, "condition is always true or false"),
+    (RESERVED_WORD, "identifier will become a reserved word"),
+    (ARITY_CHANGE, "function redefined with a different arity"),
+    (
+        MIXED_EQUALITY,
+        "'==' on values of different types (with --runtime-warn)",
+    ),
+    (
+        TRUTHY_CONDITION,
+        "condition that isn't a boolean or nil (with --runtime-warn)",
+    ),
+    (
+        FRACTIONAL_EQUALITY,
+        "'==' on fractional numbers (with --runtime-warn)",
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Off,   // not reported
+    Warn,  // reported, the script still runs
+    Error, // reported as an error instead, the script doesn't run (or stops, for the VM's warnings)
+}
+
+impl Level {
+    pub fn parse(level: &str) -> Option<Level> {
+        match level {
+            "off" => Some(Level::Off),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WarningLevels {
+    levels: HashMap<&'static str, Level>, // codes configured explicitly
+    deny_all: bool,                       // the others are errors instead of warnings
+}
+
+impl WarningLevels {
+    // Returns false if there is no warning with this code
+    pub fn set(&mut self, code: &str, level: Level) -> bool {
+        match CODES.iter().find(|(known, _)| *known == code) {
+            Some(&(code, _)) => {
+                self.levels.insert(code, level);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Parses and applies `<code>=off|warn|error`, returns false if it's invalid
+    pub fn set_from_str(&mut self, setting: &str) -> bool {
+        match setting.split_once('=') {
+            Some((code, level)) => match Level::parse(level) {
+                Some(level) => self.set(code, level),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    // Warnings that aren't configured explicitly become errors
+    pub fn deny_all(&mut self, deny: bool) {
+        self.deny_all = deny;
+    }
+
+    // Warnings without a code can only be denied as a whole
+    pub fn level(&self, code: Option<&str>) -> Level {
+        match code.and_then(|code| self.levels.get(code)) {
+            Some(&level) => level,
+            None if self.deny_all => Level::Error,
+            None => Level::Warn,
+        }
+    }
+}
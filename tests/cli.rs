@@ -0,0 +1,309 @@
+// Runs the rust-lox binary like a shell would, with stdout and stderr redirected separately
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn print_err_goes_to_stderr() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .arg("tests/fixtures/cli/streams.lox")
+        .output()
+        .expect("Could not run rust-lox");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stdout.lines().any(|line| line == "to stdout"));
+    // with debug_trace_execution, stdout also has the trace, so compare whole lines
+    assert!(!stdout.lines().any(|line| line == "to stderr"));
+    assert_eq!(stderr, "to stderr\n");
+}
+
+#[test]
+fn missing_file_exits_with_74() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .arg("tests/fixtures/cli/missing.lox")
+        .output()
+        .expect("Could not run rust-lox");
+    assert_eq!(output.status.code(), Some(74));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Could not open file 'tests/fixtures/cli/missing.lox': "));
+    // no panic message or backtrace
+    assert!(!stderr.contains("panicked"));
+}
+
+#[test]
+fn warning_flags() {
+    let run = |flags: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .args(flags)
+            .arg("tests/fixtures/cli/warnings.lox")
+            .output()
+            .expect("Could not run rust-lox")
+    };
+    let output = run(&[]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr,
+        "[line 2] Warning W0001: Condition is always false.\n"
+    );
+
+    let output = run(&["--warn", "W0001=off"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = run(&["--deny-warnings"]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr, "[line 2] Error W0001: Condition is always false.\n");
+
+    let output = run(&["--warn", "W0001=sometimes"]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn audit_flag() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .args(["--audit", "tests/fixtures/cli/warnings.lox"])
+        .output()
+        .expect("Could not run rust-lox");
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let audit: Vec<&str> = stderr
+        .lines()
+        .filter(|l| l.starts_with("[audit]"))
+        .collect();
+    assert_eq!(audit, vec!["[audit] define a = 1"]);
+}
+
+#[test]
+fn max_errors_flag() {
+    let run = |flags: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .args(flags)
+            .arg("tests/fixtures/cli/errors.lox")
+            .output()
+            .expect("Could not run rust-lox")
+    };
+    let error_lines = |stderr: &str| {
+        stderr
+            .lines()
+            .filter(|line| line.starts_with("[line "))
+            .count()
+    };
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(error_lines(&stderr), 3);
+    assert!(!stderr.contains("Too many errors"));
+
+    let output = run(&["--max-errors", "2"]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(error_lines(&stderr), 2);
+    assert!(stderr.ends_with("Too many errors (2), stopping.\n"));
+
+    let output = run(&["--max-errors", "0"]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn prelude_and_rc_file() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .args(["--prelude", "tests/fixtures/prelude/helpers.lox"])
+        .arg("tests/fixtures/cli/uses_prelude.lox")
+        .output()
+        .expect("Could not run rust-lox");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "hi"));
+    assert!(stdout.lines().any(|line| line == "42"));
+
+    // a broken rc file is reported, and the REPL still starts
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .env("LOX_RC", "tests/fixtures/prelude/broken.lox")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not run rust-lox");
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(b"print one() + 1;\n")
+        .unwrap();
+    let output = repl.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Undefined variable 'undefinedInPrelude'."));
+    assert!(stdout.lines().any(|line| line == "2"), "{}", stdout);
+}
+
+#[test]
+fn disasm_diff() {
+    let disasm = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .arg("disasm")
+            .args(args)
+            .output()
+            .expect("Could not run rust-lox")
+    };
+    let path = "tests/fixtures/disasm/loop.lox";
+    let output = disasm(&[path]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "L3.2:"));
+
+    // a file against itself has no hunks
+    let output = disasm(&["--diff", path, path]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("@@"));
+
+    let output = disasm(&["--diff-opt", path]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "-   2 OP_FALSE"));
+    assert!(!stdout.lines().any(|line| line.starts_with('+')));
+
+    let output = disasm(&["--diff", path]);
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn trace_flags() {
+    let trace_path = std::env::temp_dir().join(format!("rust-lox-trace-{}", std::process::id()));
+    let run_traced = |args: &[&str], script: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .arg("--trace-file")
+            .arg(&trace_path)
+            .args(args)
+            .arg(script)
+            .output()
+            .expect("Could not run rust-lox");
+        let trace = std::fs::read_to_string(&trace_path).unwrap();
+        (output.status.code(), trace)
+    };
+
+    // only the instructions of `square`, once per call
+    let (code, trace) = run_traced(
+        &["--trace-filter", "square"],
+        "tests/fixtures/cli/trace.lox",
+    );
+    assert_eq!(code, Some(0));
+    assert!(
+        trace.lines().all(|line| line.starts_with("square ")),
+        "{}",
+        trace
+    );
+    assert_eq!(
+        trace
+            .lines()
+            .filter(|line| line.ends_with("OP_RETURN"))
+            .count(),
+        3
+    );
+
+    // the last instructions, ending at the one that failed
+    let (code, trace) = run_traced(&["--trace-last", "3"], "tests/fixtures/cli/trace_error.lox");
+    assert_eq!(code, Some(70));
+    assert_eq!(
+        trace.lines().collect::<Vec<_>>(),
+        vec![
+            "check 5 3 OP_GET_LOCAL 1",
+            "check 6 3 OP_NIL",
+            "check 7 3 OP_ADD"
+        ]
+    );
+    let _ = std::fs::remove_file(&trace_path);
+}
+
+#[test]
+fn call_main_flag() {
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .args(args)
+            .output()
+            .expect("Could not run rust-lox")
+    };
+    let output = run(&["--call-main", "tests/fixtures/cli/main.lox", "3"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "main 3"), "{}", stdout);
+
+    // without the flag, main is just a function
+    let output = run(&["tests/fixtures/cli/main.lox", "3"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("main 3"));
+
+    let output = run(&["--call-main", "tests/fixtures/cli/main_missing.lox"]);
+    assert_eq!(output.status.code(), Some(70));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr, "Undefined function 'main'.\n");
+}
+
+#[test]
+fn options_end_at_the_path() {
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+            .args(args)
+            .output()
+            .expect("Could not run rust-lox");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let path = "tests/fixtures/cli/args.lox";
+    // options after the path are arguments of the script
+    let stdout = run(&["--opt", path, "--opt", "x"]);
+    assert!(
+        stdout.lines().any(|line| line == "args: --opt x"),
+        "{}",
+        stdout
+    );
+    // and so is everything after `--`
+    let stdout = run(&["--opt", "--", path, "--", "a"]);
+    assert!(
+        stdout.lines().any(|line| line == "args: -- a"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn usage_lists_every_option() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-lox"))
+        .args(["--lang-level", "newest"])
+        .output()
+        .expect("Could not run rust-lox");
+    assert_eq!(output.status.code(), Some(64));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for option in [
+        "--audit",
+        "--call-main",
+        "--checked-math",
+        "--compat-bare-natives",
+        "--deny-warnings",
+        "--dev",
+        "--integers",
+        "--lang-level",
+        "--max-errors",
+        "--opt",
+        "--paranoid",
+        "--prelude",
+        "--runtime-warn",
+        "--strict-bool",
+        "--strip-lines",
+        "--trace-file",
+        "--trace-filter",
+        "--trace-last",
+        "--warn",
+    ] {
+        assert!(
+            stderr
+                .lines()
+                .any(|line| line.starts_with(&format!("  {} ", option))),
+            "{} is missing from:\n{}",
+            option,
+            stderr
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// Runs the core of every example in `examples/`, so API changes that break them fail `cargo test`
+use std::fs;
+use std::path::Path;
+
+#[path = "../examples/embed_config.rs"]
+mod embed_config;
+#[path = "../examples/native_extension.rs"]
+mod native_extension;
+#[path = "../examples/plugin_host.rs"]
+mod plugin_host;
+#[path = "../examples/precompiled.rs"]
+mod precompiled;
+#[path = "../examples/repl_custom.rs"]
+mod repl_custom;
+
+#[test]
+fn embed_config() {
+    assert_eq!(
+        embed_config::load("production", 4.0),
+        Ok(embed_config::Config {
+            name: "server-production".to_owned(),
+            workers: 10.0,
+            debug: false,
+        })
+    );
+    let staging = embed_config::load("staging", 2.0).unwrap();
+    assert_eq!(staging.workers, 4.0);
+    assert!(staging.debug);
+}
+
+#[test]
+fn native_extension() {
+    assert_eq!(
+        native_extension::run(
+            "print clamp(15, 0, 10);\nprint text.upper(\"lox\") + text.repeat(\"!\", 3);"
+        ),
+        "10\nLOX!!!\n"
+    );
+    assert_eq!(
+        native_extension::run("clamp(\"a\", 0, 1);"),
+        "clamp() expects numbers.\n[line 1] in <script>"
+    );
+}
+
+#[test]
+fn precompiled() {
+    let (prices, compiled) =
+        precompiled::price_orders(&[(1.0, 5.0), (10.0, 5.0), (3.0, 2.5)]).unwrap();
+    assert_eq!(prices, vec![5.0, 45.0, 7.5]);
+    // the rule was compiled once
+    assert_eq!(compiled, 1);
+}
+
+#[test]
+fn repl_custom() {
+    let input = "var x = 1;\nprint x + 1;\n:globals\nprint y;\nprint ;\nprint x;\n";
+    let responses = repl_custom::session(input.as_bytes());
+    assert_eq!(responses.len(), 6);
+    assert_eq!(responses[0], "");
+    assert_eq!(responses[1], "2\n");
+    assert!(responses[2].contains("x = 1\n"));
+    assert!(responses[2].contains("_lastError = nil\n"));
+    assert_eq!(
+        responses[3],
+        "Undefined variable 'y'.\n[file <repl>, line 1] in <script>\n"
+    );
+    assert_eq!(responses[4], "[line 1] Error: Expect expression.\n");
+    // the errors are gone on the next line
+    assert_eq!(responses[5], "1\n");
+}
+
+#[test]
+fn plugin_host() {
+    // plugins are copied, so the test can change one of them
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/plugins");
+    let dir = std::env::temp_dir().join(format!("rust-lox-plugins-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for name in ["counter.lox", "greeter.lox", "runaway.lox"] {
+        fs::copy(fixtures.join(name), dir.join(name)).unwrap();
+    }
+
+    let mut host = plugin_host::PluginHost::load_dir(&dir, 1_000).unwrap();
+    let greeter = host.plugin("greeter").unwrap();
+    assert_eq!(greeter.version, 2.0);
+    assert!(greeter.disabled.is_none());
+    let results = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, result)| (name.to_string(), result.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        host.dispatch("start"),
+        results(&[
+            ("counter", "1"),
+            ("greeter", "hello start"),
+            ("runaway", "ok")
+        ])
+    );
+
+    // a plugin running out of gas is disabled, the others still get the event
+    assert_eq!(
+        host.dispatch("spin"),
+        results(&[("counter", "2"), ("greeter", "hello spin")])
+    );
+    let disabled = host.plugin("runaway").unwrap().disabled.as_ref().unwrap();
+    assert!(
+        disabled.starts_with("Gas limit (1000) exhausted."),
+        "{}",
+        disabled
+    );
+    assert_eq!(
+        host.dispatch("stop"),
+        results(&[("counter", "3"), ("greeter", "hello stop")])
+    );
+
+    // a changed plugin is reloaded: its script runs again, which also resets `seen`
+    let counter = fs::read_to_string(dir.join("counter.lox")).unwrap();
+    let counter = counter.replace("return seen;", "return seen * 100;");
+    fs::write(dir.join("counter.lox"), counter).unwrap();
+    assert_eq!(host.reload_changed(), vec!["counter".to_owned()]);
+    assert_eq!(host.reload_changed(), Vec::<String>::new());
+    assert_eq!(
+        host.dispatch("again"),
+        results(&[("counter", "100"), ("greeter", "hello again")])
+    );
+
+    // fixing a disabled plugin enables it again
+    let runaway = fs::read_to_string(dir.join("runaway.lox")).unwrap();
+    fs::write(dir.join("runaway.lox"), runaway.replace("while", "if")).unwrap();
+    assert_eq!(host.reload_changed(), vec!["runaway".to_owned()]);
+    assert_eq!(host.dispatch("spin").len(), 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
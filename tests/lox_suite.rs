@@ -0,0 +1,43 @@
+// Runs the golden scripts in `tests/scripts/` (see `src/golden.rs`).
+//   LOX_TEST_FILTER=closures/*  only runs the scripts whose path matches the glob
+//   LOX_TEST_BLESS=1            rewrites the expectations of failing scripts from their output
+use std::env;
+use std::path::Path;
+
+use rust_lox::glob::Pattern;
+use rust_lox::golden::{bless_file, discover, run_golden_file};
+
+#[test]
+fn lox_suite() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let filter = env::var("LOX_TEST_FILTER")
+        .ok()
+        .map(|filter| Pattern::parse(&filter).expect("invalid LOX_TEST_FILTER"));
+    let bless = env::var("LOX_TEST_BLESS").is_ok_and(|bless| bless == "1");
+
+    let scripts = discover(&dir, filter.as_ref()).unwrap();
+    if filter.is_none() {
+        assert!(scripts.len() >= 5, "the suite is missing scripts");
+    }
+    let mut failures = Vec::new();
+    for path in &scripts {
+        let result = run_golden_file(path).unwrap();
+        if result.is_success() {
+            continue;
+        }
+        if bless {
+            match bless_file(&result) {
+                Ok(diff) => {
+                    println!("blessed {}\n{}", path.display(), diff);
+                    continue;
+                }
+                Err(error) => failures.push(format!("{}: {}", path.display(), error)),
+            }
+        }
+        for failure in result.failures() {
+            failures.push(format!("{}: {}", path.display(), failure));
+        }
+    }
+    println!("{} scripts", scripts.len());
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}